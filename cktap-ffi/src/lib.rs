@@ -1,6 +1,6 @@
 uniffi::setup_scaffolding!();
 
-use cktap_direct::apdu::{AppletSelect, CommandApdu, ResponseApdu, StatusResponse};
+use cktap_direct::apdu::{AppletSelect, CommandApdu, ParseMode, ResponseApdu, StatusResponse};
 use cktap_direct::{Error as CoreError, rand_nonce as core_rand_nonce};
 use std::fmt::Debug;
 
@@ -66,11 +66,11 @@ pub trait CkTransportFfi: Send + Sync + Debug + 'static {
 #[uniffi::export]
 pub async fn get_status(transport: Box<dyn CkTransportFfi>) -> Result<FfiStatusResponse, Error> {
     let cmd = AppletSelect::default();
-    let command_apdu = cmd.apdu_bytes();
+    let command_apdu = cmd.apdu_bytes()?;
     let rapdu = transport
         .transmit_apdu(command_apdu)
         .map_err(|e| Error::Transport { msg: e.to_string() })?;
-    let response = StatusResponse::from_cbor(rapdu)?;
+    let response = StatusResponse::from_cbor(rapdu, ParseMode::Lenient)?;
     Ok(response.into())
 }
 
@@ -0,0 +1,148 @@
+//! Audit log of the authenticated commands, set in config.toml with `audit_log = "path"`: every
+//! command sent with the CVC (sign, derive, xpub, change, backup, new, unseal, ...) is appended
+//! to the file as a JSON line with the time, the card, the command and how it went. The CVC is
+//! never written.
+
+use crate::error_code::ErrorCode;
+use crate::output::{Event, emit};
+use anyhow::{Context, Result};
+use cktap_direct::Error;
+use cktap_direct::audit::{self, AuditLog};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    /// Unix time in seconds
+    time: u64,
+    card_ident: &'a str,
+    command: &'static str,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The OS user running the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+impl<'a> Entry<'a> {
+    fn new(card_ident: &'a str, command: &'static str, outcome: Result<(), &Error>) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            card_ident,
+            command,
+            success: outcome.is_ok(),
+            error_code: outcome.err().map(|e| ErrorCode::of(e)),
+            error: outcome.err().map(ToString::to_string),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .ok(),
+        }
+    }
+}
+
+/// Appends to the file, `--all-readers` and `serve` send commands from several tasks
+struct JsonLines {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl JsonLines {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn append(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow::anyhow!("audit log lock poisoned"))?;
+        // one write per line, so lines from other processes appending don't interleave
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl AuditLog for JsonLines {
+    fn auth_attempt(&self, ident: &str, command: &'static str, outcome: Result<(), &Error>) {
+        if let Err(e) = self.append(&Entry::new(ident, command, outcome)) {
+            emit(Event::Warning {
+                message: &format!(
+                    "Audit log {path} not written: {e:#}",
+                    path = self.path.display()
+                ),
+            });
+        }
+    }
+}
+
+/// Append the authenticated commands to `path` from now on
+pub fn install(path: &Path) -> Result<()> {
+    audit::set_audit_log(JsonLines::open(path)?).context("Failed to set up the audit log")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cktap_direct::apdu::CkTapError;
+
+    #[test]
+    fn test_audit_entries() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "cktap-direct-audit-{pid}/audit.jsonl",
+            pid = std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let log = JsonLines::open(&path)?;
+        log.auth_attempt("CARD-0279BE66", "sign", Ok(()));
+        log.auth_attempt(
+            "CARD-0279BE66",
+            "unseal",
+            Err(&Error::CkTap(CkTapError::BadAuth)),
+        );
+        // opened again, it appends
+        JsonLines::open(&path)?.auth_attempt("CARD-1234ABCD", "xpub", Ok(()));
+
+        let text = std::fs::read_to_string(&path)?;
+        let entries: Vec<serde_json::Value> = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["card_ident"], "CARD-0279BE66");
+        assert_eq!(entries[0]["command"], "sign");
+        assert_eq!(entries[0]["success"], true);
+        assert!(entries[0].get("error_code").is_none());
+        assert_eq!(entries[1]["success"], false);
+        assert_eq!(entries[1]["error_code"], "bad_auth");
+        assert_eq!(entries[2]["command"], "xpub");
+        assert!(entries[2]["time"].as_u64().is_some_and(|time| time > 0));
+
+        let _ = std::fs::remove_dir_all(path.parent().expect("temp dir"));
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! Backup reminders before signing, set in config.toml: a TapSigner never backed up, or one
+//! that signed `backup_reminder_after` times since its backup count last changed, warns (or
+//! with `require_backup = true` refuses to sign). The signatures are counted per card in
+//! `backups.json` in the config directory.
+
+use crate::output::{Event, emit};
+use crate::{card_ident, config};
+use anyhow::{Context, Result};
+use bitcoin::secp256k1::PublicKey;
+use cktap_direct::backup_policy::{BackupReminder, BackupRule, BackupState};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::{CkTapCard, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// `--all-readers` and `serve` sign from several tasks, each updating the file
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counters {
+    cards: BTreeMap<String, BackupState>,
+}
+
+impl Counters {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid backup counters {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read backup counters {}", path.display()))
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write backup counters {}", path.display()))
+    }
+}
+
+/// The rule of the config
+fn rule() -> BackupRule {
+    let config = config::get();
+    BackupRule {
+        max_signatures: config.backup_reminder_after,
+        enforce: config.require_backup,
+    }
+}
+
+fn path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("backups.json"))
+}
+
+/// Have a TapSigner or SatsChip check its backups before signing
+pub fn install<T: CkTransport>(card: CkTapCard<T>) -> CkTapCard<T> {
+    match card {
+        CkTapCard::TapSigner(ts) => CkTapCard::TapSigner(ts.with_backup_policy(before_sign)),
+        CkTapCard::SatsChip(ts) => CkTapCard::SatsChip(ts.with_backup_policy(before_sign)),
+        CkTapCard::SatsCard(sc) => CkTapCard::SatsCard(sc),
+    }
+}
+
+/// The [`cktap_direct::backup_policy::BackupPolicy`] of the CLI: warn about the reminder due, or
+/// refuse to sign with `require_backup`
+fn before_sign(card_pubkey: &PublicKey, num_backups: Option<usize>) -> Result<(), Error> {
+    let rule = rule();
+    let ident = card_ident(card_pubkey);
+    let reminder = match (rule.max_signatures, path()) {
+        (Some(_), Some(path)) => count(&path, &ident, num_backups, &rule).unwrap_or_else(|e| {
+            emit(Event::Warning {
+                message: &format!("Backup counter not updated: {e:#}"),
+            });
+            None
+        }),
+        // nothing to count, only cards never backed up remind
+        _ => BackupState::default().record_signature(num_backups, &rule),
+    };
+    match reminder {
+        Some(reminder) if rule.enforce => Err(Error::BackupRequired(format!(
+            "{reminder}, run `tapsigner backup` first (require_backup is set)"
+        ))),
+        Some(reminder) => {
+            emit(Event::Warning {
+                message: &message(&ident, reminder),
+            });
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+fn message(ident: &str, reminder: BackupReminder) -> String {
+    match reminder {
+        BackupReminder::NeverBackedUp => {
+            format!("{ident} was never backed up, run `backup` before funding it")
+        }
+        BackupReminder::Stale { .. } => format!("{ident}: {reminder}, consider a new backup"),
+    }
+}
+
+/// Count a signature of the card in the file, returning the reminder due
+fn count(
+    path: &Path,
+    ident: &str,
+    num_backups: Option<usize>,
+    rule: &BackupRule,
+) -> Result<Option<BackupReminder>> {
+    let _lock = FILE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut counters = Counters::load(path)?;
+    let reminder = counters
+        .cards
+        .entry(ident.to_string())
+        .or_default()
+        .record_signature(num_backups, rule);
+    counters.save(path)?;
+    Ok(reminder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "cktap-direct-backups-{pid}/backups.json",
+            pid = std::process::id()
+        ));
+        let rule = BackupRule {
+            max_signatures: Some(2),
+            enforce: false,
+        };
+        assert_eq!(count(&path, "CARD-1", Some(1), &rule)?, None);
+        assert_eq!(count(&path, "CARD-2", Some(1), &rule)?, None);
+        assert_eq!(count(&path, "CARD-1", Some(1), &rule)?, None);
+        assert_eq!(
+            count(&path, "CARD-1", Some(1), &rule)?,
+            Some(BackupReminder::Stale { signatures: 2 })
+        );
+        // a new backup starts over
+        assert_eq!(count(&path, "CARD-1", Some(2), &rule)?, None);
+        let counters = Counters::load(&path)?;
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        assert_eq!(counters.cards["CARD-1"].signatures, 1);
+        assert_eq!(counters.cards["CARD-2"].signatures, 1);
+        Ok(())
+    }
+}
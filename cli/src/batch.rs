@@ -0,0 +1,393 @@
+//! Run a script of commands against one card session: one connection, and the CVC asked once.
+//!
+//! ```json
+//! {
+//!   "on_error": "continue",
+//!   "commands": [
+//!     "tapsigner init",
+//!     ["tapsigner", "derive", "--path", "84,0,0"],
+//!     "tapsigner xpub",
+//!     "forget"
+//!   ]
+//! }
+//! ```
+//!
+//! The CVC is only kept in memory, for `--cvc-ttl` seconds after it was entered. The `forget`
+//! command drops it, and the card rejecting it drops it too, so the next command asks again.
+
+use crate::deadline::{self, Phase};
+use crate::error_code::ErrorCode;
+use crate::output::*;
+use crate::{
+    CARD_POLL_INTERVAL, Commands, ConfirmArgs, ConnectArgs, DebugCommand, connect, debug,
+    handle_auto_command, handle_satscard_command, handle_satschip_command,
+    handle_tapsigner_command,
+};
+use anyhow::{Context, Result, bail};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::{CkTapCard, Cvc};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What to do when a command fails
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OnError {
+    #[default]
+    Stop,
+    Continue,
+}
+
+/// A command as a line split on whitespace, or as its arguments
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScriptCommand {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl ScriptCommand {
+    fn args(&self) -> Vec<String> {
+        match self {
+            ScriptCommand::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+            ScriptCommand::Args(args) => args.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Script {
+    #[serde(default)]
+    on_error: OnError,
+    commands: Vec<ScriptCommand>,
+}
+
+/// A script command, parsed like the command line without the global options
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct ScriptLine {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// A script command: forget the CVC, or a card command
+enum Step {
+    Forget,
+    Run(Commands),
+}
+
+impl Step {
+    fn parse(args: &[String]) -> Result<Self, clap::Error> {
+        if args == ["forget"] {
+            return Ok(Step::Forget);
+        }
+        ScriptLine::try_parse_from(args).map(|parsed| Step::Run(parsed.command))
+    }
+}
+
+/// The CVC cache of a running batch
+struct SessionCvc {
+    /// How long the CVC is kept after it was entered, zero to ask every time
+    ttl: Duration,
+    cached: Option<(Cvc, Instant)>,
+}
+
+impl SessionCvc {
+    fn cache(&mut self, cvc: &Cvc) {
+        self.cached =
+            (!self.ttl.is_zero()).then(|| (Cvc::from(cvc.expose_secret()), Instant::now()));
+    }
+}
+
+/// The CVC of the running batch, `None` outside a batch
+static SESSION_CVC: Mutex<Option<SessionCvc>> = Mutex::new(None);
+
+fn lock_session() -> Result<MutexGuard<'static, Option<SessionCvc>>> {
+    SESSION_CVC
+        .lock()
+        .map_err(|_| anyhow::anyhow!("CVC lock poisoned"))
+}
+
+/// The CVC to use: asked with `prompt` outside a batch, and within one only when none was
+/// entered in the last `--cvc-ttl` seconds
+pub fn session_cvc(prompt: impl FnOnce() -> Result<Cvc>) -> Result<Cvc> {
+    let mut session = lock_session()?;
+    let Some(session) = &mut *session else {
+        return prompt();
+    };
+    if let Some((cvc, entered)) = &session.cached
+        && entered.elapsed() < session.ttl
+    {
+        return Ok(Cvc::from(cvc.expose_secret()));
+    }
+    let cvc = prompt()?;
+    session.cache(&cvc);
+    Ok(cvc)
+}
+
+/// Keep using `cvc` for the rest of the batch, after a command changed it
+pub fn remember_cvc(cvc: &Cvc) {
+    if let Ok(mut session) = SESSION_CVC.lock()
+        && let Some(session) = &mut *session
+    {
+        session.cache(cvc);
+    }
+}
+
+/// Drop the batch's cached CVC, the next command that needs it asks again
+fn forget_cvc() {
+    if let Ok(mut session) = SESSION_CVC.lock()
+        && let Some(session) = &mut *session
+    {
+        session.cached = None;
+    }
+}
+
+/// Run the commands of the script at `file` in order, then print each command's response
+pub async fn run_batch(
+    file: &Path,
+    cvc_ttl: Duration,
+    connection: &ConnectArgs,
+    format: OutputFormat,
+    confirm: ConfirmArgs,
+) -> Result<()> {
+    let script = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read batch file {path}", path = file.display()))?;
+    let script: Script = serde_json::from_str(&script)
+        .with_context(|| format!("Invalid batch file {path}", path = file.display()))?;
+
+    // parse everything first, a typo shouldn't leave the card half provisioned
+    let commands = script
+        .commands
+        .iter()
+        .map(|command| {
+            let args = command.args();
+            let line = args.join(" ");
+            Step::parse(&args)
+                .map(|step| (line.clone(), args, step))
+                .with_context(|| format!("Invalid batch command '{line}'"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut card = connect(connection).await?;
+    confirm.guard_version(&mut card);
+    *lock_session()? = Some(SessionCvc {
+        ttl: cvc_ttl,
+        cached: None,
+    });
+
+    let total = commands.len();
+    let mut results = Vec::with_capacity(total);
+    for (step, (line, args, command)) in commands.into_iter().enumerate() {
+        emit(Event::Progress {
+            step: step + 1,
+            total,
+            message: &line,
+        });
+
+        let Step::Run(command) = command else {
+            forget_cvc();
+            results.push(BatchResult {
+                command: line,
+                success: true,
+                response: serde_json::json!({"success": true}),
+            });
+            continue;
+        };
+
+        capture_responses();
+        let mut outcome = run_command(&mut card, command, confirm).await;
+        if outcome
+            .as_ref()
+            .is_err_and(|e| ErrorCode::of(e.as_ref()) == ErrorCode::CardRemoved)
+        {
+            // the card refuses a repeated state change, e.g. a second unseal of the same slot
+            outcome = match wait_for_card_back(&mut card, connection).await {
+                Ok(()) => {
+                    capture_responses();
+                    let command = ScriptLine::try_parse_from(&args)?.command;
+                    run_command(&mut card, command, confirm).await
+                }
+                Err(e) => Err(e),
+            };
+        }
+        let captured = captured_responses();
+        let response = match outcome {
+            Ok(()) => captured.into_iter().last().unwrap_or(Value::Null),
+            Err(e) => {
+                let error_code = ErrorCode::of(e.as_ref());
+                if error_code == ErrorCode::BadAuth {
+                    forget_cvc();
+                }
+                serde_json::to_value(CommandResponse::<()> {
+                    success: false,
+                    error: Some(format!("{e:#}")),
+                    error_code: Some(error_code),
+                    data: None,
+                })?
+            }
+        };
+        let success = response["success"].as_bool().unwrap_or(false);
+        results.push(BatchResult {
+            command: line,
+            success,
+            response,
+        });
+        if !success && script.on_error == OnError::Stop {
+            break;
+        }
+    }
+
+    if let Ok(mut session) = SESSION_CVC.lock() {
+        *session = None;
+    }
+    output_response(batch_response(total, results), format)
+}
+
+/// How long to wait for a card removed during a batch, without `--wait-for-card`
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait for the card to be put back after it was removed mid-command
+async fn wait_for_card_back<T: CkTransport>(
+    card: &mut CkTapCard<T>,
+    connection: &ConnectArgs,
+) -> Result<()> {
+    emit(Event::Info {
+        message: "Card removed, place it back on the reader",
+    });
+    let timeout = connection
+        .wait_for_card
+        .map_or(RECONNECT_TIMEOUT, Duration::from_secs);
+    deadline::enter(Phase::WaitingForCard);
+    card.reconnect(timeout, CARD_POLL_INTERVAL, tokio::time::sleep)
+        .await
+        .context("The card wasn't put back on the reader")?;
+    deadline::enter(Phase::Running);
+    Ok(())
+}
+
+fn batch_response(total: usize, results: Vec<BatchResult>) -> CommandResponse<BatchResponse> {
+    let failed = results.iter().filter(|result| !result.success).count();
+    let first_error_code = results.iter().find(|result| !result.success).map(|result| {
+        serde_json::from_value(result.response["error_code"].clone()).unwrap_or(ErrorCode::Other)
+    });
+    let skipped = total - results.len();
+    CommandResponse {
+        success: failed == 0,
+        error: (failed > 0)
+            .then(|| format!("{failed} of {total} commands failed, {skipped} not run")),
+        error_code: first_error_code,
+        data: Some(BatchResponse {
+            completed: results.len() - failed,
+            skipped,
+            results,
+        }),
+    }
+}
+
+/// Run one command on the session's card. Responses are captured, so they print as JSON.
+async fn run_command<T: CkTransport>(
+    card: &mut CkTapCard<T>,
+    command: Commands,
+    confirm: ConfirmArgs,
+) -> Result<()> {
+    let format = OutputFormat::Json;
+    match command {
+        Commands::Auto(cmd) => handle_auto_command(card, cmd, format, confirm).await,
+        Commands::Satscard(cmd) => handle_satscard_command(card, cmd, format, confirm).await,
+        Commands::Tapsigner(cmd) => handle_tapsigner_command(card, cmd, format).await,
+        Commands::Satschip(cmd) => handle_satschip_command(card, cmd, format).await,
+        Commands::Debug(DebugCommand::Apdu { apdu }) => debug::raw_apdu(card, &apdu, format).await,
+        _ => {
+            bail!("Only card commands (auto, satscard, tapsigner, satschip, debug) run in a batch")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() -> Result<()> {
+        let script: Script = serde_json::from_str(
+            r#"{"commands": ["tapsigner  derive --path 84,0,0", ["auto", "status"]]}"#,
+        )?;
+        assert_eq!(script.on_error, OnError::Stop);
+        assert_eq!(
+            script.commands[0].args(),
+            ["tapsigner", "derive", "--path", "84,0,0"]
+        );
+        assert!(ScriptLine::try_parse_from(script.commands[0].args()).is_ok());
+        assert!(ScriptLine::try_parse_from(script.commands[1].args()).is_ok());
+        assert!(ScriptLine::try_parse_from(["tapsigner", "fly"]).is_err());
+        assert!(matches!(
+            Step::parse(&["forget".to_string()]),
+            Ok(Step::Forget)
+        ));
+
+        let script: Script = serde_json::from_str(r#"{"on_error": "continue", "commands": []}"#)?;
+        assert_eq!(script.on_error, OnError::Continue);
+        assert!(serde_json::from_str::<Script>(r#"{"command": []}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_cvc() -> Result<()> {
+        let asked = std::cell::Cell::new(0);
+        let prompt = || {
+            asked.set(asked.get() + 1);
+            Ok(Cvc::from("123456"))
+        };
+        *lock_session()? = Some(SessionCvc {
+            ttl: Duration::from_secs(60),
+            cached: None,
+        });
+        session_cvc(prompt)?;
+        session_cvc(prompt)?;
+        assert_eq!(asked.get(), 1);
+        forget_cvc();
+        session_cvc(prompt)?;
+        assert_eq!(asked.get(), 2);
+
+        // expired
+        if let Some(session) = &mut *lock_session()? {
+            session.ttl = Duration::ZERO;
+        }
+        session_cvc(prompt)?;
+        assert_eq!(asked.get(), 3);
+
+        *lock_session()? = None;
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_response() {
+        let results = vec![
+            BatchResult {
+                command: "auto status".to_string(),
+                success: true,
+                response: serde_json::json!({"success": true}),
+            },
+            BatchResult {
+                command: "tapsigner read".to_string(),
+                success: false,
+                response: serde_json::json!({"success": false, "error_code": "bad_auth"}),
+            },
+        ];
+        let response = batch_response(3, results);
+        assert!(!response.success);
+        assert_eq!(response.error_code, Some(ErrorCode::BadAuth));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("1 of 3 commands failed, 1 not run")
+        );
+        let data = response.data.expect("batch data");
+        assert_eq!((data.completed, data.skipped), (1, 1));
+    }
+}
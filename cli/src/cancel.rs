@@ -0,0 +1,53 @@
+//! Ctrl-C handling: the first Ctrl-C stops the command before its next APDU, so the card is
+//! dropped normally, which powers it down and gives the reader back. A second Ctrl-C quits at
+//! once, e.g. while waiting at a prompt.
+
+use crate::output::{Event, emit};
+use anyhow::Result;
+use std::fmt;
+use std::sync::Arc;
+use tokio::signal;
+use tokio::sync::Notify;
+
+/// The command was stopped with Ctrl-C
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Exit code of a process killed by SIGINT
+pub const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+/// Run `command` until it finishes or Ctrl-C is pressed
+pub async fn until_interrupted(command: impl Future<Output = Result<()>>) -> Result<()> {
+    let interrupted = Arc::new(Notify::new());
+    let handler = tokio::spawn({
+        let interrupted = interrupted.clone();
+        async move {
+            if signal::ctrl_c().await.is_err() {
+                return;
+            }
+            emit(Event::Info {
+                message: "Interrupted, releasing the card (Ctrl-C again to quit now)",
+            });
+            interrupted.notify_one();
+            if signal::ctrl_c().await.is_ok() {
+                std::process::exit(INTERRUPTED_EXIT_CODE.into());
+            }
+        }
+    });
+
+    let result = tokio::select! {
+        biased;
+        () = interrupted.notified() => Err(Interrupted.into()),
+        result = command => result,
+    };
+    handler.abort();
+    result
+}
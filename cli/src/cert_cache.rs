@@ -0,0 +1,194 @@
+//! `--cert-cache`: remember the certificate chain verified for each card, so checking the same
+//! card again skips walking the chain, and with `--pin` notice a card whose chain changed, e.g.
+//! a look-alike swapped in with a copied ident.
+
+use anyhow::{Context, Result};
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::PublicKey;
+use cktap_direct::commands::VerifiedChain;
+use cktap_direct::factory_root_key::FactoryRootKey;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct CertCacheArgs {
+    /// Remember verified certificate chains in this file, keyed by card ident, and don't walk a
+    /// card's chain again while it stays the same
+    #[arg(long, value_name = "FILE")]
+    pub cert_cache: Option<PathBuf>,
+
+    /// Fail if a card already in the cache presents a different certificate chain
+    #[arg(long, requires = "cert_cache")]
+    pub pin: bool,
+}
+
+/// A card's certificate chain changed since it was cached
+#[derive(Debug)]
+pub struct ChainChanged {
+    pub ident: String,
+    pub verified_at: u64,
+}
+
+impl fmt::Display for ChainChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{ident} presents a different certificate chain than when it was verified (at {verified_at}, unix time)",
+            ident = self.ident,
+            verified_at = self.verified_at
+        )
+    }
+}
+
+impl std::error::Error for ChainChanged {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedChain {
+    card_pubkey: String,
+    root: String,
+    chain_hash: String,
+    /// Unix time of the first verification
+    verified_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CertCache {
+    cards: BTreeMap<String, CachedChain>,
+}
+
+/// `--all-readers` checks cards from several threads, each updating the file
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+impl CertCache {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid certificate cache {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read certificate cache {}", path.display())),
+        }
+    }
+
+    /// Write to a temporary file and rename it over the cache, so an interrupted write doesn't
+    /// lose the cards already pinned
+    fn save(&self, path: &Path) -> Result<()> {
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write certificate cache {}", path.display()))
+    }
+}
+
+/// The chain cached for `ident` when the cache is on, to pass to `check_certificate_chain`
+pub fn known_chain(args: &CertCacheArgs, ident: &str) -> Result<Option<VerifiedChain>> {
+    let Some(path) = &args.cert_cache else {
+        return Ok(None);
+    };
+    let _lock = FILE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let cache = CertCache::load(path)?;
+    // an entry that doesn't parse is walked again, and replaced
+    Ok(cache.cards.get(ident).and_then(|cached| {
+        let root = PublicKey::from_str(&cached.root).ok()?;
+        Some(VerifiedChain {
+            root: FactoryRootKey::try_from(root).ok()?,
+            chain_hash: sha256::Hash::from_str(&cached.chain_hash).ok()?,
+        })
+    }))
+}
+
+/// Record the chain just verified for `ident`. With `--pin`, a chain other than the cached one
+/// fails instead of replacing it.
+pub fn record(
+    args: &CertCacheArgs,
+    ident: &str,
+    card_pubkey: &PublicKey,
+    chain: &VerifiedChain,
+) -> Result<()> {
+    let Some(path) = &args.cert_cache else {
+        return Ok(());
+    };
+    let _lock = FILE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut cache = CertCache::load(path)?;
+    if let Some(cached) = cache.cards.get(ident) {
+        if cached.chain_hash == chain.chain_hash.to_string() {
+            return Ok(());
+        }
+        if args.pin {
+            return Err(ChainChanged {
+                ident: ident.to_string(),
+                verified_at: cached.verified_at,
+            }
+            .into());
+        }
+    }
+    let verified_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    cache.cards.insert(
+        ident.to_string(),
+        CachedChain {
+            card_pubkey: card_pubkey.to_string(),
+            root: chain.root.pubkey().to_string(),
+            chain_hash: chain.chain_hash.to_string(),
+            verified_at,
+        },
+    );
+    cache.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_pinning() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "cktap-direct-cert-cache-{pid}.json",
+            pid = std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut args = CertCacheArgs {
+            cert_cache: Some(path.clone()),
+            pin: true,
+        };
+        let root = PublicKey::from_str(
+            "03028a0e89e70d0ec0d932053a89ab1da7d9182bdc6d2f03e706ee99517d05d9e1",
+        )?;
+        let chain = VerifiedChain {
+            root: FactoryRootKey::try_from(root)?,
+            chain_hash: sha256::Hash::hash(b"chain"),
+        };
+
+        assert_eq!(known_chain(&args, "CARD-1")?, None);
+        record(&args, "CARD-1", &root, &chain)?;
+        assert_eq!(known_chain(&args, "CARD-1")?, Some(chain));
+
+        let swapped = VerifiedChain {
+            chain_hash: sha256::Hash::hash(b"other chain"),
+            ..chain
+        };
+        let error = record(&args, "CARD-1", &root, &swapped).unwrap_err();
+        assert!(error.is::<ChainChanged>());
+
+        // without pinning the new chain replaces the old one
+        args.pin = false;
+        record(&args, "CARD-1", &root, &swapped)?;
+        assert_eq!(known_chain(&args, "CARD-1")?, Some(swapped));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Chain backend: what an address holds, from the Esplora HTTP API of e.g. a local electrs or
+//! mempool instance, set with `esplora_url` in the config. Only plain `http://` is spoken, remote
+//! https servers need a TLS-terminating proxy in front.
+
+use crate::config;
+use crate::deadline::{self, Phase};
+use anyhow::{Context, Result, bail, ensure};
+use bitcoin::Amount;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Time the backend gets to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Largest answer read, address stats are a few hundred bytes
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// An Esplora API at `http://{host}{path}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Esplora {
+    host: String,
+    path: String,
+}
+
+impl Esplora {
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("Unsupported Esplora URL '{url}', only http:// is supported");
+        };
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        ensure!(!host.is_empty(), "Esplora URL '{url}' has no host");
+        Ok(Self {
+            host: host.to_string(),
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The backend set with `esplora_url`, if any
+    pub fn configured() -> Result<Option<Self>> {
+        config::get()
+            .esplora_url
+            .as_deref()
+            .map(Self::new)
+            .transpose()
+            .context("Invalid esplora_url in the config")
+    }
+
+    /// What `address` holds, unconfirmed transactions included
+    pub async fn balance(&self, address: &str) -> Result<Amount> {
+        deadline::enter(Phase::Chain);
+        let body = tokio::time::timeout(REQUEST_TIMEOUT, self.get(&format!("/address/{address}")))
+            .await
+            .context("Esplora didn't answer in time")??;
+        deadline::enter(Phase::Running);
+        let stats: AddressStats =
+            serde_json::from_slice(&body).context("Invalid address stats from Esplora")?;
+        Ok(stats.balance())
+    }
+
+    /// The body of the answer to `GET` `path` under the API
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{host}:80", host = self.host)
+        };
+        let mut stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("Failed to connect to Esplora at {address}"))?;
+        // HTTP/1.0, so the answer isn't chunked and ends with the connection
+        let request = format!(
+            "GET {prefix}{path} HTTP/1.0\r\nHost: {host}\r\nAccept: application/json\r\n\r\n",
+            prefix = self.path,
+            host = self.host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut response)
+            .await
+            .context("Failed to read the Esplora answer")?;
+        response_body(&response)
+    }
+}
+
+/// The body of an HTTP answer, an error unless its status is `200`
+fn response_body(response: &[u8]) -> Result<Vec<u8>> {
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("Invalid HTTP answer from Esplora")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+    let body = &response[end + 4..];
+    ensure!(
+        status == "200",
+        "Esplora answered {status}: {body}",
+        body = String::from_utf8_lossy(body).trim()
+    );
+    Ok(body.to_vec())
+}
+
+#[derive(Debug, Deserialize)]
+struct AddressStats {
+    chain_stats: TxoStats,
+    mempool_stats: TxoStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct TxoStats {
+    funded_txo_sum: u64,
+    spent_txo_sum: u64,
+}
+
+impl AddressStats {
+    fn balance(&self) -> Amount {
+        let funded = self.chain_stats.funded_txo_sum + self.mempool_stats.funded_txo_sum;
+        let spent = self.chain_stats.spent_txo_sum + self.mempool_stats.spent_txo_sum;
+        Amount::from_sat(funded.saturating_sub(spent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_esplora_url() -> Result<()> {
+        assert_eq!(
+            Esplora::new("http://127.0.0.1:3002/api/")?,
+            Esplora {
+                host: "127.0.0.1:3002".to_string(),
+                path: "/api".to_string()
+            }
+        );
+        assert_eq!(Esplora::new("http://electrs")?.path, "");
+        assert!(Esplora::new("https://mempool.space/api").is_err());
+        assert!(Esplora::new("http:///api").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{address}/api", address = listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = vec![0; 1024];
+            let read = stream.read(&mut request).await?;
+            stream
+                .write_all(
+                    b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n\
+                      {\"address\": \"bc1qexample\", \
+                       \"chain_stats\": {\"funded_txo_sum\": 150000, \"spent_txo_sum\": 50000, \"tx_count\": 2}, \
+                       \"mempool_stats\": {\"funded_txo_sum\": 2000, \"spent_txo_sum\": 0, \"tx_count\": 1}}",
+                )
+                .await?;
+            anyhow::Ok(String::from_utf8_lossy(&request[..read]).into_owned())
+        });
+
+        let balance = Esplora::new(&url)?.balance("bc1qexample").await?;
+        assert_eq!(balance, Amount::from_sat(102_000));
+        assert!(
+            server
+                .await??
+                .starts_with("GET /api/address/bc1qexample HTTP/1.0\r\n")
+        );
+
+        let error = response_body(b"HTTP/1.1 400 Bad Request\r\n\r\nInvalid Bitcoin address\n")
+            .map_err(|e| e.to_string());
+        assert_eq!(
+            error,
+            Err("Esplora answered 400: Invalid Bitcoin address".to_string())
+        );
+        Ok(())
+    }
+}
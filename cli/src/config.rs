@@ -0,0 +1,165 @@
+//! Optional settings file, `config.toml` in the config directory (`$XDG_CONFIG_HOME/cktap-direct`,
+//! `~/.config/cktap-direct` or `%APPDATA%\cktap-direct`) or the file given with `--config`.
+//!
+//! ```toml
+//! # block explorer for address links, `{address}` is replaced by the address, "" for no links
+//! explorer_url = "https://blockstream.info/address/{address}"
+//!
+//! # Esplora API (http:// only) checked for funds before `satscard unseal`
+//! esplora_url = "http://127.0.0.1:3002"
+//!
+//! # defaults for --reader, --network, --timeout-ms, --format and the emulator pipe, below the
+//! # flags and the CKTAP_* environment variables (see `settings`)
+//! reader = "076b:5422"
+//! network = "testnet"
+//! timeout_ms = 10000
+//! format = "plain"
+//! emulator = "/tmp/ecard-pipe"
+//!
+//! # keep a registry of the cards seen, cards.json next to this file (see `registry`)
+//! registry = true
+//!
+//! # warn before signing with a TapSigner that signed this many times since its last backup
+//! # (or was never backed up), and refuse to sign instead (see `backup_policy`)
+//! backup_reminder_after = 100
+//! require_backup = true
+//!
+//! # append every command sent with the CVC to this JSON lines file (see `audit`)
+//! audit_log = "/var/log/cktap-direct/audit.jsonl"
+//! ```
+
+use crate::explorer_url;
+use anyhow::{Context, Result};
+use bitcoin::Network;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub const DEFAULT_EXPLORER_URL: &str = "https://mempool.space/address/{address}";
+
+/// mempool.space's explorer for `network`, `None` for a local regtest chain
+pub fn default_explorer_url(network: Network) -> Option<&'static str> {
+    match network {
+        Network::Bitcoin => Some(DEFAULT_EXPLORER_URL),
+        Network::Testnet => Some("https://mempool.space/testnet/address/{address}"),
+        Network::Testnet4 => Some("https://mempool.space/testnet4/address/{address}"),
+        Network::Signet => Some("https://mempool.space/signet/address/{address}"),
+        _ => None,
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Block explorer URL template for address links, mempool.space for the address' network if
+    /// not set
+    explorer_url: Option<String>,
+    /// Esplora API telling what an address holds, no chain lookups if not set
+    pub esplora_url: Option<String>,
+    pub reader: Option<String>,
+    pub network: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub format: Option<String>,
+    pub emulator: Option<PathBuf>,
+    /// Record every card seen in the registry and warn about unknown ones
+    #[serde(default)]
+    pub registry: bool,
+    /// Signatures since the last backup before reminding, only cards never backed up remind if
+    /// not set
+    pub backup_reminder_after: Option<u64>,
+    /// Refuse to sign when a backup reminder is due
+    #[serde(default)]
+    pub require_backup: bool,
+    /// Append-only log of the authenticated commands, one JSON line each
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Link to `address`, on `network`, on the configured explorer, `None` if links are turned
+    /// off or there is no default explorer for the network
+    pub fn explorer_link(&self, address: &str, network: Network) -> Option<String> {
+        let template = match self.explorer_url.as_deref() {
+            Some(template) => template,
+            None => default_explorer_url(network)?,
+        };
+        (!template.is_empty()).then(|| explorer_url(template, address))
+    }
+}
+
+/// Directory of the CLI's settings and state files
+pub fn dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("cktap-direct"))
+}
+
+/// Read `path`, or the default config file if there is one
+pub fn load(path: Option<&Path>) -> Result<()> {
+    let config = match path {
+        Some(path) => read(path)?,
+        None => match dir().map(|dir| dir.join("config.toml")) {
+            Some(path) if path.exists() => read(&path)?,
+            _ => Config::default(),
+        },
+    };
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {path}", path = path.display()))?;
+    Config::parse(&text).with_context(|| format!("Invalid config {path}", path = path.display()))
+}
+
+/// The loaded settings, defaults if none were loaded
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explorer_link() -> Result<()> {
+        let address = "bc1qexample";
+        assert_eq!(
+            Config::default()
+                .explorer_link(address, Network::Bitcoin)
+                .as_deref(),
+            Some("https://mempool.space/address/bc1qexample")
+        );
+        assert_eq!(
+            Config::default()
+                .explorer_link("tb1qexample", Network::Testnet)
+                .as_deref(),
+            Some("https://mempool.space/testnet/address/tb1qexample")
+        );
+        assert_eq!(
+            Config::default().explorer_link("bcrt1qexample", Network::Regtest),
+            None
+        );
+
+        let config = Config::parse(r#"explorer_url = "https://blockstream.info/address/""#)?;
+        assert_eq!(
+            config.explorer_link(address, Network::Bitcoin).as_deref(),
+            Some("https://blockstream.info/address/bc1qexample")
+        );
+
+        assert_eq!(
+            Config::parse(r#"explorer_url = """#)?.explorer_link(address, Network::Bitcoin),
+            None
+        );
+        assert!(Config::parse("explorer = 1").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Non-interactive CVC sources for automation that shouldn't put the CVC in the environment:
+//! `--cvc-file` (first line of a file only its owner can read) and `--cvc-fd` (first line read
+//! from an inherited file descriptor, e.g. `--cvc-fd 3 3< <(pass show card)`).
+
+use anyhow::{Context, Result, bail};
+use cktap_direct::Cvc;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+static CVC: OnceLock<Cvc> = OnceLock::new();
+
+/// Read the CVC from `--cvc-file` or `--cvc-fd` once, at start
+pub fn load(file: Option<&Path>, fd: Option<u32>) -> Result<()> {
+    let cvc = match (file, fd) {
+        (Some(path), _) => read_file(path)
+            .with_context(|| format!("Failed to read CVC file {path}", path = path.display()))?,
+        (None, Some(fd)) => {
+            read_fd(fd).with_context(|| format!("Failed to read CVC from file descriptor {fd}"))?
+        }
+        (None, None) => return Ok(()),
+    };
+    let _ = CVC.set(cvc);
+    Ok(())
+}
+
+/// The CVC given with `--cvc-file` or `--cvc-fd`, if any
+pub fn given() -> Option<Cvc> {
+    CVC.get().map(|cvc| Cvc::from(cvc.expose_secret()))
+}
+
+fn read_file(path: &Path) -> Result<Cvc> {
+    let file = File::open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = file.metadata()?.permissions().mode();
+        if mode & 0o077 != 0 {
+            bail!(
+                "Other users can access it (mode {mode:o}), restrict it with chmod 600",
+                mode = mode & 0o777
+            );
+        }
+    }
+    first_line(file)
+}
+
+#[cfg(unix)]
+fn read_fd(fd: u32) -> Result<Cvc> {
+    // reopening through /dev/fd fails cleanly when the descriptor wasn't passed
+    first_line(File::open(format!("/dev/fd/{fd}"))?)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: u32) -> Result<Cvc> {
+    bail!("--cvc-fd is only supported on Unix")
+}
+
+fn first_line(reader: impl Read) -> Result<Cvc> {
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+    let cvc = Cvc::from(line);
+    if cvc.is_empty() {
+        bail!("No CVC on the first line");
+    }
+    Ok(cvc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_line() -> Result<()> {
+        let cvc = first_line("123456\nsecond line\n".as_bytes())?;
+        assert_eq!(cvc.expose_secret(), "123456");
+        assert!(first_line("\n123456".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_file_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("cktap-cvc-{pid}", pid = std::process::id()));
+        std::fs::write(&path, "123456\n")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        assert!(read_file(&path).is_err());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let cvc = read_file(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(cvc?.expose_secret(), "123456");
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! `--timeout`: one time budget for the whole command, USB exchanges, retries and waits included.
+//! Once it is spent the command stops at its next await point, with an error naming the phase it
+//! was in.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What the command is doing, for the error when the budget runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Starting,
+    /// Finding the reader and the card, `--wait-for-card` included
+    Connecting,
+    Running,
+    /// Waiting for the card to be put back on the reader
+    WaitingForCard,
+    /// Waiting out the delay the card imposes after wrong CVCs
+    AuthDelay,
+    /// Asking the chain backend what an address holds
+    Chain,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Starting => "starting",
+            Phase::Connecting => "connecting to the card",
+            Phase::Running => "running the command",
+            Phase::WaitingForCard => "waiting for the card",
+            Phase::AuthDelay => "waiting out the card's delay",
+            Phase::Chain => "querying the chain backend",
+        })
+    }
+}
+
+static PHASE: Mutex<Phase> = Mutex::new(Phase::Starting);
+
+/// Record that the command moved on to `phase`
+pub fn enter(phase: Phase) {
+    *PHASE.lock().unwrap_or_else(|e| e.into_inner()) = phase;
+}
+
+fn current() -> Phase {
+    *PHASE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The command took longer than `--timeout`
+#[derive(Debug)]
+pub struct TimedOut {
+    pub phase: Phase,
+    pub budget: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out after {budget:?} while {phase}",
+            budget = self.budget,
+            phase = self.phase
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Run `command`, failing with [`TimedOut`] if it takes longer than `budget`
+pub async fn within(
+    budget: Option<Duration>,
+    command: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let Some(budget) = budget else {
+        return command.await;
+    };
+    match tokio::time::timeout(budget, command).await {
+        Ok(result) => result,
+        Err(_) => Err(TimedOut {
+            phase: current(),
+            budget,
+        }
+        .into()),
+    }
+}
+
+/// Parse a `--timeout` like `30s`, `500ms` or `2m`, seconds without a unit
+pub fn parse_timeout(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid timeout '{s}', expected e.g. 30s"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => anyhow::bail!("Invalid timeout unit '{unit}', expected ms, s or m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timeout() -> Result<()> {
+        assert_eq!(parse_timeout("30s")?, Duration::from_secs(30));
+        assert_eq!(parse_timeout("45")?, Duration::from_secs(45));
+        assert_eq!(parse_timeout("500ms")?, Duration::from_millis(500));
+        assert_eq!(parse_timeout("2m")?, Duration::from_secs(120));
+        assert!(parse_timeout("s").is_err());
+        assert!(parse_timeout("10h").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_within() {
+        let slow = async {
+            enter(Phase::Connecting);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        };
+        let error = within(Some(Duration::from_millis(10)), slow)
+            .await
+            .map_err(|e| e.to_string());
+        assert_eq!(
+            error,
+            Err("Timed out after 10ms while connecting to the card".to_string())
+        );
+        assert!(within(None, async { Ok(()) }).await.is_ok());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::output::*;
+use anyhow::{Context, Result};
+use ciborium::Value as Cbor;
+use cktap_direct::CkTapCard;
+use cktap_direct::commands::CkTransport;
+use cktap_direct::secp256k1::hashes::hex::{DisplayHex, FromHex};
+use serde_json::{Map, Value};
+
+/// Convert a CBOR value to JSON for display, byte strings become hex
+fn cbor_to_json(cbor: &Cbor) -> Value {
+    match cbor {
+        Cbor::Integer(i) => {
+            let i = i128::from(*i);
+            i64::try_from(i).map_or_else(|_| Value::from(i.to_string()), Value::from)
+        }
+        Cbor::Bytes(bytes) => Value::from(bytes.as_hex().to_string()),
+        Cbor::Float(f) => Value::from(*f),
+        Cbor::Text(text) => Value::from(text.as_str()),
+        Cbor::Bool(b) => Value::from(*b),
+        Cbor::Null => Value::Null,
+        Cbor::Tag(_, value) => cbor_to_json(value),
+        Cbor::Array(values) => Value::Array(values.iter().map(cbor_to_json).collect()),
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Cbor::Text(text) => text.clone(),
+                        other => cbor_to_json(other).to_string(),
+                    };
+                    (key, cbor_to_json(value))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        _ => Value::Null,
+    }
+}
+
+/// Send a raw APDU (hex) to the card, after the applet was selected when connecting
+pub async fn raw_apdu<T: CkTransport>(
+    card: &CkTapCard<T>,
+    apdu_hex: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let apdu = Vec::<u8>::from_hex(apdu_hex.trim())
+        .with_context(|| format!("Invalid APDU '{apdu_hex}', expected hex"))?;
+    let transport = match card {
+        CkTapCard::SatsCard(sc) => &sc.transport,
+        CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => &ts.transport,
+    };
+
+    let response = transport
+        .transmit_raw(apdu.clone())
+        .await
+        .context("Failed to transmit APDU")?;
+
+    let result = RawApduResponse {
+        apdu: apdu.as_hex().to_string(),
+        response: response.body.as_hex().to_string(),
+        sw: response.sw.map(|sw| format!("{sw:04x}")),
+        cbor: response.cbor.as_ref().map(cbor_to_json),
+    };
+    output_response(success_response(result), format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cbor_to_json() {
+        let cbor = Cbor::Map(vec![
+            (Cbor::from("proto"), Cbor::from(1)),
+            (Cbor::from("pubkey"), Cbor::Bytes(vec![0x02, 0xab])),
+            (
+                Cbor::from("path"),
+                Cbor::Array(vec![Cbor::from(84), Cbor::from(0)]),
+            ),
+            (Cbor::from("tapsigner"), Cbor::Bool(true)),
+        ]);
+        assert_eq!(
+            cbor_to_json(&cbor),
+            serde_json::json!({
+                "proto": 1,
+                "pubkey": "02ab",
+                "path": [84, 0],
+                "tapsigner": true,
+            })
+        );
+    }
+}
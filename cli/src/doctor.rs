@@ -0,0 +1,269 @@
+use crate::output::*;
+use crate::readers::ReaderSelector;
+use anyhow::Result;
+use cktap_direct::CkTapCard;
+use cktap_direct::discovery::{self, CcidDeviceInfo};
+
+/// udev rules giving the logged in user access to Coinkite devices and CCID readers
+const UDEV_RULES: &str = r#"# udev rules for cktap-direct, install with:
+#   cktap-direct doctor --print-udev | sudo tee /etc/udev/rules.d/70-cktap.rules
+#   sudo udevadm control --reload-rules && sudo udevadm trigger
+
+# Coinkite
+SUBSYSTEM=="usb", ATTRS{idVendor}=="d13e", MODE="0660", GROUP="plugdev", TAG+="uaccess"
+# HID Global OMNIKEY
+SUBSYSTEM=="usb", ATTRS{idVendor}=="076b", MODE="0660", GROUP="plugdev", TAG+="uaccess"
+# ACS (ACR122U, ACR1252U, ...)
+SUBSYSTEM=="usb", ATTRS{idVendor}=="072f", MODE="0660", GROUP="plugdev", TAG+="uaccess"
+# Identiv / SCM Microsystems
+SUBSYSTEM=="usb", ATTRS{idVendor}=="04e6", MODE="0660", GROUP="plugdev", TAG+="uaccess"
+# any other reader with a smart card (CCID) interface
+SUBSYSTEM=="usb", ENV{DEVTYPE}=="usb_device", ENV{ID_USB_INTERFACES}=="*:0b0000:*", MODE="0660", GROUP="plugdev", TAG+="uaccess"
+"#;
+
+const STOP_PCSCD: &str =
+    "Stop pcscd while using cktap-direct: sudo systemctl stop pcscd.socket pcscd";
+const INSTALL_UDEV: &str = "Install udev rules: cktap-direct doctor --print-udev | sudo tee /etc/udev/rules.d/70-cktap.rules, then sudo udevadm control --reload-rules && sudo udevadm trigger and replug the reader";
+
+fn check(name: &str, status: CheckStatus, detail: String, fix: Option<&str>) -> DoctorCheck {
+    DoctorCheck {
+        name: name.to_string(),
+        status,
+        detail,
+        fix: fix.map(str::to_string),
+    }
+}
+
+fn describe(info: &CcidDeviceInfo) -> String {
+    let name = info
+        .product
+        .as_deref()
+        .or(info.manufacturer.as_deref())
+        .unwrap_or("unknown reader");
+    format!("{name} ({:04x}:{:04x})", info.vendor_id, info.product_id)
+}
+
+fn pcscd_check() -> DoctorCheck {
+    match discovery::pcscd_running() {
+        Some(true) => check(
+            "pcscd",
+            CheckStatus::Warn,
+            "pcscd is running and may hold the reader".to_string(),
+            Some(STOP_PCSCD),
+        ),
+        Some(false) => check(
+            "pcscd",
+            CheckStatus::Ok,
+            "pcscd is not running".to_string(),
+            None,
+        ),
+        None => check(
+            "pcscd",
+            CheckStatus::Ok,
+            "not checked on this platform".to_string(),
+            None,
+        ),
+    }
+}
+
+/// Checks on the readers found: presence, permissions and kernel driver claims
+fn reader_checks(devices: &[CcidDeviceInfo]) -> Vec<DoctorCheck> {
+    let readers: Vec<&CcidDeviceInfo> = devices.iter().filter(|info| info.is_ccid).collect();
+    if readers.is_empty() {
+        return vec![check(
+            "readers",
+            CheckStatus::Fail,
+            "no CCID reader found".to_string(),
+            Some("Plug in a USB NFC/smart card reader, or check `lsusb` lists it"),
+        )];
+    }
+
+    let mut checks = vec![check(
+        "readers",
+        CheckStatus::Ok,
+        readers
+            .iter()
+            .map(|info| describe(info))
+            .collect::<Vec<_>>()
+            .join(", "),
+        None,
+    )];
+
+    let inaccessible: Vec<String> = readers
+        .iter()
+        .filter(|info| !info.accessible)
+        .map(|info| describe(info))
+        .collect();
+    checks.push(if inaccessible.is_empty() {
+        check(
+            "permissions",
+            CheckStatus::Ok,
+            "all readers can be opened".to_string(),
+            None,
+        )
+    } else {
+        check(
+            "permissions",
+            CheckStatus::Fail,
+            format!("no permission to open {}", inaccessible.join(", ")),
+            Some(INSTALL_UDEV),
+        )
+    });
+
+    let claimed: Vec<String> = readers
+        .iter()
+        .filter(|info| info.kernel_driver_active == Some(true))
+        .map(|info| describe(info))
+        .collect();
+    checks.push(if claimed.is_empty() {
+        check(
+            "kernel driver",
+            CheckStatus::Ok,
+            "no kernel driver bound to the readers".to_string(),
+            None,
+        )
+    } else {
+        check(
+            "kernel driver",
+            CheckStatus::Warn,
+            format!(
+                "a kernel driver is bound to {}, it will be detached when connecting",
+                claimed.join(", ")
+            ),
+            Some(STOP_PCSCD),
+        )
+    });
+
+    checks
+}
+
+async fn card_check(reader: Option<&ReaderSelector>) -> DoctorCheck {
+    let card = match reader {
+        Some(reader) => discovery::find_matching(|info| reader.matches(info)).await,
+        None => discovery::find_first().await,
+    };
+    match card {
+        Ok(card) => {
+            let card_type = match card {
+                CkTapCard::SatsCard(_) => "SATSCARD",
+                CkTapCard::TapSigner(_) => "TAPSIGNER",
+                CkTapCard::SatsChip(_) => "SATSCHIP",
+            };
+            check(
+                "card",
+                CheckStatus::Ok,
+                format!("found a {card_type}"),
+                None,
+            )
+        }
+        Err(e) => check(
+            "card",
+            CheckStatus::Fail,
+            format!("no card found: {e}"),
+            Some("Place the card on the reader and keep it still"),
+        ),
+    }
+}
+
+fn print_checks(checks: &[DoctorCheck]) {
+    for check in checks {
+        let status = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "skip",
+        };
+        println!(
+            "[{status:>4}] {name}: {detail}",
+            name = check.name,
+            detail = check.detail
+        );
+        if let Some(fix) = &check.fix {
+            println!("       fix: {fix}");
+        }
+    }
+}
+
+/// Diagnose why no card can be used: USB access, readers, permissions, pcscd and the card itself
+pub async fn doctor(
+    print_udev: bool,
+    reader: Option<&ReaderSelector>,
+    format: OutputFormat,
+) -> Result<()> {
+    if print_udev {
+        print!("{UDEV_RULES}");
+        return Ok(());
+    }
+
+    let mut checks = Vec::new();
+    match discovery::list_devices() {
+        Ok(devices) => {
+            checks.push(check(
+                "usb",
+                CheckStatus::Ok,
+                "USB access works".to_string(),
+                None,
+            ));
+            checks.push(pcscd_check());
+            let readers = reader_checks(&devices);
+            let reader_found = readers[0].status == CheckStatus::Ok;
+            checks.extend(readers);
+            if reader_found {
+                checks.push(card_check(reader).await);
+            }
+        }
+        Err(e) => checks.push(check(
+            "usb",
+            CheckStatus::Fail,
+            format!("can't access USB devices: {e}"),
+            Some(
+                "Check libusb is installed and /dev/bus/usb is available (e.g. inside containers)",
+            ),
+        )),
+    }
+
+    match format {
+        OutputFormat::Json | OutputFormat::Ndjson | OutputFormat::Yaml | OutputFormat::Cbor => {
+            output_response(success_response(DoctorResponse { checks }), format)
+        }
+        OutputFormat::Plain => {
+            print_checks(&checks);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(accessible: bool, kernel_driver_active: Option<bool>) -> CcidDeviceInfo {
+        CcidDeviceInfo {
+            vendor_id: 0x076b,
+            product_id: 0x5422,
+            manufacturer: None,
+            product: Some("OMNIKEY 5422".to_string()),
+            serial: None,
+            is_coinkite: false,
+            is_ccid: true,
+            accessible,
+            kernel_driver_active,
+        }
+    }
+
+    #[test]
+    fn test_reader_checks() {
+        let checks = reader_checks(&[]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, CheckStatus::Fail);
+
+        let checks = reader_checks(&[reader(true, Some(false))]);
+        assert!(checks.iter().all(|check| check.status == CheckStatus::Ok));
+        assert_eq!(checks[0].detail, "OMNIKEY 5422 (076b:5422)");
+
+        let checks = reader_checks(&[reader(false, None), reader(true, Some(true))]);
+        assert_eq!(checks[1].status, CheckStatus::Fail);
+        assert_eq!(checks[1].fix.as_deref(), Some(INSTALL_UDEV));
+        assert_eq!(checks[2].status, CheckStatus::Warn);
+    }
+}
@@ -0,0 +1,249 @@
+//! Stable error categories for scripts: the `error_code` of a failed JSON response, and the
+//! process exit code.
+//!
+//! | exit code | `error_code` |
+//! |-----------|--------------|
+//! | 0 | success |
+//! | 1 | `other` |
+//! | 2 | invalid command line (from clap), `invalid_input` |
+//! | 10 | `card_not_found` |
+//! | 11 | `wrong_card_type`, `wrong_card` (`--expect-ident`), `unsupported` |
+//! | 12 | `needs_auth`, `bad_auth` |
+//! | 13 | `rate_limited` |
+//! | 14 | `card_error` |
+//! | 20 | `usb_error`, `reader_busy`, `card_removed`, `protocol_error` |
+//! | 21 | `timed_out` |
+//! | 30 | `verification_failed` |
+//! | 40 | `policy_denied` (`serve --policy`, `require_backup`) |
+//! | 130 | `interrupted` (Ctrl-C) |
+//!
+//! The tens group the codes by what a pipeline would do about them: 1x is about the card in
+//! front of the reader, 2x about reaching it, 3x about trusting its answers.
+
+use crate::cancel::{INTERRUPTED_EXIT_CODE, Interrupted};
+use crate::cert_cache::ChainChanged;
+use crate::deadline::TimedOut;
+use crate::policy::PolicyDenied;
+use crate::transport::{NoCardFound, UnsupportedTransport};
+use cktap_direct::Error;
+use cktap_direct::apdu::CkTapError;
+use cktap_direct::tap_signer::{CvcChangeError, PsbtSignError, TapSignerError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The command needs the CVC
+    NeedsAuth,
+    /// Wrong CVC
+    BadAuth,
+    /// Too many wrong CVCs, wait before trying again (`auto wait`)
+    RateLimited,
+    CardNotFound,
+    /// The card left the reader during the command
+    CardRemoved,
+    /// The command is for another type of card
+    WrongCardType,
+    /// Not the card given with `--expect-ident`
+    WrongCard,
+    UsbError,
+    /// Another process (or pcscd) is using the reader
+    ReaderBusy,
+    /// The command ran past `--timeout`
+    TimedOut,
+    /// The card's firmware or protocol version doesn't support the command
+    Unsupported,
+    /// A signature, certificate or address didn't check out
+    VerificationFailed,
+    /// The card refused the command
+    CardError,
+    /// The card's answer couldn't be decoded
+    ProtocolError,
+    InvalidInput,
+    /// The signing policy (or the backup rule) refused to sign
+    PolicyDenied,
+    /// Stopped with Ctrl-C
+    Interrupted,
+    Other,
+}
+
+impl ErrorCode {
+    /// Category of the first error in the chain the CLI knows about
+    pub fn of(error: &(dyn std::error::Error + 'static)) -> Self {
+        let mut source = Some(error);
+        while let Some(error) = source {
+            if let Some(error) = error.downcast_ref::<Error>() {
+                return Self::of_card_error(error);
+            }
+            if let Some(error) = error.downcast_ref::<TapSignerError>() {
+                return match error {
+                    TapSignerError::ApduError(error) => Self::of_card_error(error),
+                    TapSignerError::CvcChangeError(_) => Self::InvalidInput,
+                };
+            }
+            if let Some(error) = error.downcast_ref::<PsbtSignError>() {
+                return match error {
+                    PsbtSignError::TapSignerError(error) => Self::of_card_error(error),
+                    PsbtSignError::SignatureError(_) => Self::VerificationFailed,
+                    _ => Self::InvalidInput,
+                };
+            }
+            // bad batch scripts
+            if error.is::<clap::Error>() || error.is::<serde_json::Error>() {
+                return Self::InvalidInput;
+            }
+            if error.is::<CvcChangeError>() {
+                return Self::InvalidInput;
+            }
+            if error.is::<WrongCardType>() {
+                return Self::WrongCardType;
+            }
+            if error.is::<ChainChanged>() {
+                return Self::VerificationFailed;
+            }
+            if error.is::<PolicyDenied>() {
+                return Self::PolicyDenied;
+            }
+            if error.is::<UnsupportedTransport>() {
+                return Self::Unsupported;
+            }
+            // what stopped the first backend, e.g. pcscd holding the reader
+            if let Some(NoCardFound(failures)) = error.downcast_ref::<NoCardFound>() {
+                return failures
+                    .first()
+                    .map_or(Self::CardNotFound, |(_, error)| Self::of(error.as_ref()));
+            }
+            if error.is::<TimedOut>() {
+                return Self::TimedOut;
+            }
+            if error.is::<Interrupted>() {
+                return Self::Interrupted;
+            }
+            source = error.source();
+        }
+        Self::Other
+    }
+
+    fn of_card_error(error: &Error) -> Self {
+        match error {
+            Error::CkTap(CkTapError::NeedsAuth) => Self::NeedsAuth,
+            Error::CkTap(CkTapError::BadAuth) => Self::BadAuth,
+            Error::CkTap(CkTapError::RateLimited) => Self::RateLimited,
+            Error::CkTap(_) | Error::StatusWord(_) => Self::CardError,
+            Error::CiborDe(_)
+            | Error::CiborValue(_)
+            | Error::Bip32(_)
+            | Error::InvalidVersion(_) => Self::ProtocolError,
+            Error::IncorrectSignature(_)
+            | Error::AddressMismatch(_)
+            | Error::SignatureMismatch(_)
+            | Error::UnexpectedNonce(_) => Self::VerificationFailed,
+            Error::UnknownCardType(_) => Self::WrongCardType,
+            Error::WrongCard { .. } => Self::WrongCard,
+            Error::InvalidUrl(_) => Self::InvalidInput,
+            Error::BackupRequired(_) => Self::PolicyDenied,
+            Error::DeviceNotFound | Error::NoCardOnReader => Self::CardNotFound,
+            Error::CardRemoved => Self::CardRemoved,
+            Error::PcscdConflict | Error::ReaderLocked(_) => Self::ReaderBusy,
+            Error::Usb(_)
+            | Error::UsbAccess(_)
+            | Error::Ccid(_)
+            | Error::Pn532(_)
+            | Error::NotCcidDevice => Self::UsbError,
+            Error::UnsupportedProtocol { .. } | Error::UnsupportedByFirmware { .. } => {
+                Self::Unsupported
+            }
+            // emulator and metrics errors, depending on the library's features
+            #[allow(unreachable_patterns)]
+            _ => Self::Other,
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::InvalidInput => 2,
+            Self::CardNotFound => 10,
+            Self::WrongCardType | Self::WrongCard | Self::Unsupported => 11,
+            Self::NeedsAuth | Self::BadAuth => 12,
+            Self::RateLimited => 13,
+            Self::CardError => 14,
+            Self::UsbError | Self::ReaderBusy | Self::CardRemoved | Self::ProtocolError => 20,
+            Self::TimedOut => 21,
+            Self::VerificationFailed => 30,
+            Self::PolicyDenied => 40,
+            Self::Interrupted => INTERRUPTED_EXIT_CODE,
+        }
+    }
+}
+
+/// The connected card isn't the type the command is for
+#[derive(Debug)]
+pub struct WrongCardType(pub String);
+
+impl fmt::Display for WrongCardType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for WrongCardType {}
+
+/// Exit code of the last failed response printed, 0 if none failed
+static EXIT_CODE: AtomicU8 = AtomicU8::new(0);
+
+pub fn record_failure(code: ErrorCode) {
+    EXIT_CODE.store(code.exit_code(), Ordering::Relaxed);
+}
+
+pub fn exit_code() -> u8 {
+    EXIT_CODE.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_code() {
+        let error = Err::<(), _>(Error::CkTap(CkTapError::BadAuth))
+            .context("Failed to sign")
+            .unwrap_err();
+        assert_eq!(ErrorCode::of(error.as_ref()), ErrorCode::BadAuth);
+
+        let error = TapSignerError::ApduError(Error::DeviceNotFound);
+        assert_eq!(ErrorCode::of(&error), ErrorCode::CardNotFound);
+        assert_eq!(ErrorCode::of(&error).exit_code(), 10);
+
+        let error = anyhow::Error::new(WrongCardType("not a SatsCard".to_string()));
+        assert_eq!(ErrorCode::of(error.as_ref()), ErrorCode::WrongCardType);
+        assert_eq!(ErrorCode::WrongCardType.exit_code(), 11);
+
+        let error = Err::<(), _>(Error::WrongCard {
+            expected: "CARD-1234ABCD".to_string(),
+            found: "CARD-2ABCDEF0".to_string(),
+        })
+        .context("Failed to connect")
+        .unwrap_err();
+        assert_eq!(ErrorCode::of(error.as_ref()), ErrorCode::WrongCard);
+        assert_eq!(ErrorCode::BadAuth.exit_code(), 12);
+        assert_eq!(ErrorCode::RateLimited.exit_code(), 13);
+        assert_eq!(ErrorCode::UsbError.exit_code(), 20);
+        assert_eq!(ErrorCode::VerificationFailed.exit_code(), 30);
+        assert_eq!(ErrorCode::InvalidInput.exit_code(), 2);
+
+        let error = anyhow::Error::new(Interrupted);
+        assert_eq!(ErrorCode::of(error.as_ref()).exit_code(), 130);
+
+        let error = anyhow::anyhow!("Invalid dice roll");
+        assert_eq!(ErrorCode::of(error.as_ref()), ErrorCode::Other);
+
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::RateLimited).unwrap(),
+            "\"rate_limited\""
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use crate::output::*;
+use crate::registry;
+use crate::wallet::{ScriptType, hardened_path};
+use crate::{card_ident, get_cvc_from_env_or_prompt};
+use anyhow::{Context, Result};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpub};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Network, NetworkKind};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::{Cvc, TapSigner};
+use serde_json::{Value, json};
+use std::path::PathBuf;
+use strum::{Display, EnumString, VariantNames};
+
+/// Watch-only wallet file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase")]
+pub enum WalletExport {
+    /// Electrum standard wallet file with the account xpub
+    Electrum,
+    /// Descriptor wallet JSON (label, blockheight, descriptor) as imported by Sparrow
+    Sparrow,
+    /// Coldcard generic JSON (`coldcard-export.json`) with the BIP-44/49/84 account xpubs
+    Coldcard,
+}
+
+/// Account keys of an initialized TapSigner, as needed to build wallet files
+struct AccountKeys {
+    label: String,
+    birth: usize,
+    path: DerivationPath,
+    script_type: ScriptType,
+    fingerprint: Fingerprint,
+    xpub: Xpub,
+}
+
+async fn account_keys<T: CkTransport>(ts: &mut TapSigner<T>, cvc: &Cvc) -> Result<AccountKeys> {
+    let path = ts
+        .path
+        .as_ref()
+        .context("Card is not initialized, run `tapsigner init` first")?;
+    let path: DerivationPath = path
+        .iter()
+        .map(|&index| ChildNumber::from(index as u32))
+        .collect::<Vec<_>>()
+        .into();
+    let script_type = ScriptType::from_path(&path)
+        .with_context(|| format!("Unsupported account path m/{path}"))?;
+
+    let master_xpub = ts
+        .xpub(true, cvc)
+        .await
+        .context("Failed to read master xpub")?;
+    let xpub = ts
+        .xpub(false, cvc)
+        .await
+        .context("Failed to read account xpub")?;
+    registry::record_fingerprint(&card_ident(&ts.pubkey), master_xpub.fingerprint());
+
+    Ok(AccountKeys {
+        label: card_ident(&ts.pubkey),
+        birth: ts.birth,
+        path,
+        script_type,
+        fingerprint: master_xpub.fingerprint(),
+        xpub,
+    })
+}
+
+fn electrum_wallet(keys: &AccountKeys) -> Value {
+    json!({
+        "keystore": {
+            "type": "bip32",
+            "label": keys.label,
+            "derivation": format!("m/{path}", path = keys.path),
+            "root_fingerprint": keys.fingerprint.to_string(),
+            "xpub": keys.script_type.slip132(&keys.xpub),
+            "xprv": null,
+        },
+        "wallet_type": "standard",
+        "use_encryption": false,
+        "seed_version": 17,
+    })
+}
+
+fn sparrow_wallet(keys: &AccountKeys) -> Result<Value> {
+    Ok(json!({
+        "label": keys.label,
+        "blockheight": keys.birth,
+        "descriptor": keys.script_type.descriptor(keys.fingerprint, &keys.path, &keys.xpub, 0)?,
+    }))
+}
+
+/// One account section of a Coldcard generic JSON export
+fn coldcard_account(
+    script_type: ScriptType,
+    fingerprint: Fingerprint,
+    path: &DerivationPath,
+    xpub: &Xpub,
+) -> Result<Value> {
+    let network = match xpub.network {
+        NetworkKind::Main => Network::Bitcoin,
+        NetworkKind::Test => Network::Testnet,
+    };
+    let secp = Secp256k1::verification_only();
+    let first = xpub
+        .derive_pub(&secp, &[ChildNumber::Normal { index: 0 }; 2])
+        .context("Failed to derive first address")?
+        .to_pub();
+
+    let mut account = json!({
+        "name": match script_type {
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2shP2wpkh => "p2wpkh-p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+        },
+        "deriv": format!("m/{path}"),
+        "xpub": xpub.to_string(),
+        "xfp": xpub.fingerprint().to_string().to_uppercase(),
+        "desc": script_type.descriptor(fingerprint, path, xpub, 0)?,
+        "first": script_type.address(&first, network).to_string(),
+    });
+    if script_type != ScriptType::P2pkh {
+        account["_pub"] = Value::from(script_type.slip132(xpub));
+    }
+    Ok(account)
+}
+
+/// Build a Coldcard generic JSON export. TapSigner only signs ECDSA, so the BIP-86 taproot
+/// account is left out.
+///
+/// Every account has to be derived on the card to read its xpub, afterwards the card is
+/// switched back to the path it was using before.
+async fn coldcard_wallet<T: CkTransport>(ts: &mut TapSigner<T>, cvc: &Cvc) -> Result<Value> {
+    let master_xpub = ts
+        .xpub(true, cvc)
+        .await
+        .context("Failed to read master xpub")?;
+    let fingerprint = master_xpub.fingerprint();
+    let (chain, coin) = match master_xpub.network {
+        NetworkKind::Main => ("BTC", 0),
+        NetworkKind::Test => ("XTN", 1),
+    };
+
+    let mut wallet = json!({
+        "chain": chain,
+        "xfp": fingerprint.to_string().to_uppercase(),
+        "account": 0,
+        "xpub": master_xpub.to_string(),
+    });
+
+    let script_types = [
+        ScriptType::P2pkh,
+        ScriptType::P2shP2wpkh,
+        ScriptType::P2wpkh,
+    ];
+    let paths: Vec<Vec<u32>> = script_types
+        .iter()
+        .map(|script_type| vec![script_type.purpose(), coin, 0])
+        .collect();
+    let xpubs = ts
+        .derive_many(&paths, cvc)
+        .await
+        .context("Failed to derive account xpubs")?;
+
+    for (script_type, path) in script_types.into_iter().zip(paths) {
+        let path = hardened_path(&path)?;
+        let xpub = xpubs
+            .get(&path)
+            .with_context(|| format!("Missing xpub for m/{path}"))?;
+        wallet[format!("bip{purpose}", purpose = script_type.purpose())] =
+            coldcard_account(script_type, fingerprint, &path, xpub)?;
+    }
+    Ok(wallet)
+}
+
+/// Export a watch-only wallet file for the card's current account. The wallet file goes to
+/// `output` if given, otherwise it is printed as is so it can be redirected to a file.
+pub async fn tapsigner_export<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    wallet: WalletExport,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+) -> Result<()> {
+    let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+    let wallet_file = match wallet {
+        WalletExport::Electrum => electrum_wallet(&account_keys(ts, &cvc).await?),
+        WalletExport::Sparrow => sparrow_wallet(&account_keys(ts, &cvc).await?)?,
+        WalletExport::Coldcard => coldcard_wallet(ts, &cvc).await?,
+    };
+    let wallet_json = serde_json::to_string_pretty(&wallet_file)?;
+
+    match output {
+        Some(path) => {
+            write_atomic(&path, wallet_json.as_bytes())?;
+            let result = ExportResponse {
+                wallet: wallet.to_string(),
+                file: path.display().to_string(),
+            };
+            output_response(success_response(result), format)
+        }
+        None => {
+            println!("{wallet_json}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keys() -> Result<AccountKeys> {
+        Ok(AccountKeys {
+            label: "CARD-01020304".to_string(),
+            birth: 700_000,
+            path: hardened_path(&[84, 0, 0])?,
+            script_type: ScriptType::P2wpkh,
+            fingerprint: "73c5da0a".parse()?,
+            xpub: "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V".parse()?,
+        })
+    }
+
+    #[test]
+    fn test_electrum_wallet() -> Result<()> {
+        let wallet = electrum_wallet(&test_keys()?);
+        assert_eq!(wallet["keystore"]["derivation"], "m/84'/0'/0'");
+        assert_eq!(wallet["keystore"]["root_fingerprint"], "73c5da0a");
+        assert_eq!(wallet["keystore"]["label"], "CARD-01020304");
+        assert!(
+            wallet["keystore"]["xpub"]
+                .as_str()
+                .context("xpub is a string")?
+                .starts_with("zpub")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparrow_wallet() -> Result<()> {
+        let wallet = sparrow_wallet(&test_keys()?)?;
+        let descriptor = wallet["descriptor"]
+            .as_str()
+            .context("descriptor is a string")?;
+        assert!(descriptor.starts_with("wpkh([73c5da0a/84h/0h/0h]xpub6CatW"));
+        assert!(descriptor.contains("/0/*)#"));
+        assert_eq!(wallet["blockheight"], 700_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_coldcard_account() -> Result<()> {
+        let keys = test_keys()?;
+        let account =
+            coldcard_account(ScriptType::P2wpkh, keys.fingerprint, &keys.path, &keys.xpub)?;
+        assert_eq!(account["name"], "p2wpkh");
+        assert_eq!(account["deriv"], "m/84'/0'/0'");
+        // first receive address of the BIP-84 test vector
+        assert_eq!(
+            account["first"],
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        assert!(
+            account["_pub"]
+                .as_str()
+                .context("_pub is a string")?
+                .starts_with("zpub")
+        );
+
+        assert!(
+            account["desc"]
+                .as_str()
+                .context("desc is a string")?
+                .starts_with("wpkh([73c5da0a/84h/0h/0h]")
+        );
+
+        let account =
+            coldcard_account(ScriptType::P2pkh, keys.fingerprint, &keys.path, &keys.xpub)?;
+        assert!(account.get("_pub").is_none());
+        Ok(())
+    }
+}
@@ -1,32 +1,268 @@
+mod audit;
+mod backup_policy;
+mod batch;
+mod cancel;
+mod cert_cache;
+mod chain;
+mod config;
+mod cvc_source;
+mod deadline;
+mod debug;
+mod doctor;
+mod error_code;
+mod export;
+mod multi;
 mod output;
+mod pinentry;
+mod plan;
+mod policy;
+mod provision;
+mod psbt;
+mod qr;
+mod readers;
+mod recover;
+mod registry;
+mod schema;
+mod serve;
+mod settings;
+mod transcript;
+mod transport;
+mod verify;
+mod wallet;
+mod watch;
+mod wizard;
+mod yaml;
 
 use anyhow::{Context, Result};
-use cktap_direct::commands::{CkTransport, Read};
-#[cfg(not(feature = "emulator"))]
-use cktap_direct::discovery;
-#[cfg(feature = "emulator")]
-use cktap_direct::emulator;
-use cktap_direct::secp256k1::hashes::{Hash as _, hex::DisplayHex};
-use cktap_direct::secp256k1::rand;
-use cktap_direct::{CkTapCard, commands::Certificate, rand_chaincode};
-use clap::{Parser, Subcommand};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint};
+use cert_cache::{CertCacheArgs, ChainChanged};
+use cktap_direct::apdu::{self, CkTapError, CommandApdu as _, StatusCommand};
+use cktap_direct::challenge::Challenge;
+use cktap_direct::commands::{Authentication as _, CkTransport, Read, Wait};
+use cktap_direct::discovery::DiscoveryBuilder;
+use cktap_direct::secp256k1::hashes::hex::{DisplayHex, FromHex};
+use cktap_direct::secp256k1::{PublicKey, rand};
+use cktap_direct::slot_url;
+use cktap_direct::tap_signer::TapSignerError;
+use cktap_direct::version::FirmwareVersion;
+use cktap_direct::{
+    CkTapCard, Cvc, SatsCard, StatusSummary, TapSigner, commands::Certificate, mix_chaincode,
+    rand_chaincode,
+};
+use clap::{Args, Parser, Subcommand};
+use error_code::{ErrorCode, WrongCardType};
+use export::WalletExport;
 use output::*;
-use rpassword::read_password;
-use std::io;
-use std::io::Write;
+use readers::ReaderSelector;
+use std::io::{self, IsTerminal, Read as _};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use transport::{Backend, Transport, TransportKind};
 
 /// CLI for cktap-direct - interact with Coinkite TapSigner and SatsCard devices
 #[derive(Parser)]
 #[command(author, version = option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"), about, long_about = None, propagate_version = true)]
 struct Cli {
-    /// Output format
-    #[arg(long, value_parser = clap::value_parser!(OutputFormat), default_value = "json", global = true)]
-    format: OutputFormat,
+    /// Output format: json (default), plain, ndjson, yaml or cbor
+    #[arg(long, value_parser = clap::value_parser!(OutputFormat), global = true)]
+    format: Option<OutputFormat>,
+
+    #[command(flatten)]
+    confirm: ConfirmArgs,
+
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    /// Log more: debug messages with -v, and every APDU with -vv. RUST_LOG overrides it.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log errors, and don't show progress and info messages
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Write the result to this file instead of stdout, replacing it atomically. For sign-psbt
+    /// and export this is where the signed PSBT or the wallet goes.
+    #[arg(short, long, value_name = "PATH", global = true)]
+    output: Option<PathBuf>,
+
+    /// Write a timestamped transcript of the APDUs (secrets redacted), CCID framing and errors
+    /// to this file, to attach to bug reports
+    #[arg(long, global = true)]
+    trace_file: Option<PathBuf>,
+
+    /// Show APDUs in full in the -v/-vv logs, secrets included, instead of redacting them. Only
+    /// allowed with the emulator, to debug the protocol.
+    #[arg(long, global = true)]
+    log_secrets: bool,
+
+    /// Give up on the command after this long, waits and retries included, e.g. 30s, 500ms or 2m
+    #[arg(long, value_parser = deadline::parse_timeout, global = true)]
+    timeout: Option<Duration>,
+
+    /// Settings file to use instead of config.toml in the config directory
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Ask for the CVC with this pinentry program (e.g. pinentry-gnome3) instead of the terminal
+    #[arg(long, value_name = "PATH", global = true)]
+    pinentry: Option<PathBuf>,
+
+    /// Read the CVC from the first line of this file, which only its owner may access
+    #[arg(long, value_name = "PATH", global = true, conflicts_with = "cvc_fd")]
+    cvc_file: Option<PathBuf>,
+
+    /// Read the CVC from the first line of this inherited file descriptor (Unix)
+    #[arg(long, value_name = "FD", global = true)]
+    cvc_fd: Option<u32>,
 
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Options controlling commands that permanently change card state
+#[derive(Args, Clone, Copy)]
+struct ConfirmArgs {
+    /// Skip the confirmation prompt for state-changing commands (unseal, new)
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+
+    /// Show what a state-changing command (unseal, new) would do without sending it to the card
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Refuse state-changing commands (init, new, unseal, derive, change, backup) on a card with
+    /// older firmware, e.g. `>=1.0.3`
+    #[arg(long, value_name = "VERSION", value_parser = parse_required_version, global = true)]
+    require_version: Option<FirmwareVersion>,
+}
+
+/// Parse `--require-version`: a firmware version, optionally prefixed with `>=`
+fn parse_required_version(s: &str) -> Result<FirmwareVersion> {
+    let version = s.trim().strip_prefix(">=").unwrap_or(s).trim();
+    version
+        .parse()
+        .with_context(|| format!("Invalid version requirement '{s}', expected e.g. >=1.0.3"))
+}
+
+impl ConfirmArgs {
+    /// Make the card check `--require-version` before its state-changing commands
+    fn guard_version<T: CkTransport>(&self, card: &mut CkTapCard<T>) {
+        match card {
+            CkTapCard::SatsCard(sc) => sc.required_version = self.require_version,
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                ts.required_version = self.require_version;
+            }
+        }
+    }
+}
+
+/// Options for finding the card
+#[derive(Args, Clone)]
+struct ConnectArgs {
+    /// Reader to use, as USB VID:PID in hex (e.g. 076b:5422) or serial number, see `readers`
+    #[arg(long, value_parser = readers::parse_reader, global = true)]
+    reader: Option<ReaderSelector>,
+
+    /// Don't lock the reader against other cktap-direct processes while using it
+    #[arg(long, global = true)]
+    no_lock: bool,
+
+    /// Seconds to wait for another process to release the reader
+    #[arg(long, value_name = "SECS", default_value_t = 10, global = true)]
+    lock_timeout: u64,
+
+    /// Query the card in every reader at once (`auto status` and `auto certs`), results are keyed
+    /// by card ident
+    #[arg(long, global = true, conflicts_with = "reader")]
+    all_readers: bool,
+
+    /// Abort unless the card is this one (its ident as `status` shows it), before anything but
+    /// selecting the applet and reading its status is sent to it
+    #[arg(
+        long,
+        value_name = "CARD-IDENT",
+        global = true,
+        conflicts_with = "all_readers"
+    )]
+    expect_ident: Option<String>,
+
+    /// Seconds to wait for a card to be placed on the reader, instead of failing when there is none
+    #[arg(long, value_name = "SECS", global = true)]
+    wait_for_card: Option<u64>,
+
+    /// Milliseconds each USB transfer with the reader may take, 5000 by default
+    #[arg(long, value_name = "MS", global = true)]
+    timeout_ms: Option<u64>,
+
+    /// How to reach the card: usb, emulator, emulator:<pipe>, or auto to try them in this
+    /// order. Defaults to auto, or to the emulator in builds with the emulator feature.
+    #[arg(long, env = "CKTAP_TRANSPORT", global = true)]
+    transport: Option<TransportKind>,
+
+    /// Fail on answers of the card on a USB reader with CBOR keys this version doesn't know, for
+    /// conformance testing. By default they are ignored, so newer firmware keeps working.
+    #[arg(long, global = true)]
+    strict_cbor: bool,
+}
+
+/// How often `--wait-for-card` looks for a card
+const CARD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+impl Cli {
+    /// The flags layered over the environment and the config in [`settings`]
+    fn settings_flags(&self) -> settings::Flags {
+        settings::Flags {
+            reader: self.connect.reader.clone(),
+            timeout_ms: self.connect.timeout_ms,
+            format: self.format,
+        }
+    }
+}
+
+impl Commands {
+    /// Commands meant for cards never seen before, which the registry doesn't warn about
+    fn expects_new_cards(&self) -> bool {
+        matches!(
+            self,
+            Commands::Tapsigner(TapSignerCommand::Init { .. })
+                | Commands::Satschip(SatsChipCommand::Init { .. })
+                | Commands::Watch(_)
+                | Commands::Provision(_)
+        )
+    }
+}
+
+impl ConnectArgs {
+    fn transport(&self) -> TransportKind {
+        self.transport.clone().unwrap_or_default()
+    }
+
+    /// Reader discovery with the lock and wait settings
+    fn discovery(&self) -> DiscoveryBuilder {
+        let discovery = DiscoveryBuilder::default()
+            .lock_timeout((!self.no_lock).then(|| Duration::from_secs(self.lock_timeout)))
+            .io_timeout(settings::get().timeout)
+            .parse_mode(match self.strict_cbor {
+                true => apdu::ParseMode::Strict,
+                false => apdu::ParseMode::Lenient,
+            });
+        match self.wait_for_card {
+            Some(secs) => discovery
+                .retry(Duration::from_secs(secs), CARD_POLL_INTERVAL)
+                .on_waiting(|e| {
+                    let message = match e {
+                        cktap_direct::Error::NoCardOnReader => "Place the card on the reader",
+                        _ => "Waiting for a reader with a card",
+                    };
+                    emit(Event::Info { message });
+                }),
+            None => discovery,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// SatsCard-specific commands
@@ -37,55 +273,263 @@ enum Commands {
     #[command(subcommand)]
     Tapsigner(TapSignerCommand),
 
+    /// SatsChip-specific commands
+    #[command(subcommand)]
+    Satschip(SatsChipCommand),
+
     /// Auto-detect card type and run command
     #[command(subcommand)]
     Auto(AutoCommand),
+
+    /// Work with PSBTs to be signed by a TapSigner
+    #[command(subcommand)]
+    Psbt(PsbtCommand),
+
+    /// List USB card readers, to see why one isn't picked or what to pass to --reader
+    Readers {
+        #[command(subcommand)]
+        command: Option<ReadersCommand>,
+    },
+
+    /// Low level commands for diagnosing reader and firmware problems
+    #[command(subcommand)]
+    Debug(DebugCommand),
+
+    /// Check USB permissions, pcscd, readers and card presence, and suggest fixes
+    Doctor {
+        /// Print udev rules for Coinkite devices and common CCID readers instead
+        #[clap(long)]
+        print_udev: bool,
+    },
+
+    /// Run the card commands of a JSON script in one session, asking for the CVC once
+    Batch {
+        /// Script with the commands and what to do when one fails, e.g.
+        /// {"on_error": "stop", "commands": ["tapsigner init", "tapsigner xpub"]}
+        file: PathBuf,
+
+        /// Keep the CVC in memory this many seconds after it was entered, 0 to ask for every
+        /// command. Never written to disk.
+        #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+        cvc_ttl: u64,
+    },
+
+    /// Wait for cards to be tapped and show each one (SatsCard address, QR code) or run a command
+    Watch(watch::WatchArgs),
+
+    /// Provision cards one after the other: initialize TapSigners and SatsChips, verify
+    /// SatsCards, and record each one in a CSV or JSON inventory
+    Provision(provision::ProvisionArgs),
+
+    /// The registry of cards seen, kept with `registry = true` in config.toml
+    #[command(subcommand)]
+    Cards(CardsCommand),
+
+    /// Print the JSON Schema of the output of every command, by the type of its data
+    Schema,
+
+    /// Keep the reader and answer JSON-RPC requests over HTTP (status, derive, xpub, sign_psbt,
+    /// verify) from local apps holding the token
+    Serve(serve::ServeArgs),
+}
+
+/// Card registry commands
+#[derive(Subcommand)]
+enum CardsCommand {
+    /// List the cards seen, in the order they were first seen
+    List,
+}
+
+/// Reader commands, listing them without one
+#[derive(Subcommand)]
+enum ReadersCommand {
+    /// Print what the readers' CCID descriptors say (exchange level, max message length, data
+    /// rates, voltages, features), all of them or the one of --reader
+    Info,
+}
+
+/// Debug commands
+#[derive(Subcommand)]
+enum DebugCommand {
+    /// Send a raw APDU (hex) and show the response, decoded as CBOR when possible
+    Apdu {
+        /// APDU bytes in hex, e.g. 00cb0000 followed by the length and CBOR command
+        apdu: String,
+    },
+}
+
+/// PSBT commands
+#[derive(Subcommand)]
+enum PsbtCommand {
+    /// Show which inputs the card can sign, without signing anything
+    Inspect {
+        /// PSBT file, binary or base64
+        file: PathBuf,
+        /// Master key fingerprint of the card, skips reading it from a connected card
+        #[clap(long)]
+        fingerprint: Option<Fingerprint>,
+    },
+    /// Finalize the P2WPKH and P2SH-P2WPKH inputs of a signed PSBT
+    Finalize {
+        /// Signed PSBT file, binary or base64
+        file: PathBuf,
+        /// Also extract the signed transaction, ready to broadcast
+        #[clap(long)]
+        extract: bool,
+    },
 }
 
 /// Commands that work with any card type
 #[derive(Subcommand)]
 enum AutoCommand {
     /// Show the card status
-    Status,
+    Status {
+        /// Include the raw status response (CBOR, in hex), e.g. for a support request
+        #[arg(long)]
+        raw: bool,
+    },
     /// Check this card was made by Coinkite
-    Certs,
+    Certs {
+        #[command(flatten)]
+        cache: CertCacheArgs,
+    },
+    /// Show current deposit address (SatsCard only)
+    Address {
+        #[command(flatten)]
+        details: AddressDetailsArgs,
+    },
+    /// Read the pubkey (requires CVC on TapSigner and SatsChip)
+    Read,
+    /// Verify the SatsCard payment address, or derive a TapSigner key at the given path
+    Derive {
+        /// Derivation path components, TapSigner and SatsChip only (e.g., 84,0,0 for m/84'/0'/0')
+        #[clap(short, long, value_delimiter = ',', num_args = 1..)]
+        path: Vec<u32>,
+        #[command(flatten)]
+        preview: AddressPreviewArgs,
+    },
+    /// Wait out the delay the card imposes after wrong CVC attempts
+    Wait,
+    /// Run every check at once: certs, read signature, derive and (SatsCard) address
+    Verify,
 }
 
 /// Commands supported by SatsCard cards
 #[derive(Subcommand)]
 enum SatsCardCommand {
     /// Show the card status
-    Status,
+    Status {
+        /// Include the raw status response (CBOR, in hex), e.g. for a support request
+        #[arg(long)]
+        raw: bool,
+    },
     /// Show current deposit address
-    Address,
+    Address {
+        #[command(flatten)]
+        details: AddressDetailsArgs,
+    },
     /// Check this card was made by Coinkite
-    Certs,
+    Certs {
+        #[command(flatten)]
+        cache: CertCacheArgs,
+    },
     /// Read the pubkey
     Read,
     /// Pick a new private key and start a fresh slot
-    New,
+    New {
+        /// Slot to start (defaults to the current slot)
+        #[clap(long)]
+        slot: Option<u8>,
+        #[command(flatten)]
+        entropy: ChainCodeArgs,
+    },
     /// Unseal the current slot
-    Unseal,
-    /// Get the payment address and verify it
+    Unseal {
+        /// Slot to unseal (defaults to the current slot)
+        #[clap(long)]
+        slot: Option<u8>,
+        /// Unseal a slot the config's `esplora_url` shows funded without asking, e.g. to sweep it
+        /// from a script
+        #[arg(long)]
+        i_understand_funds_exposed: bool,
+    },
+    /// Get the payment address and check its signature, pubkey and address
     Derive,
+    /// Check the address follows from the slot pubkey (read) and the master key (derive)
+    Verify,
+    /// Guided check of a new card: certs, read, derive and address check
+    VerifyNew,
+    /// Make a link proving the current slot's address is sealed on this card, in the style of
+    /// the card's NFC URL, that anyone can check without the card
+    Url {
+        /// Page the link opens, the proof goes in its fragment
+        #[clap(long, default_value = slot_url::DEFAULT_BASE_URL)]
+        base_url: String,
+        /// Also show the link as a QR code
+        #[clap(long)]
+        qr: bool,
+    },
+    /// Show which slots a series of gifts would use and the new and unseal commands for them,
+    /// without changing the card
+    Plan {
+        /// Number of gifts
+        #[clap(long, required_unless_present = "amounts", conflicts_with = "amounts")]
+        gifts: Option<u8>,
+        /// Amount of each gift, e.g. "0.001 BTC,50000 sat"
+        #[clap(long, value_delimiter = ',')]
+        amounts: Vec<bitcoin::Amount>,
+        /// Allow plans unsealing the last slot, after which the card can't hold funds again
+        #[clap(long)]
+        allow_last_slot: bool,
+    },
+    /// Recompute an unsealed slot's address and WIF from the saved output of unseal, offline,
+    /// e.g. when the card was lost
+    Recover {
+        /// Saved JSON output of satscard unseal, or - for stdin
+        #[clap(long)]
+        from_json: PathBuf,
+        /// Network of the address and WIF (bitcoin, testnet, signet or regtest), bitcoin by
+        /// default
+        #[clap(long)]
+        network: Option<bitcoin::Network>,
+    },
 }
 
 /// Commands supported by TapSigner cards
 #[derive(Subcommand)]
 enum TapSignerCommand {
     /// Show the card status
-    Status,
+    Status {
+        /// Include the raw status response (CBOR, in hex), e.g. for a support request
+        #[arg(long)]
+        raw: bool,
+    },
     /// Check this card was made by Coinkite
-    Certs,
+    Certs {
+        #[command(flatten)]
+        cache: CertCacheArgs,
+    },
     /// Read the pubkey (requires CVC)
     Read,
-    /// Initialize a new card
-    Init,
+    /// Initialize a new card, doing nothing if it already is
+    Init {
+        #[command(flatten)]
+        entropy: ChainCodeArgs,
+        /// Read the card's status again to tell whether it's initialized, rather than trusting
+        /// the one read when connecting
+        #[clap(long)]
+        force_check: bool,
+    },
     /// Derive a public key at the given hardened path
     Derive {
         /// Derivation path components (e.g., 84,0,0 for m/84'/0'/0')
         #[clap(short, long, value_delimiter = ',', num_args = 1..)]
         path: Vec<u32>,
+        /// Several paths separated by ';' (e.g. "84,0,0;49,0,0"), derived with one CVC entry
+        #[clap(long, value_parser = parse_derive_paths, conflicts_with = "path")]
+        paths: Option<DerivePaths>,
+        #[command(flatten)]
+        preview: AddressPreviewArgs,
     },
     /// Get an encrypted backup of the card's private key
     Backup,
@@ -96,148 +540,723 @@ enum TapSignerCommand {
     },
     /// Sign a digest
     Sign {
-        /// Data to sign (will be hashed with SHA256)
+        /// Data to sign, read as --input says and hashed as --hash says
         to_sign: String,
+        #[command(flatten)]
+        digest: DigestArgs,
+        /// Signature encoding: compact (r || s as the card returns it), der or base64
+        #[clap(long, value_parser = clap::value_parser!(SigFormat), default_value = "compact")]
+        sig_format: SigFormat,
+    },
+    /// Sign a PSBT's P2WPKH and P2SH-P2WPKH inputs, keeping its binary or base64 encoding. With
+    /// -o the signed PSBT goes to that file, otherwise to stdout.
+    SignPsbt {
+        /// PSBT file, binary or base64, or - for stdin
+        input: PathBuf,
+    },
+    /// Sign a login or 2FA challenge with the card's challenge key, for a service to verify
+    /// against the pubkey it enrolled
+    Challenge {
+        /// The service's challenge, 64 hex characters (defaults to a random one)
+        #[clap(long, value_parser = parse_challenge)]
+        challenge: Option<Challenge>,
+    },
+    /// Guided setup of a new card: init, backup, change CVC, derive and show xpub
+    Setup {
+        #[command(flatten)]
+        entropy: ChainCodeArgs,
+        /// File to write the encrypted backup to (defaults to backup-<card ident>.aes)
+        #[clap(long)]
+        backup_file: Option<PathBuf>,
+        /// Account derivation path components
+        #[clap(short, long, value_delimiter = ',', num_args = 1.., default_value = "84,0,0")]
+        path: Vec<u32>,
+    },
+    /// Export a watch-only wallet file for the card's current account
+    Export {
+        /// Wallet file format
+        #[clap(value_parser = clap::value_parser!(WalletExport))]
+        wallet: WalletExport,
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+/// Commands supported by SatsChip cards
+#[derive(Subcommand)]
+enum SatsChipCommand {
+    /// Show the card status
+    Status {
+        /// Include the raw status response (CBOR, in hex), e.g. for a support request
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Initialize a new card, doing nothing if it already is
+    Init {
+        #[command(flatten)]
+        entropy: ChainCodeArgs,
+        /// Read the card's status again to tell whether it's initialized, rather than trusting
+        /// the one read when connecting
+        #[clap(long)]
+        force_check: bool,
+    },
+    /// Derive a public key at the given hardened path
+    Derive {
+        /// Derivation path components (e.g., 84,0,0 for m/84'/0'/0')
+        #[clap(short, long, value_delimiter = ',', num_args = 1..)]
+        path: Vec<u32>,
+        /// Several paths separated by ';' (e.g. "84,0,0;49,0,0"), derived with one CVC entry
+        #[clap(long, value_parser = parse_derive_paths, conflicts_with = "path")]
+        paths: Option<DerivePaths>,
+        #[command(flatten)]
+        preview: AddressPreviewArgs,
+    },
+    /// Sign a digest
+    Sign {
+        /// Data to sign, read as --input says and hashed as --hash says
+        to_sign: String,
+        #[command(flatten)]
+        digest: DigestArgs,
+        /// Signature encoding: compact (r || s as the card returns it), der or base64
+        #[clap(long, value_parser = clap::value_parser!(SigFormat), default_value = "compact")]
+        sig_format: SigFormat,
+    },
+    /// Sign a PSBT's P2WPKH and P2SH-P2WPKH inputs, keeping its binary or base64 encoding. With
+    /// -o the signed PSBT goes to that file, otherwise to stdout.
+    SignPsbt {
+        /// PSBT file, binary or base64, or - for stdin
+        input: PathBuf,
+    },
+    /// Get an encrypted backup of the card's private key
+    Backup,
+}
+
+/// A SatsChip is a TapSigner in a different form factor, so every SatsChip command maps onto the
+/// equivalent TapSigner command.
+impl From<SatsChipCommand> for TapSignerCommand {
+    fn from(command: SatsChipCommand) -> Self {
+        match command {
+            SatsChipCommand::Status { raw } => TapSignerCommand::Status { raw },
+            SatsChipCommand::Init {
+                entropy,
+                force_check,
+            } => TapSignerCommand::Init {
+                entropy,
+                force_check,
+            },
+            SatsChipCommand::Derive {
+                path,
+                paths,
+                preview,
+            } => TapSignerCommand::Derive {
+                path,
+                paths,
+                preview,
+            },
+            SatsChipCommand::Sign {
+                to_sign,
+                digest,
+                sig_format,
+            } => TapSignerCommand::Sign {
+                to_sign,
+                digest,
+                sig_format,
+            },
+            SatsChipCommand::SignPsbt { input } => TapSignerCommand::SignPsbt { input },
+            SatsChipCommand::Backup => TapSignerCommand::Backup,
+        }
+    }
+}
+
+/// Where the chain code for a new slot or freshly initialized card comes from
+#[derive(Args, Clone)]
+struct ChainCodeArgs {
+    /// Chain code to commit, as 64 hex characters (defaults to random)
+    #[arg(long, value_parser = parse_chain_code, conflicts_with = "entropy_dice")]
+    chain_code: Option<[u8; 32]>,
+
+    /// Dice rolls (e.g. "1,4,2,6,...") mixed with OS randomness to pick the chain code
+    #[arg(long, value_parser = parse_dice_rolls)]
+    entropy_dice: Option<DiceRolls>,
+}
+
+impl ChainCodeArgs {
+    fn chain_code(&self, rng: &mut rand::rngs::ThreadRng) -> [u8; 32] {
+        match (&self.chain_code, &self.entropy_dice) {
+            (Some(chain_code), _) => *chain_code,
+            (None, Some(dice)) => mix_chaincode(rng, &dice.0),
+            (None, None) => rand_chaincode(rng),
+        }
+    }
+}
+
+/// How `sign` turns its argument into the digest the card signs
+#[derive(Args, Clone)]
+struct DigestArgs {
+    /// How the data is given: text, hex, or file (a path, - for stdin)
+    #[arg(long, value_parser = clap::value_parser!(SignInput), default_value = "text")]
+    input: SignInput,
 
+    /// How the data is hashed: sha256, sha256d (double SHA256, e.g. a file's txid-style hash), or
+    /// none when it is the 32-byte digest itself
+    #[arg(long, value_parser = clap::value_parser!(HashMode), default_value = "sha256")]
+    hash: HashMode,
+}
+
+impl DigestArgs {
+    fn digest(&self, to_sign: &str) -> Result<[u8; 32]> {
+        let data = match self.input {
+            SignInput::Text => to_sign.as_bytes().to_vec(),
+            SignInput::Hex => Vec::from_hex(to_sign).context("Invalid hex data to sign")?,
+            SignInput::File if to_sign == "-" => {
+                let mut data = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut data)
+                    .context("Failed to read the data to sign from stdin")?;
+                data
+            }
+            SignInput::File => {
+                std::fs::read(to_sign).with_context(|| format!("Failed to read {to_sign}"))?
+            }
+        };
+        self.hash.digest(&data)
+    }
+}
+
+/// Local address preview for a derived account
+#[derive(Args, Clone)]
+struct AddressPreviewArgs {
+    /// Show the first N receive and change addresses of the derived account
+    #[arg(long, value_name = "N", conflicts_with = "paths")]
+    show_addresses: Option<u32>,
+
+    /// Network for the previewed addresses (bitcoin, testnet, signet or regtest), bitcoin by
+    /// default
+    #[arg(long, requires = "show_addresses")]
+    network: Option<bitcoin::Network>,
+}
+
+/// Extra details shown with the deposit address
+#[derive(Args, Clone)]
+struct AddressDetailsArgs {
+    /// Include the slot the address belongs to
+    #[arg(long)]
+    slot: bool,
+
+    /// Include a QR code of the address, drawn in the terminal with `--format plain`
+    #[arg(long)]
+    qr: bool,
+
+    /// Include a block explorer link, `{address}` in the URL is replaced by the address. Without
+    /// a URL, the config's `explorer_url` or mempool.space for the card's network.
+    #[arg(long, value_name = "URL", num_args = 0..=1)]
+    explorer: Option<Option<String>>,
+}
+
+impl AddressDetailsArgs {
+    /// The explorer link asked for with `--explorer`
+    fn explorer_link(&self, address: &str, network: bitcoin::Network) -> Option<String> {
+        match self.explorer.as_ref()? {
+            Some(url) => Some(explorer_url(url, address)),
+            None => config::get().explorer_link(address, network),
+        }
+    }
+}
+
+/// Link to `address` on the explorer at `url`, appended when the URL has no `{address}` placeholder
+fn explorer_url(url: &str, address: &str) -> String {
+    if url.contains("{address}") {
+        url.replace("{address}", address)
+    } else {
+        format!("{url}{address}")
+    }
+}
+
+/// Dice rolls supplied by the user, each between 1 and 6
+#[derive(Clone)]
+struct DiceRolls(Vec<u8>);
+
+/// Derivation paths for a batch derive, each as unhardened path components
+#[derive(Clone)]
+struct DerivePaths(Vec<Vec<u32>>);
+
+#[tokio::main]
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    let settings = match config::load(cli.config.as_deref())
+        .and_then(|()| settings::init(cli.settings_flags()))
+    {
+        Ok(settings) => settings,
+        Err(e) => return report_error(&e, cli.format.unwrap_or(OutputFormat::Json)),
+    };
+    let format = settings.format;
+    output::set_format(format);
+    output::set_quiet(cli.quiet);
+    if let Some(path) = &cli.output {
+        output::set_output_file(path.clone());
+    }
+    if let Some(program) = &cli.pinentry {
+        pinentry::set_program(program.clone());
+    }
+    if cli.log_secrets {
+        if !matches!(
+            cli.connect.transport(),
+            TransportKind::Only(Backend::Emulator(_))
+        ) {
+            let e = anyhow::anyhow!("--log-secrets is only allowed with the emulator");
+            return report_error(&e, format);
+        }
+        cktap_direct::transcript::set_log_secrets(true);
+    }
+    if let Err(e) = transcript::init(
+        transcript::level(cli.quiet, cli.verbose),
+        cli.trace_file.as_deref(),
+    ) {
+        return report_error(&e, format);
+    }
+    if let Err(e) = cvc_source::load(cli.cvc_file.as_deref(), cli.cvc_fd) {
+        return report_error(&e, format);
+    }
+    if let Some(path) = &config::get().audit_log
+        && let Err(e) = audit::install(path)
+    {
+        return report_error(&e, format);
+    }
 
-    // Connect to card
-    #[cfg(not(feature = "emulator"))]
-    let card = discovery::find_first()
-        .await
-        .context("Failed to find card")?;
+    let timeout = cli.timeout;
+    let result = cancel::until_interrupted(deadline::within(timeout, run(cli, format))).await;
+    transcript::finish(&result);
+    match result {
+        Ok(()) => ExitCode::from(error_code::exit_code()),
+        Err(e) => report_error(&e, format),
+    }
+}
 
-    #[cfg(feature = "emulator")]
-    let card = emulator::find_emulator()
-        .await
-        .context("Failed to connect to emulator")?;
+/// Print an error that ended the command, as a JSON response or on stderr, and exit with the
+/// code of its category
+fn report_error(error: &anyhow::Error, format: OutputFormat) -> ExitCode {
+    let code = ErrorCode::of(error.as_ref());
+    let response = CommandResponse::<()> {
+        success: false,
+        error: Some(format!("{error:#}")),
+        error_code: Some(code),
+        data: None,
+    };
+    if format == OutputFormat::Plain || output_response(response, format).is_err() {
+        eprintln!("Error: {error:?}");
+    }
+    ExitCode::from(code.exit_code())
+}
 
+async fn run(cli: Cli, format: OutputFormat) -> Result<()> {
+    let connection = &cli.connect;
+    if cli.command.expects_new_cards() {
+        registry::expect_new_cards();
+    }
     match cli.command {
-        Commands::Auto(cmd) => handle_auto_command(card, cmd, cli.format).await,
-        Commands::Satscard(cmd) => handle_satscard_command(card, cmd, cli.format).await,
-        Commands::Tapsigner(cmd) => handle_tapsigner_command(card, cmd, cli.format).await,
+        Commands::Auto(cmd) if connection.all_readers => {
+            multi::run_all_readers(cmd, connection, format).await
+        }
+        Commands::Auto(cmd) => {
+            let mut card = connect(connection).await?;
+            cli.confirm.guard_version(&mut card);
+            handle_auto_command(&mut card, cmd, format, cli.confirm).await
+        }
+        Commands::Satscard(SatsCardCommand::Recover { from_json, network }) => {
+            let network = network.unwrap_or(settings::get().network);
+            let response = recover::recover(&from_json, network)?;
+            output_response(success_response(response), format)
+        }
+        Commands::Satscard(cmd) => {
+            let mut card = connect(connection).await?;
+            cli.confirm.guard_version(&mut card);
+            handle_satscard_command(&mut card, cmd, format, cli.confirm).await
+        }
+        Commands::Tapsigner(cmd) => {
+            let mut card = connect(connection).await?;
+            cli.confirm.guard_version(&mut card);
+            handle_tapsigner_command(&mut card, cmd, format).await
+        }
+        Commands::Satschip(cmd) => {
+            let mut card = connect(connection).await?;
+            cli.confirm.guard_version(&mut card);
+            handle_satschip_command(&mut card, cmd, format).await
+        }
+        Commands::Psbt(cmd) => handle_psbt_command(cmd, connection, format).await,
+        Commands::Readers { command: None } => readers::list_readers(format),
+        Commands::Readers {
+            command: Some(ReadersCommand::Info),
+        } => readers::readers_info(settings::get().reader.as_ref(), format),
+        Commands::Cards(CardsCommand::List) => {
+            output_response(success_response(registry::list()?), format)
+        }
+        Commands::Schema => output_response(success_response(schema::schemas()?), format),
+        Commands::Debug(DebugCommand::Apdu { apdu }) => {
+            debug::raw_apdu(&connect(connection).await?, &apdu, format).await
+        }
+        Commands::Doctor { print_udev } => {
+            doctor::doctor(print_udev, settings::get().reader.as_ref(), format).await
+        }
+        Commands::Batch { file, cvc_ttl } => {
+            let cvc_ttl = Duration::from_secs(cvc_ttl);
+            batch::run_batch(&file, cvc_ttl, connection, format, cli.confirm).await
+        }
+        Commands::Watch(args) => watch::watch(&args, connection, format).await,
+        Commands::Provision(args) => provision::provision(&args, connection, format).await,
+        Commands::Serve(args) => serve::serve(&args, connection, cli.confirm).await,
+    }
+}
+
+/// Connect to the selected reader, or the first card found (or the emulator)
+async fn connect(connection: &ConnectArgs) -> Result<CkTapCard<impl CkTransport>> {
+    deadline::enter(deadline::Phase::Connecting);
+    let card = transport::first_found(&connection.transport(), |backend| {
+        connect_with(connection, backend)
+    })
+    .await?;
+    deadline::enter(deadline::Phase::Running);
+    let card = backup_policy::install(card);
+    registry::seen(&card);
+    if let Some(expected) = &connection.expect_ident {
+        match &card {
+            CkTapCard::SatsCard(sc) => sc.assert_ident(expected),
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => ts.assert_ident(expected),
+        }
+        .context("Connected to another card than --expect-ident")?;
+    }
+    Ok(card)
+}
+
+/// Connect through one backend
+async fn connect_with(connection: &ConnectArgs, backend: Backend) -> Result<CkTapCard<Transport>> {
+    match backend {
+        Backend::Usb => {
+            let discovery = connection.discovery();
+            let card = match &settings::get().reader {
+                Some(reader) => discovery
+                    .find_matching(|info| reader.matches(info))
+                    .await
+                    .with_context(|| format!("Failed to find card in reader {reader:?}"))?,
+                None => discovery.find().await.context("Failed to find card")?,
+            };
+            Ok(card.map_transport(Transport::Usb))
+        }
+        Backend::Emulator(pipe) => {
+            if let Some(reader) = &settings::get().reader {
+                eprintln!("Ignoring --reader {reader:?}, connecting to the emulator");
+            }
+            let pipe = pipe.or_else(|| settings::get().emulator.clone());
+            transport::connect_emulator(pipe.as_deref()).await
+        }
+    }
+}
+
+/// Connect to the card in every reader (or the emulator)
+async fn connect_all(connection: &ConnectArgs) -> Result<Vec<CkTapCard<impl CkTransport + Send>>> {
+    deadline::enter(deadline::Phase::Connecting);
+    let cards = transport::first_found(&connection.transport(), |backend| {
+        connect_all_with(connection, backend)
+    })
+    .await?;
+    deadline::enter(deadline::Phase::Running);
+    cards.iter().for_each(registry::seen);
+    Ok(cards.into_iter().map(backup_policy::install).collect())
+}
+
+async fn connect_all_with(
+    connection: &ConnectArgs,
+    backend: Backend,
+) -> Result<Vec<CkTapCard<Transport>>> {
+    match backend {
+        Backend::Usb => {
+            let cards = connection
+                .discovery()
+                .find_all()
+                .await
+                .context("Failed to find cards")?;
+            Ok(cards
+                .into_iter()
+                .map(|card| card.map_transport(Transport::Usb))
+                .collect())
+        }
+        Backend::Emulator(pipe) => {
+            let pipe = pipe.or_else(|| settings::get().emulator.clone());
+            Ok(vec![transport::connect_emulator(pipe.as_deref()).await?])
+        }
+    }
+}
+
+async fn handle_psbt_command(
+    command: PsbtCommand,
+    connection: &ConnectArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    match command {
+        PsbtCommand::Inspect { file, fingerprint } => {
+            psbt::inspect(&file, fingerprint, connection, format).await
+        }
+        PsbtCommand::Finalize { file, extract } => psbt::finalize(&file, extract, format),
     }
 }
 
 async fn handle_auto_command<T: CkTransport>(
-    mut card: CkTapCard<T>,
+    card: &mut CkTapCard<T>,
     command: AutoCommand,
     format: OutputFormat,
+    confirm: ConfirmArgs,
 ) -> Result<()> {
+    let card_type = card_type(card);
     match command {
-        AutoCommand::Status => {
-            let response = match &card {
-                CkTapCard::SatsCard(sc) => {
-                    let slots = SlotInfo {
-                        current: sc.slots.0,
-                        total: sc.slots.1,
-                    };
-                    DebugResponse {
-                        card_type: "satscard".to_string(),
-                        card_ident: format!(
-                            "CARD-{:X}",
-                            sc.pubkey.serialize()[0..4]
-                                .iter()
-                                .fold(0u32, |acc, &b| (acc << 8) | b as u32)
-                        ),
-                        birth_height: Some(sc.birth as u32),
-                        slots: Some(slots),
-                        path: None,
-                        applet_version: sc.ver.clone(),
-                        is_testnet: false,
-                    }
+        AutoCommand::Status { raw } => {
+            if let CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) = card {
+                warn_no_backup(ts);
+            }
+            let response = card_status(card, raw).await?;
+            output_response(success_response(response), format)?;
+        }
+        AutoCommand::Certs { cache } => {
+            let result = match card {
+                CkTapCard::SatsCard(sc) => check_cert(sc, &cache).await?,
+                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                    check_cert(ts, &cache).await?
                 }
-                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => DebugResponse {
-                    card_type: if matches!(card, CkTapCard::SatsChip(_)) {
-                        "satschip".to_string()
-                    } else {
-                        "tapsigner".to_string()
-                    },
-                    card_ident: format!(
-                        "CARD-{:X}",
-                        ts.pubkey.serialize()[0..4]
-                            .iter()
-                            .fold(0u32, |acc, &b| (acc << 8) | b as u32)
-                    ),
-                    birth_height: Some(ts.birth as u32),
-                    slots: None,
-                    path: ts
-                        .path
-                        .as_ref()
-                        .map(|p| p.iter().map(|&v| v as u32).collect()),
-                    applet_version: ts.ver.clone(),
-                    is_testnet: false,
-                },
             };
-            output_response(success_response(response), format)?;
+            output_response(result, format)?;
         }
-        AutoCommand::Certs => {
-            let result = match &mut card {
-                CkTapCard::SatsCard(sc) => check_cert(sc).await,
-                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => check_cert(ts).await,
+        AutoCommand::Address { details } => match card {
+            CkTapCard::SatsCard(_) => {
+                let command = SatsCardCommand::Address { details };
+                handle_satscard_command(card, command, format, confirm).await?;
+            }
+            _ => output_response(unsupported_response("address", card_type), format)?,
+        },
+        AutoCommand::Read => match card {
+            CkTapCard::SatsCard(_) => {
+                handle_satscard_command(card, SatsCardCommand::Read, format, confirm).await?;
+            }
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                run_tapsigner_command(ts, card_type, TapSignerCommand::Read, format).await?;
+            }
+        },
+        AutoCommand::Derive { path, preview } => match card {
+            CkTapCard::SatsCard(_) if path.is_empty() && preview.show_addresses.is_none() => {
+                handle_satscard_command(card, SatsCardCommand::Derive, format, confirm).await?;
+            }
+            CkTapCard::SatsCard(_) => {
+                output_response(unsupported_response("derive --path", card_type), format)?;
+            }
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                let command = TapSignerCommand::Derive {
+                    path,
+                    paths: None,
+                    preview,
+                };
+                run_tapsigner_command(ts, card_type, command, format).await?;
+            }
+        },
+        AutoCommand::Wait => {
+            let result = match card {
+                CkTapCard::SatsCard(sc) => wait_for_card(sc).await,
+                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => wait_for_card(ts).await,
             };
             output_response(result, format)?;
         }
+        AutoCommand::Verify => {
+            let report = match card {
+                CkTapCard::SatsCard(sc) => verify::verify_satscard(sc).await,
+                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                    let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+                    verify::verify_tapsigner(ts, card_type, &cvc).await
+                }
+            };
+            match format {
+                OutputFormat::Json
+                | OutputFormat::Ndjson
+                | OutputFormat::Yaml
+                | OutputFormat::Cbor => output_response(report, format)?,
+                OutputFormat::Plain => {
+                    report.record_outcome();
+                    verify::print_report(&report);
+                }
+            }
+        }
     }
     Ok(())
 }
 
+/// Type, ident, birth height, slots or path, and applet version of the card
+async fn card_status<T: CkTransport>(card: &CkTapCard<T>, raw: bool) -> Result<DebugResponse> {
+    let mut response = status_response(card.status_summary());
+    if raw {
+        let transport = match card {
+            CkTapCard::SatsCard(sc) => &sc.transport,
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => &ts.transport,
+        };
+        response.raw_status = Some(raw_status(transport).await?);
+    }
+    Ok(response)
+}
+
+/// The status response as the card sent it, CBOR in hex
+async fn raw_status<T: CkTransport>(transport: &T) -> Result<String> {
+    let response = transport
+        .transmit_raw(StatusCommand::default().apdu_bytes()?)
+        .await
+        .context("Failed to get the raw status")?;
+    Ok(response.body.as_hex().to_string())
+}
+
+fn status_response(summary: StatusSummary) -> DebugResponse {
+    let path: Option<Vec<u32>> = summary.path.map(|p| p.iter().map(|&v| v as u32).collect());
+    DebugResponse {
+        card_type: summary.card_type.to_string(),
+        card_ident: summary.ident,
+        birth_height: Some(summary.birth as u32),
+        slots: summary
+            .slots
+            .map(|(current, total)| SlotInfo { current, total }),
+        derivation_path: path.as_deref().map(format_path),
+        path,
+        num_backups: summary.num_backups,
+        protocol_version: summary.proto,
+        applet_semver: AppletSemver::parse(&summary.version),
+        applet_version: summary.version,
+        raw_status: None,
+        is_testnet: summary.testnet,
+    }
+}
+
+/// Format a card path (hardened steps have the high bit set) like m/84'/0'/0'
+fn format_path(path: &[u32]) -> String {
+    let path: DerivationPath = path.iter().map(|&index| ChildNumber::from(index)).collect();
+    if path.is_empty() {
+        return "m".to_string();
+    }
+    format!("m/{path}")
+}
+
+/// Nudge to take a backup before funding a card that was never backed up
+fn warn_no_backup<T: CkTransport>(ts: &TapSigner<T>) {
+    if ts.num_backups == Some(0) {
+        emit(Event::Warning {
+            message: "This card was never backed up, run `backup` before funding it",
+        });
+    }
+}
+
+fn card_type<T: CkTransport>(card: &CkTapCard<T>) -> &'static str {
+    match card {
+        CkTapCard::SatsCard(_) => "satscard",
+        CkTapCard::TapSigner(_) => "tapsigner",
+        CkTapCard::SatsChip(_) => "satschip",
+    }
+}
+
+/// Response for an `auto` command the connected card doesn't support
+fn unsupported_response(command: &str, card_type: &str) -> CommandResponse<UnsupportedResponse> {
+    CommandResponse {
+        success: false,
+        error: Some(format!("'{command}' is not supported by {card_type} cards")),
+        error_code: Some(ErrorCode::WrongCardType),
+        data: Some(UnsupportedResponse {
+            command: command.to_string(),
+            card_type: card_type.to_string(),
+        }),
+    }
+}
+
 async fn handle_satscard_command<T: CkTransport>(
-    card: CkTapCard<T>,
+    card: &mut CkTapCard<T>,
     command: SatsCardCommand,
     format: OutputFormat,
+    confirm: ConfirmArgs,
 ) -> Result<()> {
-    let mut sc = match card {
+    let sc = match card {
         CkTapCard::SatsCard(sc) => sc,
-        _ => anyhow::bail!("Connected card is not a SatsCard"),
+        _ => anyhow::bail!(WrongCardType(
+            "Connected card is not a SatsCard".to_string()
+        )),
     };
 
     let rng = &mut rand::thread_rng();
 
     match command {
-        SatsCardCommand::Status => {
-            let slots = SlotInfo {
-                current: sc.slots.0,
-                total: sc.slots.1,
-            };
-            let response = DebugResponse {
-                card_type: "satscard".to_string(),
-                card_ident: format!(
-                    "CARD-{:X}",
-                    sc.pubkey.serialize()[0..4]
-                        .iter()
-                        .fold(0u32, |acc, &b| (acc << 8) | b as u32)
-                ),
-                birth_height: Some(sc.birth as u32),
-                slots: Some(slots),
-                path: None,
-                applet_version: sc.ver.clone(),
-                is_testnet: false, // TODO: check if card is testnet
-            };
+        SatsCardCommand::Status { raw } => {
+            let mut response = status_response(sc.status_summary());
+            if raw {
+                response.raw_status = Some(raw_status(&sc.transport).await?);
+            }
             output_response(success_response(response), format)?;
         }
-        SatsCardCommand::Address => {
-            let address = sc.address().await.context("Failed to get address")?;
-            let response = AddressResponse { address };
-            output_response(success_response(response), format)?;
+        SatsCardCommand::Address { details } => {
+            let address = sc
+                .address()
+                .await
+                .context("Failed to get address")?
+                .to_string();
+            let qr = details
+                .qr
+                .then(|| qr::QrCode::encode(address.as_bytes()))
+                .transpose()
+                .context("Failed to encode address QR code")?;
+            let response = AddressResponse {
+                slot: details.slot.then_some(sc.slots.0),
+                qr: qr.as_ref().map(qr::QrCode::to_terminal),
+                explorer_url: details.explorer_link(&address, sc.network()),
+                address,
+            };
+            match format {
+                OutputFormat::Plain => print_address(&response),
+                _ => output_response(success_response(response), format)?,
+            }
         }
-        SatsCardCommand::Certs => {
-            let result = check_cert(&mut sc).await;
+        SatsCardCommand::Certs { cache } => {
+            let result = check_cert(sc, &cache).await?;
             output_response(result, format)?;
         }
         SatsCardCommand::Read => {
-            let result = read_card(&mut sc, None).await;
+            let result = read_card(sc, None).await;
             output_response(result, format)?;
         }
-        SatsCardCommand::New => {
-            let slot = sc.slot().context("No available slot")?;
-            let chain_code = Some(rand_chaincode(rng));
+        SatsCardCommand::New { slot, entropy } => {
+            let slot = match slot {
+                Some(slot) => {
+                    let dump = dump_slot(sc, slot).await?;
+                    anyhow::ensure!(dump.used != Some(true), "Slot {slot} is already in use");
+                    slot
+                }
+                None => sc.slot().context("No available slot")?,
+            };
+            let chain_code = Some(entropy.chain_code(rng));
+
+            // an unused slot has no key yet, so there is normally no address or funds to show
+            let address = slot_address(sc, slot).await?;
+            let funds = slot_funds(slot, &address).await?;
+            if confirm.dry_run {
+                let result = DryRunResponse {
+                    action: "new".to_string(),
+                    slot,
+                    explorer_url: address
+                        .known()
+                        .and_then(|address| config::get().explorer_link(address, sc.network())),
+                    address: address.known().map(str::to_string),
+                    balance: funds.map(|funds| funds.to_string()),
+                    chain_code: chain_code.map(|cc| cc.as_hex().to_string()),
+                };
+                output_response(success_response(result), format)?;
+                return Ok(());
+            }
+            let prompt = match (address.known(), funds) {
+                (Some(address), Some(funds)) => format!(
+                    "Start new slot {slot} with address {address} holding {funds}? The card will show its address from now on."
+                ),
+                (Some(address), None) => format!(
+                    "Start new slot {slot} with address {address}? The card will show its address from now on."
+                ),
+                (None, _) => format!(
+                    "Start new slot {slot}? The card picks a new key for it and shows its address from now on."
+                ),
+            };
+            confirm_action(&prompt, confirm)?;
+
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
 
             let response = sc
@@ -247,11 +1266,70 @@ async fn handle_satscard_command<T: CkTransport>(
 
             let result = NewSlotResponse {
                 slot: response.slot,
+                chain_code: chain_code.map(|cc| cc.as_hex().to_string()),
             };
             output_response(success_response(result), format)?;
         }
-        SatsCardCommand::Unseal => {
-            let slot = sc.slot().context("No available slot")?;
+        SatsCardCommand::Unseal {
+            slot,
+            i_understand_funds_exposed,
+        } => {
+            let slot = match slot {
+                Some(slot) => {
+                    let dump = dump_slot(sc, slot).await?;
+                    anyhow::ensure!(
+                        dump.used != Some(false),
+                        "Slot {slot} has not been used yet, nothing to unseal"
+                    );
+                    anyhow::ensure!(
+                        dump.sealed != Some(false),
+                        "Slot {slot} is already unsealed"
+                    );
+                    slot
+                }
+                None => sc.slot().context("No available slot")?,
+            };
+
+            let address = slot_address(sc, slot).await?;
+            // with a chain backend, unsealing a funded slot takes --i-understand-funds-exposed or
+            // a confirmation showing what it holds, which --yes doesn't give
+            let funds = slot_funds(slot, &address).await?;
+            if confirm.dry_run {
+                let result = DryRunResponse {
+                    action: "unseal".to_string(),
+                    slot,
+                    explorer_url: address
+                        .known()
+                        .and_then(|address| config::get().explorer_link(address, sc.network())),
+                    address: address.known().map(str::to_string),
+                    balance: funds.map(|funds| funds.to_string()),
+                    chain_code: None,
+                };
+                output_response(success_response(result), format)?;
+                return Ok(());
+            }
+            let address = address.known().unwrap_or("(unknown)");
+            match funds {
+                Some(funds) if funds > bitcoin::Amount::ZERO && !i_understand_funds_exposed => {
+                    anyhow::ensure!(
+                        !confirm.yes && io::stdin().is_terminal(),
+                        "Slot {slot} holds {funds}, unsealing reveals its private key. Pass --i-understand-funds-exposed to unseal it anyway"
+                    );
+                    confirm_action(
+                        &format!(
+                            "Slot {slot} with address {address} holds {funds}. Unsealing permanently reveals its private key, so anyone seeing it can take them. Unseal anyway?"
+                        ),
+                        confirm,
+                    )?;
+                }
+                _ => confirm_action(
+                    &format!(
+                        "Unseal slot {slot} with address {address}? This permanently reveals its private key; move any funds right away."
+                    ),
+                    confirm,
+                )?,
+            }
+
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
 
             let response = sc
@@ -272,90 +1350,211 @@ async fn handle_satscard_command<T: CkTransport>(
             };
             output_response(success_response(result), format)?;
         }
-        SatsCardCommand::Derive => {
-            let response = sc.derive().await.context("Failed to derive")?;
-
-            // For SatsCard, derive returns verification that the payment address
-            // follows from the chain code and master public key
-            let result = DeriveResponse {
-                path: "m".to_string(), // SatsCard uses master key
-                pubkey: response
-                    .pubkey
-                    .map(|pk| pk.as_hex().to_string())
-                    .unwrap_or_else(|| response.master_pubkey.as_hex().to_string()),
-                master_pubkey: Some(response.master_pubkey.as_hex().to_string()),
-                chain_code: Some(response.chain_code.as_hex().to_string()),
-                addresses: None, // SatsCard derive doesn't compute addresses
+        SatsCardCommand::Verify => {
+            let verified = sc
+                .verify_address()
+                .await
+                .context("Failed to verify the slot address")?;
+            let result = VerifyAddressResponse {
+                slot: verified.slot,
+                pubkey: verified.pubkey.to_string(),
+                explorer_url: config::get().explorer_link(&verified.address, sc.network()),
+                address: verified.address,
             };
             output_response(success_response(result), format)?;
         }
+        SatsCardCommand::VerifyNew => {
+            wizard::satscard_verify_new(sc, format).await?;
+        }
+        SatsCardCommand::Url { base_url, qr } => {
+            let proof = sc
+                .slot_proof(settings::get().network)
+                .await
+                .context("Failed to read the slot")?;
+            let url = proof.url(&base_url);
+            let qr = qr
+                .then(|| qr::QrCode::encode(url.as_bytes()))
+                .transpose()
+                .context("Failed to encode link QR code")?;
+            let response = SlotUrlResponse {
+                slot: proof.slot,
+                address: proof.address,
+                url,
+                qr: qr.as_ref().map(qr::QrCode::to_terminal),
+            };
+            match format {
+                OutputFormat::Plain => {
+                    println!("{url}", url = response.url);
+                    if let Some(qr) = &response.qr {
+                        print!("{qr}");
+                    }
+                }
+                _ => output_response(success_response(response), format)?,
+            }
+        }
+        SatsCardCommand::Plan {
+            gifts,
+            amounts,
+            allow_last_slot,
+        } => {
+            let gifts = match gifts {
+                Some(count) => vec![None; usize::from(count)],
+                None => amounts.into_iter().map(Some).collect(),
+            };
+            let (current, total) = sc.slots;
+            let state = plan::SlotState::of(&dump_slot(sc, current).await?);
+            let response = plan::plan(current, total, state, &gifts, allow_last_slot)?;
+            output_response(success_response(response), format)?;
+        }
+        SatsCardCommand::Recover { from_json, network } => {
+            // needs no card, normally run before connecting to one
+            let network = network.unwrap_or(settings::get().network);
+            let response = recover::recover(&from_json, network)?;
+            output_response(success_response(response), format)?;
+        }
+        SatsCardCommand::Derive => {
+            let check = sc.check_derive().await.context("Failed to derive")?;
+            let response = verify::derive_response(&check);
+            match format {
+                OutputFormat::Plain => {
+                    response.record_outcome();
+                    verify::print_derive(&response);
+                }
+                _ => output_response(response, format)?,
+            }
+        }
     }
     Ok(())
 }
 
 async fn handle_tapsigner_command<T: CkTransport>(
-    card: CkTapCard<T>,
+    card: &mut CkTapCard<T>,
     command: TapSignerCommand,
     format: OutputFormat,
 ) -> Result<()> {
-    let mut ts = match card {
-        CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => ts,
-        _ => anyhow::bail!("Connected card is not a TapSigner"),
+    let (ts, card_type) = match card {
+        CkTapCard::TapSigner(ts) => (ts, "tapsigner"),
+        CkTapCard::SatsChip(ts) => (ts, "satschip"),
+        _ => anyhow::bail!(WrongCardType(
+            "Connected card is not a TapSigner".to_string()
+        )),
+    };
+
+    run_tapsigner_command(ts, card_type, command, format).await
+}
+
+async fn handle_satschip_command<T: CkTransport>(
+    card: &mut CkTapCard<T>,
+    command: SatsChipCommand,
+    format: OutputFormat,
+) -> Result<()> {
+    let ts = match card {
+        CkTapCard::SatsChip(ts) => ts,
+        _ => anyhow::bail!(WrongCardType(
+            "Connected card is not a SatsChip".to_string()
+        )),
     };
 
+    run_tapsigner_command(ts, "satschip", command.into(), format).await
+}
+
+async fn run_tapsigner_command<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    card_type: &'static str,
+    command: TapSignerCommand,
+    format: OutputFormat,
+) -> Result<()> {
     let rng = &mut rand::thread_rng();
 
     match command {
-        TapSignerCommand::Status => {
-            let response = DebugResponse {
-                card_type: "tapsigner".to_string(),
-                card_ident: format!(
-                    "CARD-{:X}",
-                    ts.pubkey.serialize()[0..4]
-                        .iter()
-                        .fold(0u32, |acc, &b| (acc << 8) | b as u32)
-                ),
-                birth_height: Some(ts.birth as u32),
-                slots: None,
-                path: ts
-                    .path
-                    .as_ref()
-                    .map(|p| p.iter().map(|&v| v as u32).collect()),
-                applet_version: ts.ver.clone(),
-                is_testnet: false, // TODO: check if card is testnet
-            };
+        TapSignerCommand::Status { raw } => {
+            warn_no_backup(ts);
+            let mut response = status_response(StatusSummary {
+                card_type,
+                ..ts.status_summary()
+            });
+            if raw {
+                response.raw_status = Some(raw_status(&ts.transport).await?);
+            }
             output_response(success_response(response), format)?;
         }
-        TapSignerCommand::Certs => {
-            let result = check_cert(&mut ts).await;
+        TapSignerCommand::Certs { cache } => {
+            let result = check_cert(ts, &cache).await?;
             output_response(result, format)?;
         }
         TapSignerCommand::Read => {
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
-            let result = read_card(&mut ts, Some(cvc)).await;
+            let result = read_card(ts, Some(&cvc)).await;
             output_response(result, format)?;
         }
-        TapSignerCommand::Init => {
-            let chain_code = rand_chaincode(rng);
+        TapSignerCommand::Init {
+            entropy,
+            force_check,
+        } => {
+            if force_check {
+                let status = ts.status().await.context("Failed to read card status")?;
+                ts.path = status.path;
+                ts.birth = status.birth;
+            }
+            // the card refuses a second init with an opaque error, and only after the CVC
+            let result = if let Some(path) = &ts.path {
+                let path: Vec<u32> = path.iter().map(|&index| index as u32).collect();
+                InitResponse {
+                    card_ident: card_ident(&ts.pubkey),
+                    success: false,
+                    chain_code: None,
+                    already_initialized: Some(format!(
+                        "already initialized (path {path}, birth {birth})",
+                        path = format_path(&path),
+                        birth = ts.birth
+                    )),
+                }
+            } else {
+                let chain_code = entropy.chain_code(rng);
+                let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+
+                let _response = ts
+                    .init(chain_code, &cvc)
+                    .await
+                    .context("Failed to initialize card")?;
+
+                InitResponse {
+                    card_ident: card_ident(&ts.pubkey),
+                    success: true,
+                    chain_code: Some(chain_code.as_hex().to_string()),
+                    already_initialized: None,
+                }
+            };
+            output_response(success_response(result), format)?;
+        }
+        TapSignerCommand::Derive {
+            paths: Some(paths), ..
+        } => {
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
 
-            let _response = ts
-                .init(chain_code, &cvc)
+            let xpubs = ts
+                .derive_many(&paths.0, &cvc)
                 .await
-                .context("Failed to initialize card")?;
-
-            let result = InitResponse {
-                card_ident: format!(
-                    "CARD-{:X}",
-                    ts.pubkey.serialize()[0..4]
-                        .iter()
-                        .fold(0u32, |acc, &b| (acc << 8) | b as u32)
-                ),
-                success: true,
-            };
+                .context("Failed to derive keys")?;
+
+            let keys = xpubs
+                .into_iter()
+                .map(|(path, xpub)| {
+                    let key = DerivedKey {
+                        xpub: xpub.to_string(),
+                        pubkey: xpub.public_key.to_string(),
+                    };
+                    (format!("m/{path}"), key)
+                })
+                .collect();
+            let result = DeriveManyResponse { keys };
             output_response(success_response(result), format)?;
         }
-        TapSignerCommand::Derive { path } => {
+        TapSignerCommand::Derive {
+            path,
+            paths: None,
+            preview,
+        } => {
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
 
             let response = ts
@@ -363,22 +1562,15 @@ async fn handle_tapsigner_command<T: CkTransport>(
                 .await
                 .context("Failed to derive key")?;
 
-            let pubkey_hex = response.pubkey.as_ref().unwrap_or(&response.master_pubkey);
-
             let mut addresses = std::collections::HashMap::new();
 
             // Convert to Bitcoin address if BIP84 path
             if !path.is_empty() && path[0] == 84 {
-                if let Ok(pubkey) = bitcoin::PublicKey::from_slice(pubkey_hex) {
-                    if let Ok(compressed) = bitcoin::CompressedPublicKey::try_from(pubkey) {
-                        let mainnet_addr =
-                            bitcoin::Address::p2wpkh(&compressed, bitcoin::Network::Bitcoin);
-                        let testnet_addr =
-                            bitcoin::Address::p2wpkh(&compressed, bitcoin::Network::Testnet);
-                        addresses.insert("mainnet".to_string(), mainnet_addr.to_string());
-                        addresses.insert("testnet".to_string(), testnet_addr.to_string());
-                    }
-                }
+                let compressed = bitcoin::CompressedPublicKey(response.xpub.public_key);
+                let mainnet_addr = bitcoin::Address::p2wpkh(&compressed, bitcoin::Network::Bitcoin);
+                let testnet_addr = bitcoin::Address::p2wpkh(&compressed, bitcoin::Network::Testnet);
+                addresses.insert("mainnet".to_string(), mainnet_addr.to_string());
+                addresses.insert("testnet".to_string(), testnet_addr.to_string());
             }
 
             let path_str = path
@@ -387,16 +1579,42 @@ async fn handle_tapsigner_command<T: CkTransport>(
                 .collect::<Vec<_>>()
                 .join("/");
 
+            let (receive_addresses, change_addresses) = match preview.show_addresses {
+                Some(count) => {
+                    let account_path = wallet::hardened_path(&path)?;
+                    let script_type = wallet::ScriptType::from_path(&account_path)
+                        .with_context(|| {
+                            format!("Can't tell the address type of m/{account_path}, use a BIP-44/49/84 path")
+                        })?;
+                    let xpub = ts
+                        .xpub(false, &cvc)
+                        .await
+                        .context("Failed to read account xpub")?;
+                    let network = preview.network.unwrap_or(settings::get().network);
+                    let addresses = |chain| -> Result<Vec<String>> {
+                        Ok(script_type
+                            .addresses(&xpub, chain, count, network)?
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect())
+                    };
+                    (Some(addresses(0)?), Some(addresses(1)?))
+                }
+                None => (None, None),
+            };
+
             let result = DeriveResponse {
                 path: format!("m/{path_str}"),
-                pubkey: pubkey_hex.as_hex().to_string(),
-                master_pubkey: Some(response.master_pubkey.as_hex().to_string()),
-                chain_code: Some(response.chain_code.as_hex().to_string()),
+                pubkey: response.xpub.public_key.to_string(),
+                master_pubkey: Some(response.master_pubkey.to_string()),
+                chain_code: Some(response.xpub.chain_code.to_string()),
                 addresses: if addresses.is_empty() {
                     None
                 } else {
                     Some(addresses)
                 },
+                receive_addresses,
+                change_addresses,
             };
             output_response(success_response(result), format)?;
         }
@@ -411,24 +1629,67 @@ async fn handle_tapsigner_command<T: CkTransport>(
             };
             output_response(success_response(result), format)?;
         }
-        TapSignerCommand::Change { new_cvc } => {
-            let cvc = get_cvc_from_env_or_prompt().context("Failed to get current CVC")?;
+        TapSignerCommand::Challenge { challenge } => {
+            let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+            let challenge = challenge.unwrap_or_else(Challenge::random);
 
             let response = ts
-                .change(&new_cvc, &cvc)
+                .sign_challenge(challenge, &cvc)
                 .await
-                .context("Failed to change CVC")?;
+                .context("Failed to sign the challenge")?;
+
+            let result = ChallengeResponse {
+                challenge: response.challenge.0.as_hex().to_string(),
+                digest: response.challenge.digest().as_hex().to_string(),
+                pubkey: response.pubkey.to_string(),
+                path: response.path.to_string(),
+                signature: response.signature.serialize_compact().as_hex().to_string(),
+            };
+            output_response(success_response(result), format)?;
+        }
+        TapSignerCommand::Change { new_cvc } => {
+            let cvc = get_cvc_from_env_or_prompt().context("Failed to get current CVC")?;
+
+            let new_cvc = Cvc::from(new_cvc);
+            // a card penalized for wrong CVCs refuses the change until its delay is over
+            let mut delay_seconds = 0;
+            if ts.auth_delay.is_some_and(|delay| delay > 0) {
+                delay_seconds += wait_out_delay(ts).await?;
+            }
+            let response = match ts.change(&new_cvc, &cvc).await {
+                Err(TapSignerError::ApduError(cktap_direct::Error::CkTap(
+                    CkTapError::RateLimited,
+                ))) => {
+                    delay_seconds += wait_out_delay(ts).await?;
+                    ts.change(&new_cvc, &cvc).await
+                }
+                response => response,
+            }
+            .context("Failed to change CVC")?;
+            batch::remember_cvc(&new_cvc);
 
             let result = ChangeResponse {
                 success: response.success,
-                delay_seconds: None,
+                delay_seconds: (delay_seconds > 0).then_some(delay_seconds as u32),
             };
             output_response(success_response(result), format)?;
         }
-        TapSignerCommand::Sign { to_sign } => {
-            let digest: [u8; 32] =
-                cktap_direct::secp256k1::hashes::sha256::Hash::hash(to_sign.as_bytes())
-                    .to_byte_array();
+        TapSignerCommand::Setup {
+            entropy,
+            backup_file,
+            path,
+        } => {
+            wizard::tapsigner_setup(ts, &entropy, backup_file, &path, format).await?;
+        }
+        TapSignerCommand::Export { wallet } => {
+            export::tapsigner_export(ts, wallet, output::take_output_file(), format).await?;
+        }
+        TapSignerCommand::Sign {
+            to_sign,
+            digest: digest_args,
+            sig_format,
+        } => {
+            let digest = digest_args.digest(&to_sign)?;
 
             let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
 
@@ -437,46 +1698,117 @@ async fn handle_tapsigner_command<T: CkTransport>(
                 .await
                 .context("Failed to sign")?;
 
+            let signature = response
+                .signature()
+                .context("Invalid signature from card")?;
             let result = SignResponse {
-                signature: response.sig.as_hex().to_string(),
+                signature: sig_format.encode(&signature),
+                signature_format: sig_format,
                 pubkey: response.pubkey.as_hex().to_string(),
+                input: digest_args.input,
+                hash: digest_args.hash,
+                digest: digest.as_hex().to_string(),
             };
             output_response(success_response(result), format)?;
         }
+        TapSignerCommand::SignPsbt { input } => {
+            psbt::sign(ts, &input, output::take_output_file().as_deref(), format).await?;
+        }
     }
     Ok(())
 }
 
-async fn check_cert<C, T>(card: &mut C) -> CommandResponse<CertsResponse>
+/// Check the card's certificate chain. With `--cert-cache` a chain seen before isn't walked
+/// again, and with `--pin` a changed chain fails the check.
+async fn check_cert<C, T>(
+    card: &mut C,
+    cache: &CertCacheArgs,
+) -> Result<CommandResponse<CertsResponse>>
 where
     C: Certificate<T>,
     T: CkTransport,
 {
-    match card.check_certificate().await {
-        Ok(key) => {
+    let ident = card_ident(card.pubkey());
+    let known = cert_cache::known_chain(cache, &ident)?;
+    let checked = match card.check_certificate_chain(known.as_ref()).await {
+        Ok(chain) => cert_cache::record(cache, &ident, card.pubkey(), &chain).map(|()| chain),
+        Err(e) => Err(e.into()),
+    };
+    match checked {
+        Ok(chain) => {
+            registry::record_cert(&ident, Some(&chain.root.name()));
             let response = CertsResponse {
                 genuine: true,
-                signed_by: Some(key.name().to_string()),
+                signed_by: Some(chain.root.name()),
                 message: Some("Genuine card from Coinkite".to_string()),
+                cached: cache.cert_cache.is_some().then_some(known == Some(chain)),
             };
-            success_response(response)
+            Ok(success_response(response))
         }
-        Err(e) => {
+        Err(e) if e.is::<cktap_direct::Error>() || e.is::<ChainChanged>() => {
+            let message = if e.is::<ChainChanged>() {
+                "Certificate chain changed, this may not be the card seen before"
+            } else {
+                registry::record_cert(&ident, None);
+                "Card failed to verify. Not a genuine card"
+            };
             let response = CertsResponse {
                 genuine: false,
                 signed_by: None,
-                message: Some("Card failed to verify. Not a genuine card".to_string()),
+                message: Some(message.to_string()),
+                cached: None,
             };
-            CommandResponse {
+            Ok(CommandResponse {
                 success: false,
                 error: Some(e.to_string()),
+                error_code: Some(ErrorCode::of(e.as_ref())),
                 data: Some(response),
-            }
+            })
         }
+        Err(e) => Err(e),
     }
 }
 
-async fn read_card<C, T>(card: &mut C, cvc: Option<String>) -> CommandResponse<ReadResponse>
+/// Keep sending `wait` until the card no longer imposes an authentication delay
+async fn wait_for_card<C, T>(card: &mut C) -> CommandResponse<WaitCardResponse>
+where
+    C: Wait<T>,
+    T: CkTransport,
+{
+    match wait_out_delay(card).await {
+        Ok(waited) => success_response(WaitCardResponse {
+            waited_seconds: waited,
+        }),
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Send `wait` (a second each) until the card lifts its authentication delay, returning the
+/// seconds waited
+async fn wait_out_delay<C, T>(card: &mut C) -> Result<usize, cktap_direct::Error>
+where
+    C: Wait<T>,
+    T: CkTransport,
+{
+    let mut waited = 0;
+    deadline::enter(deadline::Phase::AuthDelay);
+    let result = loop {
+        match card.wait(None).await {
+            Ok(resp) if resp.auth_delay > 0 => {
+                waited += 1;
+                emit(Event::Info {
+                    message: &format!("Waiting, {delay} seconds left", delay = resp.auth_delay),
+                });
+            }
+            Ok(_) => break Ok(waited),
+            Err(e) => break Err(e),
+        }
+    };
+    deadline::enter(deadline::Phase::Running);
+    result
+}
+
+async fn read_card<C, T>(card: &mut C, cvc: Option<&Cvc>) -> CommandResponse<ReadResponse>
 where
     C: Read<T>,
     T: CkTransport,
@@ -490,20 +1822,275 @@ where
             };
             success_response(response)
         }
-        Err(e) => error_response(e.to_string()),
+        Err(e) => error_response(&e),
     }
 }
 
-fn cvc() -> Result<String> {
-    eprint!("Enter CVC: ");
-    io::stderr().flush()?;
-    let cvc = read_password()?;
-    Ok(cvc.trim().to_string())
+/// Print the address, then the details asked for, one per line
+fn print_address(response: &AddressResponse) {
+    println!("{address}", address = response.address);
+    if let Some(slot) = response.slot {
+        println!("slot: {slot}");
+    }
+    if let Some(url) = &response.explorer_url {
+        if std::io::stdout().is_terminal() {
+            // OSC 8 hyperlink, clickable in most terminals
+            println!("\x1b]8;;{url}\x1b\\{url}\x1b]8;;\x1b\\");
+        } else {
+            println!("{url}");
+        }
+    }
+    if let Some(qr) = &response.qr {
+        print!("{qr}");
+    }
 }
 
-fn get_cvc_from_env_or_prompt() -> Result<String> {
+/// Short human readable identifier for a card, derived from its pubkey
+fn card_ident(pubkey: &PublicKey) -> String {
+    cktap_direct::commands::card_ident(pubkey)
+}
+
+/// Check that `slot` exists on the card and return its current state
+async fn dump_slot<T: CkTransport>(
+    sc: &mut SatsCard<T>,
+    slot: u8,
+) -> Result<cktap_direct::apdu::DumpResponse> {
+    let total = sc.slots.1;
+    anyhow::ensure!(
+        slot < total,
+        "Slot {slot} is out of range, card has {total} slots"
+    );
+    sc.dump(slot as usize, None)
+        .await
+        .with_context(|| format!("Failed to read state of slot {slot}"))
+}
+
+/// What the card tells of a slot's address
+enum SlotAddress {
+    /// The slot has no key, so no address, yet
+    Unused,
+    Known(String),
+    /// The card didn't tell it
+    Unknown,
+}
+
+impl SlotAddress {
+    fn known(&self) -> Option<&str> {
+        match self {
+            SlotAddress::Known(address) => Some(address),
+            SlotAddress::Unused | SlotAddress::Unknown => None,
+        }
+    }
+}
+
+/// The address of `slot`: from `dump` once the slot is unsealed, from `read` while it is the
+/// current sealed slot
+async fn slot_address<T: CkTransport>(sc: &mut SatsCard<T>, slot: u8) -> Result<SlotAddress> {
+    let dump = dump_slot(sc, slot).await?;
+    let address = match (dump.used, dump.sealed) {
+        (Some(false), _) => return Ok(SlotAddress::Unused),
+        (_, Some(false)) => dump.addr,
+        _ if slot == sc.slots.0 => sc.address().await.ok().map(|address| address.to_string()),
+        _ => None,
+    };
+    Ok(address.map_or(SlotAddress::Unknown, SlotAddress::Known))
+}
+
+/// What `slot` at `address` holds, when a chain backend is configured. With one, a slot whose
+/// address the card didn't tell fails rather than passing for empty.
+async fn slot_funds(slot: u8, address: &SlotAddress) -> Result<Option<bitcoin::Amount>> {
+    let Some(esplora) = chain::Esplora::configured()? else {
+        return Ok(None);
+    };
+    match address {
+        SlotAddress::Unused => Ok(None),
+        SlotAddress::Known(address) => esplora
+            .balance(address)
+            .await
+            .map(Some)
+            .with_context(|| format!("Failed to check the funds of slot {slot}")),
+        SlotAddress::Unknown => {
+            anyhow::bail!(
+                "The card didn't tell the address of slot {slot}, so its funds can't be checked"
+            )
+        }
+    }
+}
+
+/// Parse a 32 byte chain code from 64 hex characters
+fn parse_chain_code(hex: &str) -> Result<[u8; 32]> {
+    <[u8; 32]>::from_hex(hex)
+        .with_context(|| format!("Invalid chain code '{hex}', expected 64 hex characters"))
+}
+
+fn parse_challenge(hex: &str) -> Result<Challenge> {
+    <[u8; 32]>::from_hex(hex)
+        .map(Challenge)
+        .with_context(|| format!("Invalid challenge '{hex}', expected 64 hex characters"))
+}
+
+/// Parse ';' separated derivation paths of ',' separated components, e.g. "84,0,0;49,0,0"
+fn parse_derive_paths(paths: &str) -> Result<DerivePaths> {
+    let paths = paths
+        .split(';')
+        .map(|path| {
+            path.split(',')
+                .map(|index| {
+                    let index = index.trim();
+                    index
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|index| *index < 1 << 31)
+                        .with_context(|| format!("Invalid path component '{index}'"))
+                })
+                .collect::<Result<Vec<u32>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(DerivePaths(paths))
+}
+
+/// Ask the user to confirm a state-changing operation, unless `--yes` was given
+fn confirm_action(prompt: &str, confirm: ConfirmArgs) -> Result<()> {
+    if confirm.yes {
+        return Ok(());
+    }
+
+    emit(Event::Prompt {
+        message: &format!("{prompt} [y/N]"),
+    });
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read confirmation")?;
+
+    anyhow::ensure!(
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        "Aborted, pass --yes to skip confirmation"
+    );
+    Ok(())
+}
+
+/// Parse comma separated dice rolls, e.g. "1,4,2,6"
+fn parse_dice_rolls(rolls: &str) -> Result<DiceRolls> {
+    let rolls = rolls
+        .split(',')
+        .map(|roll| {
+            let roll = roll.trim();
+            match roll.parse::<u8>() {
+                Ok(value @ 1..=6) => Ok(value),
+                _ => anyhow::bail!("Invalid dice roll '{roll}', expected a number from 1 to 6"),
+            }
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    Ok(DiceRolls(rolls))
+}
+
+fn cvc() -> Result<Cvc> {
+    Ok(Cvc::from(pinentry::read_secret("Enter CVC")?))
+}
+
+fn get_cvc_from_env_or_prompt() -> Result<Cvc> {
+    if let Some(cvc) = cvc_source::given() {
+        return Ok(cvc);
+    }
     match std::env::var("CKTAP_CVC") {
-        Ok(cvc) => Ok(cvc),
-        Err(_) => cvc(),
+        Ok(cvc) => Ok(Cvc::from(cvc)),
+        Err(_) => batch::session_cvc(cvc),
+    }
+}
+
+/// Read the new CVC for setup from `CKTAP_NEW_CVC` or prompt for it twice. An empty answer keeps
+/// the current CVC.
+fn new_cvc_from_env_or_prompt() -> Result<Option<Cvc>> {
+    if let Ok(new_cvc) = std::env::var("CKTAP_NEW_CVC") {
+        return Ok(Some(Cvc::from(new_cvc)));
+    }
+
+    let new_cvc = Cvc::from(pinentry::read_secret(
+        "Enter new CVC (leave empty to keep the current one)",
+    )?);
+    if new_cvc.is_empty() {
+        return Ok(None);
+    }
+
+    let repeated = Cvc::from(pinentry::read_secret("Repeat new CVC")?);
+    anyhow::ensure!(repeated == new_cvc, "New CVC entries do not match");
+    Ok(Some(new_cvc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chain_code() -> Result<()> {
+        let chain_code = parse_chain_code(&"ab".repeat(32))?;
+        assert_eq!(chain_code, [0xab; 32]);
+
+        assert!(parse_chain_code("abcd").is_err());
+        assert!(parse_chain_code(&"zz".repeat(32)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_dice_rolls() -> Result<()> {
+        let rolls = parse_dice_rolls("1, 4,2,6")?;
+        assert_eq!(rolls.0, vec![1, 4, 2, 6]);
+
+        assert!(parse_dice_rolls("1,7").is_err());
+        assert!(parse_dice_rolls("0").is_err());
+        assert!(parse_dice_rolls("1,,2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explorer_url() {
+        let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        assert_eq!(
+            explorer_url(config::DEFAULT_EXPLORER_URL, address),
+            format!("https://mempool.space/address/{address}")
+        );
+        assert_eq!(
+            explorer_url("https://blockstream.info/address/", address),
+            format!("https://blockstream.info/address/{address}")
+        );
+    }
+
+    #[test]
+    fn test_parse_derive_paths() -> Result<()> {
+        let paths = parse_derive_paths("84,0,0; 49,0,0;44, 0, 0")?;
+        assert_eq!(
+            paths.0,
+            vec![vec![84, 0, 0], vec![49, 0, 0], vec![44, 0, 0]]
+        );
+
+        assert!(parse_derive_paths("84,0,0;").is_err());
+        assert!(parse_derive_paths("84,x,0").is_err());
+        assert!(parse_derive_paths("2147483648").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_required_version() -> Result<()> {
+        let baseline = FirmwareVersion::new(1, 0, 3);
+        assert_eq!(parse_required_version(">=1.0.3")?, baseline);
+        assert_eq!(parse_required_version(">= 1.0.3")?, baseline);
+        assert_eq!(parse_required_version("1.0.3")?, baseline);
+        assert!(parse_required_version("<1.0.3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_path() {
+        let hardened = 1 << 31;
+        assert_eq!(
+            format_path(&[84 | hardened, hardened, hardened]),
+            "m/84'/0'/0'"
+        );
+        assert_eq!(format_path(&[48 | hardened, 1]), "m/48'/1");
+        assert_eq!(format_path(&[]), "m");
     }
 }
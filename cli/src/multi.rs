@@ -0,0 +1,127 @@
+//! `--all-readers`: run a read-only command on the card in every reader at the same time.
+
+use crate::cert_cache::CertCacheArgs;
+use crate::error_code::ErrorCode;
+use crate::output::*;
+use crate::{AutoCommand, ConnectArgs, card_status, check_cert, connect_all};
+use anyhow::{Context, Result, bail};
+use cktap_direct::CkTapCard;
+use cktap_direct::commands::CkTransport;
+use clap::error::ErrorKind;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+enum Query {
+    Status { raw: bool },
+    Certs(CertCacheArgs),
+}
+
+/// Run `command` on every card found and print the responses keyed by card ident
+pub async fn run_all_readers(
+    command: AutoCommand,
+    connection: &ConnectArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let query = match command {
+        AutoCommand::Status { raw } => Query::Status { raw },
+        AutoCommand::Certs { cache } => Query::Certs(cache),
+        _ => bail!(clap::Error::raw(
+            ErrorKind::ArgumentConflict,
+            "--all-readers only works with `auto status` and `auto certs`",
+        )),
+    };
+    let cards = connect_all(connection).await?;
+
+    // USB transfers block, so each card gets its own thread rather than a task
+    let results = std::thread::scope(|scope| {
+        let threads: Vec<_> = cards
+            .into_iter()
+            .map(|card| scope.spawn(|| query_card(card, &query)))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Card thread panicked"))?
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    output_response(all_readers_response(results), format)
+}
+
+/// Run `query` on its own runtime, so the card is queried in parallel with the others
+fn query_card<T: CkTransport>(mut card: CkTapCard<T>, query: &Query) -> Result<(String, Value)> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start card runtime")?;
+    runtime.block_on(async {
+        let ident = card.ident();
+        let response = match (query, &mut card) {
+            (Query::Status { raw }, card) => {
+                serde_json::to_value(success_response(card_status(card, *raw).await?))
+            }
+            (Query::Certs(cache), CkTapCard::SatsCard(sc)) => {
+                serde_json::to_value(check_cert(sc, cache).await?)
+            }
+            (Query::Certs(cache), CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts)) => {
+                serde_json::to_value(check_cert(ts, cache).await?)
+            }
+        }?;
+        Ok((ident, response))
+    })
+}
+
+fn all_readers_response(results: Vec<(String, Value)>) -> CommandResponse<AllReadersResponse> {
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|(_, response)| !response["success"].as_bool().unwrap_or(false))
+        .collect();
+    let error_code = failed.first().map(|(_, response)| {
+        serde_json::from_value(response["error_code"].clone()).unwrap_or(ErrorCode::Other)
+    });
+    CommandResponse {
+        success: failed.is_empty(),
+        error: (!failed.is_empty()).then(|| {
+            format!(
+                "{failed} of {total} cards failed",
+                failed = failed.len(),
+                total = results.len()
+            )
+        }),
+        error_code,
+        data: Some(AllReadersResponse {
+            cards: results.into_iter().collect::<BTreeMap<_, _>>(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_readers_response() {
+        let results = vec![
+            (
+                "CARD-00000002".to_string(),
+                serde_json::json!({"success": false, "error_code": "card_error"}),
+            ),
+            (
+                "CARD-00000001".to_string(),
+                serde_json::json!({"success": true}),
+            ),
+        ];
+        let response = all_readers_response(results);
+        assert!(!response.success);
+        assert_eq!(response.error_code, Some(ErrorCode::CardError));
+        assert_eq!(response.error.as_deref(), Some("1 of 2 cards failed"));
+        let cards = response.data.expect("cards").cards;
+        assert_eq!(
+            cards.keys().collect::<Vec<_>>(),
+            ["CARD-00000001", "CARD-00000002"]
+        );
+    }
+}
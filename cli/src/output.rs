@@ -1,5 +1,18 @@
+use crate::error_code::{self, ErrorCode};
+use crate::yaml;
+use anyhow::Context as _;
+use bitcoin::base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use bitcoin::hashes::{Hash as _, sha256, sha256d};
+use bitcoin::hex::DisplayHex as _;
+use bitcoin::secp256k1::ecdsa::Signature;
+use cktap_direct::version::FirmwareVersion;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
 use strum::{Display, EnumString, VariantNames};
 
 /// Output format for CLI commands
@@ -8,6 +21,102 @@ use strum::{Display, EnumString, VariantNames};
 pub enum OutputFormat {
     Json,
     Plain,
+    /// One JSON event per line: progress, prompts and messages while the command runs, then the
+    /// result
+    Ndjson,
+    Yaml,
+    /// The response as CBOR, raw bytes
+    Cbor,
+}
+
+/// The `--format` of this run, for what is written outside of the final response
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+pub fn set_format(format: OutputFormat) {
+    FORMAT.get_or_init(|| format);
+}
+
+/// With `-q`, progress and info messages aren't shown on stderr
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Something happening while a command runs, on stderr for people or as an NDJSON line on stdout
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Progress {
+        step: usize,
+        total: usize,
+        message: &'a str,
+    },
+    /// The command waits for input, e.g. the CVC
+    Prompt {
+        message: &'a str,
+    },
+    Info {
+        message: &'a str,
+    },
+    /// Something the user should act on, the command still succeeds
+    Warning {
+        message: &'a str,
+    },
+}
+
+pub fn emit(event: Event<'_>) {
+    if FORMAT.get() == Some(&OutputFormat::Ndjson) {
+        if let Ok(json) = serde_json::to_string(&event) {
+            println!("{json}");
+        }
+        return;
+    }
+    if QUIET.load(Ordering::Relaxed) && matches!(event, Event::Progress { .. } | Event::Info { .. })
+    {
+        return;
+    }
+
+    match event {
+        Event::Progress {
+            step,
+            total,
+            message,
+        } => eprintln!("[{step}/{total}] {message}"),
+        Event::Prompt { message } => {
+            eprint!("{message}: ");
+            let _ = std::io::stderr().flush();
+        }
+        Event::Info { message } => eprintln!("{message}"),
+        Event::Warning { message } => eprintln!("Warning: {message}"),
+    }
+}
+
+/// Responses collected instead of printed, while a batch runs a command
+static CAPTURED: Mutex<Option<Vec<serde_json::Value>>> = Mutex::new(None);
+
+/// Collect the responses of the next command instead of printing them
+pub fn capture_responses() {
+    if let Ok(mut captured) = CAPTURED.lock() {
+        *captured = Some(Vec::new());
+    }
+}
+
+/// The responses collected since `capture_responses`, printing again from now on
+pub fn captured_responses() -> Vec<serde_json::Value> {
+    CAPTURED
+        .lock()
+        .ok()
+        .and_then(|mut captured| captured.take())
+        .unwrap_or_default()
+}
+
+/// The final response as an NDJSON event
+#[derive(Serialize)]
+struct ResultEvent<'a, T> {
+    event: &'static str,
+    #[serde(flatten)]
+    response: &'a CommandResponse<T>,
 }
 
 /// Generic command response wrapper
@@ -16,14 +125,137 @@ pub struct CommandResponse<T> {
     pub success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// Category of the error, stable for scripts to branch on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<T>,
 }
 
+impl<T> CommandResponse<T> {
+    /// Make the process exit with the code of this response's error category, if it failed
+    pub fn record_outcome(&self) {
+        if !self.success {
+            error_code::record_failure(self.error_code.unwrap_or(ErrorCode::Other));
+        }
+    }
+}
+
 /// Address command response
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddressResponse {
     pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u8>,
+    /// The address as a QR code drawn with Unicode half blocks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explorer_url: Option<String>,
+}
+
+/// `satscard url` response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SlotUrlResponse {
+    pub slot: u8,
+    pub address: String,
+    /// The link proving the slot's address is sealed on the card
+    pub url: String,
+    /// The link as a QR code drawn with Unicode half blocks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qr: Option<String>,
+}
+
+/// A card presented to `watch`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardPresentedResponse {
+    pub card_type: String,
+    pub card_ident: String,
+    /// SatsCard only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<AddressResponse>,
+    /// Exit code of the `--on-insert` command, if it ran to completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action_exit_code: Option<i32>,
+}
+
+/// `cards list` response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardsResponse {
+    /// The registry file
+    pub registry: String,
+    pub cards: Vec<KnownCard>,
+}
+
+/// A card of the registry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KnownCard {
+    pub card_ident: String,
+    pub card_type: String,
+    pub firmware: String,
+    /// Unix time
+    pub first_seen: u64,
+    /// Unix time
+    pub last_seen: u64,
+    /// How the last certificate check went, `None` if it was never checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genuine: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+    /// TapSigner and SatsChip only, once a command read it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub master_fingerprint: Option<String>,
+}
+
+/// `provision` response, the cards of the run as recorded in the inventory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProvisionResponse {
+    pub cards: Vec<ProvisionedCard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<String>,
+}
+
+/// One card of a `provision` run, a row of the inventory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProvisionedCard {
+    pub card_ident: String,
+    pub card_type: String,
+    pub status: ProvisionStatus,
+    /// The certificate chain leads to a Coinkite root
+    pub genuine: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+    /// Master key fingerprint, TapSigner and SatsChip only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Xpub at `path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xpub: Option<String>,
+    /// SatsCard only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisionStatus {
+    /// A new TapSigner or SatsChip, initialized by this run
+    Initialized,
+    /// A TapSigner or SatsChip initialized before, left as it was
+    AlreadyInitialized,
+    /// A SatsCard whose address checked out
+    Verified,
+    /// Not genuine, or a step failed, see `error`
+    Failed,
 }
 
 /// Certificate verification response
@@ -34,6 +266,9 @@ pub struct CertsResponse {
     pub signed_by: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// With `--cert-cache`: the chain was the cached one, so it wasn't walked again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<bool>,
 }
 
 /// Read command response
@@ -50,6 +285,8 @@ pub struct ReadResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewSlotResponse {
     pub slot: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_code: Option<String>,
 }
 
 /// Unseal response
@@ -63,6 +300,68 @@ pub struct UnsealResponse {
     pub chain_code: Option<String>,
 }
 
+/// `satscard recover` response, the slot's keys recomputed from saved unseal output
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoverResponse {
+    pub slot: u8,
+    pub address: String,
+    pub pubkey: String,
+    /// The slot's private key in wallet import format
+    pub wif: String,
+}
+
+/// Dry-run response for state-changing commands
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DryRunResponse {
+    pub action: String,
+    pub slot: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// What the slot holds, when a chain backend is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explorer_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_code: Option<String>,
+}
+
+/// Slot plan response (`satscard plan`)
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlanResponse {
+    pub current_slot: u8,
+    pub total_slots: u8,
+    /// Slots that can still hold a gift, the current one included unless it was unsealed
+    pub slots_left: u8,
+    /// Slot of each gift, in order
+    pub slots: Vec<u8>,
+    pub steps: Vec<PlanStep>,
+    /// The plan unseals the last slot, after which the card can't hold funds again
+    pub uses_last_slot: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlanStep {
+    pub action: PlanAction,
+    pub slot: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_sat: Option<u64>,
+    /// The command doing it, none for funding the address, which happens in a wallet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    /// Pick the slot's key, so it has an address
+    New,
+    /// Send the gift to the slot's address
+    Fund,
+    /// Reveal the slot's key to sweep the gift
+    Unseal,
+}
+
 /// Derive response
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeriveResponse {
@@ -74,13 +373,301 @@ pub struct DeriveResponse {
     pub chain_code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub addresses: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receive_addresses: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_addresses: Option<Vec<String>>,
+}
+
+/// Batch derive response, keyed by derivation path
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeriveManyResponse {
+    pub keys: BTreeMap<String, DerivedKey>,
 }
 
-/// Init response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DerivedKey {
+    pub xpub: String,
+    pub pubkey: String,
+}
+
+/// Response for a command the connected card type doesn't support
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsupportedResponse {
+    pub command: String,
+    pub card_type: String,
+}
+
+/// Wait response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitCardResponse {
+    pub waited_seconds: usize,
+}
+
+/// USB readers response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadersResponse {
+    pub readers: Vec<ReaderInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReaderInfo {
+    pub usb_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub is_coinkite: bool,
+    pub is_ccid: bool,
+    pub accessible: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel_driver_active: Option<bool>,
+}
+
+/// `readers info` response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadersInfoResponse {
+    pub readers: Vec<ReaderDetails>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReaderDetails {
+    pub usb_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// The reader's CCID class descriptor, `None` if it has none
+    pub descriptor: Option<CcidDescriptorInfo>,
+}
+
+/// What a reader's CCID class descriptor says it handles
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CcidDescriptorInfo {
+    pub ccid_version: String,
+    pub slots: u16,
+    /// character, TPDU, short APDU or short and extended APDU
+    pub exchange_level: String,
+    /// dwMaxCCIDMessageLength, the 10 bytes header included
+    pub max_message_length: u32,
+    /// The most APDU bytes sent in one message, longer ones are chained
+    pub max_block_data: usize,
+    pub voltages: Vec<String>,
+    pub protocols: Vec<String>,
+    pub default_clock_khz: u32,
+    pub max_clock_khz: u32,
+    pub data_rate_bps: u32,
+    pub max_data_rate_bps: u32,
+    pub max_ifsd: u32,
+    /// dwFeatures in hex, then the features it lists
+    pub features_raw: String,
+    pub features: Vec<String>,
+}
+
+/// Doctor response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorResponse {
+    pub checks: Vec<DoctorCheck>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+    /// Not run, e.g. because it needs the result of a check that failed
+    Skip,
+}
+
+/// Raw APDU debug response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawApduResponse {
+    pub apdu: String,
+    pub response: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cbor: Option<serde_json::Value>,
+}
+
+/// Init response. A card initialized before is left alone, with `success` false and
+/// `already_initialized` saying why.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InitResponse {
     pub card_ident: String,
     pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_code: Option<String>,
+    /// e.g. "already initialized (path m/84'/0'/0', birth 800000)"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub already_initialized: Option<String>,
+}
+
+/// Setup wizard response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetupResponse {
+    pub card_ident: String,
+    pub chain_code: String,
+    pub backup_file: String,
+    pub cvc_changed: bool,
+    pub path: String,
+    pub master_fingerprint: String,
+    pub xpub: String,
+    pub receive_descriptor: String,
+    pub change_descriptor: String,
+}
+
+/// SatsCard verify-new wizard response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyNewResponse {
+    pub card_ident: String,
+    pub slot: u8,
+    pub signed_by: String,
+    pub pubkey: String,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explorer_url: Option<String>,
+}
+
+/// SatsCard address verification response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyAddressResponse {
+    pub slot: u8,
+    pub pubkey: String,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explorer_url: Option<String>,
+}
+
+/// One command of a batch and its response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub command: String,
+    pub success: bool,
+    pub response: serde_json::Value,
+}
+
+/// Batch response, with the results in the order the commands ran
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub completed: usize,
+    /// Commands not run because an earlier one failed
+    pub skipped: usize,
+    pub results: Vec<BatchResult>,
+}
+
+/// Responses of the cards in every reader, by card ident
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AllReadersResponse {
+    pub cards: BTreeMap<String, serde_json::Value>,
+}
+
+/// One `auto verify` check
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// `auto verify` report, passed when no check failed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyResponse {
+    pub card_type: String,
+    pub card_ident: String,
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+/// SATSCARD `derive` response: the slot key and address, with each check of them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SlotDeriveResponse {
+    pub slot: u8,
+    pub master_pubkey: String,
+    pub chain_code: String,
+    /// The slot pubkey derived from the master pubkey and chain code
+    pub pubkey: String,
+    pub address: String,
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+/// Wallet export response, when the wallet file was written to disk
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportResponse {
+    pub wallet: String,
+    pub file: String,
+}
+
+/// PSBT inspect response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PsbtInspectResponse {
+    pub fingerprint: String,
+    pub inputs: Vec<PsbtInputInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_in_sat: Option<u64>,
+    pub total_out_sat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_sat: Option<u64>,
+    pub signable_inputs: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PsbtInputInfo {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_type: Option<String>,
+    pub sighash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub signable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// PSBT sign response, when the signed PSBT was written to a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PsbtSignResponse {
+    pub file: String,
+    pub signed_inputs: usize,
+}
+
+/// PSBT sign response, with the signed PSBT in base64
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedPsbtResponse {
+    pub psbt: String,
+    pub signed_inputs: usize,
+}
+
+/// Xpub response, of the master key or of the card's current path
+#[derive(Debug, Serialize, Deserialize)]
+pub struct XpubResponse {
+    pub xpub: String,
+    pub master: bool,
+}
+
+/// PSBT finalize response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PsbtFinalizeResponse {
+    pub psbt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<String>,
 }
 
 /// Backup response
@@ -94,6 +681,7 @@ pub struct BackupResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChangeResponse {
     pub success: bool,
+    /// Seconds spent waiting out the delay of a card penalized for wrong CVCs, if it had one
     pub delay_seconds: Option<u32>,
 }
 
@@ -101,7 +689,94 @@ pub struct ChangeResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignResponse {
     pub signature: String,
+    pub signature_format: SigFormat,
     pub pubkey: String,
+    /// How the input was read and hashed, and the digest the card signed, in hex
+    pub input: SignInput,
+    pub hash: HashMode,
+    pub digest: String,
+}
+
+/// How `sign` reads the data it's given
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, VariantNames, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SignInput {
+    /// The argument's UTF-8 bytes
+    Text,
+    /// The argument as hex
+    Hex,
+    /// The content of the file the argument names, stdin for `-`
+    File,
+}
+
+/// How `sign` turns the data into the 32-byte digest the card signs
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, VariantNames, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum HashMode {
+    Sha256,
+    /// SHA256 twice, as txids and message hashes are
+    Sha256d,
+    /// The data is the digest itself, exactly 32 bytes
+    None,
+}
+
+impl HashMode {
+    pub fn digest(self, data: &[u8]) -> anyhow::Result<[u8; 32]> {
+        match self {
+            HashMode::Sha256 => Ok(sha256::Hash::hash(data).to_byte_array()),
+            HashMode::Sha256d => Ok(sha256d::Hash::hash(data).to_byte_array()),
+            HashMode::None => <[u8; 32]>::try_from(data).map_err(|_| {
+                anyhow::anyhow!(
+                    "--hash none needs a 32-byte digest, got {len} bytes",
+                    len = data.len()
+                )
+            }),
+        }
+    }
+}
+
+/// `tapsigner challenge` response, what a service needs to check the card signed its challenge
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub challenge: String,
+    /// The tagged hash of the challenge the card signed
+    pub digest: String,
+    pub pubkey: String,
+    /// Path of the key that signed, the card's derivation path followed by the challenge sub path
+    pub path: String,
+    /// 64 bytes `r || s` in hex
+    pub signature: String,
+}
+
+/// Encoding of the signature in a sign response
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, VariantNames, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SigFormat {
+    /// 64 bytes `r || s` in hex, as the card returns it
+    Compact,
+    /// DER in hex, as OpenSSL and script debuggers expect it
+    Der,
+    /// 64 bytes `r || s` in base64
+    Base64,
+}
+
+impl SigFormat {
+    pub fn encode(self, signature: &Signature) -> String {
+        match self {
+            SigFormat::Compact => signature.serialize_compact().as_hex().to_string(),
+            SigFormat::Der => signature.serialize_der().as_hex().to_string(),
+            SigFormat::Base64 => BASE64.encode(signature.serialize_compact()),
+        }
+    }
 }
 
 /// Debug/Status response
@@ -115,10 +790,46 @@ pub struct DebugResponse {
     pub slots: Option<SlotInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<Vec<u32>>,
+    /// `path` as text, e.g. m/84'/0'/0'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derivation_path: Option<String>,
+    /// Backups taken so far, TapSigner and SatsChip only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_backups: Option<usize>,
+    /// `proto` of the status response
+    pub protocol_version: usize,
+    /// `applet_version` split into numbers, if it parses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applet_semver: Option<AppletSemver>,
     pub applet_version: String,
+    /// The status response CBOR in hex, with `--raw`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_status: Option<String>,
     pub is_testnet: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppletSemver {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl AppletSemver {
+    pub fn parse(ver: &str) -> Option<Self> {
+        let FirmwareVersion {
+            major,
+            minor,
+            patch,
+        } = ver.parse().ok()?;
+        Some(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SlotInfo {
     pub current: u8,
@@ -126,18 +837,96 @@ pub struct SlotInfo {
 }
 
 /// Helper function to output response based on format
-pub fn output_response<T: Serialize>(response: T, format: OutputFormat) -> anyhow::Result<()> {
-    match format {
-        OutputFormat::Json => {
-            println!("{json}", json = serde_json::to_string_pretty(&response)?);
+pub fn output_response<T: Serialize>(
+    response: CommandResponse<T>,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    if let Ok(mut captured) = CAPTURED.lock()
+        && let Some(captured) = &mut *captured
+    {
+        captured.push(serde_json::to_value(&response)?);
+        return Ok(());
+    }
+
+    response.record_outcome();
+    let Some(bytes) = serialize(&response, format)? else {
+        // For plain output, we'll need custom formatting per response type
+        // This will be implemented as needed for each command
+        eprintln!("Plain output not yet implemented for this command");
+        return Ok(());
+    };
+    match OUTPUT_FILE.lock().ok().and_then(|path| path.clone()) {
+        Some(path) => write_atomic(&path, &bytes),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(&bytes)
+                .and_then(|()| stdout.flush())
+                .context("Failed to write the result to stdout")
         }
-        OutputFormat::Plain => {
-            // For plain output, we'll need custom formatting per response type
-            // This will be implemented as needed for each command
-            eprintln!("Plain output not yet implemented for this command");
+    }
+}
+
+/// A response in `format`, the text formats ending with a newline. `None` for plain output,
+/// which commands print themselves.
+pub fn serialize<T: Serialize>(
+    response: &CommandResponse<T>,
+    format: OutputFormat,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let text = match format {
+        OutputFormat::Json => serde_json::to_string_pretty(response)?,
+        OutputFormat::Plain => return Ok(None),
+        OutputFormat::Ndjson => serde_json::to_string(&ResultEvent {
+            event: "result",
+            response,
+        })?,
+        OutputFormat::Yaml => {
+            return Ok(Some(
+                yaml::to_string(&serde_json::to_value(response)?).into_bytes(),
+            ));
         }
+        OutputFormat::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(response, &mut bytes)?;
+            return Ok(Some(bytes));
+        }
+    };
+    Ok(Some(format!("{text}\n").into_bytes()))
+}
+
+/// `-o/--output`: the file the result goes to instead of stdout
+static OUTPUT_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn set_output_file(path: PathBuf) {
+    if let Ok(mut output_file) = OUTPUT_FILE.lock() {
+        *output_file = Some(path);
+    }
+}
+
+/// Claim the `-o` file for a command writing a file of its own (signed PSBT, wallet), its
+/// result is then printed to stdout
+pub fn take_output_file() -> Option<PathBuf> {
+    OUTPUT_FILE.lock().ok().and_then(|mut path| path.take())
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`, so readers never
+/// see a partly written file
+pub fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{path} is not a file", path = path.display()))?;
+    let temp = path.with_file_name(format!(
+        ".{name}.{pid}.tmp",
+        name = name.to_string_lossy(),
+        pid = std::process::id()
+    ));
+    let written = File::create(&temp)
+        .and_then(|mut file| file.write_all(contents).and_then(|()| file.sync_all()))
+        .and_then(|()| std::fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
     }
-    Ok(())
+    written.with_context(|| format!("Failed to write {path}", path = path.display()))
 }
 
 /// Helper to create success response
@@ -145,15 +934,114 @@ pub fn success_response<T>(data: T) -> CommandResponse<T> {
     CommandResponse {
         success: true,
         error: None,
+        error_code: None,
         data: Some(data),
     }
 }
 
 /// Helper to create error response
-pub fn error_response<T>(error: String) -> CommandResponse<T> {
+pub fn error_response<T>(error: &(dyn std::error::Error + 'static)) -> CommandResponse<T> {
     CommandResponse {
         success: false,
-        error: Some(error),
+        error: Some(error.to_string()),
+        error_code: Some(ErrorCode::of(error)),
         data: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ndjson_events() -> anyhow::Result<()> {
+        let progress = Event::Progress {
+            step: 1,
+            total: 5,
+            message: "Initializing card",
+        };
+        assert_eq!(
+            serde_json::to_value(&progress)?,
+            json!({"event": "progress", "step": 1, "total": 5, "message": "Initializing card"})
+        );
+
+        let response = success_response(WaitCardResponse { waited_seconds: 3 });
+        let event = ResultEvent {
+            event: "result",
+            response: &response,
+        };
+        assert_eq!(
+            serde_json::to_string(&event)?,
+            r#"{"event":"result","success":true,"data":{"waited_seconds":3}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sig_format() -> anyhow::Result<()> {
+        // r = 1, s = 1
+        let mut compact = [0u8; 64];
+        compact[31] = 1;
+        compact[63] = 1;
+        let signature = Signature::from_compact(&compact)?;
+        assert_eq!(
+            SigFormat::Compact.encode(&signature),
+            compact.as_hex().to_string()
+        );
+        assert_eq!(SigFormat::Der.encode(&signature), "3006020101020101");
+        assert_eq!(SigFormat::Base64.encode(&signature), BASE64.encode(compact));
+        assert_eq!("der".parse::<SigFormat>()?, SigFormat::Der);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_mode() -> anyhow::Result<()> {
+        assert_eq!(
+            HashMode::Sha256.digest(b"abc")?.as_hex().to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            HashMode::Sha256d.digest(b"abc")?.as_hex().to_string(),
+            "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358"
+        );
+        assert_eq!(HashMode::None.digest(&[7; 32])?, [7; 32]);
+        assert!(HashMode::None.digest(b"abc").is_err());
+        assert_eq!("sha256d".parse::<HashMode>()?, HashMode::Sha256d);
+        assert_eq!("none".parse::<HashMode>()?, HashMode::None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomic() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("cktap-output-{pid}", pid = std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("result.json");
+        write_atomic(&path, b"first")?;
+        write_atomic(&path, b"second")?;
+        let written = std::fs::read_to_string(&path)?;
+        let files = std::fs::read_dir(&dir)?.count();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(written, "second");
+        assert_eq!(files, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize() -> anyhow::Result<()> {
+        let response = success_response(WaitCardResponse { waited_seconds: 3 });
+        let yaml = serialize(&response, OutputFormat::Yaml)?;
+        assert_eq!(
+            yaml.as_deref(),
+            Some(&b"data:\n  waited_seconds: 3\nsuccess: true\n"[..])
+        );
+
+        let cbor = serialize(&response, OutputFormat::Cbor)?.unwrap_or_default();
+        let decoded: serde_json::Value = ciborium::de::from_reader(cbor.as_slice())?;
+        assert_eq!(decoded, serde_json::to_value(&response)?);
+
+        assert!(serialize(&response, OutputFormat::Plain)?.is_none());
+        Ok(())
+    }
+}
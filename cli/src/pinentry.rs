@@ -0,0 +1,136 @@
+//! Ask for the CVC with an external pinentry program (GnuPG's Assuan protocol), so desktops get a
+//! dialog and the CVC doesn't go through the terminal. Without `--pinentry` it is read from the
+//! terminal.
+
+use crate::output::{Event, emit};
+use anyhow::{Context, Result, bail};
+use rpassword::read_password;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+static PROGRAM: OnceLock<PathBuf> = OnceLock::new();
+
+/// Ask every secret of this run through `program`
+pub fn set_program(program: PathBuf) {
+    let _ = PROGRAM.set(program);
+}
+
+/// Ask for a secret with `prompt`, through the pinentry program if one is set
+pub fn read_secret(prompt: &str) -> Result<String> {
+    emit(Event::Prompt { message: prompt });
+    match PROGRAM.get() {
+        Some(program) => ask(program, prompt),
+        None => Ok(read_password()?),
+    }
+}
+
+fn ask(program: &Path, description: &str) -> Result<String> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| {
+            format!(
+                "Failed to start pinentry {program}",
+                program = program.display()
+            )
+        })?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        bail!("Failed to talk to pinentry");
+    };
+    let secret = get_pin(&mut BufReader::new(stdout), stdin, description);
+    let _ = child.wait();
+    secret.with_context(|| format!("pinentry {program} failed", program = program.display()))
+}
+
+/// Run a pinentry session: greeting, dialog texts, `GETPIN`, then `BYE`
+fn get_pin(reader: &mut impl BufRead, mut writer: impl Write, description: &str) -> Result<String> {
+    let mut session = |command: Option<&str>| -> Result<Option<String>> {
+        if let Some(command) = command {
+            writeln!(writer, "{command}")?;
+            writer.flush()?;
+        }
+        let mut data = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                bail!("pinentry exited");
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            match line.split_once(' ').unwrap_or((line, "")) {
+                ("OK", _) => return Ok(data),
+                ("D", value) => data = Some(unescape(value)?),
+                ("ERR", error) => {
+                    // GPG_ERR_CANCELED from the pinentry source
+                    if error.starts_with("83886179") {
+                        bail!("CVC entry cancelled");
+                    }
+                    bail!("{error}");
+                }
+                // status and comment lines
+                ("S", _) | ("#", _) => {}
+                _ => bail!("unexpected answer '{line}'"),
+            }
+        }
+    };
+
+    session(None)?;
+    session(Some("SETTITLE cktap-direct"))?;
+    session(Some(&format!(
+        "SETDESC {description}",
+        description = escape(description)
+    )))?;
+    session(Some("SETPROMPT CVC:"))?;
+    let pin = session(Some("GETPIN"))?.unwrap_or_default();
+    let _ = session(Some("BYE"));
+    Ok(pin)
+}
+
+/// Percent-escape the characters Assuan lines can't carry
+fn escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\n', "%0A")
+        .replace('\r', "%0D")
+}
+
+fn unescape(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2])?;
+            bytes.push(u8::from_str_radix(hex, 16).context("invalid escape")?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_pin() -> Result<()> {
+        let mut answers: &[u8] =
+            b"OK Pleased to meet you\nOK\nOK\n# comment\nOK\nS PASSPHRASE\nD 12%25456\nOK\nOK closing\n";
+        let mut commands = Vec::new();
+        let pin = get_pin(&mut answers, &mut commands, "Enter CVC\nfor CARD-1")?;
+        assert_eq!(pin, "12%456");
+        assert_eq!(
+            String::from_utf8(commands)?,
+            "SETTITLE cktap-direct\nSETDESC Enter CVC%0Afor CARD-1\nSETPROMPT CVC:\nGETPIN\nBYE\n"
+        );
+
+        let mut cancelled: &[u8] = b"OK\nOK\nOK\nOK\nERR 83886179 Operation cancelled <Pinentry>\n";
+        let error = get_pin(&mut cancelled, Vec::new(), "Enter CVC").unwrap_err();
+        assert_eq!(error.to_string(), "CVC entry cancelled");
+        Ok(())
+    }
+}
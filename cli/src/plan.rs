@@ -0,0 +1,148 @@
+//! `satscard plan`: which slots a series of gifts would use, and the `new` and `unseal` commands
+//! getting there. Only the status and the current slot's state are read from the card.
+
+use crate::output::{PlanAction, PlanResponse, PlanStep};
+use anyhow::{Result, bail, ensure};
+use bitcoin::Amount;
+use cktap_direct::apdu::DumpResponse;
+
+/// State of the card's current slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    /// Has an address, ready to be funded
+    Sealed,
+    /// No key picked yet, needs `new`
+    Unused,
+    /// Its key was revealed, gifts go to the next slots
+    Unsealed,
+}
+
+impl SlotState {
+    pub fn of(dump: &DumpResponse) -> Self {
+        match (dump.used, dump.sealed) {
+            (Some(false), _) => SlotState::Unused,
+            (_, Some(false)) => SlotState::Unsealed,
+            _ => SlotState::Sealed,
+        }
+    }
+}
+
+/// Plan one gift per entry of `gifts`, with its amount if known, from the card's `current` of
+/// `total` slots. Refuses plans unsealing the last slot unless `allow_last_slot`.
+pub fn plan(
+    current: u8,
+    total: u8,
+    state: SlotState,
+    gifts: &[Option<Amount>],
+    allow_last_slot: bool,
+) -> Result<PlanResponse> {
+    let first = match state {
+        SlotState::Sealed | SlotState::Unused => current,
+        SlotState::Unsealed => current.saturating_add(1),
+    };
+    let slots_left = total.saturating_sub(first);
+    ensure!(
+        !gifts.is_empty(),
+        "Nothing to plan, give --gifts or --amounts"
+    );
+    ensure!(
+        gifts.len() <= usize::from(slots_left),
+        "Only {slots_left} slots left on this card, not enough for {count} gifts",
+        count = gifts.len()
+    );
+
+    let slots: Vec<u8> = (first..).take(gifts.len()).collect();
+    let last = slots[slots.len() - 1];
+    let uses_last_slot = last + 1 == total;
+    if uses_last_slot && !allow_last_slot {
+        bail!(
+            "The plan unseals slot {last}, the last one, after which the card can't hold funds \
+             again. Plan fewer gifts, or pass --allow-last-slot"
+        );
+    }
+
+    let mut steps = Vec::new();
+    for (&slot, amount) in slots.iter().zip(gifts) {
+        if slot != current || state == SlotState::Unused {
+            steps.push(PlanStep {
+                action: PlanAction::New,
+                slot,
+                amount_sat: None,
+                command: Some(format!("cktap-direct satscard new --slot {slot}")),
+            });
+        }
+        steps.push(PlanStep {
+            action: PlanAction::Fund,
+            slot,
+            amount_sat: amount.map(Amount::to_sat),
+            command: None,
+        });
+        steps.push(PlanStep {
+            action: PlanAction::Unseal,
+            slot,
+            amount_sat: None,
+            command: Some(format!("cktap-direct satscard unseal --slot {slot}")),
+        });
+    }
+
+    Ok(PlanResponse {
+        current_slot: current,
+        total_slots: total,
+        slots_left,
+        slots,
+        steps,
+        uses_last_slot,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        let gifts = [Some(Amount::from_sat(50_000)), None];
+        let response = plan(3, 10, SlotState::Sealed, &gifts, false)?;
+        assert_eq!(response.slots_left, 7);
+        assert_eq!(response.slots, [3, 4]);
+        assert!(!response.uses_last_slot);
+        let actions: Vec<_> = response
+            .steps
+            .iter()
+            .map(|step| (step.action, step.slot))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (PlanAction::Fund, 3),
+                (PlanAction::Unseal, 3),
+                (PlanAction::New, 4),
+                (PlanAction::Fund, 4),
+                (PlanAction::Unseal, 4),
+            ]
+        );
+        assert_eq!(response.steps[0].amount_sat, Some(50_000));
+        assert_eq!(
+            response.steps[2].command.as_deref(),
+            Some("cktap-direct satscard new --slot 4")
+        );
+
+        let response = plan(3, 10, SlotState::Unused, &[None], false)?;
+        assert_eq!(response.steps[0].action, PlanAction::New);
+        assert_eq!(response.steps[0].slot, 3);
+
+        // the last slot is only used when asked for
+        assert!(plan(8, 10, SlotState::Sealed, &[None, None], false).is_err());
+        assert!(plan(8, 10, SlotState::Sealed, &[None, None], true)?.uses_last_slot);
+        assert!(plan(8, 10, SlotState::Sealed, &[None; 3], true).is_err());
+        assert_eq!(
+            plan(9, 10, SlotState::Unsealed, &[], true)
+                .map_err(|e| e.to_string())
+                .err()
+                .as_deref(),
+            Some("Nothing to plan, give --gifts or --amounts")
+        );
+        assert!(plan(9, 10, SlotState::Unsealed, &[None], true).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,509 @@
+//! Signing policy of the `serve` daemon, checked before the card signs a PSBT or derives the
+//! account it signs with.
+//!
+//! ```toml
+//! # paths the inputs may be signed with, `*` matches any step
+//! allowed_paths = ["m/84'/0'/0'/*/*"]
+//! max_signatures_per_hour = 20
+//! # outputs must pay one of these, or be change back to the card (allow_change)
+//! network = "bitcoin"
+//! allowed_addresses = ["bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"]
+//! allow_change = true
+//! # asked last, with the transaction on stdin as JSON; signs only when it exits with 0
+//! confirm_command = "zenity --question --text 'Sign with the TapSigner?'"
+//! ```
+
+use anyhow::{Context, Result, bail};
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::bip32::{ChildNumber, DerivationPath};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, CompressedPublicKey, Network, Psbt, ScriptBuf};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::{Cvc, TapSigner};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// The policy refused to sign
+#[derive(Debug)]
+pub struct PolicyDenied(pub String);
+
+impl fmt::Display for PolicyDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Refused by the signing policy: {reason}",
+            reason = self.0
+        )
+    }
+}
+
+impl std::error::Error for PolicyDenied {}
+
+/// The policy file, see the module docs
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    allowed_paths: Vec<String>,
+    max_signatures_per_hour: Option<usize>,
+    #[serde(default = "default_network")]
+    network: String,
+    allowed_addresses: Option<Vec<String>>,
+    #[serde(default)]
+    allow_change: bool,
+    confirm_command: Option<String>,
+}
+
+fn default_network() -> String {
+    Network::Bitcoin.to_string()
+}
+
+/// A derivation path where `*` matches any step
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathPattern(Vec<Option<ChildNumber>>);
+
+impl FromStr for PathPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let steps = s.strip_prefix("m").unwrap_or(s).trim_start_matches('/');
+        steps
+            .split('/')
+            .filter(|step| !step.is_empty())
+            .map(|step| match step {
+                "*" => Ok(None),
+                step => ChildNumber::from_str(step)
+                    .map(Some)
+                    .with_context(|| format!("Invalid step '{step}' in path '{s}'")),
+            })
+            .collect::<Result<_>>()
+            .map(PathPattern)
+    }
+}
+
+impl PathPattern {
+    fn matches(&self, path: &DerivationPath) -> bool {
+        self.0.len() == path.len() && self.matches_prefix(path)
+    }
+
+    /// Whether keys of `account`, the two steps of a sub path below it, can match
+    fn matches_account(&self, account: &DerivationPath) -> bool {
+        self.0.len() == account.len() + 2 && self.matches_prefix(account)
+    }
+
+    fn matches_prefix(&self, path: &DerivationPath) -> bool {
+        self.0
+            .iter()
+            .zip(path)
+            .all(|(pattern, step)| pattern.is_none_or(|pattern| pattern == *step))
+    }
+}
+
+/// Limits on what the daemon signs, and the signatures made in the last hour
+#[derive(Debug)]
+pub struct SigningPolicy {
+    allowed_paths: Vec<PathPattern>,
+    max_signatures_per_hour: Option<usize>,
+    network: Network,
+    allowed_addresses: Option<Vec<Address>>,
+    allow_change: bool,
+    confirm_command: Option<String>,
+    signed: VecDeque<Instant>,
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+impl SigningPolicy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy {path}", path = path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid policy {path}", path = path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let file: PolicyFile = toml::from_str(text)?;
+        let network: Network = file
+            .network
+            .parse()
+            .with_context(|| format!("Unknown network '{network}'", network = file.network))?;
+        let allowed_addresses = file
+            .allowed_addresses
+            .map(|addresses| {
+                addresses
+                    .iter()
+                    .map(|address| {
+                        address
+                            .parse::<Address<NetworkUnchecked>>()?
+                            .require_network(network)
+                            .with_context(|| format!("Address {address} is not for {network}"))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        Ok(Self {
+            allowed_paths: file
+                .allowed_paths
+                .iter()
+                .map(|path| path.parse())
+                .collect::<Result<_>>()?,
+            max_signatures_per_hour: file.max_signatures_per_hour,
+            network,
+            allowed_addresses,
+            allow_change: file.allow_change,
+            confirm_command: file.confirm_command,
+            signed: VecDeque::new(),
+        })
+    }
+
+    /// Check `psbt` before `ts` signs it: input paths, the hourly limit, the outputs, then the
+    /// confirmation command. Fails with [`PolicyDenied`].
+    pub async fn check_psbt<T: CkTransport>(
+        &mut self,
+        ts: &mut TapSigner<T>,
+        psbt: &Psbt,
+        cvc: &Cvc,
+    ) -> Result<()> {
+        self.check_paths(ts.path.as_deref(), psbt)?;
+        self.check_rate(psbt.inputs.len(), Instant::now())?;
+        for index in self.unlisted_outputs(psbt) {
+            if !self.allow_change {
+                bail!(PolicyDenied(format!(
+                    "output {index} pays an address that isn't allowed"
+                )));
+            }
+            check_change(ts, psbt, index, cvc).await?;
+        }
+        self.confirm(psbt).await
+    }
+
+    /// Check the card may switch to the account at hardened `path` (as `derive` takes it): some
+    /// allowed path must be under it. Fails with [`PolicyDenied`].
+    pub fn check_derive(&self, path: &[u32]) -> Result<()> {
+        if self.allowed_paths.is_empty() {
+            return Ok(());
+        }
+        let account: DerivationPath = path
+            .iter()
+            .map(|&index| ChildNumber::from(index | 1 << 31))
+            .collect();
+        if !self
+            .allowed_paths
+            .iter()
+            .any(|allowed| allowed.matches_account(&account))
+        {
+            bail!(PolicyDenied(format!(
+                "no allowed path is under m/{account}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Count the signatures of a signed PSBT towards the hourly limit
+    pub fn record(&mut self, signatures: usize) {
+        let now = Instant::now();
+        self.signed.extend(std::iter::repeat_n(now, signatures));
+    }
+
+    /// Check the paths the card signs the inputs with: its current `account` (`TapSigner::path`)
+    /// and the last two steps of each input's path. An input naming another account is refused,
+    /// as `sign_psbt` would switch the card to it.
+    fn check_paths(&self, account: Option<&[usize]>, psbt: &Psbt) -> Result<()> {
+        if self.allowed_paths.is_empty() {
+            return Ok(());
+        }
+        let Some(account) = account else {
+            bail!(PolicyDenied("the card has no derived account".to_string()));
+        };
+        let account: DerivationPath = account
+            .iter()
+            .map(|&step| ChildNumber::from(step as u32))
+            .collect();
+        for (index, input) in psbt.inputs.iter().enumerate() {
+            // the key the card signs with, see TapSigner::sign_psbt
+            let Some((_, (_, claimed))) = input.bip32_derivation.iter().next() else {
+                bail!(PolicyDenied(format!(
+                    "input {index} has no derivation path"
+                )));
+            };
+            let (claimed_account, sub_path) =
+                claimed.as_ref().split_at(claimed.len().saturating_sub(2));
+            if sub_path.len() != 2 || claimed_account != account.as_ref() {
+                bail!(PolicyDenied(format!(
+                    "input {index} names m/{claimed}, not a key of the card's account m/{account}"
+                )));
+            }
+            let path = account.extend(sub_path);
+            if !self
+                .allowed_paths
+                .iter()
+                .any(|allowed| allowed.matches(&path))
+            {
+                bail!(PolicyDenied(format!(
+                    "input {index} is signed with m/{path}, which isn't allowed"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rate(&mut self, signatures: usize, now: Instant) -> Result<()> {
+        while self
+            .signed
+            .front()
+            .is_some_and(|signed| now.duration_since(*signed) >= RATE_WINDOW)
+        {
+            self.signed.pop_front();
+        }
+        match self.max_signatures_per_hour {
+            Some(max) if self.signed.len() + signatures > max => bail!(PolicyDenied(format!(
+                "{signatures} more signatures would exceed {max} per hour, {made} made in the last hour",
+                made = self.signed.len()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Indexes of the outputs not paying an allowed address
+    fn unlisted_outputs(&self, psbt: &Psbt) -> Vec<usize> {
+        let Some(allowed) = &self.allowed_addresses else {
+            return Vec::new();
+        };
+        psbt.unsigned_tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, txout)| {
+                Address::from_script(&txout.script_pubkey, self.network)
+                    .map_or(true, |address| !allowed.contains(&address))
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Run the confirmation command with the transaction on stdin
+    async fn confirm(&self, psbt: &Psbt) -> Result<()> {
+        let Some(command) = &self.confirm_command else {
+            return Ok(());
+        };
+        let summary = serde_json::to_vec(&self.summary(psbt))?;
+        let mut child = if cfg!(windows) {
+            Command::new("cmd")
+                .args(["/C", command])
+                .stdin(Stdio::piped())
+                .spawn()
+        } else {
+            Command::new("sh")
+                .args(["-c", command])
+                .stdin(Stdio::piped())
+                .spawn()
+        }
+        .with_context(|| format!("Failed to run confirmation command '{command}'"))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // a command that doesn't read its input may already be gone
+            let _ = stdin.write_all(&summary).await;
+        }
+        let status = child
+            .wait()
+            .await
+            .with_context(|| format!("Failed to run confirmation command '{command}'"))?;
+        if !status.success() {
+            bail!(PolicyDenied(format!(
+                "'{command}' didn't confirm ({status})"
+            )));
+        }
+        Ok(())
+    }
+
+    fn summary(&self, psbt: &Psbt) -> serde_json::Value {
+        let analysis = cktap_direct::psbt::analyze_psbt(psbt, None);
+        let inputs: Vec<_> = psbt
+            .inputs
+            .iter()
+            .zip(&analysis.inputs)
+            .map(|(input, analysis)| {
+                serde_json::json!({
+                    "index": analysis.index,
+                    "path": input.bip32_derivation.values().next().map(|(_, path)| format!("m/{path}")),
+                    "amount_sat": analysis.amount.map(|amount| amount.to_sat()),
+                })
+            })
+            .collect();
+        let outputs: Vec<_> = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|txout| {
+                serde_json::json!({
+                    "address": Address::from_script(&txout.script_pubkey, self.network)
+                        .map(|address| address.to_string())
+                        .ok(),
+                    "amount_sat": txout.value.to_sat(),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "inputs": inputs,
+            "outputs": outputs,
+            "fee_sat": analysis.fee.map(|amount| amount.to_sat()),
+        })
+    }
+}
+
+/// Check output `index` pays the card's own key: its derivation must follow from the xpub of
+/// the card's current account. The PSBT's word for it isn't enough.
+async fn check_change<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    psbt: &Psbt,
+    index: usize,
+    cvc: &Cvc,
+) -> Result<()> {
+    let denied = |reason: &str| PolicyDenied(format!("output {index} {reason}"));
+    let Some((pubkey, (_, path))) = psbt.outputs[index].bip32_derivation.iter().next() else {
+        bail!(denied("pays an address that isn't allowed"));
+    };
+    let account: Vec<usize> = path
+        .into_iter()
+        .take(path.len().saturating_sub(2))
+        .map(|&step| u32::from(step) as usize)
+        .collect();
+    if path.len() < 2 || ts.path.as_ref() != Some(&account) {
+        bail!(denied("isn't change to the card's current account"));
+    }
+    let xpub = ts
+        .xpub(false, cvc)
+        .await
+        .context("Failed to read the account xpub")?;
+    let change = &path.as_ref()[path.len() - 2..];
+    let derived = xpub
+        .derive_pub(&Secp256k1::new(), &change)
+        .map_err(|_| denied("has a hardened change path"))?;
+    let key = CompressedPublicKey(derived.public_key);
+    let scripts = [
+        ScriptBuf::new_p2wpkh(&key.wpubkey_hash()),
+        ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&key.wpubkey_hash()).script_hash()),
+    ];
+    if *pubkey != derived.public_key
+        || !scripts.contains(&psbt.unsigned_tx.output[index].script_pubkey)
+    {
+        bail!(denied("claims a change key the card doesn't have"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::bip32::Fingerprint;
+    use bitcoin::secp256k1::rand;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, Transaction, TxIn, TxOut};
+
+    #[test]
+    fn test_path_pattern() -> Result<()> {
+        let pattern: PathPattern = "m/84'/0'/0'/*/*".parse()?;
+        let path = |path: &str| DerivationPath::from_str(path).expect("path");
+        assert!(pattern.matches(&path("m/84'/0'/0'/1/7")));
+        assert!(pattern.matches(&path("m/84h/0h/0h/0/0")));
+        assert!(!pattern.matches(&path("m/84'/0'/1'/0/0")));
+        assert!(!pattern.matches(&path("m/84'/0'/0'/0")));
+        assert!("m/84'/x".parse::<PathPattern>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signing_paths() -> Result<()> {
+        let policy = SigningPolicy::parse(r#"allowed_paths = ["m/84'/0'/0'/*/*"]"#)?;
+        let psbt = |path: &str| -> Result<Psbt> {
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: Vec::new(),
+            };
+            let mut psbt = Psbt::from_unsigned_tx(tx)?;
+            let key = Secp256k1::new().generate_keypair(&mut rand::thread_rng()).1;
+            psbt.inputs[0]
+                .bip32_derivation
+                .insert(key, (Fingerprint::default(), path.parse()?));
+            Ok(psbt)
+        };
+        const HARDENED: usize = 1 << 31;
+        let allowed = [84 | HARDENED, HARDENED, HARDENED];
+        let other = [84 | HARDENED, HARDENED, 1 | HARDENED];
+
+        policy.check_paths(Some(&allowed), &psbt("m/84'/0'/0'/0/5")?)?;
+        // the card signs with its own account, whatever the PSBT names
+        let denied = |account: Option<&[usize]>, path| -> Result<bool> {
+            let result = policy.check_paths(account, &psbt(path)?);
+            Ok(result.is_err_and(|e| e.is::<PolicyDenied>()))
+        };
+        assert!(denied(Some(&other), "m/84'/0'/0'/0/5")?);
+        assert!(denied(Some(&other), "m/84'/0'/1'/0/5")?);
+        assert!(denied(Some(&allowed), "m/84'/0'/1'/0/5")?);
+        assert!(denied(Some(&allowed), "m/0/5")?);
+        assert!(denied(None, "m/84'/0'/0'/0/5")?);
+
+        policy.check_derive(&[84, 0, 0])?;
+        assert!(policy.check_derive(&[84, 0, 1]).is_err());
+        assert!(policy.check_derive(&[84, 0]).is_err());
+        SigningPolicy::parse("")?.check_derive(&[84, 0, 1])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() -> Result<()> {
+        let mut policy = SigningPolicy::parse("max_signatures_per_hour = 3")?;
+        let start = Instant::now();
+        policy.check_rate(2, start)?;
+        policy.signed.extend([start, start]);
+        let denied = policy.check_rate(2, start).unwrap_err();
+        assert!(denied.is::<PolicyDenied>());
+        policy.check_rate(1, start)?;
+        policy.check_rate(3, start + RATE_WINDOW)?;
+        assert!(policy.signed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_addresses() -> Result<()> {
+        let allowed = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let policy = SigningPolicy::parse(&format!("allowed_addresses = [\"{allowed}\"]"))?;
+        let script = |address: &str| {
+            address
+                .parse::<Address<NetworkUnchecked>>()
+                .expect("address")
+                .assume_checked()
+                .script_pubkey()
+        };
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: [allowed, "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"]
+                .map(|address| TxOut {
+                    value: Amount::from_sat(1000),
+                    script_pubkey: script(address),
+                })
+                .to_vec(),
+        };
+        let psbt = Psbt::from_unsigned_tx(tx)?;
+        assert_eq!(policy.unlisted_outputs(&psbt), [1]);
+
+        assert!(
+            SigningPolicy::parse(&format!(
+                "network = \"testnet\"\nallowed_addresses = [\"{allowed}\"]"
+            ))
+            .is_err()
+        );
+        assert!(SigningPolicy::parse("max_signatures = 3").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Fleet provisioning: cards presented one after the other are checked and set up, TapSigners
+//! (and SatsChips) initialized and SatsCards verified, and each one recorded in a CSV or JSON
+//! inventory. The inventory is written after every card, so an interrupted run keeps what was
+//! done.
+
+use crate::deadline::{self, Phase};
+use crate::output::*;
+use crate::registry;
+use crate::{ConnectArgs, card_type, connect, format_path};
+use anyhow::{Context, Result, ensure};
+use cktap_direct::commands::{Certificate, CkTransport};
+use cktap_direct::secp256k1::rand;
+use cktap_direct::{CkTapCard, SatsCard, TapSigner, rand_chaincode};
+use clap::Args;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct ProvisionArgs {
+    /// Number of cards to provision
+    #[arg(long)]
+    count: usize,
+
+    /// CSV inventory to append a row per card to, with a header if it's new
+    #[arg(long, value_name = "FILE")]
+    csv: Option<PathBuf>,
+
+    /// JSON inventory, an array of the cards of this run
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+
+    /// Milliseconds between checks for the next card
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    interval: u64,
+}
+
+const CSV_HEADER: &str =
+    "card_ident,card_type,status,genuine,signed_by,fingerprint,path,xpub,slot,address,error";
+
+/// Provision `--count` cards, each one once, waiting for the next one to be presented after
+/// each. A card failing a step is recorded as failed and counts towards `--count`.
+pub async fn provision(
+    args: &ProvisionArgs,
+    connection: &ConnectArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    ensure!(
+        args.count > 0,
+        "Nothing to provision, --count must be at least 1"
+    );
+    let mut cards = Vec::new();
+    let mut done = HashSet::new();
+    while cards.len() < args.count {
+        emit(Event::Info {
+            message: &format!(
+                "Present card {number} of {count}",
+                number = cards.len() + 1,
+                count = args.count
+            ),
+        });
+        let mut card = next_card(connection, &done, args.interval).await;
+        let ident = card.ident();
+        let provisioned = provision_card(&mut card, &ident).await;
+        emit(Event::Info {
+            message: &format!("{ident}: {status}, remove it", status = provisioned.status),
+        });
+        if let Some(path) = &args.csv {
+            append_csv(path, &provisioned)?;
+        }
+        done.insert(ident);
+        cards.push(provisioned);
+        if let Some(path) = &args.json {
+            write_atomic(path, &serde_json::to_vec_pretty(&cards)?)?;
+        }
+    }
+
+    let failed = cards
+        .iter()
+        .filter(|card| card.status == ProvisionStatus::Failed)
+        .count();
+    if failed > 0 {
+        emit(Event::Warning {
+            message: &format!("{failed} of {count} cards failed", count = cards.len()),
+        });
+    }
+    let response = ProvisionResponse {
+        cards,
+        csv: args.csv.as_ref().map(|path| path.display().to_string()),
+        json: args.json.as_ref().map(|path| path.display().to_string()),
+    };
+    output_response(success_response(response), format)
+}
+
+/// The first card presented that wasn't provisioned in this run yet
+async fn next_card<'a>(
+    connection: &'a ConnectArgs,
+    done: &HashSet<String>,
+    interval: u64,
+) -> CkTapCard<impl CkTransport + use<'a>> {
+    loop {
+        match connect(connection).await {
+            Ok(card) if !done.contains(&card.ident()) => return card,
+            Ok(_) => log::debug!("Card still on the reader"),
+            Err(e) => log::debug!("No card: {e:#}"),
+        }
+        deadline::enter(Phase::WaitingForCard);
+        tokio::time::sleep(Duration::from_millis(interval)).await;
+    }
+}
+
+/// Check the card is genuine and set it up, recording what failed rather than stopping
+async fn provision_card<T: CkTransport>(card: &mut CkTapCard<T>, ident: &str) -> ProvisionedCard {
+    let mut provisioned = ProvisionedCard {
+        card_ident: ident.to_string(),
+        card_type: card_type(card).to_string(),
+        status: ProvisionStatus::Failed,
+        genuine: false,
+        signed_by: None,
+        fingerprint: None,
+        path: None,
+        xpub: None,
+        slot: None,
+        address: None,
+        error: None,
+    };
+    let result = match card {
+        CkTapCard::SatsCard(sc) => provision_satscard(sc, &mut provisioned).await,
+        CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+            provision_tapsigner(ts, &mut provisioned).await
+        }
+    };
+    match result {
+        Ok(status) => provisioned.status = status,
+        Err(e) => provisioned.error = Some(format!("{e:#}")),
+    }
+    provisioned
+}
+
+async fn check_genuine<C: Certificate<T>, T: CkTransport>(
+    card: &mut C,
+    provisioned: &mut ProvisionedCard,
+) -> Result<()> {
+    let checked = card.check_certificate().await;
+    let signed_by = checked.as_ref().ok().map(|root_key| root_key.name());
+    registry::record_cert(&provisioned.card_ident, signed_by.as_deref());
+    let root_key = checked.context("Card failed to verify, not a genuine card")?;
+    provisioned.genuine = true;
+    provisioned.signed_by = Some(root_key.name().to_string());
+    Ok(())
+}
+
+/// Initialize a new card, leaving one initialized before as it is, and read its xpubs
+async fn provision_tapsigner<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    provisioned: &mut ProvisionedCard,
+) -> Result<ProvisionStatus> {
+    check_genuine(ts, provisioned).await?;
+    let cvc = crate::get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+    let status = if ts.path.is_some() {
+        ProvisionStatus::AlreadyInitialized
+    } else {
+        let chain_code = rand_chaincode(&mut rand::thread_rng());
+        ts.init(chain_code, &cvc)
+            .await
+            .context("Failed to initialize card")?;
+        ts.path = ts
+            .status()
+            .await
+            .context("Failed to read card status")?
+            .path;
+        ProvisionStatus::Initialized
+    };
+
+    let master_xpub = ts
+        .xpub(true, &cvc)
+        .await
+        .context("Failed to read master xpub")?;
+    let account_xpub = ts
+        .xpub(false, &cvc)
+        .await
+        .context("Failed to read account xpub")?;
+    registry::record_fingerprint(&provisioned.card_ident, master_xpub.fingerprint());
+    provisioned.fingerprint = Some(master_xpub.fingerprint().to_string());
+    provisioned.xpub = Some(account_xpub.to_string());
+    provisioned.path = ts.path.as_ref().map(|path| {
+        let path: Vec<u32> = path.iter().map(|&index| index as u32).collect();
+        format_path(&path)
+    });
+    Ok(status)
+}
+
+/// Check the current slot's address follows from the card's keys
+async fn provision_satscard<T: CkTransport>(
+    sc: &mut SatsCard<T>,
+    provisioned: &mut ProvisionedCard,
+) -> Result<ProvisionStatus> {
+    check_genuine(sc, provisioned).await?;
+    let verified = sc
+        .verify_address()
+        .await
+        .context("Failed to verify the slot address")?;
+    provisioned.slot = Some(verified.slot);
+    provisioned.address = Some(verified.address);
+    Ok(ProvisionStatus::Verified)
+}
+
+fn append_csv(path: &Path, card: &ProvisionedCard) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path}", path = path.display()))?;
+    let is_new = file.metadata().map(|metadata| metadata.len() == 0)?;
+    let mut text = String::new();
+    if is_new {
+        text.push_str(CSV_HEADER);
+        text.push('\n');
+    }
+    text.push_str(&csv_row(card));
+    text.push('\n');
+    file.write_all(text.as_bytes())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {path}", path = path.display()))
+}
+
+/// The card's row, in the order of [`CSV_HEADER`]
+fn csv_row(card: &ProvisionedCard) -> String {
+    let slot = card.slot.map(|slot| slot.to_string());
+    let status = card.status.to_string();
+    let genuine = card.genuine.to_string();
+    [
+        Some(card.card_ident.as_str()),
+        Some(card.card_type.as_str()),
+        Some(status.as_str()),
+        Some(genuine.as_str()),
+        card.signed_by.as_deref(),
+        card.fingerprint.as_deref(),
+        card.path.as_deref(),
+        card.xpub.as_deref(),
+        slot.as_deref(),
+        card.address.as_deref(),
+        card.error.as_deref(),
+    ]
+    .map(|field| csv_field(field.unwrap_or_default()))
+    .join(",")
+}
+
+/// Quote a field holding a separator, quote or line break, doubling its quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{field}\"", field = field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv() -> Result<()> {
+        let mut card = ProvisionedCard {
+            card_ident: "ABCDE-FGHIJ-KLMNO-PQRST".to_string(),
+            card_type: "tapsigner".to_string(),
+            status: ProvisionStatus::Initialized,
+            genuine: true,
+            signed_by: Some("Root Factory Certificate".to_string()),
+            fingerprint: Some("d34db33f".to_string()),
+            path: Some("m/84'/0'/0'".to_string()),
+            xpub: Some("xpub6C".to_string()),
+            slot: None,
+            address: None,
+            error: None,
+        };
+        assert_eq!(
+            csv_row(&card),
+            "ABCDE-FGHIJ-KLMNO-PQRST,tapsigner,initialized,true,Root Factory Certificate,d34db33f,m/84'/0'/0',xpub6C,,,"
+        );
+        assert_eq!(
+            csv_row(&card).split(',').count(),
+            CSV_HEADER.split(',').count()
+        );
+
+        card.status = ProvisionStatus::Failed;
+        card.error = Some("Failed to initialize card: \"bad auth\", try again".to_string());
+        assert!(
+            csv_row(&card).ends_with(",\"Failed to initialize card: \"\"bad auth\"\", try again\"")
+        );
+
+        let dir =
+            std::env::temp_dir().join(format!("cktap-provision-{pid}", pid = std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("inventory.csv");
+        append_csv(&path, &card)?;
+        append_csv(&path, &card)?;
+        let written = std::fs::read_to_string(&path)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(written.lines().count(), 3);
+        assert!(written.starts_with(CSV_HEADER));
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use crate::error_code::WrongCardType;
+use crate::output::*;
+use crate::registry;
+use crate::{ConnectArgs, card_ident, connect, get_cvc_from_env_or_prompt};
+use anyhow::{Context, Result, bail};
+use bitcoin::bip32::Fingerprint;
+use bitcoin::{Psbt, consensus};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::psbt::{analyze_psbt, finalize_psbt};
+use cktap_direct::{CkTapCard, TapSigner};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// How a PSBT was encoded, so a signed PSBT is written back the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsbtEncoding {
+    Binary,
+    Base64,
+}
+
+/// Parse a PSBT, either binary or base64 encoded
+pub fn parse_psbt(data: &[u8]) -> Result<(Psbt, PsbtEncoding)> {
+    if data.starts_with(b"psbt\xff") {
+        let psbt = Psbt::deserialize(data).context("Invalid binary PSBT")?;
+        return Ok((psbt, PsbtEncoding::Binary));
+    }
+    let text = std::str::from_utf8(data).context("PSBT is neither binary nor base64")?;
+    let psbt = text.trim().parse().context("Invalid base64 PSBT")?;
+    Ok((psbt, PsbtEncoding::Base64))
+}
+
+/// Read a PSBT file, or stdin for `-`, either binary or base64 encoded
+pub fn read_psbt(path: &Path) -> Result<Psbt> {
+    read_psbt_with_encoding(path).map(|(psbt, _)| psbt)
+}
+
+fn read_psbt_with_encoding(path: &Path) -> Result<(Psbt, PsbtEncoding)> {
+    let data = if path == Path::new("-") {
+        let mut data = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .context("Failed to read PSBT from stdin")?;
+        data
+    } else {
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+    };
+    parse_psbt(&data)
+}
+
+fn encode_psbt(psbt: &Psbt, encoding: PsbtEncoding) -> Vec<u8> {
+    match encoding {
+        PsbtEncoding::Binary => psbt.serialize(),
+        PsbtEncoding::Base64 => format!("{psbt}\n").into_bytes(),
+    }
+}
+
+/// Master key fingerprint of the connected TapSigner or SatsChip
+async fn card_fingerprint(connection: &ConnectArgs) -> Result<Fingerprint> {
+    let mut ts = match connect(connection).await? {
+        CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => ts,
+        CkTapCard::SatsCard(_) => {
+            bail!(WrongCardType(
+                "Connected card is not a TapSigner, SatsCards can't sign PSBTs".to_string()
+            ))
+        }
+    };
+    let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+    let master_xpub = ts
+        .xpub(true, &cvc)
+        .await
+        .context("Failed to read master xpub")?;
+    registry::record_fingerprint(&card_ident(&ts.pubkey), master_xpub.fingerprint());
+    Ok(master_xpub.fingerprint())
+}
+
+/// Report which inputs of a PSBT the card can sign. Only the master xpub is read from the card,
+/// no signing commands are sent, and with a known `fingerprint` no card is needed at all.
+pub async fn inspect(
+    file: &Path,
+    fingerprint: Option<Fingerprint>,
+    connection: &ConnectArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let psbt = read_psbt(file)?;
+    let fingerprint = match fingerprint {
+        Some(fingerprint) => fingerprint,
+        None => card_fingerprint(connection).await?,
+    };
+
+    let analysis = analyze_psbt(&psbt, Some(fingerprint));
+    let inputs: Vec<PsbtInputInfo> = analysis
+        .inputs
+        .iter()
+        .map(|input| PsbtInputInfo {
+            index: input.index,
+            amount_sat: input.amount.map(|amount| amount.to_sat()),
+            script_type: input
+                .script_type
+                .map(|script_type| format!("{script_type:?}")),
+            sighash: input.sighash_type.to_string(),
+            path: input
+                .key_source
+                .as_ref()
+                .map(|(_, path)| format!("m/{path}")),
+            signable: input.signable(),
+            reason: input.unsupported_reason.as_ref().map(ToString::to_string),
+        })
+        .collect();
+
+    let result = PsbtInspectResponse {
+        fingerprint: fingerprint.to_string(),
+        signable_inputs: inputs.iter().filter(|input| input.signable).count(),
+        inputs,
+        total_in_sat: analysis.total_in.map(|amount| amount.to_sat()),
+        total_out_sat: analysis.total_out.to_sat(),
+        fee_sat: analysis.fee.map(|amount| amount.to_sat()),
+    };
+    output_response(success_response(result), format)
+}
+
+/// Sign every input of a PSBT with the card. The signed PSBT keeps the input's encoding and goes to
+/// `output` if given, otherwise to stdout so the command works in a pipeline:
+/// `cat tx.psbt | cktap-direct tapsigner sign-psbt - > signed.psbt`
+pub async fn sign<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    input: &Path,
+    output: Option<&Path>,
+    format: OutputFormat,
+) -> Result<()> {
+    let (psbt, encoding) = read_psbt_with_encoding(input)?;
+    let cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+    let signed = ts
+        .sign_psbt(psbt, &cvc)
+        .await
+        .context("Failed to sign PSBT")?;
+    let signed_data = encode_psbt(&signed, encoding);
+
+    match output.filter(|path| *path != Path::new("-")) {
+        Some(path) => {
+            write_atomic(path, &signed_data)?;
+            let result = PsbtSignResponse {
+                file: path.display().to_string(),
+                signed_inputs: signed.inputs.len(),
+            };
+            output_response(success_response(result), format)
+        }
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(&signed_data)
+                .and_then(|()| stdout.flush())
+                .context("Failed to write signed PSBT to stdout")
+        }
+    }
+}
+
+/// Finalize a signed PSBT and optionally extract the transaction
+pub fn finalize(file: &Path, extract: bool, format: OutputFormat) -> Result<()> {
+    let mut psbt = read_psbt(file)?;
+    finalize_psbt(&mut psbt).context("Failed to finalize PSBT")?;
+
+    let mut result = PsbtFinalizeResponse {
+        psbt: psbt.to_string(),
+        tx: None,
+        txid: None,
+    };
+    if extract {
+        let tx = psbt.extract_tx().context("Failed to extract transaction")?;
+        result.txid = Some(tx.compute_txid().to_string());
+        result.tx = Some(consensus::encode::serialize_hex(&tx));
+    }
+    output_response(success_response(result), format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP 174 test vector: one P2PKH input, two outputs
+    const PSBT_BASE64: &str = "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA";
+
+    #[test]
+    fn test_parse_psbt() -> Result<()> {
+        let (psbt, encoding) = parse_psbt(format!("{PSBT_BASE64}\n").as_bytes())?;
+        assert_eq!(encoding, PsbtEncoding::Base64);
+        assert_eq!(
+            encode_psbt(&psbt, encoding),
+            format!("{PSBT_BASE64}\n").into_bytes()
+        );
+
+        let binary = psbt.serialize();
+        let (parsed, encoding) = parse_psbt(&binary)?;
+        assert_eq!(encoding, PsbtEncoding::Binary);
+        assert_eq!(parsed, psbt);
+        assert_eq!(encode_psbt(&parsed, encoding), binary);
+
+        assert!(parse_psbt(b"not a psbt").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,662 @@
+//! Minimal QR code encoder for showing addresses in the terminal: byte mode, error correction
+//! level M, versions 1 to 10 (up to 213 bytes, an address needs at most version 5).
+
+use anyhow::Result;
+
+const MAX_VERSION: usize = 10;
+/// Error correction codewords per block and number of blocks at level M, by version
+const ECC_CODEWORDS_PER_BLOCK: [usize; MAX_VERSION + 1] =
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+const NUM_BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+/// Format bits of error correction level M
+const ECL_M_FORMAT_BITS: u32 = 0;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<Vec<bool>>,
+}
+
+impl QrCode {
+    pub fn encode(data: &[u8]) -> Result<Self> {
+        let version = (1..=MAX_VERSION)
+            .find(|&version| data_capacity_bits(version) >= data_bits(version, data.len()))
+            .ok_or_else(|| anyhow::anyhow!("{len} bytes don't fit a QR code", len = data.len()))?;
+
+        let codewords = add_ecc_and_interleave(version, &data_codewords(version, data));
+        let mut qr = Matrix::new(version);
+        qr.draw_function_patterns();
+        qr.draw_codewords(&codewords);
+
+        // keep the mask with the lowest penalty, as readers expect
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                qr.apply_mask(mask);
+                qr.draw_format_bits(mask);
+                let penalty = qr.penalty();
+                qr.apply_mask(mask);
+                penalty
+            })
+            .unwrap_or(0);
+        qr.apply_mask(mask);
+        qr.draw_format_bits(mask);
+
+        Ok(Self {
+            size: qr.size,
+            modules: qr.modules,
+        })
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y][x]
+    }
+
+    /// Render with half blocks, two module rows per line, with a quiet zone. Light modules are
+    /// drawn, so it reads on the usual dark terminal background.
+    pub fn to_terminal(&self) -> String {
+        const QUIET: usize = 2;
+        let size = self.size + 2 * QUIET;
+        let light = |x: usize, y: usize| {
+            x < QUIET
+                || y < QUIET
+                || x >= self.size + QUIET
+                || y >= self.size + QUIET
+                || !self.is_dark(x - QUIET, y - QUIET)
+        };
+
+        let mut out = String::new();
+        for y in (0..size).step_by(2) {
+            for x in 0..size {
+                let top = light(x, y);
+                let bottom = y + 1 < size && light(x, y + 1);
+                out.push(match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn size(version: usize) -> usize {
+    version * 4 + 17
+}
+
+/// Modules left for data and error correction once the function patterns are drawn
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        result -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn data_capacity_bits(version: usize) -> usize {
+    (num_raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * NUM_BLOCKS[version]) * 8
+}
+
+fn char_count_bits(version: usize) -> usize {
+    if version < 10 { 8 } else { 16 }
+}
+
+/// Mode indicator, character count and the data
+fn data_bits(version: usize, len: usize) -> usize {
+    4 + char_count_bits(version) + 8 * len
+}
+
+struct BitBuffer(Vec<bool>);
+
+impl BitBuffer {
+    fn push(&mut self, value: usize, bits: usize) {
+        self.0
+            .extend((0..bits).rev().map(|i| (value >> i) & 1 == 1));
+    }
+}
+
+/// Byte mode segment, terminator and padding up to the version's data capacity
+fn data_codewords(version: usize, data: &[u8]) -> Vec<u8> {
+    let capacity = data_capacity_bits(version);
+    let mut bits = BitBuffer(Vec::with_capacity(capacity));
+    bits.push(0b0100, 4);
+    bits.push(data.len(), char_count_bits(version));
+    for &byte in data {
+        bits.push(usize::from(byte), 8);
+    }
+    bits.push(0, (capacity - bits.0.len()).min(4));
+    bits.push(0, (8 - bits.0.len() % 8) % 8);
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if bits.0.len() >= capacity {
+            break;
+        }
+        bits.push(pad, 8);
+    }
+
+    bits.0
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | u8::from(bit)))
+        .collect()
+}
+
+/// Split the data in blocks, add each block's error correction and interleave them
+fn add_ecc_and_interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let num_blocks = NUM_BLOCKS[version];
+    let block_ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = num_raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+
+    let divisor = reed_solomon_divisor(block_ecc_len);
+    let mut blocks = Vec::with_capacity(num_blocks);
+    let mut data = data;
+    for i in 0..num_blocks {
+        let data_len = short_block_len - block_ecc_len + usize::from(i >= num_short_blocks);
+        let (block_data, rest) = data.split_at(data_len);
+        data = rest;
+        let mut block = block_data.to_vec();
+        if i < num_short_blocks {
+            // placeholder so all blocks line up, skipped when interleaving
+            block.push(0);
+        }
+        block.extend(reed_solomon_remainder(block_data, &divisor));
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_len - block_ecc_len || j >= num_short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+/// Product in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= u16::from((y >> i) & 1) * u16::from(x);
+    }
+    z as u8
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (x, &y) in result.iter_mut().zip(divisor) {
+            *x ^= gf_multiply(y, factor);
+        }
+    }
+    result
+}
+
+/// 15 format bits for level M and `mask`, BCH protected and masked
+fn format_bits(mask: u8) -> u32 {
+    let data = ECL_M_FORMAT_BITS << 3 | u32::from(mask);
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+fn bit(value: u32, i: usize) -> bool {
+    (value >> i) & 1 == 1
+}
+
+struct Matrix {
+    version: usize,
+    size: usize,
+    modules: Vec<Vec<bool>>,
+    is_function: Vec<Vec<bool>>,
+}
+
+impl Matrix {
+    fn new(version: usize) -> Self {
+        let size = size(version);
+        Self {
+            version,
+            size,
+            modules: vec![vec![false; size]; size],
+            is_function: vec![vec![false; size]; size],
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        self.is_function[y][x] = true;
+    }
+
+    fn alignment_positions(&self) -> Vec<usize> {
+        if self.version == 1 {
+            return Vec::new();
+        }
+        let num_align = self.version / 7 + 2;
+        let step = (self.version * 4 + num_align * 2 + 1) / (num_align * 2 - 2) * 2;
+        let mut result: Vec<usize> = (0..num_align - 1)
+            .map(|i| self.size - 7 - i * step)
+            .collect();
+        result.push(6);
+        result.reverse();
+        result
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..self.size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        let far = self.size - 4;
+        for (x, y) in [(3, 3), (far, 3), (3, far)] {
+            self.draw_finder(x, y);
+        }
+
+        let positions = self.alignment_positions();
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // the corners with finder patterns
+                if ![(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+
+        // reserve the format areas, drawn once the mask is chosen
+        self.draw_format_bits(0);
+        self.draw_version();
+    }
+
+    fn draw_finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4..=4_isize {
+            for dx in -4..=4_isize {
+                let (x, y) = (cx as isize + dx, cy as isize + dy);
+                if (0..self.size as isize).contains(&x) && (0..self.size as isize).contains(&y) {
+                    let dist = dx.abs().max(dy.abs());
+                    self.set_function(x as usize, y as usize, dist != 2 && dist != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, cx: usize, cy: usize) {
+        for dy in -2..=2_isize {
+            for dx in -2..=2_isize {
+                let dark = dx.abs().max(dy.abs()) != 1;
+                self.set_function(
+                    (cx as isize + dx) as usize,
+                    (cy as isize + dy) as usize,
+                    dark,
+                );
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u8) {
+        let bits = format_bits(mask);
+        let size = self.size;
+
+        // around the top left finder
+        for i in 0..=5 {
+            self.set_function(8, i, bit(bits, i));
+        }
+        self.set_function(8, 7, bit(bits, 6));
+        self.set_function(8, 8, bit(bits, 7));
+        self.set_function(7, 8, bit(bits, 8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(bits, i));
+        }
+
+        // the copy next to the other two finders
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(bits, i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(bits, i));
+        }
+        // always dark
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let version = self.version as u32;
+        let mut rem = version;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1f25);
+        }
+        let bits = version << 12 | rem;
+        for i in 0..18 {
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, bit(bits, i));
+            self.set_function(b, a, bit(bits, i));
+        }
+    }
+
+    /// Place the codewords in the zigzag order, two columns at a time from the bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let total_bits = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = self.size as isize - 1;
+        while right >= 1 {
+            if right == 6 {
+                // skip the vertical timing pattern
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vert in 0..self.size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let y = if upward { self.size - 1 - vert } else { vert };
+                    if !self.is_function[y][x] && i < total_bits {
+                        self.modules[y][x] = bit(u32::from(codewords[i >> 3]), 7 - (i & 7));
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    /// XOR the data modules with a mask pattern, applying it twice undoes it
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.is_function[y][x] {
+                    self.modules[y][x] = !self.modules[y][x];
+                }
+            }
+        }
+    }
+
+    /// Penalty of the spec's mask evaluation: long runs, 2x2 blocks, finder-like patterns and
+    /// dark/light imbalance
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let at = |x: usize, y: usize, transposed: bool| {
+            if transposed {
+                self.modules[x][y]
+            } else {
+                self.modules[y][x]
+            }
+        };
+        const FINDER_LIKE: [bool; 11] = [
+            true, false, true, true, true, false, true, false, false, false, false,
+        ];
+
+        let mut penalty = 0;
+        for transposed in [false, true] {
+            for y in 0..size {
+                let mut run = 1;
+                for x in 1..size {
+                    if at(x, y, transposed) == at(x - 1, y, transposed) {
+                        run += 1;
+                    } else {
+                        run = 1;
+                    }
+                    if run == 5 {
+                        penalty += 3;
+                    } else if run > 5 {
+                        penalty += 1;
+                    }
+                }
+                for x in 0..size.saturating_sub(10) {
+                    let window = |reversed: bool| {
+                        (0..11).all(|i| {
+                            let expected = FINDER_LIKE[if reversed { 10 - i } else { i }];
+                            at(x + i, y, transposed) == expected
+                        })
+                    };
+                    if window(false) || window(true) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.modules[y][x];
+                if color == self.modules[y][x + 1]
+                    && color == self.modules[y + 1][x]
+                    && color == self.modules[y + 1][x + 1]
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let dark = self.modules.iter().flatten().filter(|&&dark| dark).count();
+        let total = size * size;
+        // deviation from 50% dark in steps of 5%
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon() {
+        // "HELLO WORLD" at 1-M, from the worked example in the spec tutorials
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        assert_eq!(
+            reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn test_format_bits() {
+        assert_eq!(format_bits(0), 0b101010000010010);
+    }
+
+    /// Error correction level M at versions 1 to 6, per the spec tables
+    const DECODE_ECC: [usize; 7] = [0, 10, 16, 26, 18, 24, 16];
+    const DECODE_BLOCKS: [usize; 7] = [0, 1, 1, 1, 2, 2, 4];
+
+    /// Read a QR code back the way a scanner does, from the spec rather than the encoder: the
+    /// format bits give the mask, the unmasked data modules in zigzag order give the codewords,
+    /// each block's error correction must check, and the byte segment gives the data.
+    fn decode(qr: &QrCode) -> Result<Vec<u8>> {
+        let size = qr.size;
+        let version = (size - 17) / 4;
+        anyhow::ensure!(
+            (1..=6).contains(&version),
+            "version {version} has version bits"
+        );
+
+        // finders with separators and format areas, timing patterns, the alignment pattern
+        let mut function = vec![vec![false; size]; size];
+        let mut mark = |x0: usize, y0: usize, width: usize, height: usize| {
+            for row in function.iter_mut().skip(y0).take(height) {
+                for module in row.iter_mut().skip(x0).take(width) {
+                    *module = true;
+                }
+            }
+        };
+        mark(0, 0, 9, 9);
+        mark(size - 8, 0, 8, 9);
+        mark(0, size - 8, 9, 8);
+        mark(6, 0, 1, size);
+        mark(0, 6, size, 1);
+        if version >= 2 {
+            let center = 4 * version + 10;
+            mark(center - 2, center - 2, 5, 5);
+        }
+
+        let format = (0..6)
+            .map(|i| (8, i))
+            .chain([(8, 7), (8, 8), (7, 8)])
+            .chain((9..15).map(|i| (14 - i, 8)))
+            .enumerate()
+            .fold(0, |bits, (i, (x, y))| {
+                bits | u32::from(qr.is_dark(x, y)) << i
+            });
+        let mask = (0..8)
+            .find(|&mask| format_bits(mask) == format)
+            .ok_or_else(|| anyhow::anyhow!("format bits {format:015b} aren't level M"))?;
+        let masked = |x: usize, y: usize| match mask {
+            0 => (x + y).is_multiple_of(2),
+            1 => y.is_multiple_of(2),
+            2 => x.is_multiple_of(3),
+            3 => (x + y).is_multiple_of(3),
+            4 => (x / 3 + y / 2).is_multiple_of(2),
+            5 => x * y % 2 + x * y % 3 == 0,
+            6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+            _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+        };
+
+        let mut bits = Vec::new();
+        let mut right = size - 1;
+        loop {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !function[y][x] {
+                        bits.push(qr.is_dark(x, y) ^ masked(x, y));
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+        let codewords: Vec<u8> = bits
+            .chunks_exact(8)
+            .map(|byte| {
+                byte.iter()
+                    .fold(0, |value, &bit| value << 1 | u8::from(bit))
+            })
+            .collect();
+
+        let (ecc, num_blocks) = (DECODE_ECC[version], DECODE_BLOCKS[version]);
+        let short_len = codewords.len() / num_blocks;
+        let num_short = num_blocks - codewords.len() % num_blocks;
+        let mut blocks = vec![Vec::new(); num_blocks];
+        let mut next = codewords.iter();
+        for i in 0..=short_len - ecc {
+            for (b, block) in blocks.iter_mut().enumerate() {
+                if i < short_len - ecc || b >= num_short {
+                    block.extend(next.next());
+                }
+            }
+        }
+        for _ in 0..ecc {
+            for block in &mut blocks {
+                block.extend(next.next());
+            }
+        }
+
+        let mut data = Vec::new();
+        for block in &blocks {
+            let (block_data, block_ecc) = block.split_at(block.len() - ecc);
+            anyhow::ensure!(
+                reed_solomon_remainder(block_data, &reed_solomon_divisor(ecc)) == block_ecc,
+                "error correction doesn't check"
+            );
+            data.extend_from_slice(block_data);
+        }
+
+        let bit = |i: usize| data[i / 8] >> (7 - i % 8) & 1;
+        let read = |start: usize, len: usize| {
+            (start..start + len).fold(0, |value, i| value << 1 | usize::from(bit(i)))
+        };
+        anyhow::ensure!(read(0, 4) == 0b0100, "not a byte segment");
+        let len = read(4, 8);
+        Ok((0..len).map(|i| read(12 + 8 * i, 8) as u8).collect())
+    }
+
+    #[test]
+    fn test_decode() -> Result<()> {
+        for address in [
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+            "bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297",
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+        ] {
+            assert_eq!(
+                decode(&QrCode::encode(address.as_bytes())?)?,
+                address.as_bytes()
+            );
+        }
+        // every version the decoder reads, including the interleaved ones
+        for len in 1..=106 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + len) as u8).collect();
+            assert_eq!(decode(&QrCode::encode(&data)?)?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_address() -> Result<()> {
+        let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let qr = QrCode::encode(address.as_bytes())?;
+        // 42 bytes need version 3 at level M
+        assert_eq!(qr.size, 29);
+        // finder pattern corners and the always dark module
+        assert!(qr.is_dark(0, 0) && qr.is_dark(28, 0) && qr.is_dark(0, 28));
+        assert!(!qr.is_dark(7, 7) && qr.is_dark(8, 29 - 8));
+
+        let rendered = qr.to_terminal();
+        assert_eq!(rendered.lines().count(), (29 + 4_usize).div_ceil(2));
+
+        assert!(QrCode::encode(&[0; 300]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use crate::output::*;
+use anyhow::{Context, Result};
+use cktap_direct::ccid::CcidDescriptor;
+use cktap_direct::discovery::{self, CcidDeviceInfo};
+
+/// Reader chosen with `--reader`, by USB vendor and product ID or by serial number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReaderSelector {
+    UsbId { vendor_id: u16, product_id: u16 },
+    Serial(String),
+}
+
+impl ReaderSelector {
+    pub fn matches(&self, info: &CcidDeviceInfo) -> bool {
+        match self {
+            Self::UsbId {
+                vendor_id,
+                product_id,
+            } => info.vendor_id == *vendor_id && info.product_id == *product_id,
+            Self::Serial(serial) => info.serial.as_deref() == Some(serial.as_str()),
+        }
+    }
+}
+
+/// Parse a reader selector: `VID:PID` in hex (e.g. `076b:5422`) or a serial number
+pub fn parse_reader(reader: &str) -> Result<ReaderSelector> {
+    let reader = reader.trim();
+    anyhow::ensure!(!reader.is_empty(), "Empty reader selector");
+
+    if let Some((vendor_id, product_id)) = reader.split_once(':') {
+        let parse = |id: &str| {
+            u16::from_str_radix(id, 16)
+                .with_context(|| format!("Invalid USB ID '{id}', expected 4 hex digits"))
+        };
+        return Ok(ReaderSelector::UsbId {
+            vendor_id: parse(vendor_id)?,
+            product_id: parse(product_id)?,
+        });
+    }
+    Ok(ReaderSelector::Serial(reader.to_string()))
+}
+
+fn reader_info(info: &CcidDeviceInfo) -> ReaderInfo {
+    ReaderInfo {
+        usb_id: format!("{:04x}:{:04x}", info.vendor_id, info.product_id),
+        manufacturer: info.manufacturer.clone(),
+        product: info.product.clone(),
+        serial: info.serial.clone(),
+        is_coinkite: info.is_coinkite,
+        is_ccid: info.is_ccid,
+        accessible: info.accessible,
+        kernel_driver_active: info.kernel_driver_active,
+    }
+}
+
+fn yes_no(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "?",
+    }
+}
+
+fn print_table(readers: &[ReaderInfo]) {
+    println!(
+        "{:<10} {:<20} {:<28} {:<16} {:<9} {:<5} {:<11} KERNEL",
+        "USB ID", "MANUFACTURER", "PRODUCT", "SERIAL", "COINKITE", "CCID", "ACCESSIBLE"
+    );
+    for reader in readers {
+        println!(
+            "{:<10} {:<20} {:<28} {:<16} {:<9} {:<5} {:<11} {}",
+            reader.usb_id,
+            reader.manufacturer.as_deref().unwrap_or("-"),
+            reader.product.as_deref().unwrap_or("-"),
+            reader.serial.as_deref().unwrap_or("-"),
+            yes_no(Some(reader.is_coinkite)),
+            yes_no(Some(reader.is_ccid)),
+            yes_no(Some(reader.accessible)),
+            yes_no(reader.kernel_driver_active),
+        );
+    }
+}
+
+/// List the USB readers discovery considers, as JSON or as a table with `--format plain`
+pub fn list_readers(format: OutputFormat) -> Result<()> {
+    let devices = discovery::list_devices().context("Failed to list USB devices")?;
+    let readers: Vec<ReaderInfo> = devices.iter().map(reader_info).collect();
+
+    match format {
+        OutputFormat::Json | OutputFormat::Ndjson | OutputFormat::Yaml | OutputFormat::Cbor => {
+            output_response(success_response(ReadersResponse { readers }), format)
+        }
+        OutputFormat::Plain => {
+            print_table(&readers);
+            Ok(())
+        }
+    }
+}
+
+fn descriptor_info(descriptor: &CcidDescriptor) -> CcidDescriptorInfo {
+    let names = |names: Vec<&str>| names.into_iter().map(str::to_string).collect();
+    CcidDescriptorInfo {
+        ccid_version: descriptor.version(),
+        slots: u16::from(descriptor.max_slot_index) + 1,
+        exchange_level: descriptor.exchange_level().to_string(),
+        max_message_length: descriptor.max_message_length,
+        max_block_data: descriptor.max_xfr_data(),
+        voltages: names(descriptor.voltages()),
+        protocols: names(descriptor.protocol_names()),
+        default_clock_khz: descriptor.default_clock,
+        max_clock_khz: descriptor.max_clock,
+        data_rate_bps: descriptor.data_rate,
+        max_data_rate_bps: descriptor.max_data_rate,
+        max_ifsd: descriptor.max_ifsd,
+        features_raw: format!("{features:#010x}", features = descriptor.features),
+        features: names(descriptor.feature_names()),
+    }
+}
+
+fn print_details(reader: &ReaderDetails) {
+    println!(
+        "{usb_id} {product}{serial}",
+        usb_id = reader.usb_id,
+        product = reader.product.as_deref().unwrap_or("-"),
+        serial = reader
+            .serial
+            .as_deref()
+            .map(|serial| format!(" ({serial})"))
+            .unwrap_or_default()
+    );
+    let Some(ccid) = &reader.descriptor else {
+        println!("  no CCID class descriptor");
+        return;
+    };
+    let list = |items: &[String]| match items {
+        [] => "-".to_string(),
+        items => items.join(", "),
+    };
+    println!(
+        "  CCID version:       {version}",
+        version = ccid.ccid_version
+    );
+    println!("  slots:              {slots}", slots = ccid.slots);
+    println!("  exchange level:     {level}", level = ccid.exchange_level);
+    println!(
+        "  max message length: {len} ({block} bytes of APDU per block)",
+        len = ccid.max_message_length,
+        block = ccid.max_block_data
+    );
+    println!(
+        "  voltages:           {voltages}",
+        voltages = list(&ccid.voltages)
+    );
+    println!(
+        "  protocols:          {protocols}",
+        protocols = list(&ccid.protocols)
+    );
+    println!(
+        "  clock:              {clock} kHz, max {max} kHz",
+        clock = ccid.default_clock_khz,
+        max = ccid.max_clock_khz
+    );
+    println!(
+        "  data rate:          {rate} bps, max {max} bps",
+        rate = ccid.data_rate_bps,
+        max = ccid.max_data_rate_bps
+    );
+    println!("  max IFSD:           {ifsd}", ifsd = ccid.max_ifsd);
+    println!(
+        "  features:           {raw} {names}",
+        raw = ccid.features_raw,
+        names = list(&ccid.features)
+    );
+}
+
+/// `readers info`: the CCID descriptors of the readers, or of the one picked by `reader`, to
+/// tell why a reader model misbehaves
+pub fn readers_info(reader: Option<&ReaderSelector>, format: OutputFormat) -> Result<()> {
+    let devices = discovery::describe_devices().context("Failed to list USB devices")?;
+    let readers: Vec<ReaderDetails> = devices
+        .iter()
+        .filter(|(info, _)| reader.is_none_or(|reader| reader.matches(info)))
+        .map(|(info, descriptor)| ReaderDetails {
+            usb_id: format!(
+                "{vendor:04x}:{product:04x}",
+                vendor = info.vendor_id,
+                product = info.product_id
+            ),
+            product: info.product.clone(),
+            serial: info.serial.clone(),
+            descriptor: descriptor.as_ref().map(descriptor_info),
+        })
+        .collect();
+    if let Some(reader) = reader {
+        anyhow::ensure!(!readers.is_empty(), "No reader matches --reader {reader:?}");
+    }
+
+    match format {
+        OutputFormat::Json | OutputFormat::Ndjson | OutputFormat::Yaml | OutputFormat::Cbor => {
+            output_response(success_response(ReadersInfoResponse { readers }), format)
+        }
+        OutputFormat::Plain => {
+            readers.iter().for_each(print_details);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reader() -> Result<()> {
+        assert_eq!(
+            parse_reader("076b:5422")?,
+            ReaderSelector::UsbId {
+                vendor_id: 0x076b,
+                product_id: 0x5422
+            }
+        );
+        assert_eq!(
+            parse_reader("0123456789")?,
+            ReaderSelector::Serial("0123456789".to_string())
+        );
+        assert!(parse_reader("076b:zzzz").is_err());
+        assert!(parse_reader("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! `satscard recover`: an unsealed slot's address and WIF recomputed from the saved output of
+//! `satscard unseal`, for when the card itself is lost. Works offline, no reader needed.
+
+use crate::output::{RecoverResponse, UnsealResponse};
+use anyhow::{Context, Result, ensure};
+use bitcoin::Network;
+use bitcoin::hex::FromHex;
+use cktap_direct::sats_card;
+use serde_json::Value;
+use std::io::Read as _;
+use std::path::Path;
+
+/// Recover the slot of the `unseal` output in `file`, or stdin for `-`
+pub fn recover(file: &Path, network: Network) -> Result<RecoverResponse> {
+    let json = if file == Path::new("-") {
+        let mut json = String::new();
+        std::io::stdin()
+            .read_to_string(&mut json)
+            .context("Failed to read the unseal output from stdin")?;
+        json
+    } else {
+        std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {file}", file = file.display()))?
+    };
+    recover_json(&json, network)
+}
+
+fn recover_json(json: &str, network: Network) -> Result<RecoverResponse> {
+    let value: Value = serde_json::from_str(json).context("Invalid JSON")?;
+    // the whole output of `unseal`, or only its data
+    let data = match value.get("data") {
+        Some(data) => data.clone(),
+        None => value,
+    };
+    let unsealed: UnsealResponse =
+        serde_json::from_value(data).context("Not the output of satscard unseal")?;
+    let chain_code = unsealed
+        .chain_code
+        .as_deref()
+        .context("The unseal output has no chain_code, the slot can't be recomputed")?;
+    let master_pk = Vec::from_hex(&unsealed.master_pk).context("Invalid master_pk")?;
+    let chain_code = Vec::from_hex(chain_code).context("Invalid chain_code")?;
+
+    let recovered = sats_card::recover_slot(&master_pk, &chain_code, network)
+        .context("Failed to recompute the slot key")?;
+    let pubkey = recovered.pubkey.to_string();
+    ensure!(
+        pubkey == unsealed.pubkey,
+        "master_pk and chain_code lead to pubkey {pubkey}, not the slot's {slot_pubkey}. Output of \
+         unseal from before the keys were decrypted can't be recovered",
+        slot_pubkey = unsealed.pubkey
+    );
+    Ok(RecoverResponse {
+        slot: unsealed.slot,
+        address: recovered.address.to_string(),
+        pubkey,
+        wif: recovered.privkey.to_wif(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hex::DisplayHex as _;
+
+    #[test]
+    fn test_recover_json() -> Result<()> {
+        let master_pk = [0x11; 32];
+        let chain_code = [0x22; 32];
+        let slot = sats_card::recover_slot(&master_pk, &chain_code, Network::Bitcoin)?;
+        let data = serde_json::json!({
+            "slot": 3,
+            "master_pk": master_pk.as_hex().to_string(),
+            "pubkey": slot.pubkey.to_string(),
+            "privkey": slot.privkey.inner.secret_bytes().as_hex().to_string(),
+            "chain_code": chain_code.as_hex().to_string(),
+        });
+
+        let recovered = recover_json(&data.to_string(), Network::Bitcoin)?;
+        assert_eq!(recovered.slot, 3);
+        assert_eq!(recovered.wif, slot.privkey.to_wif());
+        assert!(recovered.wif.starts_with('K') || recovered.wif.starts_with('L'));
+        assert!(recovered.address.starts_with("bc1q"));
+
+        let envelope = serde_json::json!({"success": true, "data": data});
+        let recovered = recover_json(&envelope.to_string(), Network::Testnet)?;
+        assert!(recovered.address.starts_with("tb1q"));
+        assert!(recovered.wif.starts_with('c'));
+
+        // keys saved still encrypted don't lead to the slot's pubkey
+        let mut encrypted = data.clone();
+        encrypted["master_pk"] = Value::from([0x33; 32].as_hex().to_string());
+        assert!(recover_json(&encrypted.to_string(), Network::Bitcoin).is_err());
+        let mut no_chain_code = data;
+        no_chain_code["chain_code"] = Value::Null;
+        assert!(recover_json(&no_chain_code.to_string(), Network::Bitcoin).is_err());
+        Ok(())
+    }
+}
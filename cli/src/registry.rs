@@ -0,0 +1,204 @@
+//! Registry of the cards this CLI has seen, `cards.json` in the config directory, kept when
+//! `registry = true` is in config.toml: each card's ident, type and firmware, when it was first
+//! seen, and what later commands learned about it (certificate check, master fingerprint).
+//!
+//! Once the registry holds cards, connecting to one it doesn't know warns, except for the
+//! commands meant for new cards (`init`, `provision`, `watch`): a stranger where one of your
+//! cards was expected may be a look-alike, or simply the wrong card.
+//!
+//! Keeping the registry never fails a command, problems with the file are warnings.
+
+use crate::card_type;
+use crate::config;
+use crate::output::{CardsResponse, Event, KnownCard, emit};
+use anyhow::{Context, Result};
+use bitcoin::bip32::Fingerprint;
+use cktap_direct::CkTapCard;
+use cktap_direct::commands::CkTransport;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `--all-readers` connects to cards from several threads, each updating the file
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// The command is meant for cards never seen before, see [`expect_new_cards`]
+static NEW_CARDS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    /// In the order they were first seen
+    cards: Vec<KnownCard>,
+}
+
+impl Registry {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid card registry {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read card registry {}", path.display()))
+            }
+        }
+    }
+
+    /// Write to a temporary file and rename it over the registry, so an interrupted write
+    /// doesn't lose the cards already known
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write card registry {}", path.display()))
+    }
+
+    fn get_mut(&mut self, ident: &str) -> Option<&mut KnownCard> {
+        self.cards.iter_mut().find(|card| card.card_ident == ident)
+    }
+
+    /// Record a sighting of the card, returning whether it's one the registry didn't know
+    /// while it knew others
+    fn seen(&mut self, ident: &str, card_type: &str, firmware: &str, now: u64) -> bool {
+        if let Some(card) = self.get_mut(ident) {
+            card.card_type = card_type.to_string();
+            card.firmware = firmware.to_string();
+            card.last_seen = now;
+            return false;
+        }
+        let stranger = !self.cards.is_empty();
+        self.cards.push(KnownCard {
+            card_ident: ident.to_string(),
+            card_type: card_type.to_string(),
+            firmware: firmware.to_string(),
+            first_seen: now,
+            last_seen: now,
+            genuine: None,
+            signed_by: None,
+            master_fingerprint: None,
+        });
+        stranger
+    }
+}
+
+/// The registry file
+pub fn path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("cards.json"))
+}
+
+/// Don't warn about cards missing from the registry, the command is for new ones
+pub fn expect_new_cards() {
+    NEW_CARDS.store(true, Ordering::Relaxed);
+}
+
+/// Record the card just connected to, warning if the registry knows other cards but not this one
+pub fn seen<T: CkTransport>(card: &CkTapCard<T>) {
+    let ident = card.ident();
+    let stranger = update(|registry| registry.seen(&ident, card_type(card), card.version(), now()));
+    if stranger == Some(true) && !NEW_CARDS.load(Ordering::Relaxed) {
+        emit(Event::Warning {
+            message: &format!(
+                "{ident} was never seen before, it's not one of the cards in the registry. Check \
+                 it's the card you meant to use"
+            ),
+        });
+    }
+}
+
+/// Record how the card's certificate check went, `signed_by` being the root it led to
+pub fn record_cert(ident: &str, signed_by: Option<&str>) {
+    update(|registry| {
+        if let Some(card) = registry.get_mut(ident) {
+            card.genuine = Some(signed_by.is_some());
+            card.signed_by = signed_by.map(str::to_string);
+        }
+    });
+}
+
+/// Record the master key fingerprint read from a TapSigner or SatsChip
+pub fn record_fingerprint(ident: &str, fingerprint: Fingerprint) {
+    update(|registry| {
+        if let Some(card) = registry.get_mut(ident) {
+            card.master_fingerprint = Some(fingerprint.to_string());
+        }
+    });
+}
+
+/// `cards list`
+pub fn list() -> Result<CardsResponse> {
+    let path = path().context("No config directory, set HOME or XDG_CONFIG_HOME")?;
+    if !config::get().registry {
+        emit(Event::Info {
+            message: "The registry is off, set `registry = true` in config.toml to keep it",
+        });
+    }
+    let registry = Registry::load(&path)?;
+    Ok(CardsResponse {
+        registry: path.display().to_string(),
+        cards: registry.cards,
+    })
+}
+
+/// Apply `change` to the registry and save it, when it's on. `None` if it's off or the file
+/// couldn't be used, which is a warning.
+fn update<R>(change: impl FnOnce(&mut Registry) -> R) -> Option<R> {
+    if !config::get().registry {
+        return None;
+    }
+    let path = path()?;
+    let _lock = FILE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = Registry::load(&path).and_then(|mut registry| {
+        let result = change(&mut registry);
+        registry.save(&path).map(|()| result)
+    });
+    result
+        .inspect_err(|e| {
+            emit(Event::Warning {
+                message: &format!("Card registry not updated: {e:#}"),
+            })
+        })
+        .ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "cktap-direct-registry-{pid}/cards.json",
+            pid = std::process::id()
+        ));
+        let mut registry = Registry::load(&path)?;
+        assert!(registry.cards.is_empty());
+
+        // the first card ever seen is no stranger
+        assert!(!registry.seen("CARD-1", "tapsigner", "1.0.3", 100));
+        assert!(!registry.seen("CARD-1", "tapsigner", "1.0.3", 200));
+        assert!(registry.seen("CARD-2", "satscard", "1.0.0", 300));
+        registry.save(&path)?;
+
+        let mut registry = Registry::load(&path)?;
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        assert_eq!(registry.cards.len(), 2);
+        let card = registry.get_mut("CARD-1").unwrap();
+        assert_eq!((card.first_seen, card.last_seen), (100, 200));
+        assert_eq!(registry.cards[1].card_type, "satscard");
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+//! JSON Schemas of the command responses, for `schema`. They are read from the responses'
+//! `Deserialize` impls: a tracing deserializer records what each type asks for (a struct with
+//! these fields, an integer, an optional string, ...) and answers with placeholder values, so the
+//! schemas can't drift from the JSON the commands print.
+
+use crate::output::*;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde_json::{Map, Value, json};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+macro_rules! response_schemas {
+    ($($response:ident),* $(,)?) => {
+        /// The schema of the output of every command, by the type of its `data`
+        pub fn schemas() -> Result<BTreeMap<&'static str, Value>, TraceError> {
+            Ok(BTreeMap::from([$(
+                (
+                    stringify!($response),
+                    document::<CommandResponse<$response>>(stringify!($response))?,
+                ),
+            )*]))
+        }
+    };
+}
+
+response_schemas!(
+    AddressResponse,
+    AllReadersResponse,
+    BackupResponse,
+    BatchResponse,
+    CardPresentedResponse,
+    CardsResponse,
+    CertsResponse,
+    ChallengeResponse,
+    ChangeResponse,
+    DebugResponse,
+    DeriveManyResponse,
+    DeriveResponse,
+    DoctorResponse,
+    DryRunResponse,
+    ExportResponse,
+    InitResponse,
+    NewSlotResponse,
+    PlanResponse,
+    ProvisionResponse,
+    PsbtFinalizeResponse,
+    PsbtInspectResponse,
+    PsbtSignResponse,
+    RawApduResponse,
+    ReadResponse,
+    ReadersInfoResponse,
+    ReadersResponse,
+    RecoverResponse,
+    SetupResponse,
+    SignResponse,
+    SignedPsbtResponse,
+    SlotDeriveResponse,
+    SlotUrlResponse,
+    UnsealResponse,
+    UnsupportedResponse,
+    VerifyAddressResponse,
+    VerifyNewResponse,
+    VerifyResponse,
+    WaitCardResponse,
+    XpubResponse,
+);
+
+/// JSON Schema document of `T`, titled `title`, with the named types it uses under `$defs`
+pub fn document<T: DeserializeOwned>(title: &str) -> Result<Value, TraceError> {
+    let defs = Defs::default();
+    let slot = Slot::default();
+    T::deserialize(Tracer {
+        defs: &defs,
+        slot: &slot,
+    })?;
+    let mut defs = defs.into_inner();
+    let root = slot.schema.into_inner();
+    // the root type is inlined rather than referenced
+    let mut schema = match root["$ref"]
+        .as_str()
+        .and_then(|r| r.strip_prefix("#/$defs/"))
+    {
+        Some(name) => defs.remove(name).unwrap_or(root),
+        None => root,
+    };
+    schema["$schema"] = json!(DRAFT);
+    schema["title"] = json!(title);
+    if !defs.is_empty() {
+        schema["$defs"] =
+            Value::Object(defs.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    }
+    Ok(schema)
+}
+
+#[derive(Debug)]
+pub struct TraceError(String);
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to trace response type: {reason}",
+            reason = self.0
+        )
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<M: fmt::Display>(msg: M) -> Self {
+        TraceError(msg.to_string())
+    }
+}
+
+/// The structs and enums met so far, by name
+type Defs = RefCell<BTreeMap<&'static str, Value>>;
+
+/// Where the tracer of one value puts its schema
+#[derive(Default)]
+struct Slot {
+    schema: RefCell<Value>,
+    /// An `Option`: the field may be missing or null
+    optional: Cell<bool>,
+}
+
+impl Slot {
+    fn set(&self, schema: Value) {
+        *self.schema.borrow_mut() = schema;
+    }
+
+    fn take(&self) -> Value {
+        self.schema.take()
+    }
+}
+
+struct Tracer<'a> {
+    defs: &'a Defs,
+    slot: &'a Slot,
+}
+
+impl<'a> Tracer<'a> {
+    fn nested(&self, slot: &'a Slot) -> Self {
+        Tracer {
+            defs: self.defs,
+            slot,
+        }
+    }
+
+    fn define(&self, name: &'static str, schema: Value) {
+        self.defs.borrow_mut().insert(name, schema);
+        self.slot.set(json!({ "$ref": format!("#/$defs/{name}") }));
+    }
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = TraceError;
+
+    /// Self-describing types like `serde_json::Value`: anything
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({}));
+        visitor.visit_unit()
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "boolean" }));
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_u64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_u64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_u64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "integer", "minimum": 0 }));
+        visitor.visit_u64(0)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "integer" }));
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "number" }));
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "string" }));
+        visitor.visit_char('0')
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "string" }));
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let inner = Slot::default();
+        let value = visitor.visit_some(self.nested(&inner))?;
+        self.slot
+            .set(json!({ "anyOf": [inner.take(), { "type": "null" }] }));
+        self.slot.optional.set(true);
+        Ok(value)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.slot.set(json!({ "type": "null" }));
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let items = Slot::default();
+        let value = visitor.visit_seq(Elements {
+            tracer: self.nested(&items),
+            remaining: 1,
+        })?;
+        self.slot
+            .set(json!({ "type": "array", "items": items.take() }));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        // the elements of tuples are traced in the same slot, fine for the (String, T) pairs
+        // responses use
+        let items = Slot::default();
+        let value = visitor.visit_seq(Elements {
+            tracer: self.nested(&items),
+            remaining: len,
+        })?;
+        self.slot.set(json!({
+            "type": "array",
+            "items": items.take(),
+            "minItems": len,
+            "maxItems": len,
+        }));
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let keys = Slot::default();
+        let values = Slot::default();
+        let value = visitor.visit_map(Entry {
+            key: self.nested(&keys),
+            value: self.nested(&values),
+            done: false,
+        })?;
+        self.slot.set(json!({
+            "type": "object",
+            "additionalProperties": values.take(),
+        }));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let mut access = Fields {
+            defs: self.defs,
+            fields,
+            traced: Vec::new(),
+        };
+        let value = visitor.visit_map(&mut access)?;
+
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for (field, slot) in access.traced {
+            if !slot.optional.get() {
+                required.push(json!(field));
+            }
+            properties.insert(field.to_string(), slot.take());
+        }
+        self.define(
+            name,
+            json!({ "type": "object", "properties": properties, "required": required }),
+        );
+        Ok(value)
+    }
+
+    /// Enums of responses are unit variants, written as their name
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let variant = variants
+            .first()
+            .ok_or_else(|| TraceError(format!("enum {name} has no variants")))?;
+        let value = visitor.visit_enum(UnitVariant(variant))?;
+        self.define(name, json!({ "type": "string", "enum": variants }));
+        Ok(value)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf identifier
+    }
+}
+
+/// Hands out `remaining` traced elements
+struct Elements<'a> {
+    tracer: Tracer<'a>,
+    remaining: usize,
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_> {
+    type Error = TraceError;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(self.tracer.nested(self.tracer.slot))
+            .map(Some)
+    }
+}
+
+/// Hands out one traced entry of a map
+struct Entry<'a> {
+    key: Tracer<'a>,
+    value: Tracer<'a>,
+    done: bool,
+}
+
+impl<'de> MapAccess<'de> for Entry<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, TraceError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        seed.deserialize(self.key.nested(self.key.slot)).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, TraceError> {
+        seed.deserialize(self.value.nested(self.value.slot))
+    }
+}
+
+/// Hands out every field of a struct, tracing each value in its own slot
+struct Fields<'a> {
+    defs: &'a Defs,
+    fields: &'static [&'static str],
+    traced: Vec<(&'static str, Slot)>,
+}
+
+impl<'de> MapAccess<'de> for &mut Fields<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, TraceError> {
+        let Some(&field) = self.fields.get(self.traced.len()) else {
+            return Ok(None);
+        };
+        self.traced.push((field, Slot::default()));
+        seed.deserialize(field.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, TraceError> {
+        let (_, slot) = self
+            .traced
+            .last()
+            .ok_or_else(|| TraceError("value before its key".to_string()))?;
+        seed.deserialize(Tracer {
+            defs: self.defs,
+            slot,
+        })
+    }
+}
+
+/// Picks the first variant of an enum
+struct UnitVariant(&'static str);
+
+impl<'de> EnumAccess<'de> for UnitVariant {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self), TraceError> {
+        let variant = seed.deserialize(self.0.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for UnitVariant {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        _seed: S,
+    ) -> Result<S::Value, TraceError> {
+        Err(TraceError(format!(
+            "variant {variant} has data",
+            variant = self.0
+        )))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(TraceError(format!(
+            "variant {variant} has data",
+            variant = self.0
+        )))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(TraceError(format!(
+            "variant {variant} has data",
+            variant = self.0
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schemas() -> anyhow::Result<()> {
+        let schemas = schemas()?;
+        let wait = &schemas["WaitCardResponse"];
+        assert_eq!(wait["title"], "WaitCardResponse");
+        assert_eq!(wait["required"], json!(["success"]));
+        assert_eq!(
+            wait["properties"]["data"]["anyOf"][0],
+            json!({ "$ref": "#/$defs/WaitCardResponse" })
+        );
+        assert_eq!(
+            wait["$defs"]["WaitCardResponse"],
+            json!({
+                "type": "object",
+                "properties": { "waited_seconds": { "type": "integer", "minimum": 0 } },
+                "required": ["waited_seconds"],
+            })
+        );
+        assert_eq!(
+            wait["$defs"]["ErrorCode"]["enum"][0],
+            serde_json::to_value(crate::error_code::ErrorCode::NeedsAuth)?
+        );
+
+        let readers = &schemas["ReadersResponse"]["$defs"]["ReadersResponse"];
+        assert_eq!(
+            readers["properties"]["readers"],
+            json!({ "type": "array", "items": { "$ref": "#/$defs/ReaderInfo" } })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,591 @@
+//! Daemon owning the reader: card commands as JSON-RPC 2.0 over HTTP on a local socket, for web
+//! backends and desktop wallets that keep one long-lived process talking to the card.
+//!
+//! ```text
+//! curl -s -H "Authorization: Bearer $TOKEN" http://127.0.0.1:7380/ \
+//!     -d '{"jsonrpc": "2.0", "id": 1, "method": "xpub", "params": {"cvc": "123456"}}'
+//! ```
+//!
+//! Methods: `status` (`raw`), `derive` (`path`, `cvc`), `xpub` (`master`, `cvc`), `sign_psbt`
+//! (`psbt` in base64, `cvc`) and `verify` (`cvc`). Without a `cvc` param the daemon's
+//! `--cvc-file`, `--cvc-fd` or `CKTAP_CVC` is used, it never prompts.
+
+use crate::cvc_source;
+use crate::error_code::{ErrorCode, WrongCardType};
+use crate::output::*;
+use crate::policy::SigningPolicy;
+use crate::{ConfirmArgs, ConnectArgs, card_status, card_type, connect, format_path, psbt, verify};
+use anyhow::{Context, Result, anyhow, bail};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::secp256k1::hashes::hex::DisplayHex;
+use cktap_direct::secp256k1::rand::{self, RngCore};
+use cktap_direct::{CkTapCard, Cvc, TapSigner};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Address to listen on. Keep it on localhost, anyone reaching it with the token can use the
+    /// card.
+    #[arg(long, default_value = "127.0.0.1:7380")]
+    listen: SocketAddr,
+
+    /// Token clients send as `Authorization: Bearer <token>` (defaults to `CKTAP_SERVE_TOKEN`, or
+    /// a random one printed at start)
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Signing policy checked before each `sign_psbt` and `derive` (TOML: allowed paths,
+    /// signatures per hour, allowed output addresses, confirmation command)
+    #[arg(long, value_name = "FILE")]
+    policy: Option<PathBuf>,
+}
+
+/// Largest request accepted, enough for a PSBT with many inputs
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer JSON-RPC requests until interrupted. Requests run one at a time on the same card, which
+/// is connected on the first request and again after it left the reader.
+pub async fn serve(args: &ServeArgs, connection: &ConnectArgs, confirm: ConfirmArgs) -> Result<()> {
+    let token = match args
+        .token
+        .clone()
+        .or_else(|| std::env::var("CKTAP_SERVE_TOKEN").ok())
+    {
+        Some(token) if token.is_empty() => bail!("The serve token can't be empty"),
+        Some(token) => token,
+        None => {
+            let mut bytes = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let token = bytes.to_lower_hex_string();
+            emit(Event::Info {
+                message: &format!("Token: {token}"),
+            });
+            token
+        }
+    };
+
+    let mut policy = args
+        .policy
+        .as_deref()
+        .map(SigningPolicy::load)
+        .transpose()?;
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {addr}", addr = args.listen))?;
+    emit(Event::Info {
+        message: &format!(
+            "Listening on http://{addr}/, press Ctrl-C to stop",
+            addr = args.listen
+        ),
+    });
+
+    let mut card = None;
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .context("Failed to accept connection")?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut reader))
+            .await
+            .unwrap_or(Err(HttpError::Timeout));
+
+        let (status, body) = match request {
+            Err(e) => (e.status(), rpc_error(Value::Null, RpcError::from(e))),
+            Ok(request) if !authorized(request.authorization.as_deref(), &token) => {
+                log::warn!("Refused request from {peer} without a valid token");
+                (
+                    "401 Unauthorized",
+                    rpc_error(Value::Null, RpcError::unauthorized()),
+                )
+            }
+            Ok(request) => match parse_request(&request.body) {
+                Err(response) => ("200 OK", response),
+                Ok((id, method)) => {
+                    let connected = match card.take() {
+                        Some(connected) => Ok(connected),
+                        None => connect(connection).await.map(|mut connected| {
+                            confirm.guard_version(&mut connected);
+                            connected
+                        }),
+                    };
+                    let result = match connected {
+                        Ok(mut connected) => {
+                            let result = run(&mut connected, method, policy.as_mut()).await;
+                            // connect again on the next request after the card left
+                            if !result.as_ref().is_err_and(card_gone) {
+                                card = Some(connected);
+                            }
+                            result
+                        }
+                        Err(e) => Err(e),
+                    };
+                    ("200 OK", rpc_response(id, result))
+                }
+            },
+        };
+        let body = serde_json::to_vec(&body)?;
+        let head = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {len}\r\nConnection: close\r\n\r\n",
+            len = body.len()
+        );
+        if let Err(e) = async {
+            writer.write_all(head.as_bytes()).await?;
+            writer.write_all(&body).await?;
+            writer.shutdown().await
+        }
+        .await
+        {
+            log::warn!("Failed to answer {peer}: {e}");
+        }
+    }
+}
+
+/// Compare the bearer token in constant time
+fn authorized(authorization: Option<&str>, token: &str) -> bool {
+    let Some(sent) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The parts of an HTTP request the daemon looks at
+#[derive(Debug)]
+struct HttpRequest {
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum HttpError {
+    BadRequest(String),
+    MethodNotAllowed,
+    TooLarge,
+    Timeout,
+}
+
+impl HttpError {
+    fn status(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest(_) => "400 Bad Request",
+            HttpError::MethodNotAllowed => "405 Method Not Allowed",
+            HttpError::TooLarge => "413 Payload Too Large",
+            HttpError::Timeout => "408 Request Timeout",
+        }
+    }
+}
+
+/// Read a `POST` request with a `Content-Length` body
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<HttpRequest, HttpError> {
+    let bad_request = |message: &str| HttpError::BadRequest(message.to_string());
+    let mut line = String::new();
+    let mut head_size = 0;
+    let mut read_line = async |line: &mut String| {
+        line.clear();
+        let read = reader
+            .read_line(line)
+            .await
+            .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+        head_size += read;
+        if head_size > 16 * 1024 {
+            return Err(HttpError::TooLarge);
+        }
+        Ok(read)
+    };
+
+    read_line(&mut line).await?;
+    let method = line.split_whitespace().next().unwrap_or_default();
+    if method != "POST" {
+        return Err(match method {
+            "" => bad_request("empty request"),
+            _ => HttpError::MethodNotAllowed,
+        });
+    }
+
+    let mut authorization = None;
+    let mut content_length = None;
+    loop {
+        if read_line(&mut line).await? == 0 {
+            return Err(bad_request("request ends in the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| bad_request("invalid header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| bad_request("invalid Content-Length"))?,
+            );
+        }
+    }
+
+    let length = content_length.ok_or_else(|| bad_request("missing Content-Length"))?;
+    if length > MAX_REQUEST_SIZE {
+        return Err(HttpError::TooLarge);
+    }
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+    Ok(HttpRequest {
+        authorization,
+        body,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<RpcErrorData>,
+}
+
+/// Category of a failed card command, the `error_code` of the CLI's JSON output
+#[derive(Debug, Serialize)]
+struct RpcErrorData {
+    error_code: ErrorCode,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    fn unauthorized() -> Self {
+        Self::new(-32001, "Missing or wrong token")
+    }
+
+    /// A card command failed
+    fn command(error: &anyhow::Error) -> Self {
+        Self {
+            code: -32000,
+            message: format!("{error:#}"),
+            data: Some(RpcErrorData {
+                error_code: ErrorCode::of(error.as_ref()),
+            }),
+        }
+    }
+}
+
+impl From<HttpError> for RpcError {
+    fn from(error: HttpError) -> Self {
+        match error {
+            HttpError::BadRequest(message) => Self::new(-32600, message),
+            HttpError::MethodNotAllowed => Self::new(-32600, "Send requests with POST"),
+            HttpError::TooLarge => Self::new(-32600, "Request too large"),
+            HttpError::Timeout => Self::new(-32600, "Timed out reading the request"),
+        }
+    }
+}
+
+fn rpc_error(id: Value, error: RpcError) -> RpcResponse {
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(error),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatusParams {
+    #[serde(default)]
+    raw: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeriveParams {
+    path: Vec<u32>,
+    cvc: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct XpubParams {
+    #[serde(default)]
+    master: bool,
+    cvc: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignPsbtParams {
+    psbt: String,
+    cvc: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifyParams {
+    cvc: Option<String>,
+}
+
+/// A parsed request, checked before connecting to the card
+#[derive(Debug)]
+enum Method {
+    Status(StatusParams),
+    Derive(DeriveParams),
+    Xpub(XpubParams),
+    SignPsbt(SignPsbtParams),
+    Verify(VerifyParams),
+}
+
+impl Method {
+    fn parse(method: &str, params: Value) -> Result<Self, RpcError> {
+        fn optional<P: DeserializeOwned + Default>(params: Value) -> Result<P, RpcError> {
+            match params {
+                Value::Null => Ok(P::default()),
+                params => required(params),
+            }
+        }
+        fn required<P: DeserializeOwned>(params: Value) -> Result<P, RpcError> {
+            serde_json::from_value(params).map_err(|e| RpcError::new(-32602, e.to_string()))
+        }
+
+        match method {
+            "status" => optional(params).map(Method::Status),
+            "derive" => required(params).map(Method::Derive),
+            "xpub" => optional(params).map(Method::Xpub),
+            "sign_psbt" => required(params).map(Method::SignPsbt),
+            "verify" => optional(params).map(Method::Verify),
+            _ => Err(RpcError::new(
+                -32601,
+                format!(
+                    "Unknown method '{method}', expected status, derive, xpub, sign_psbt or verify"
+                ),
+            )),
+        }
+    }
+}
+
+/// The id and method of a JSON-RPC request, or the error response
+fn parse_request(body: &[u8]) -> Result<(Value, Method), RpcResponse> {
+    let request: RpcRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) if e.is_data() => {
+            return Err(rpc_error(Value::Null, RpcError::new(-32600, e.to_string())));
+        }
+        Err(e) => return Err(rpc_error(Value::Null, RpcError::new(-32700, e.to_string()))),
+    };
+    log::info!("{method}", method = request.method);
+    match Method::parse(&request.method, request.params) {
+        Ok(method) => Ok((request.id, method)),
+        Err(e) => Err(rpc_error(request.id, e)),
+    }
+}
+
+fn rpc_response(id: Value, result: Result<Value>) -> RpcResponse {
+    match result {
+        Ok(result) => RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => rpc_error(id, RpcError::command(&e)),
+    }
+}
+
+/// Whether the card left the reader, or never was on it
+fn card_gone(error: &anyhow::Error) -> bool {
+    matches!(
+        ErrorCode::of(error.as_ref()),
+        ErrorCode::CardNotFound | ErrorCode::CardRemoved | ErrorCode::UsbError
+    )
+}
+
+fn cvc(cvc: Option<String>) -> Result<Cvc> {
+    cvc.map(Cvc::from)
+        .or_else(cvc_source::given)
+        .or_else(|| std::env::var("CKTAP_CVC").ok().map(Cvc::from))
+        .ok_or_else(|| {
+            anyhow!("Missing 'cvc' param, and the daemon wasn't given a CVC (--cvc-file, --cvc-fd or CKTAP_CVC)")
+        })
+}
+
+fn tapsigner<T: CkTransport>(card: &mut CkTapCard<T>) -> Result<&mut TapSigner<T>> {
+    match card {
+        CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => Ok(ts),
+        CkTapCard::SatsCard(_) => bail!(WrongCardType(
+            "Connected card is a SatsCard, not a TapSigner".to_string()
+        )),
+    }
+}
+
+async fn run<T: CkTransport>(
+    card: &mut CkTapCard<T>,
+    method: Method,
+    mut policy: Option<&mut SigningPolicy>,
+) -> Result<Value> {
+    let result = match method {
+        Method::Status(StatusParams { raw }) => serde_json::to_value(card_status(card, raw).await?),
+        Method::Derive(DeriveParams { path, cvc: given }) => {
+            let cvc = cvc(given)?;
+            if let Some(policy) = policy.as_deref() {
+                policy.check_derive(&path)?;
+            }
+            let response = tapsigner(card)?
+                .derive(&path, &cvc)
+                .await
+                .context("Failed to derive key")?;
+            let hardened: Vec<u32> = path.iter().map(|&index| index | 1 << 31).collect();
+            serde_json::to_value(DeriveResponse {
+                path: format_path(&hardened),
+                pubkey: response.xpub.public_key.to_string(),
+                master_pubkey: Some(response.master_pubkey.to_string()),
+                chain_code: Some(response.xpub.chain_code.to_string()),
+                addresses: None,
+                receive_addresses: None,
+                change_addresses: None,
+            })
+        }
+        Method::Xpub(XpubParams { master, cvc: given }) => {
+            let cvc = cvc(given)?;
+            let xpub = tapsigner(card)?
+                .xpub(master, &cvc)
+                .await
+                .context("Failed to read xpub")?;
+            serde_json::to_value(XpubResponse {
+                xpub: xpub.to_string(),
+                master,
+            })
+        }
+        Method::SignPsbt(SignPsbtParams { psbt, cvc: given }) => {
+            let (psbt, _) = psbt::parse_psbt(psbt.as_bytes())?;
+            let cvc = cvc(given)?;
+            let ts = tapsigner(card)?;
+            if let Some(policy) = policy.as_deref_mut() {
+                policy.check_psbt(ts, &psbt, &cvc).await?;
+            }
+            let signed = ts
+                .sign_psbt(psbt, &cvc)
+                .await
+                .context("Failed to sign PSBT")?;
+            if let Some(policy) = policy {
+                policy.record(signed.inputs.len());
+            }
+            serde_json::to_value(SignedPsbtResponse {
+                signed_inputs: signed.inputs.len(),
+                psbt: signed.to_string(),
+            })
+        }
+        Method::Verify(VerifyParams { cvc: given }) => {
+            let card_type = card_type(card);
+            let report = match card {
+                CkTapCard::SatsCard(sc) => verify::verify_satscard(sc).await,
+                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => {
+                    verify::verify_tapsigner(ts, card_type, &cvc(given)?).await
+                }
+            };
+            serde_json::to_value(report.data)
+        }
+    };
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut request: &[u8] = b"POST / HTTP/1.1\r\nHost: localhost\r\nauthorization: Bearer abc\r\nContent-Length: 4\r\n\r\nbodyextra";
+        let request = read_request(&mut request).await.expect("request");
+        assert_eq!(request.authorization.as_deref(), Some("Bearer abc"));
+        assert_eq!(request.body, b"body");
+
+        let mut get: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            read_request(&mut get).await.unwrap_err(),
+            HttpError::MethodNotAllowed
+        );
+        let mut chunked: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(matches!(
+            read_request(&mut chunked).await,
+            Err(HttpError::BadRequest(_))
+        ));
+        let mut large: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n";
+        assert_eq!(
+            read_request(&mut large).await.unwrap_err(),
+            HttpError::TooLarge
+        );
+    }
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(Some("Bearer secret"), "secret"));
+        assert!(!authorized(Some("Bearer secreT"), "secret"));
+        assert!(!authorized(Some("Bearer secret2"), "secret"));
+        assert!(!authorized(Some("secret"), "secret"));
+        assert!(!authorized(None, "secret"));
+    }
+
+    #[test]
+    fn test_parse_method() {
+        assert!(matches!(
+            Method::parse("status", Value::Null),
+            Ok(Method::Status(StatusParams { raw: false }))
+        ));
+        assert!(matches!(
+            Method::parse("derive", serde_json::json!({"path": [84, 0, 0]})),
+            Ok(Method::Derive(DeriveParams { cvc: None, .. }))
+        ));
+        let error = |method, params| Method::parse(method, params).unwrap_err().code;
+        assert_eq!(error("derive", Value::Null), -32602);
+        assert_eq!(error("xpub", serde_json::json!({"pin": "123456"})), -32602);
+        assert_eq!(error("unseal", Value::Null), -32601);
+    }
+}
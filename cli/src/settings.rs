@@ -0,0 +1,208 @@
+//! Settings that can be given with a flag, an environment variable or in the config file, the
+//! first one found winning. They are resolved once at startup, for the commands and the `serve`
+//! daemon alike.
+//!
+//! | Flag | Variable | Config key | Default |
+//! |------|----------|------------|---------|
+//! | `--reader` | `CKTAP_READER` | `reader` | the first reader with a card |
+//! | `--network` | `CKTAP_NETWORK` | `network` | `bitcoin` |
+//! | `--timeout-ms` | `CKTAP_TIMEOUT_MS` | `timeout_ms` | 5000 |
+//! | `--format` | `CKTAP_FORMAT` | `format` | `json` |
+//! | `--transport emulator:<pipe>` | `CKTAP_EMULATOR` | `emulator` | `/tmp/ecard-pipe` |
+//!
+//! `--network` and `--transport` belong to the commands using them, so they are applied there
+//! over the value resolved here. Empty variables count as unset.
+
+use crate::config::Config;
+use crate::output::OutputFormat;
+use crate::readers::{self, ReaderSelector};
+use anyhow::{Context, Result};
+use bitcoin::Network;
+use cktap_direct::usb_transport;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// The global flags with a variable and a config key, `None` where not given
+#[derive(Debug, Default)]
+pub struct Flags {
+    pub reader: Option<ReaderSelector>,
+    pub timeout_ms: Option<u64>,
+    pub format: Option<OutputFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub reader: Option<ReaderSelector>,
+    pub network: Network,
+    /// How long each USB transfer with the reader may take
+    pub timeout: Duration,
+    pub format: OutputFormat,
+    /// Pipe of the emulator, its default one if `None`
+    pub emulator: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            reader: None,
+            network: Network::Bitcoin,
+            timeout: usb_transport::DEFAULT_TIMEOUT,
+            format: OutputFormat::Json,
+            emulator: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Layer `flags` over the variables read with `env` and over `config`
+    pub fn resolve(
+        flags: Flags,
+        env: impl Fn(&str) -> Option<String>,
+        config: &Config,
+    ) -> Result<Self> {
+        let var = |name: &str| env(name).filter(|value| !value.is_empty());
+        let defaults = Settings::default();
+        let reader = match flags.reader {
+            Some(reader) => Some(reader),
+            None => pick(
+                var("CKTAP_READER"),
+                "CKTAP_READER",
+                config.reader.as_deref(),
+                "reader",
+                readers::parse_reader,
+            )?,
+        };
+        let network = pick(
+            var("CKTAP_NETWORK"),
+            "CKTAP_NETWORK",
+            config.network.as_deref(),
+            "network",
+            |network| Ok(Network::from_str(network)?),
+        )?;
+        let timeout_ms = match flags.timeout_ms {
+            Some(timeout_ms) => Some(timeout_ms),
+            None => match var("CKTAP_TIMEOUT_MS") {
+                Some(value) => Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("Invalid CKTAP_TIMEOUT_MS '{value}'"))?,
+                ),
+                None => config.timeout_ms,
+            },
+        };
+        let format = match flags.format {
+            Some(format) => Some(format),
+            None => pick(
+                var("CKTAP_FORMAT"),
+                "CKTAP_FORMAT",
+                config.format.as_deref(),
+                "format",
+                |format| Ok(OutputFormat::from_str(format)?),
+            )?,
+        };
+        let emulator = var("CKTAP_EMULATOR")
+            .map(PathBuf::from)
+            .or_else(|| config.emulator.clone());
+        Ok(Settings {
+            reader,
+            network: network.unwrap_or(defaults.network),
+            timeout: timeout_ms.map_or(defaults.timeout, Duration::from_millis),
+            format: format.unwrap_or(defaults.format),
+            emulator,
+        })
+    }
+}
+
+/// The variable's value, else the config's, parsed with `parse`
+fn pick<T>(
+    value: Option<String>,
+    var: &str,
+    config_value: Option<&str>,
+    key: &str,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<Option<T>> {
+    if let Some(value) = value {
+        return parse(&value)
+            .map(Some)
+            .with_context(|| format!("Invalid {var} '{value}'"));
+    }
+    config_value
+        .map(|value| parse(value).with_context(|| format!("Invalid {key} '{value}' in the config")))
+        .transpose()
+}
+
+/// Resolve the settings from `flags`, the environment and the loaded config
+pub fn init(flags: Flags) -> Result<&'static Settings> {
+    let settings = Settings::resolve(flags, |name| std::env::var(name).ok(), crate::config::get())?;
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+/// The resolved settings, defaults if they weren't resolved
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            reader = "076b:5422"
+            network = "testnet"
+            timeout_ms = 10000
+            format = "plain"
+            emulator = "/tmp/config-pipe"
+            "#,
+        )?;
+        let env = HashMap::from([
+            ("CKTAP_NETWORK", "signet"),
+            ("CKTAP_FORMAT", "yaml"),
+            ("CKTAP_EMULATOR", ""),
+        ]);
+        let env = |name: &str| env.get(name).map(|value| value.to_string());
+        let flags = Flags {
+            format: Some(OutputFormat::Ndjson),
+            ..Flags::default()
+        };
+
+        assert_eq!(
+            Settings::resolve(flags, env, &config)?,
+            Settings {
+                reader: Some(readers::parse_reader("076b:5422")?),
+                network: Network::Signet,
+                timeout: Duration::from_millis(10000),
+                format: OutputFormat::Ndjson,
+                emulator: Some(PathBuf::from("/tmp/config-pipe")),
+            }
+        );
+        assert_eq!(
+            Settings::resolve(Flags::default(), |_| None, &Config::default())?,
+            Settings::default()
+        );
+
+        let error = Settings::resolve(
+            Flags::default(),
+            |name| (name == "CKTAP_TIMEOUT_MS").then(|| "soon".to_string()),
+            &Config::default(),
+        )
+        .map_err(|e| e.to_string());
+        assert_eq!(error, Err("Invalid CKTAP_TIMEOUT_MS 'soon'".to_string()));
+        assert!(
+            Settings::resolve(
+                Flags::default(),
+                |_| None,
+                &Config::parse("format = \"xml\"")?
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use cktap_direct::transcript::TARGET;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Logger passing records on to env_logger, and writing the library's session transcript
+/// (APDUs with secrets redacted, CCID framing and errors) to the `--trace-file`
+struct Logger {
+    env: env_logger::Logger,
+    transcript: Option<Mutex<BufWriter<File>>>,
+    start: Instant,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.env.enabled(metadata) || (self.transcript.is_some() && metadata.target() == TARGET)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.env.matches(record) {
+            self.env.log(record);
+        }
+        if record.target() == TARGET
+            && let Some(transcript) = &self.transcript
+            && let Ok(mut file) = transcript.lock()
+        {
+            let _ = writeln!(
+                file,
+                "[{elapsed:>9.3}] {args}",
+                elapsed = self.start.elapsed().as_secs_f64(),
+                args = record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.env.flush();
+        if let Some(transcript) = &self.transcript
+            && let Ok(mut file) = transcript.lock()
+        {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Log level for `-q` and `-v`/`-vv`: errors only, info by default, debug, then trace with the
+/// APDUs
+pub fn level(quiet: bool, verbose: u8) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// Set up logging at `level`, which `RUST_LOG` overrides, and, with `--trace-file`, the session
+/// transcript
+pub fn init(level: LevelFilter, trace_file: Option<&Path>) -> Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let env = builder.build();
+    let transcript = trace_file
+        .map(|path| -> Result<_> {
+            let mut file = BufWriter::new(
+                File::create(path)
+                    .with_context(|| format!("Failed to create trace file {}", path.display()))?,
+            );
+            writeln!(
+                file,
+                "# cktap-direct {version} session transcript, started at unix time {started}",
+                version = env!("CARGO_PKG_VERSION"),
+                started = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or_default()
+            )?;
+            writeln!(
+                file,
+                "# > command APDU, < response APDU, ! error, secrets redacted"
+            )?;
+            Ok(Mutex::new(file))
+        })
+        .transpose()?;
+
+    let max_level = if transcript.is_some() {
+        LevelFilter::Trace
+    } else {
+        env.filter()
+    };
+    log::set_boxed_logger(Box::new(Logger {
+        env,
+        transcript,
+        start: Instant::now(),
+    }))
+    .context("Failed to set up logging")?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Record the error a command failed with, and write out the transcript
+pub fn finish(result: &Result<()>) {
+    if let Err(e) = result {
+        log::trace!(target: TARGET, "! {e:#}");
+    }
+    log::logger().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(false, 0), LevelFilter::Info);
+        assert_eq!(level(false, 1), LevelFilter::Debug);
+        assert_eq!(level(false, 2), LevelFilter::Trace);
+        assert_eq!(level(true, 0), LevelFilter::Error);
+    }
+}
@@ -0,0 +1,250 @@
+//! How the CLI reaches the card, chosen at runtime with `--transport` (or `CKTAP_TRANSPORT`):
+//! a USB CCID reader, or the Python emulator on its default pipe or `emulator:<pipe>`. The
+//! emulator needs a build with the `emulator` feature, which then uses it by default. Other
+//! builds default to `auto`, trying USB, then the emulator.
+
+#[cfg(feature = "emulator")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(not(feature = "emulator"))]
+use anyhow::bail;
+use cktap_direct::CkTapCard;
+use cktap_direct::apdu::{Error, ParseMode};
+use cktap_direct::commands::CkTransport;
+#[cfg(feature = "emulator")]
+use cktap_direct::emulator::{self, CardEmulator};
+use cktap_direct::usb_transport::UsbTransport;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The `--transport` value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Each backend in turn until one finds a card
+    Auto,
+    Only(Backend),
+}
+
+/// One way to reach the card
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Usb,
+    /// The emulator listening on this pipe, its default one if `None`
+    Emulator(Option<PathBuf>),
+}
+
+impl Default for TransportKind {
+    fn default() -> Self {
+        if cfg!(feature = "emulator") {
+            TransportKind::Only(Backend::Emulator(None))
+        } else {
+            TransportKind::Auto
+        }
+    }
+}
+
+impl TransportKind {
+    /// The backends to try, in order
+    pub fn backends(&self) -> Vec<Backend> {
+        match self {
+            TransportKind::Auto => vec![Backend::Usb, Backend::Emulator(None)],
+            TransportKind::Only(backend) => vec![backend.clone()],
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "auto" => Ok(TransportKind::Auto),
+            None if s == "usb" => Ok(TransportKind::Only(Backend::Usb)),
+            None if s == "emulator" => Ok(TransportKind::Only(Backend::Emulator(None))),
+            Some(("emulator", pipe)) if !pipe.is_empty() => Ok(TransportKind::Only(
+                Backend::Emulator(Some(PathBuf::from(pipe))),
+            )),
+            _ => Err(format!(
+                "unknown transport '{s}', expected auto, usb, emulator or emulator:<pipe>"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportKind::Auto => f.write_str("auto"),
+            TransportKind::Only(backend) => backend.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Usb => f.write_str("usb"),
+            Backend::Emulator(None) => f.write_str("emulator"),
+            Backend::Emulator(Some(pipe)) => write!(f, "emulator:{pipe}", pipe = pipe.display()),
+        }
+    }
+}
+
+/// No backend of `--transport auto` found a card, with what each one failed with
+#[derive(Debug)]
+pub struct NoCardFound(pub Vec<(Backend, anyhow::Error)>);
+
+impl fmt::Display for NoCardFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("No card found with any transport")?;
+        for (backend, error) in &self.0 {
+            write!(f, "\n  {backend}: {error:#}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NoCardFound {}
+
+/// Connect with each backend of `kind` in turn, the first one finding a card wins. A single
+/// backend's error is returned as is.
+pub async fn first_found<T, F, Fut>(kind: &TransportKind, mut connect: F) -> Result<T>
+where
+    F: FnMut(Backend) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut failures = Vec::new();
+    for backend in kind.backends() {
+        match connect(backend.clone()).await {
+            Ok(found) => return Ok(found),
+            Err(e) => {
+                log::debug!("No card through {backend}: {e:#}");
+                failures.push((backend, e));
+            }
+        }
+    }
+    match <[_; 1]>::try_from(failures) {
+        Ok([(_, e)]) => Err(e),
+        Err(failures) => Err(NoCardFound(failures).into()),
+    }
+}
+
+/// A `--transport` this build can't use
+#[derive(Debug)]
+pub struct UnsupportedTransport(&'static str);
+
+impl fmt::Display for UnsupportedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedTransport {}
+
+/// Connect to the emulator listening on `pipe`, or on its default pipe
+pub async fn connect_emulator(pipe: Option<&Path>) -> Result<CkTapCard<Transport>> {
+    #[cfg(feature = "emulator")]
+    {
+        let card = match pipe {
+            Some(pipe) => emulator::connect_emulator(pipe).await,
+            None => emulator::find_emulator().await,
+        }
+        .context("Failed to connect to emulator")?;
+        Ok(card.map_transport(Transport::Emulator))
+    }
+    #[cfg(not(feature = "emulator"))]
+    {
+        let _ = pipe;
+        bail!(UnsupportedTransport(
+            "This build has no emulator transport, build it with the emulator feature"
+        ))
+    }
+}
+
+/// The transport of a connected card, whichever `--transport` picked
+pub enum Transport {
+    Usb(UsbTransport),
+    #[cfg(feature = "emulator")]
+    Emulator(CardEmulator),
+}
+
+impl CkTransport for Transport {
+    async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self {
+            Transport::Usb(usb) => usb.transmit_apdu(command_apdu).await,
+            #[cfg(feature = "emulator")]
+            Transport::Emulator(emulator) => emulator.transmit_apdu(command_apdu).await,
+        }
+    }
+
+    fn parse_mode(&self) -> ParseMode {
+        match self {
+            Transport::Usb(usb) => usb.parse_mode(),
+            #[cfg(feature = "emulator")]
+            Transport::Emulator(emulator) => emulator.parse_mode(),
+        }
+    }
+
+    async fn card_present(&self) -> Result<bool, Error> {
+        match self {
+            Transport::Usb(usb) => usb.card_present().await,
+            #[cfg(feature = "emulator")]
+            Transport::Emulator(emulator) => emulator.card_present().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_transport_kind() {
+        for value in ["auto", "usb", "emulator", "emulator:/tmp/pipe"] {
+            assert_eq!(
+                value.parse::<TransportKind>().map(|kind| kind.to_string()),
+                Ok(value.to_string())
+            );
+        }
+        assert_eq!(
+            "emulator:/tmp/pipe".parse(),
+            Ok(TransportKind::Only(Backend::Emulator(Some(PathBuf::from(
+                "/tmp/pipe"
+            )))))
+        );
+        assert!("emulator:".parse::<TransportKind>().is_err());
+        assert!("nfc".parse::<TransportKind>().is_err());
+        assert!("pcsc".parse::<TransportKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_first_found() {
+        let found = first_found(&TransportKind::Auto, |backend| async move {
+            match backend {
+                Backend::Emulator(_) => Ok(backend),
+                _ => Err(anyhow!("nothing on {backend}")),
+            }
+        })
+        .await;
+        assert_eq!(found.ok(), Some(Backend::Emulator(None)));
+
+        let error = first_found(&TransportKind::Auto, |backend| async move {
+            Err::<(), _>(anyhow!("nothing on {backend}"))
+        })
+        .await
+        .map_err(|e| e.to_string());
+        assert_eq!(
+            error,
+            Err("No card found with any transport\n  usb: nothing on usb\n  emulator: nothing on emulator".to_string())
+        );
+
+        let error = first_found(&TransportKind::Only(Backend::Usb), |backend| async move {
+            Err::<(), _>(anyhow!("nothing on {backend}"))
+        })
+        .await
+        .map_err(|e| e.to_string());
+        assert_eq!(error, Err("nothing on usb".to_string()));
+    }
+}
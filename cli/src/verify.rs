@@ -0,0 +1,343 @@
+use crate::card_ident;
+use crate::error_code::ErrorCode;
+use crate::output::*;
+use anyhow::{Context, Result, anyhow, ensure};
+use cktap_direct::commands::{Certificate, CkTransport, Read};
+use cktap_direct::sats_card::DeriveCheck;
+use cktap_direct::secp256k1::PublicKey;
+use cktap_direct::{Cvc, SatsCard, TapSigner};
+
+/// Results of the checks run so far. A failed check is recorded and the next one still runs.
+#[derive(Default)]
+struct Report(Vec<CheckResult>);
+
+impl Report {
+    fn record(&mut self, name: &str, result: Result<String>) {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Ok, detail),
+            Err(e) => (CheckStatus::Fail, format!("{e:#}")),
+        };
+        self.0.push(CheckResult {
+            name: name.to_string(),
+            status,
+            detail,
+        });
+    }
+
+    fn skip(&mut self, name: &str, reason: &str) {
+        self.0.push(CheckResult {
+            name: name.to_string(),
+            status: CheckStatus::Skip,
+            detail: reason.to_string(),
+        });
+    }
+
+    /// A response with the `data` made of the checks and whether they passed, failed if any
+    /// check failed
+    fn respond<T>(self, data: impl FnOnce(Vec<CheckResult>, bool) -> T) -> CommandResponse<T> {
+        let failed = self
+            .0
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .count();
+        CommandResponse {
+            success: failed == 0,
+            error: (failed > 0)
+                .then(|| format!("{failed} of {total} checks failed", total = self.0.len())),
+            error_code: (failed > 0).then_some(ErrorCode::VerificationFailed),
+            data: Some(data(self.0, failed == 0)),
+        }
+    }
+
+    fn into_response(self, card_type: &str, pubkey: &PublicKey) -> CommandResponse<VerifyResponse> {
+        self.respond(|checks, passed| VerifyResponse {
+            card_type: card_type.to_string(),
+            card_ident: card_ident(pubkey),
+            passed,
+            checks,
+        })
+    }
+}
+
+async fn check_certs<C: Certificate<T>, T: CkTransport>(card: &mut C) -> Result<String> {
+    let root_key = card
+        .check_certificate()
+        .await
+        .context("Card failed to verify, not a genuine card")?;
+    Ok(format!("signed by {name}", name = root_key.name()))
+}
+
+/// Certificate chain, signed `read`, slot pubkey derived from the master key, and the address
+pub async fn verify_satscard<T: CkTransport>(
+    sc: &mut SatsCard<T>,
+) -> CommandResponse<VerifyResponse> {
+    let mut report = Report::default();
+    report.record("certs", check_certs(sc).await);
+
+    let read_pubkey = async {
+        let read = sc.read(None).await.context("Failed to read card")?;
+        read.pubkey(None).context("Invalid pubkey from read")
+    }
+    .await;
+    let read_pubkey = match read_pubkey {
+        Ok(pubkey) => {
+            report.record(
+                "read",
+                Ok(format!("slot {slot} pubkey {pubkey}", slot = sc.slots.0)),
+            );
+            Some(pubkey)
+        }
+        Err(e) => {
+            report.record("read", Err(e));
+            None
+        }
+    };
+
+    match read_pubkey {
+        Some(read_pubkey) => {
+            let derive = async {
+                let derive = sc.derive().await.context("Failed to derive")?;
+                let derived_pubkey = sc
+                    .derive_slot_pubkey(&derive)
+                    .context("Failed to compute slot pubkey")?;
+                ensure!(
+                    derived_pubkey == read_pubkey,
+                    "Slot pubkey {read_pubkey} does not match pubkey {derived_pubkey} derived from the card's master key"
+                );
+                Ok("slot pubkey follows from the master key and chain code".to_string())
+            }
+            .await;
+            report.record("derive", derive);
+        }
+        None => report.skip("derive", "needs the pubkey from read"),
+    }
+
+    let address = sc
+        .verify_address()
+        .await
+        .map(|verified| format!("{address} matches the card", address = verified.address))
+        .context("Failed to verify the slot address");
+    report.record("address", address);
+
+    report.into_response("satscard", &sc.pubkey)
+}
+
+/// `satscard derive`: the slot key, with the signature, pubkey and address checks of
+/// [`SatsCard::check_derive`] each passing or failing
+pub fn derive_response(check: &DeriveCheck) -> CommandResponse<SlotDeriveResponse> {
+    let mut report = Report::default();
+    let signature = if check.signature_valid {
+        Ok(format!(
+            "chain code signed by master key {master_pubkey}",
+            master_pubkey = check.master_pubkey
+        ))
+    } else {
+        Err(anyhow!(
+            "Signature over the chain code doesn't verify with master key {master_pubkey}",
+            master_pubkey = check.master_pubkey
+        ))
+    };
+    report.record("signature", signature);
+
+    let pubkey = if check.pubkey_matches() {
+        Ok(format!(
+            "slot pubkey {pubkey} follows from the master key and chain code",
+            pubkey = check.slot_pubkey
+        ))
+    } else {
+        Err(anyhow!(
+            "Slot pubkey {slot_pubkey} does not match pubkey {derived_pubkey} derived from the card's master key",
+            slot_pubkey = check.slot_pubkey,
+            derived_pubkey = check.derived_pubkey
+        ))
+    };
+    report.record("pubkey", pubkey);
+
+    match (&check.card_address, check.address_matches()) {
+        (Some(card_address), Some(true)) => report.record(
+            "address",
+            Ok(format!("{address} matches {card_address}", address = check.address)),
+        ),
+        (Some(card_address), _) => report.record(
+            "address",
+            Err(anyhow!(
+                "Derived address {address} does not match address {card_address} reported by the card",
+                address = check.address
+            )),
+        ),
+        (None, _) => report.skip("address", "the card reported no address"),
+    }
+
+    report.respond(|checks, passed| SlotDeriveResponse {
+        slot: check.slot,
+        master_pubkey: check.master_pubkey.to_string(),
+        chain_code: check.chain_code.to_string(),
+        pubkey: check.derived_pubkey.to_string(),
+        address: check.address.clone(),
+        passed,
+        checks,
+    })
+}
+
+/// Certificate chain, authenticated `read`, and the key at the current path through `derive`
+/// and `xpub`
+pub async fn verify_tapsigner<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    card_type: &str,
+    cvc: &Cvc,
+) -> CommandResponse<VerifyResponse> {
+    let mut report = Report::default();
+    report.record("certs", check_certs(ts).await);
+
+    let read = ts
+        .read(Some(cvc))
+        .await
+        .map(|_| "signature by the card's current key verified".to_string())
+        .context("Failed to read card");
+    report.record("read", read);
+
+    match ts.path.clone() {
+        Some(path) => {
+            let derive = async {
+                // the card reports hardened components, derive hardens them again
+                let path: Vec<u32> = path.iter().map(|&p| p as u32 & !(1 << 31)).collect();
+                let derived = ts
+                    .derive(&path, cvc)
+                    .await
+                    .context("Failed to derive")?
+                    .xpub;
+                let xpub = ts.xpub(false, cvc).await.context("Failed to read xpub")?;
+                ensure!(
+                    xpub.public_key == derived.public_key && xpub.chain_code == derived.chain_code,
+                    "Derived pubkey {pubkey} does not match xpub {xpub}",
+                    pubkey = derived.public_key
+                );
+                Ok(format!("derived key matches xpub {xpub}"))
+            }
+            .await;
+            report.record("derive", derive);
+        }
+        None => report.skip("derive", "card is not initialized"),
+    }
+
+    report.into_response(card_type, &ts.pubkey)
+}
+
+/// One line per check, then the overall result
+pub fn print_report(response: &CommandResponse<VerifyResponse>) {
+    let Some(report) = &response.data else {
+        return;
+    };
+    print_checks(&report.checks);
+    println!(
+        "{ident}: {result}",
+        ident = report.card_ident,
+        result = if report.passed { "PASSED" } else { "FAILED" }
+    );
+}
+
+/// `satscard derive` in plain output: one line per check, then the slot's address
+pub fn print_derive(response: &CommandResponse<SlotDeriveResponse>) {
+    let Some(derived) = &response.data else {
+        return;
+    };
+    print_checks(&derived.checks);
+    println!(
+        "slot {slot} {address}: {result}",
+        slot = derived.slot,
+        address = derived.address,
+        result = if derived.passed { "PASSED" } else { "FAILED" }
+    );
+}
+
+fn print_checks(checks: &[CheckResult]) {
+    for check in checks {
+        let status = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "skip",
+        };
+        println!(
+            "[{status:>4}] {name}: {detail}",
+            name = check.name,
+            detail = check.detail
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cktap_direct::secp256k1::{Secp256k1, SecretKey};
+
+    #[test]
+    fn test_report() -> Result<()> {
+        let pubkey = SecretKey::from_slice(&[1; 32])?.public_key(&Secp256k1::new());
+
+        let mut report = Report::default();
+        report.record("certs", Ok("signed by root".to_string()));
+        report.skip("derive", "card is not initialized");
+        let response = report.into_response("tapsigner", &pubkey);
+        assert!(response.success && response.error.is_none());
+
+        let mut report = Report::default();
+        report.record("certs", Ok("signed by root".to_string()));
+        report.record("read", Err(anyhow::anyhow!("bad signature")));
+        let response = report.into_response("satscard", &pubkey);
+        assert!(!response.success);
+        assert_eq!(response.error.as_deref(), Some("1 of 2 checks failed"));
+        let report = response.data.expect("report");
+        assert!(!report.passed);
+        assert_eq!(report.checks[1].status, CheckStatus::Fail);
+        assert_eq!(report.checks[1].detail, "bad signature");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive_response() -> Result<()> {
+        let secp = Secp256k1::new();
+        let master_pubkey = SecretKey::from_slice(&[1; 32])?.public_key(&secp);
+        let slot_pubkey = SecretKey::from_slice(&[2; 32])?.public_key(&secp);
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let mut check = DeriveCheck {
+            slot: 2,
+            master_pubkey,
+            chain_code: [7; 32].into(),
+            signature_valid: true,
+            slot_pubkey,
+            derived_pubkey: slot_pubkey,
+            address: address.to_string(),
+            card_address: Some("bc1qw508d6___xw7kv8f3t4".to_string()),
+        };
+        let response = derive_response(&check);
+        assert!(response.success);
+        let derived = response.data.expect("derive");
+        assert!(derived.passed);
+        assert_eq!(derived.address, address);
+        assert_eq!(derived.chain_code, "07".repeat(32));
+
+        // each check fails on its own, the address is skipped when the card reported none
+        check.derived_pubkey = master_pubkey;
+        check.card_address = None;
+        let response = derive_response(&check);
+        assert_eq!(response.error_code, Some(ErrorCode::VerificationFailed));
+        let statuses: Vec<_> = response
+            .data
+            .expect("derive")
+            .checks
+            .iter()
+            .map(|check| (check.name.clone(), check.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("signature".to_string(), CheckStatus::Ok),
+                ("pubkey".to_string(), CheckStatus::Fail),
+                ("address".to_string(), CheckStatus::Skip),
+            ]
+        );
+        Ok(())
+    }
+}
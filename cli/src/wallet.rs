@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpub};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, CompressedPublicKey, Network, NetworkKind};
+
+/// Characters allowed in output descriptors, in checksum symbol order (BIP-380)
+const DESCRIPTOR_INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+/// Characters used to encode the descriptor checksum
+const DESCRIPTOR_CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const DESCRIPTOR_GENERATOR: [u64; 5] = [
+    0xf5dee51989,
+    0xa9fdca3312,
+    0x1bab10e32d,
+    0x3706b1677a,
+    0x644d626ffd,
+];
+
+fn descriptor_polymod(symbols: &[u64]) -> u64 {
+    let mut chk = 1u64;
+    for value in symbols {
+        let top = chk >> 35;
+        chk = ((chk & 0x7ffffffff) << 5) ^ value;
+        for (i, generator) in DESCRIPTOR_GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// Compute the BIP-380 checksum of a descriptor, `None` if it contains invalid characters
+pub fn descriptor_checksum(descriptor: &str) -> Option<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in descriptor.chars() {
+        let value = DESCRIPTOR_INPUT_CHARSET.find(c)? as u64;
+        symbols.push(value & 31);
+        groups.push(value >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.as_slice() {
+        [a] => symbols.push(*a),
+        [a, b] => symbols.push(a * 3 + b),
+        _ => {}
+    }
+    symbols.extend([0; 8]);
+
+    let checksum = descriptor_polymod(&symbols) ^ 1;
+    Some(
+        (0..8)
+            .map(|i| {
+                DESCRIPTOR_CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char
+            })
+            .collect(),
+    )
+}
+
+/// Build a hardened derivation path from the CLI's unhardened path components (e.g. 84,0,0)
+pub fn hardened_path(path: &[u32]) -> Result<DerivationPath> {
+    let children = path
+        .iter()
+        .map(|&index| ChildNumber::from_hardened_idx(index))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid derivation path {path:?}"))?;
+    Ok(DerivationPath::from(children))
+}
+
+/// Format a derivation path as used in descriptor key origins, e.g. `84h/0h/0h`
+pub fn origin_path(path: &DerivationPath) -> String {
+    path.into_iter()
+        .map(|child| format!("{child:#}"))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Single-sig script types a TapSigner can sign for (ECDSA only, so no taproot)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    /// Legacy P2PKH (BIP-44)
+    P2pkh,
+    /// Nested segwit P2SH-P2WPKH (BIP-49)
+    P2shP2wpkh,
+    /// Native segwit P2WPKH (BIP-84)
+    P2wpkh,
+}
+
+impl ScriptType {
+    /// Script type implied by the BIP-44 style purpose of a derivation path
+    pub fn from_purpose(purpose: u32) -> Option<Self> {
+        match purpose {
+            44 => Some(Self::P2pkh),
+            49 => Some(Self::P2shP2wpkh),
+            84 => Some(Self::P2wpkh),
+            _ => None,
+        }
+    }
+
+    /// BIP-44 style purpose used for accounts of this script type
+    pub fn purpose(&self) -> u32 {
+        match self {
+            Self::P2pkh => 44,
+            Self::P2shP2wpkh => 49,
+            Self::P2wpkh => 84,
+        }
+    }
+
+    /// Script type of an account path such as `84h/0h/0h`
+    pub fn from_path(path: &DerivationPath) -> Option<Self> {
+        match path.into_iter().next()? {
+            ChildNumber::Hardened { index } => Self::from_purpose(*index),
+            ChildNumber::Normal { .. } => None,
+        }
+    }
+
+    /// Output descriptor for one chain (0 = receive, 1 = change) of an account, with checksum
+    pub fn descriptor(
+        &self,
+        fingerprint: Fingerprint,
+        path: &DerivationPath,
+        xpub: &Xpub,
+        chain: u32,
+    ) -> Result<String> {
+        let key = format!(
+            "[{fingerprint}/{origin}]{xpub}/{chain}/*",
+            origin = origin_path(path)
+        );
+        let descriptor = match self {
+            Self::P2pkh => format!("pkh({key})"),
+            Self::P2shP2wpkh => format!("sh(wpkh({key}))"),
+            Self::P2wpkh => format!("wpkh({key})"),
+        };
+        let checksum = descriptor_checksum(&descriptor)
+            .with_context(|| format!("Invalid character in descriptor {descriptor}"))?;
+        Ok(format!("{descriptor}#{checksum}"))
+    }
+
+    /// Address for a key of this script type
+    pub fn address(&self, pubkey: &CompressedPublicKey, network: Network) -> Address {
+        match self {
+            Self::P2pkh => Address::p2pkh(pubkey, network),
+            Self::P2shP2wpkh => Address::p2shwpkh(pubkey, network),
+            Self::P2wpkh => Address::p2wpkh(pubkey, network),
+        }
+    }
+
+    /// The first `count` addresses of one chain (0 = receive, 1 = change) of an account,
+    /// derived locally from the account xpub
+    pub fn addresses(
+        &self,
+        xpub: &Xpub,
+        chain: u32,
+        count: u32,
+        network: Network,
+    ) -> Result<Vec<Address>> {
+        let secp = Secp256k1::verification_only();
+        let chain_xpub = xpub
+            .ckd_pub(&secp, ChildNumber::from_normal_idx(chain)?)
+            .context("Failed to derive chain xpub")?;
+        (0..count)
+            .map(|index| {
+                let key = chain_xpub
+                    .ckd_pub(&secp, ChildNumber::from_normal_idx(index)?)
+                    .with_context(|| format!("Failed to derive address {index}"))?;
+                Ok(self.address(&key.to_pub(), network))
+            })
+            .collect()
+    }
+
+    /// Encode an xpub with the SLIP-132 version bytes for this script type (xpub/ypub/zpub and
+    /// their testnet counterparts), as expected by Electrum
+    pub fn slip132(&self, xpub: &Xpub) -> String {
+        let version: [u8; 4] = match (self, xpub.network) {
+            (Self::P2pkh, NetworkKind::Main) => [0x04, 0x88, 0xb2, 0x1e],
+            (Self::P2pkh, NetworkKind::Test) => [0x04, 0x35, 0x87, 0xcf],
+            (Self::P2shP2wpkh, NetworkKind::Main) => [0x04, 0x9d, 0x7c, 0xb2],
+            (Self::P2shP2wpkh, NetworkKind::Test) => [0x04, 0x4a, 0x52, 0x62],
+            (Self::P2wpkh, NetworkKind::Main) => [0x04, 0xb2, 0x47, 0x46],
+            (Self::P2wpkh, NetworkKind::Test) => [0x04, 0x5f, 0x1c, 0xf6],
+        };
+        let mut data = xpub.encode();
+        data[0..4].copy_from_slice(&version);
+        bitcoin::base58::encode_check(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_checksum() -> Result<()> {
+        // the test vectors of BIP-380: one valid checksum, a payload error it catches and a
+        // character outside the descriptor charset
+        assert_eq!(
+            descriptor_checksum("raw(deadbeef)").as_deref(),
+            Some("89f8spxm")
+        );
+        assert_ne!(
+            descriptor_checksum("raw(dedbeef)").as_deref(),
+            Some("89f8spxm")
+        );
+        assert_eq!(descriptor_checksum("raw(\u{dc})"), None);
+        // as computed by the BIP-380 reference implementation
+        assert_eq!(
+            descriptor_checksum(
+                "wpkh([d34db33f/84h/0h/0h]xpub6DJ2dNUysrn5Vt36jH2KLBT2i1auw1tTSSomg8PhqNiUtx8QX2SvC9nrHu81fT41fvDUnhMjEzQgXnQjKEu3oaqMSzhSrHMxyyoEAmUHQbY/0/*)"
+            )
+            .as_deref(),
+            Some("cjjspncu")
+        );
+        assert_eq!(descriptor_checksum("raw(deadbeef)\u{e9}"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_origin_path() -> Result<()> {
+        assert_eq!(origin_path(&hardened_path(&[84, 0, 0])?), "84h/0h/0h");
+        assert!(hardened_path(&[1 << 31]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_slip132() -> Result<()> {
+        // BIP-84 test vector account 0 key
+        let xpub: Xpub = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V".parse()?;
+        assert_eq!(
+            ScriptType::P2wpkh.slip132(&xpub),
+            "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs"
+        );
+        assert_eq!(ScriptType::P2pkh.slip132(&xpub), xpub.to_string());
+
+        let path = hardened_path(&[84, 0, 0])?;
+        assert_eq!(ScriptType::from_path(&path), Some(ScriptType::P2wpkh));
+        Ok(())
+    }
+
+    #[test]
+    fn test_addresses() -> Result<()> {
+        // BIP-84 test vector account 0 key and its first receive and change addresses
+        let xpub: Xpub = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V".parse()?;
+        let receive = ScriptType::P2wpkh.addresses(&xpub, 0, 2, Network::Bitcoin)?;
+        assert_eq!(
+            receive.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+                "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g",
+            ]
+        );
+        let change = ScriptType::P2wpkh.addresses(&xpub, 1, 1, Network::Bitcoin)?;
+        assert_eq!(
+            change[0].to_string(),
+            "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+        );
+        assert_eq!(ScriptType::from_purpose(86), None);
+        Ok(())
+    }
+}
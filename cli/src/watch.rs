@@ -0,0 +1,197 @@
+//! Kiosk mode: wait for cards to be presented and run an action for each one.
+
+use crate::output::*;
+use crate::{AddressDetailsArgs, ConnectArgs, card_type, connect, qr};
+use anyhow::{Context, Result};
+use cktap_direct::CkTapCard;
+use cktap_direct::commands::CkTransport;
+use clap::Args;
+use std::process::Command;
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Shell command to run for each card presented. `{card_type}`, `{card_ident}`, `{address}`
+    /// and `{slot}` are replaced by the card's values (address and slot are empty except on a
+    /// SatsCard)
+    #[arg(long, value_name = "COMMAND")]
+    on_insert: Option<String>,
+
+    /// Milliseconds between checks for a card
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    interval: u64,
+
+    /// Stop after this many cards (default: run until interrupted)
+    #[arg(long)]
+    count: Option<usize>,
+
+    #[command(flatten)]
+    details: AddressDetailsArgs,
+}
+
+/// Poll for a card, and handle each card once while it stays on the reader. Presenting the same
+/// card again after removing it counts as a new tap.
+pub async fn watch(args: &WatchArgs, connection: &ConnectArgs, format: OutputFormat) -> Result<()> {
+    emit(Event::Info {
+        message: "Waiting for a card, press Ctrl-C to stop",
+    });
+    let mut presented: Option<String> = None;
+    let mut handled = 0;
+    loop {
+        match connect(connection).await {
+            Ok(mut card) => {
+                let ident = card.ident();
+                if presented.as_ref() != Some(&ident) {
+                    match on_insert(&mut card, &ident, args).await {
+                        Ok(response) => {
+                            presented = Some(ident);
+                            match format {
+                                OutputFormat::Plain => print_card(&response),
+                                _ => output_response(success_response(response), format)?,
+                            }
+                            handled += 1;
+                        }
+                        // most likely the card was pulled away mid-read, try again
+                        Err(e) => log::warn!("Failed to handle card {ident}: {e:#}"),
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(ident) = presented.take() {
+                    emit(Event::Info {
+                        message: &format!("{ident} removed"),
+                    });
+                }
+                log::debug!("No card: {e:#}");
+            }
+        }
+        if args.count.is_some_and(|count| handled >= count) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(args.interval)).await;
+    }
+}
+
+async fn on_insert<T: CkTransport>(
+    card: &mut CkTapCard<T>,
+    ident: &str,
+    args: &WatchArgs,
+) -> Result<CardPresentedResponse> {
+    let card_type = card_type(card);
+    let address = match card {
+        CkTapCard::SatsCard(sc) => {
+            let address = sc
+                .address()
+                .await
+                .context("Failed to get address")?
+                .to_string();
+            let qr = args
+                .details
+                .qr
+                .then(|| qr::QrCode::encode(address.as_bytes()))
+                .transpose()
+                .context("Failed to encode address QR code")?;
+            Some(AddressResponse {
+                slot: args.details.slot.then_some(sc.slots.0),
+                qr: qr.as_ref().map(qr::QrCode::to_terminal),
+                explorer_url: args.details.explorer_link(&address, sc.network()),
+                address,
+            })
+        }
+        CkTapCard::TapSigner(_) | CkTapCard::SatsChip(_) => None,
+    };
+
+    let mut response = CardPresentedResponse {
+        card_type: card_type.to_string(),
+        card_ident: ident.to_string(),
+        address,
+        action_exit_code: None,
+    };
+    if let Some(template) = &args.on_insert {
+        let slot = match card {
+            CkTapCard::SatsCard(sc) => sc.slots.0.to_string(),
+            _ => String::new(),
+        };
+        let address = response
+            .address
+            .as_ref()
+            .map(|address| address.address.as_str());
+        let command = render_template(
+            template,
+            &[
+                ("card_type", card_type),
+                ("card_ident", ident),
+                ("address", address.unwrap_or_default()),
+                ("slot", &slot),
+            ],
+        );
+        response.action_exit_code = run_action(&command);
+    }
+    Ok(response)
+}
+
+/// Replace each `{name}` in `template` by its value. The values are card types, identifiers,
+/// addresses and numbers, so they need no shell quoting.
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |command, (name, value)| {
+            command.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+/// Run `command` with the system shell and return its exit code. A failing action doesn't stop
+/// the watch, the next card still gets its turn.
+fn run_action(command: &str) -> Option<i32> {
+    let status = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).status()
+    } else {
+        Command::new("sh").args(["-c", command]).status()
+    };
+    match status.map(|status| status.code()) {
+        Ok(Some(0)) => Some(0),
+        Ok(Some(code)) => {
+            log::warn!("'{command}' exited with {code}");
+            Some(code)
+        }
+        Ok(None) => {
+            log::warn!("'{command}' was killed by a signal");
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to run '{command}': {e}");
+            None
+        }
+    }
+}
+
+fn print_card(response: &CardPresentedResponse) {
+    println!(
+        "{ident} ({card_type})",
+        ident = response.card_ident,
+        card_type = response.card_type
+    );
+    if let Some(address) = &response.address {
+        crate::print_address(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let values = [
+            ("card_type", "satscard"),
+            ("card_ident", "CARD-0A1B2C3D"),
+            ("address", "bc1qexample"),
+            ("slot", "2"),
+        ];
+        assert_eq!(
+            render_template("notify {card_ident} {address} {address} {slot}", &values),
+            "notify CARD-0A1B2C3D bc1qexample bc1qexample 2"
+        );
+        assert_eq!(render_template("echo {unknown}", &values), "echo {unknown}");
+    }
+}
@@ -0,0 +1,166 @@
+use crate::config;
+use crate::output::*;
+use crate::wallet::{ScriptType, hardened_path};
+use crate::{ChainCodeArgs, card_ident, get_cvc_from_env_or_prompt, new_cvc_from_env_or_prompt};
+use anyhow::{Context, Result, ensure};
+use cktap_direct::commands::{Certificate, CkTransport};
+use cktap_direct::secp256k1::{hashes::hex::DisplayHex, rand};
+use cktap_direct::{SatsCard, TapSigner};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Report a wizard step, on stderr so stdout keeps the final JSON result (or as an NDJSON event)
+fn progress(step: usize, total: usize, message: &str) {
+    emit(Event::Progress {
+        step,
+        total,
+        message,
+    });
+}
+
+/// Write `data` to `path` readable by the user only, on unix
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+/// Initialize a TapSigner, back it up, optionally change the CVC and print its account xpub.
+///
+/// The card refuses to change the CVC before a backup was taken, so the backup step comes first.
+pub async fn tapsigner_setup<T: CkTransport>(
+    ts: &mut TapSigner<T>,
+    entropy: &ChainCodeArgs,
+    backup_file: Option<PathBuf>,
+    path: &[u32],
+    format: OutputFormat,
+) -> Result<()> {
+    const STEPS: usize = 5;
+
+    ensure!(
+        ts.path.is_none(),
+        "Card is already initialized, setup only works on a new card"
+    );
+    let account_path = hardened_path(path)?;
+    let script_type = ScriptType::from_path(&account_path)
+        .with_context(|| format!("Unsupported account path m/{account_path}"))?;
+    let ident = card_ident(&ts.pubkey);
+    let backup_file = backup_file.unwrap_or_else(|| PathBuf::from(format!("backup-{ident}.aes")));
+
+    let mut cvc = get_cvc_from_env_or_prompt().context("Failed to get CVC")?;
+    let new_cvc = new_cvc_from_env_or_prompt().context("Failed to get new CVC")?;
+
+    progress(1, STEPS, "Initializing card");
+    let chain_code = entropy.chain_code(&mut rand::thread_rng());
+    ts.init(chain_code, &cvc)
+        .await
+        .context("Failed to initialize card")?;
+
+    progress(2, STEPS, "Backing up private key");
+    let backup = ts.backup(&cvc).await.context("Failed to create backup")?;
+    write_private(&backup_file, &backup.data).with_context(|| {
+        format!(
+            "Failed to write backup to {path}",
+            path = backup_file.display()
+        )
+    })?;
+
+    progress(3, STEPS, "Changing CVC");
+    let cvc_changed = match new_cvc {
+        Some(new_cvc) => {
+            ts.change(&new_cvc, &cvc)
+                .await
+                .context("Failed to change CVC")?;
+            crate::batch::remember_cvc(&new_cvc);
+            cvc = new_cvc;
+            true
+        }
+        None => {
+            emit(Event::Info {
+                message: "Keeping the CVC printed on the card",
+            });
+            false
+        }
+    };
+
+    progress(4, STEPS, &format!("Deriving m/{account_path}"));
+    ts.derive(path, &cvc)
+        .await
+        .context("Failed to derive account key")?;
+
+    progress(5, STEPS, "Reading xpubs");
+    let master_xpub = ts
+        .xpub(true, &cvc)
+        .await
+        .context("Failed to read master xpub")?;
+    let account_xpub = ts
+        .xpub(false, &cvc)
+        .await
+        .context("Failed to read account xpub")?;
+    let fingerprint = master_xpub.fingerprint();
+
+    let result = SetupResponse {
+        card_ident: ident,
+        chain_code: chain_code.as_hex().to_string(),
+        backup_file: backup_file.display().to_string(),
+        cvc_changed,
+        path: format!("m/{account_path}"),
+        master_fingerprint: fingerprint.to_string(),
+        xpub: account_xpub.to_string(),
+        receive_descriptor: script_type.descriptor(fingerprint, &account_path, &account_xpub, 0)?,
+        change_descriptor: script_type.descriptor(fingerprint, &account_path, &account_xpub, 1)?,
+    };
+    output_response(success_response(result), format)
+}
+
+/// Check a SatsCard is genuine and that its current address really follows from the card's keys
+pub async fn satscard_verify_new<T: CkTransport>(
+    sc: &mut SatsCard<T>,
+    format: OutputFormat,
+) -> Result<()> {
+    const STEPS: usize = 2;
+
+    progress(1, STEPS, "Checking certificate chain");
+    let root_key = sc
+        .check_certificate()
+        .await
+        .context("Card failed to verify, not a genuine card")?;
+
+    progress(2, STEPS, "Checking address against read and derive");
+    let verified = sc
+        .verify_address()
+        .await
+        .context("Failed to verify the slot address")?;
+
+    let result = VerifyNewResponse {
+        card_ident: card_ident(&sc.pubkey),
+        slot: verified.slot,
+        signed_by: root_key.name(),
+        pubkey: verified.pubkey.to_string(),
+        explorer_url: config::get().explorer_link(&verified.address, sc.network()),
+        address: verified.address,
+    };
+    output_response(success_response(result), format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let path =
+            std::env::temp_dir().join(format!("cktap-backup-{pid}", pid = std::process::id()));
+        write_private(&path, b"backup")?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(data?, b"backup");
+        Ok(())
+    }
+}
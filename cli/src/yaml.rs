@@ -0,0 +1,133 @@
+//! YAML for `--format yaml`: block mappings and sequences, with strings quoted whenever YAML
+//! would read them as something else (numbers, booleans, hex starting with a digit, ...).
+
+use serde_json::Value;
+
+/// `value` as a YAML document
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    if is_block(value) {
+        block(value, 0, &mut out);
+    } else {
+        out.push_str(&scalar(value));
+        out.push('\n');
+    }
+    out
+}
+
+/// Non-empty mappings and sequences are written as blocks, the rest on the line of their key
+fn is_block(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => !map.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        _ => false,
+    }
+}
+
+fn block(value: &Value, indent: usize, out: &mut String) {
+    let pad = "  ".repeat(indent);
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                out.push_str(&pad);
+                out.push_str(&string(key));
+                out.push(':');
+                if is_block(value) {
+                    out.push('\n');
+                    block(value, indent + 1, out);
+                } else {
+                    out.push(' ');
+                    out.push_str(&scalar(value));
+                    out.push('\n');
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                if is_block(item) {
+                    // the item's first line goes after the dash: "- key: value"
+                    let mut nested = String::new();
+                    block(item, indent + 1, &mut nested);
+                    out.push_str(&pad);
+                    out.push_str("- ");
+                    out.push_str(&nested[pad.len() + 2..]);
+                } else {
+                    out.push_str(&pad);
+                    out.push_str("- ");
+                    out.push_str(&scalar(item));
+                    out.push('\n');
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => string(s),
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+    }
+}
+
+/// `s` as is if YAML reads it back as the same string, otherwise double-quoted (JSON string
+/// escapes are valid YAML)
+fn string(s: &str) -> String {
+    const RESERVED: &[&str] = &["true", "false", "null", "yes", "no", "on", "off", "y", "n"];
+    let plain = s.starts_with(|c: char| c.is_ascii_alphabetic())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || " _-./:".contains(c))
+        && !s.ends_with([' ', ':'])
+        && !s.contains(": ")
+        && !RESERVED.contains(&s.to_ascii_lowercase().as_str());
+    if plain {
+        s.to_string()
+    } else {
+        Value::String(s.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_string() {
+        let value = json!({
+            "success": true,
+            "error": null,
+            "data": {
+                "address": "bc1qexample",
+                "pubkey": "02abcdef",
+                "slots": [0, 1],
+                "paths": [{"path": "m/84h/0h/0h", "note": "yes"}, []],
+                "url": "https://mempool.space/address/bc1q",
+                "message": "Card: ready",
+            },
+        });
+        // serde_json maps keep their keys sorted
+        assert_eq!(
+            to_string(&value),
+            "data:\n  \
+               address: bc1qexample\n  \
+               message: \"Card: ready\"\n  \
+               paths:\n    \
+                 - note: \"yes\"\n      \
+                   path: m/84h/0h/0h\n    \
+                 - []\n  \
+               pubkey: \"02abcdef\"\n  \
+               slots:\n    \
+                 - 0\n    \
+                 - 1\n  \
+               url: https://mempool.space/address/bc1q\n\
+             error: null\n\
+             success: true\n"
+        );
+        assert_eq!(to_string(&json!("text")), "text\n");
+    }
+}
@@ -0,0 +1,10 @@
+#![no_main]
+
+use cktap_direct::ccid::{CcidHeader, CcidResponse};
+use libfuzzer_sys::fuzz_target;
+
+// CCID framing from a (possibly malicious) reader must never crash the host
+fuzz_target!(|data: &[u8]| {
+    let _ = CcidHeader::from_bytes(data);
+    let _ = CcidResponse::from_bytes(data);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+
+use cktap_direct::apdu::{CertsResponse, ParseMode, ResponseApdu};
+use cktap_direct::commands::{parse_cert_signature, recover_cert_chain};
+use cktap_direct::secp256k1::{All, PublicKey, Secp256k1};
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+static SECP: LazyLock<Secp256k1<All>> = LazyLock::new(Secp256k1::new);
+
+// A card's certificate chain is checked before the card is trusted, so it is attacker controlled
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_cert_signature(data);
+
+    if let Ok(certs) = CertsResponse::from_cbor(data.to_vec(), ParseMode::Lenient) {
+        let card_pubkey = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .expect("valid pubkey");
+        let _ = recover_cert_chain(&SECP, card_pubkey, &certs);
+    }
+});
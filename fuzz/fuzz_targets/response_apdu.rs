@@ -0,0 +1,33 @@
+#![no_main]
+
+use cktap_direct::apdu::tap_signer::{BackupResponse, ChangeResponse, XpubResponse};
+use cktap_direct::apdu::*;
+use cktap_direct::secp256k1::ecdh::SharedSecret;
+use cktap_direct::transcript;
+use libfuzzer_sys::fuzz_target;
+
+// Whatever a reader or card answers, decoding it must fail with an error rather than panic
+fuzz_target!(|data: &[u8]| {
+    let _ = RawResponse::parse(data);
+    let _ = transcript::response(data);
+
+    let cbor = data.to_vec();
+    let _ = StatusResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    if let Ok(read) = ReadResponse::from_cbor(cbor.clone(), ParseMode::Lenient) {
+        let _ = read.signature();
+        let _ = read.pubkey(None);
+        let _ = read.pubkey(Some(SharedSecret::from_bytes([1; 32])));
+    }
+    let _ = DeriveResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = CertsResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = CheckResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = NfcResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = SignResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = WaitResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = NewResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = UnsealResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = DumpResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = XpubResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = ChangeResponse::from_cbor(cbor.clone(), ParseMode::Lenient);
+    let _ = BackupResponse::from_cbor(cbor, ParseMode::Lenient);
+});
@@ -0,0 +1,118 @@
+//! Time and heap allocations per operation on the command encoding path a signing workload runs
+//! for every digest: encrypting the CVC, building the `sign` APDU and computing the digests the
+//! card's answers are checked against.
+//!
+//! ```text
+//! cargo bench -p cktap-direct --bench encode
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use cktap_direct::apdu::{CommandApdu, Error, SignCommand, StatusResponse};
+use cktap_direct::commands::{CkTransport, Read, calc_xcvc};
+use cktap_direct::secp256k1::{Secp256k1, SecretKey};
+use cktap_direct::{Cvc, TapSigner};
+
+/// Counts allocations so the bench shows buffer growth, not just time
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: u32 = 10_000;
+
+fn bench<R>(name: &str, mut op: impl FnMut() -> R) {
+    // warm up
+    for _ in 0..100 {
+        black_box(op());
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(op());
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{name:<24} {ns:>8} ns/iter {allocs:>6.1} allocs/iter",
+        ns = elapsed.as_nanos() / u128::from(ITERATIONS),
+        allocs = allocations as f64 / f64::from(ITERATIONS),
+    );
+}
+
+/// The card is only used for its digests, nothing is sent
+struct NoCard;
+
+impl CkTransport for NoCard {
+    async fn transmit_apdu(&self, _command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Err(Error::Ccid("no card in benchmarks".to_string()))
+    }
+}
+
+fn main() {
+    let secp = Secp256k1::new();
+    let card_key = SecretKey::from_slice(&[0x11; 32]).expect("valid key");
+    let card_pubkey = card_key.public_key(&secp);
+    let ephemeral_key = SecretKey::from_slice(&[0x22; 32]).expect("valid key");
+    let card_nonce = [0x33; 16];
+    let cvc = Cvc::from("123456");
+
+    let xcvc = calc_xcvc(&card_pubkey, &card_nonce, "sign", &cvc, &ephemeral_key);
+    let sign = SignCommand::for_tapsigner(
+        vec![0, 0],
+        [0x44; 32],
+        ephemeral_key.public_key(&secp),
+        xcvc,
+    );
+
+    let card = TapSigner::try_from_status(
+        NoCard,
+        StatusResponse {
+            proto: 1,
+            ver: "1.0.3".to_string(),
+            birth: 700_000,
+            slots: None,
+            addr: None,
+            tapsigner: Some(true),
+            satschip: None,
+            path: None,
+            num_backups: None,
+            pubkey: card_pubkey.serialize().to_vec(),
+            card_nonce,
+            testnet: None,
+            auth_delay: None,
+        },
+    )
+    .expect("valid status");
+
+    bench("calc_xcvc", || {
+        calc_xcvc(&card_pubkey, &card_nonce, "sign", &cvc, &ephemeral_key)
+    });
+    bench("sign apdu_bytes", || sign.apdu_bytes());
+    bench("read message_digest", || {
+        Read::message_digest(&card, card_nonce, vec![0x55; 16])
+    });
+}
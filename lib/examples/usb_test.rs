@@ -53,7 +53,7 @@ async fn main() -> Result<(), cktap_direct::Error> {
     };
 
     println!("Successfully connected!");
-    println!("Card type: {card:?}");
+    println!("Card: {card}");
 
     // Try to get status
     match card {
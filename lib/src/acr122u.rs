@@ -0,0 +1,173 @@
+//! ACR122U support. In CCID mode the reader doesn't talk to contactless cards by itself: APDUs go
+//! through its PN532 NFC controller, wrapped in the reader's "direct transmit" pseudo-APDU.
+//! [`discovery`](crate::discovery) picks this framing when it finds an ACR122U.
+
+use crate::Error;
+use crate::pn532;
+use crate::usb_transport::{Framing, UsbTransport};
+use std::sync::atomic::AtomicBool;
+
+pub const ACR122U_VENDOR_ID: u16 = 0x072F;
+pub const ACR122U_PRODUCT_ID: u16 = 0x2200;
+
+/// Pseudo-APDU header passing a PN532 command through
+const DIRECT_TRANSMIT: [u8; 4] = [0xFF, 0x00, 0x00, 0x00];
+/// Pseudo-APDU turning off the beep the reader makes whenever it detects a card
+const BUZZER_OFF_ON_DETECT: [u8; 5] = [0xFF, 0x00, 0x52, 0x00, 0x00];
+/// Pseudo-APDU header of the LED and buzzer control
+const LED_BUZZER: [u8; 3] = [0xFF, 0x00, 0x40];
+/// Status word the reader appends to the answer of a pseudo-APDU
+const SW_OK: [u8; 2] = [0x90, 0x00];
+
+pub fn is_acr122u(vendor_id: u16, product_id: u16) -> bool {
+    vendor_id == ACR122U_VENDOR_ID && product_id == ACR122U_PRODUCT_ID
+}
+
+/// Bi-color LED and buzzer signal of the ACR122U
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedBuzzer {
+    /// LED control byte: final red and green state (bits 0-1), which final states to apply
+    /// (bits 2-3), initial blinking state (bits 4-5) and which LEDs blink (bits 6-7)
+    pub led_state: u8,
+    /// Initial and toggle blinking durations, in units of 100 ms
+    pub t1: u8,
+    pub t2: u8,
+    pub repetitions: u8,
+    /// 0: silent, 1: beep during T1, 2: during T2, 3: during both
+    pub buzzer: u8,
+}
+
+impl LedBuzzer {
+    /// Green blink with a short beep, e.g. to acknowledge a tap
+    pub const OK: Self = Self {
+        led_state: 0b1010_1000,
+        t1: 1,
+        t2: 1,
+        repetitions: 1,
+        buzzer: 1,
+    };
+    /// Three red blinks with beeps
+    pub const ERROR: Self = Self {
+        led_state: 0b0101_0100,
+        t1: 1,
+        t2: 1,
+        repetitions: 3,
+        buzzer: 1,
+    };
+
+    fn apdu(&self) -> Vec<u8> {
+        let mut apdu = LED_BUZZER.to_vec();
+        apdu.extend([
+            self.led_state,
+            0x04,
+            self.t1,
+            self.t2,
+            self.repetitions,
+            self.buzzer,
+        ]);
+        apdu
+    }
+}
+
+impl UsbTransport {
+    /// Blink the LEDs and beep, on an ACR122U
+    pub async fn led_buzzer(&self, signal: LedBuzzer) -> Result<(), Error> {
+        if self.framing() != Framing::Acr122u {
+            return Err(Error::Ccid(
+                "LED and buzzer control needs an ACR122U".to_string(),
+            ));
+        }
+        let response = self.xfr_block_blocking(signal.apdu())?;
+        pseudo_apdu_response(&response).map(|_| ())
+    }
+}
+
+/// Wrap a PN532 command in a direct transmit pseudo-APDU
+fn direct_transmit(command: u8, params: &[u8]) -> Vec<u8> {
+    let frame = pn532::command_frame(command, params);
+    let mut apdu = DIRECT_TRANSMIT.to_vec();
+    apdu.push(frame.len() as u8);
+    apdu.extend(frame);
+    apdu
+}
+
+/// The answer of a pseudo-APDU, without the status word
+fn pseudo_apdu_response(response: &[u8]) -> Result<&[u8], Error> {
+    match response.split_last_chunk::<2>() {
+        Some((data, &SW_OK)) => Ok(data),
+        _ => Err(Error::Ccid(format!(
+            "ACR122U pseudo-APDU failed: {response:02x?}"
+        ))),
+    }
+}
+
+fn exchange(transport: &UsbTransport, command: u8, params: &[u8]) -> Result<Vec<u8>, Error> {
+    let response = transport.xfr_block_blocking(direct_transmit(command, params))?;
+    let frame = pseudo_apdu_response(&response)?;
+    pn532::response(command, frame).map(<[u8]>::to_vec)
+}
+
+/// Silence the beep on every card detection, and bound the passive activation retries so
+/// looking for a card returns instead of polling (FeliCa included) until one shows up
+pub(crate) fn init(transport: &UsbTransport) -> Result<(), Error> {
+    transport.power_on_blocking()?;
+    let response = transport.xfr_block_blocking(BUZZER_OFF_ON_DETECT.to_vec())?;
+    pseudo_apdu_response(&response)?;
+    exchange(transport, pn532::RF_CONFIGURATION, &pn532::BOUNDED_RETRIES)?;
+    Ok(())
+}
+
+/// Send an APDU to the card through the PN532
+pub(crate) fn transmit(
+    transport: &UsbTransport,
+    target_active: &AtomicBool,
+    apdu: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    pn532::transmit(
+        &mut |command, params| exchange(transport, command, params),
+        target_active,
+        apdu,
+    )
+}
+
+/// Whether a card is on the reader, asking the PN532
+pub(crate) fn card_present(
+    transport: &UsbTransport,
+    target_active: &AtomicBool,
+) -> Result<bool, Error> {
+    pn532::card_present(
+        &mut |command, params| exchange(transport, command, params),
+        target_active,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_transmit() {
+        assert_eq!(
+            direct_transmit(pn532::IN_LIST_PASSIVE_TARGET, &[0x01, 0x00]),
+            [0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x4A, 0x01, 0x00]
+        );
+        assert_eq!(
+            LedBuzzer::OK.apdu(),
+            [0xFF, 0x00, 0x40, 0xA8, 0x04, 0x01, 0x01, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_pn532_response() {
+        let response = [0xD5, 0x41, 0x00, 0xA0, 0x90, 0x00];
+        let frame = pseudo_apdu_response(&response).unwrap();
+        assert_eq!(
+            pn532::response(pn532::IN_DATA_EXCHANGE, frame).unwrap(),
+            [0x00, 0xA0]
+        );
+        // answer to another command
+        assert!(pn532::response(pn532::IN_LIST_PASSIVE_TARGET, frame).is_err());
+        // failed pseudo-APDU
+        assert!(pseudo_apdu_response(&[0x63, 0x00]).is_err());
+    }
+}
@@ -1,7 +1,14 @@
 /// An Application Protocol Data Unit (APDU) is the unit of communication between a smart card
 /// reader and a smart card. This file defines the Coinkite APDU and set of command/responses.
+mod fields;
 pub mod tap_signer;
 
+use crate::status_word::SwError;
+use crate::version::FirmwareVersion;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use bitcoin::secp256k1::{
     self, PublicKey, SecretKey, XOnlyPublicKey, ecdh::SharedSecret, ecdsa::Signature,
     hashes::hex::DisplayHex,
@@ -9,13 +16,15 @@ use bitcoin::secp256k1::{
 use ciborium::de::from_reader;
 use ciborium::ser::into_writer;
 use ciborium::value::Value;
+use core::fmt;
+use core::fmt::{Debug, Formatter};
 use serde;
 use serde::{Deserialize, Serialize};
-use std::fmt;
-use std::fmt::{Debug, Formatter};
 pub const APP_ID: [u8; 15] = *b"\xf0CoinkiteCARDv1";
 pub const SELECT_CLA_INS_P1P2: [u8; 4] = [0x00, 0xA4, 0x04, 0x00];
 pub const CBOR_CLA_INS_P1P2: [u8; 4] = [0x00, 0xCB, 0x00, 0x00];
+/// Header, length byte and the longest command a short APDU can carry
+const MAX_APDU_LEN: usize = CBOR_CLA_INS_P1P2.len() + 1 + 255;
 
 // Errors
 #[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
@@ -30,19 +39,86 @@ pub enum Error {
     IncorrectSignature(String),
     #[error("UnknownCardType: {0}")]
     UnknownCardType(String),
-
+    #[error("AddressMismatch: {0}")]
+    AddressMismatch(String),
+    #[error("Bip32: {0}")]
+    Bip32(String),
+    /// A `sign` response that doesn't verify, from a misbehaving card or reader
+    #[error("SignatureMismatch: {0}")]
+    SignatureMismatch(String),
+    /// The nonce in an answer can't be the card's next one, see
+    /// [`crate::commands::Authentication::advance_card_nonce`]
+    #[error(
+        "UnexpectedNonce: {0}; the answer may be replayed by the reader, reconnect to the card (select the applet again) before retrying"
+    )]
+    UnexpectedNonce(String),
+    /// The card or reader refused the command with an ISO 7816 status word
+    #[error("StatusWord: {0}")]
+    StatusWord(#[from] SwError),
+    /// A [`crate::backup_policy::BackupPolicy`] refused to sign until the card is backed up
+    #[error("BackupRequired: {0}")]
+    BackupRequired(String),
+    /// A link that isn't one of [`crate::slot_url::SlotProof::url`]
+    #[error("InvalidUrl: {0}")]
+    InvalidUrl(String),
+    /// Not the card the caller expected, see [`crate::commands::Authentication::assert_ident`]
+    #[error("WrongCard: expected {expected}, the card is {found}")]
+    WrongCard { expected: String, found: String },
+
+    #[cfg(feature = "usb")]
     #[error("USB: {0}")]
     Usb(#[from] rusb::Error),
     #[error("CCID: {0}")]
     Ccid(String),
+    /// The PN532 NFC controller, bare or in an ACR122U, failed or sent a malformed frame
+    #[cfg(any(feature = "usb", feature = "pn532"))]
+    #[error("PN532: {0}")]
+    Pn532(String),
     #[error("Device not found")]
     DeviceNotFound,
+    /// A reader was found, but no card is on it
+    #[error("No card on the reader")]
+    NoCardOnReader,
+    /// The card left the reader during the session, see [`crate::CkTapCard::reconnect`]
+    #[error("Card removed from the reader")]
+    CardRemoved,
+    #[cfg(feature = "usb")]
+    #[error(
+        "The reader is in use by pcscd. Stop it while using cktap-direct (sudo systemctl stop pcscd.socket pcscd) or talk to the card through PC/SC instead"
+    )]
+    PcscdConflict,
+    #[cfg(feature = "usb")]
+    #[error("ReaderLocked: the reader is in use by another process, {0}")]
+    ReaderLocked(String),
+    /// The reader couldn't be opened or claimed, for a likely platform-specific reason: missing
+    /// udev rules on Linux, the system's smart card driver on macOS, no WinUSB driver on Windows
+    #[cfg(feature = "usb")]
+    #[error("UsbAccess: {0}")]
+    UsbAccess(String),
     #[error("Not a CCID device")]
     NotCcidDevice,
+    #[error(
+        "UnsupportedProtocol: card speaks protocol version {proto}, this library implements version {supported}; the card predates the published protocol and can't be used"
+    )]
+    UnsupportedProtocol { proto: usize, supported: usize },
+    #[error("InvalidVersion: '{0}' is not a firmware version")]
+    InvalidVersion(String),
+    #[error("UnsupportedByFirmware: needs firmware {needs}, card has {have}")]
+    UnsupportedByFirmware {
+        needs: FirmwareVersion,
+        have: FirmwareVersion,
+    },
 
     #[cfg(feature = "emulator")]
     #[error("Emulator: {0}")]
     Emulator(String),
+
+    #[cfg(feature = "metrics")]
+    #[error("Metrics: {0}")]
+    Metrics(String),
+
+    #[error("Audit: {0}")]
+    Audit(String),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, thiserror::Error)]
@@ -121,35 +197,74 @@ impl From<ciborium::value::Error> for Error {
     }
 }
 
+impl From<bitcoin::bip32::Error> for Error {
+    fn from(e: bitcoin::bip32::Error) -> Self {
+        Error::Bip32(e.to_string())
+    }
+}
+
 impl From<secp256k1::Error> for Error {
     fn from(e: secp256k1::Error) -> Self {
         Error::IncorrectSignature(e.to_string())
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct ErrorResponse {
     pub error: String,
     pub code: u16,
 }
 
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error {code}: {error}",
+            code = self.code,
+            error = self.error
+        )
+    }
+}
+
 // Apdu Traits
 pub trait CommandApdu {
     fn name() -> &'static str;
-    fn apdu_bytes(&self) -> Vec<u8>
+    fn apdu_bytes(&self) -> Result<Vec<u8>, Error>
     where
         Self: serde::Serialize + Debug,
     {
-        let mut command = Vec::new();
-        // CBOR serialization of well-formed command structs should never fail
-        // This should never fail as we're writing to a Vec<u8>
-        into_writer(&self, &mut command).expect("Failed to serialize command to CBOR");
-        build_apdu(&CBOR_CLA_INS_P1P2, command.as_slice())
+        // serialize straight after the header, into a buffer that fits the longest short APDU so
+        // a command too long for one fails to serialize, and fill in the length once it's known
+        let mut apdu = [0u8; MAX_APDU_LEN];
+        let header_len = CBOR_CLA_INS_P1P2.len() + 1;
+        apdu[..CBOR_CLA_INS_P1P2.len()].copy_from_slice(&CBOR_CLA_INS_P1P2);
+        let mut body = &mut apdu[header_len..];
+        into_writer(&self, &mut body).map_err(|e| {
+            Error::CiborValue(format!(
+                "can't encode {name} in a short APDU: {e:?}",
+                name = Self::name()
+            ))
+        })?;
+        let command_len = MAX_APDU_LEN - header_len - body.len();
+        apdu[CBOR_CLA_INS_P1P2.len()] = command_len as u8;
+        Ok(apdu[..header_len + command_len].to_vec())
     }
 }
 
+/// How [`ResponseApdu::from_cbor`] treats answers with more than the response struct knows, see
+/// [`CkTransport::parse_mode`](crate::commands::CkTransport::parse_mode)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Ignore unknown map keys, and give missing optional fields their default, so newer
+    /// firmware adding fields keeps working
+    #[default]
+    Lenient,
+    /// Also fail on map keys the response struct doesn't know, for conformance testing
+    Strict,
+}
+
 pub trait ResponseApdu {
-    fn from_cbor<'a>(cbor: Vec<u8>) -> Result<Self, Error>
+    fn from_cbor<'a>(cbor: Vec<u8>, mode: ParseMode) -> Result<Self, Error>
     where
         Self: Deserialize<'a> + Debug,
     {
@@ -161,15 +276,67 @@ pub trait ResponseApdu {
             return Err(Error::CkTap(error));
         }
 
+        if mode == ParseMode::Strict {
+            check_known_keys(&cbor_value, fields::struct_fields::<Self>())?;
+        }
+
         let cbor_struct: Self = cbor_value.deserialized()?;
         Ok(cbor_struct)
     }
 }
 
-fn build_apdu(header: &[u8], command: &[u8]) -> Vec<u8> {
-    let command_len = command.len();
-    assert!(command_len <= 255, "apdu command too long"); // TODO use Err
-    [header, &[command_len as u8], command].concat()
+/// Fail on a key of the `response` map that isn't one of `known`
+fn check_known_keys(response: &Value, known: &[&str]) -> Result<(), Error> {
+    let Value::Map(entries) = response else {
+        return Err(Error::CiborValue("response is not a map".to_string()));
+    };
+    for (key, _) in entries {
+        match key {
+            Value::Text(key) if known.contains(&key.as_str()) => {}
+            Value::Text(key) => {
+                return Err(Error::CiborValue(format!("unexpected key '{key}'")));
+            }
+            key => return Err(Error::CiborValue(format!("unexpected key {key:?}"))),
+        }
+    }
+    Ok(())
+}
+
+/// A raw R-APDU split into body and status word, with the body decoded as CBOR if possible
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RawResponse {
+    pub body: Vec<u8>,
+    /// status word (e.g. `0x9000`), `None` if the response is too short to have one
+    pub sw: Option<u16>,
+    pub cbor: Option<Value>,
+}
+
+impl fmt::Display for RawResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sw {
+            Some(sw) => write!(f, "{len} bytes, sw {sw:04X}", len = self.body.len()),
+            None => write!(f, "{len} bytes, no status word", len = self.body.len()),
+        }
+    }
+}
+
+impl RawResponse {
+    pub fn parse(rapdu: &[u8]) -> Self {
+        let (body, sw) = match rapdu.len().checked_sub(2) {
+            Some(split) => {
+                let (body, sw) = rapdu.split_at(split);
+                (body, Some(u16::from_be_bytes([sw[0], sw[1]])))
+            }
+            None => (rapdu, None),
+        };
+        // the card answers with a single CBOR map, also when it reports an error
+        let cbor = from_reader::<Value, _>(body).ok();
+        Self {
+            body: body.to_vec(),
+            sw,
+            cbor,
+        }
+    }
 }
 
 /// Applet Select
@@ -181,8 +348,12 @@ impl CommandApdu for AppletSelect {
         ""
     }
 
-    fn apdu_bytes(&self) -> Vec<u8> {
-        build_apdu(&SELECT_CLA_INS_P1P2, &APP_ID)
+    fn apdu_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut apdu = Vec::with_capacity(SELECT_CLA_INS_P1P2.len() + 1 + APP_ID.len());
+        apdu.extend_from_slice(&SELECT_CLA_INS_P1P2);
+        apdu.push(APP_ID.len() as u8);
+        apdu.extend_from_slice(&APP_ID);
+        Ok(apdu)
     }
 }
 
@@ -205,7 +376,7 @@ impl CommandApdu for StatusCommand {
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct StatusResponse {
     pub proto: usize,
     pub ver: String,
@@ -227,6 +398,48 @@ pub struct StatusResponse {
 
 impl ResponseApdu for StatusResponse {}
 
+impl fmt::Display for StatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "applet {ver}, birth block {birth}",
+            ver = self.ver,
+            birth = self.birth
+        )?;
+        if let Some((current, total)) = self.slots {
+            write!(f, ", slot {current} of {total}")?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, ", path {path}", path = DisplayPath(path))?;
+        }
+        match self.num_backups {
+            Some(1) => f.write_str(", 1 backup")?,
+            Some(num_backups) => write!(f, ", {num_backups} backups")?,
+            None => {}
+        }
+        if let Some(auth_delay) = self.auth_delay.filter(|&delay| delay > 0) {
+            write!(f, ", {auth_delay}s auth delay")?;
+        }
+        Ok(())
+    }
+}
+
+/// A card path (hardened steps have the high bit set) shown like m/84'/0'/0'
+pub(crate) struct DisplayPath<'a>(pub &'a [usize]);
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("m")?;
+        for &step in self.0 {
+            match step & (1 << 31) {
+                0 => write!(f, "/{step}")?,
+                _ => write!(f, "/{index}'", index = step & !(1 << 31))?,
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Read Command
 ///
 /// Apps need to write a CBOR message to read a SATSCARD's current payment address, or a
@@ -302,7 +515,7 @@ impl ReadResponse {
 
     pub fn pubkey(&self, session_key: Option<SharedSecret>) -> Result<PublicKey, Error> {
         if let Some(sk) = session_key {
-            let pubkey_bytes = unzip(&self.pubkey, sk);
+            let pubkey_bytes = unzip(&self.pubkey, sk)?;
             return PublicKey::from_slice(pubkey_bytes.as_slice())
                 .map_err(|e| Error::CiborValue(e.to_string()));
         };
@@ -312,16 +525,17 @@ impl ReadResponse {
     }
 }
 
-fn unzip(encoded: &[u8], session_key: SharedSecret) -> Vec<u8> {
-    let zipped_bytes = encoded.to_owned().split_off(1);
+fn unzip(encoded: &[u8], session_key: SharedSecret) -> Result<Vec<u8>, Error> {
+    let (&prefix, zipped_bytes) = encoded
+        .split_first()
+        .filter(|(_, zipped)| zipped.len() == 32)
+        .ok_or_else(|| Error::CiborValue(format!("invalid pubkey length {}", encoded.len())))?;
     let unzipped_bytes = zipped_bytes
         .iter()
         .zip(session_key.as_ref())
         .map(|(x, y)| x ^ y);
 
-    let mut pubkey = encoded.to_owned();
-    pubkey.splice(1..33, unzipped_bytes);
-    pubkey
+    Ok(core::iter::once(prefix).chain(unzipped_bytes).collect())
 }
 
 impl fmt::Display for ReadResponse {
@@ -393,7 +607,7 @@ impl DeriveCommand {
     }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct DeriveResponse {
     #[serde(with = "serde_bytes")]
     pub sig: [u8; 64],
@@ -414,6 +628,19 @@ pub struct DeriveResponse {
 
 impl ResponseApdu for DeriveResponse {}
 
+impl fmt::Display for DeriveResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.pubkey {
+            Some(pubkey) => write!(f, "pubkey: {pubkey}", pubkey = pubkey.as_hex()),
+            None => write!(
+                f,
+                "master pubkey: {pubkey}",
+                pubkey = self.master_pubkey.as_hex()
+            ),
+        }
+    }
+}
+
 impl Debug for DeriveResponse {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("DeriveResponse")
@@ -452,7 +679,7 @@ impl Default for CertsCommand {
 /// Each entry in the list is a 65-byte signature. The first signature signs the card's public key,
 /// and each following signature signs the public key used in the previous signature. Although two
 /// levels of signatures are planned, more are possible.
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CertsResponse {
     /// list of certificates, from 'batch' to 'root'
     // TODO create custom deserializer like "serde_bytes" but for Vec<Vec<u8>>
@@ -461,6 +688,12 @@ pub struct CertsResponse {
 
 impl ResponseApdu for CertsResponse {}
 
+impl fmt::Display for CertsResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{count} certificates", count = self.cert_chain.len())
+    }
+}
+
 impl CertsResponse {
     pub fn cert_chain(&self) -> Vec<Vec<u8>> {
         self.clone()
@@ -517,7 +750,7 @@ impl CheckCommand {
 
 /// Check Certs Response
 /// ref: https://github.com/coinkite/coinkite-tap-proto/blob/master/docs/protocol.md#certs
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CheckResponse {
     /// signature using card_pubkey, 64 bytes
     #[serde(with = "serde_bytes")]
@@ -529,6 +762,12 @@ pub struct CheckResponse {
 
 impl ResponseApdu for CheckResponse {}
 
+impl fmt::Display for CheckResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "auth_sig: {sig}", sig = self.auth_sig.as_hex())
+    }
+}
+
 impl Debug for CheckResponse {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("CheckResponse")
@@ -559,7 +798,7 @@ impl CommandApdu for NfcCommand {
 /// nfc Response
 ///
 /// URL for smart phone to navigate to
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct NfcResponse {
     /// command result
     pub url: String,
@@ -567,6 +806,12 @@ pub struct NfcResponse {
 
 impl ResponseApdu for NfcResponse {}
 
+impl fmt::Display for NfcResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
 /// Sign Command
 // {
 //     'cmd': 'sign',              # command
@@ -632,7 +877,7 @@ impl CommandApdu for SignCommand {
 // SATSCARD: Arbitrary signatures can be created for unsealed slots. The app could perform this, since the private key is known, but it's best if the app isn't contaminated with private key information. This could be used for both spending and multisig wallet operations.
 //
 // TAPSIGNER: This is its core feature — signing an arbitrary message digest with a tap. Once the card is set up (the key is picked), the command will always be valid.
-#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct SignResponse {
     /// command result
     pub slot: u8,
@@ -646,6 +891,33 @@ pub struct SignResponse {
 
 impl ResponseApdu for SignResponse {}
 
+impl SignResponse {
+    /// The signature as the card sent it, 64 bytes `r || s`
+    pub fn signature(&self) -> Result<Signature, Error> {
+        Signature::from_compact(&self.sig).map_err(|e| Error::CiborValue(e.to_string()))
+    }
+
+    /// The signature DER encoded, as expected by OpenSSL or in a script (add the sighash byte)
+    pub fn der_signature(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.signature()?.serialize_der().to_vec())
+    }
+
+    pub fn pubkey(&self) -> Result<PublicKey, Error> {
+        PublicKey::from_slice(&self.pubkey).map_err(|e| Error::CiborValue(e.to_string()))
+    }
+}
+
+impl fmt::Display for SignResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sig: {sig}, pubkey: {pubkey}",
+            sig = self.sig.as_hex(),
+            pubkey = self.pubkey.as_hex()
+        )
+    }
+}
+
 impl Debug for SignResponse {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("SignResponse")
@@ -698,7 +970,7 @@ impl CommandApdu for WaitCommand {
 /// Wait Response
 ///
 /// When auth_delay is zero, the CVC can be retried and tested without side effects.
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct WaitResponse {
     /// command result
     pub success: bool,
@@ -709,6 +981,15 @@ pub struct WaitResponse {
 
 impl ResponseApdu for WaitResponse {}
 
+impl fmt::Display for WaitResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.auth_delay {
+            0 => f.write_str("no auth delay left"),
+            delay => write!(f, "{delay}s auth delay left"),
+        }
+    }
+}
+
 /// New Command
 ///
 /// SATSCARD: Use this command to pick a new private key and start a fresh slot. The operation cannot be performed if the current slot is sealed.
@@ -769,7 +1050,7 @@ impl CommandApdu for NewCommand {
 ///
 /// In either case, the status and read commands are required to learn the details of the new
 /// address/key.
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NewResponse {
     /// slot just made
     pub slot: u8,
@@ -822,7 +1103,7 @@ impl CommandApdu for UnsealCommand {
 }
 
 /// Unseal Response
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UnsealResponse {
     /// slot just unsealed
     pub slot: u8,
@@ -911,7 +1192,7 @@ impl CommandApdu for DumpCommand {
 ///
 /// Without the CVC, the dump command returns just the sealed/unsealed/unused status for each slot,
 /// with the exception of unsealed slots where the address in full is also provided.
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DumpResponse {
     /// slot just made
     pub slot: usize,
@@ -949,3 +1230,177 @@ pub struct DumpResponse {
 }
 
 impl ResponseApdu for DumpResponse {}
+
+impl fmt::Display for DumpResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match (self.sealed, self.used) {
+            (Some(true), _) => "sealed",
+            (_, Some(false)) => "unused",
+            _ => "unsealed",
+        };
+        write!(f, "slot {slot}: {state}", slot = self.slot)?;
+        if let Some(addr) = &self.addr {
+            write!(f, ", {addr}")?;
+        }
+        if self.tampered == Some(true) {
+            f.write_str(", tampered")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_response_parse() {
+        let mut rapdu = Vec::new();
+        into_writer(
+            &Value::Map(vec![(Value::from("code"), Value::from(401))]),
+            &mut rapdu,
+        )
+        .expect("serialize CBOR");
+        rapdu.extend([0x90, 0x00]);
+
+        let response = RawResponse::parse(&rapdu);
+        assert_eq!(response.sw, Some(0x9000));
+        assert_eq!(response.body, rapdu[..rapdu.len() - 2]);
+        assert!(response.cbor.is_some());
+
+        let response = RawResponse::parse(&[0x6a, 0x82]);
+        assert_eq!(response.sw, Some(0x6a82));
+        assert!(response.body.is_empty());
+        assert_eq!(response.cbor, None);
+
+        let response = RawResponse::parse(&[0x90]);
+        assert_eq!(response.sw, None);
+    }
+
+    #[test]
+    fn test_check_known_keys() {
+        let known = fields::struct_fields::<WaitResponse>();
+        assert_eq!(known, ["success", "auth_delay"]);
+
+        let response = Value::Map(vec![(Value::from("success"), Value::from(true))]);
+        assert!(check_known_keys(&response, known).is_ok());
+        let response = Value::Map(vec![
+            (Value::from("success"), Value::from(true)),
+            (Value::from("future"), Value::from(1)),
+        ]);
+        assert_eq!(
+            check_known_keys(&response, known),
+            Err(Error::CiborValue("unexpected key 'future'".to_string()))
+        );
+        let mut cbor = Vec::new();
+        into_writer(&response, &mut cbor).expect("serialize wait");
+        assert!(WaitResponse::from_cbor(cbor.clone(), ParseMode::Strict).is_err());
+        // lenient parsing ignores it
+        let response = WaitResponse::from_cbor(cbor, ParseMode::Lenient).expect("lenient");
+        assert_eq!(response.auth_delay, 0);
+    }
+
+    #[test]
+    fn test_apdu_bytes() -> Result<(), Error> {
+        // {"cmd": "status"}
+        let mut cbor = vec![0xa1, 0x63];
+        cbor.extend(b"cmd");
+        cbor.push(0x66);
+        cbor.extend(b"status");
+
+        let apdu = StatusCommand::default().apdu_bytes()?;
+        assert_eq!(apdu[..4], CBOR_CLA_INS_P1P2);
+        assert_eq!(apdu[4] as usize, cbor.len());
+        assert_eq!(apdu[5..], cbor);
+
+        let apdu = AppletSelect::default().apdu_bytes()?;
+        assert_eq!(apdu[..4], SELECT_CLA_INS_P1P2);
+        assert_eq!(apdu[4] as usize, APP_ID.len());
+        assert_eq!(apdu[5..], APP_ID);
+
+        #[derive(Serialize, Debug)]
+        struct Oversized {
+            cmd: &'static str,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        }
+
+        impl CommandApdu for Oversized {
+            fn name() -> &'static str {
+                "oversized"
+            }
+        }
+
+        // 22 bytes of CBOR around the data: 233 bytes fill the 255 a short APDU can carry
+        let command = |len| Oversized {
+            cmd: Oversized::name(),
+            data: vec![0; len],
+        };
+        assert_eq!(command(233).apdu_bytes()?.len(), MAX_APDU_LEN);
+        assert!(matches!(
+            command(234).apdu_bytes(),
+            Err(Error::CiborValue(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let status = StatusResponse {
+            proto: 1,
+            ver: "1.0.3".to_string(),
+            birth: 700_000,
+            slots: None,
+            addr: None,
+            tapsigner: Some(true),
+            satschip: None,
+            path: Some(vec![0x8000_0054, 0x8000_0000, 0x8000_0000]),
+            num_backups: Some(1),
+            pubkey: vec![2; 33],
+            card_nonce: [7; 16],
+            testnet: None,
+            auth_delay: None,
+        };
+        let mut cbor = Vec::new();
+        into_writer(&status, &mut cbor).expect("serialize status");
+        let parsed: StatusResponse = from_reader(&cbor[..]).expect("deserialize status");
+        assert_eq!(parsed, status);
+        assert_eq!(
+            status.to_string(),
+            "applet 1.0.3, birth block 700000, path m/84'/0'/0', 1 backup"
+        );
+
+        let wait = WaitResponse {
+            success: true,
+            auth_delay: 15,
+        };
+        let mut cbor = Vec::new();
+        into_writer(&wait, &mut cbor).expect("serialize wait");
+        assert_eq!(
+            from_reader::<WaitResponse, _>(&cbor[..]).ok(),
+            Some(wait.clone())
+        );
+        assert_eq!(wait.to_string(), "15s auth delay left");
+    }
+
+    #[test]
+    fn test_sign_response_der() -> Result<(), Error> {
+        // r = 1, s = 1
+        let mut sig = [0u8; 64];
+        sig[31] = 1;
+        sig[63] = 1;
+        let response = SignResponse {
+            slot: 0,
+            sig,
+            pubkey: [0; 33],
+            card_nonce: [0; 16],
+        };
+        assert_eq!(response.signature()?.serialize_compact(), sig);
+        assert_eq!(
+            response.der_signature()?,
+            [0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01]
+        );
+        assert!(response.pubkey().is_err());
+        Ok(())
+    }
+}
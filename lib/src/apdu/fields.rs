@@ -0,0 +1,58 @@
+//! The CBOR map keys a response struct knows, read from its `Deserialize` impl: the derive passes
+//! them to `deserialize_struct`, which this deserializer records before bailing out.
+
+use core::cell::Cell;
+use core::fmt;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+/// Keys of the struct `T` deserializes from, empty if it isn't a struct
+pub(crate) fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let fields = Cell::new(&[][..]);
+    let _ = T::deserialize(FieldsDeserializer { fields: &fields });
+    fields.get()
+}
+
+struct FieldsDeserializer<'a> {
+    fields: &'a Cell<&'static [&'static str]>,
+}
+
+#[derive(Debug)]
+struct Stop;
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("fields recorded")
+    }
+}
+
+impl core::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<M: fmt::Display>(_msg: M) -> Self {
+        Stop
+    }
+}
+
+impl<'de> Deserializer<'de> for FieldsDeserializer<'_> {
+    type Error = Stop;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Stop> {
+        Err(Stop)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Stop> {
+        self.fields.set(fields);
+        Err(Stop)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
@@ -2,6 +2,9 @@ use core::fmt::{self, Formatter};
 
 use super::{CommandApdu, ResponseApdu};
 
+use alloc::vec::Vec;
+
+use bitcoin::bip32::Xpub;
 use bitcoin::secp256k1::{PublicKey, hashes::hex::DisplayHex as _};
 use serde::{Deserialize, Serialize};
 
@@ -34,7 +37,7 @@ impl XpubCommand {
     }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct XpubResponse {
     #[serde(with = "serde_bytes")]
     pub xpub: Vec<u8>,
@@ -44,7 +47,16 @@ pub struct XpubResponse {
 
 impl ResponseApdu for XpubResponse {}
 
-impl std::fmt::Debug for XpubResponse {
+impl fmt::Display for XpubResponse {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match Xpub::decode(&self.xpub) {
+            Ok(xpub) => write!(f, "{xpub}"),
+            Err(_) => write!(f, "xpub: {xpub}", xpub = self.xpub.as_hex()),
+        }
+    }
+}
+
+impl fmt::Debug for XpubResponse {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("XpubResponse")
             .field("xpub", &self.xpub.to_lower_hex_string())
@@ -99,6 +111,15 @@ pub struct ChangeResponse {
 
 impl ResponseApdu for ChangeResponse {}
 
+impl fmt::Display for ChangeResponse {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.success {
+            true => f.write_str("CVC changed"),
+            false => f.write_str("CVC not changed"),
+        }
+    }
+}
+
 // MARK: - BackupCommand
 /// TAPSIGNER only - Get an encrypted backup of the card's private key
 
@@ -141,3 +162,9 @@ pub struct BackupResponse {
 }
 
 impl ResponseApdu for BackupResponse {}
+
+impl fmt::Display for BackupResponse {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{len} bytes of encrypted backup", len = self.data.len())
+    }
+}
@@ -0,0 +1,151 @@
+//! Audit of the authenticated commands, the ones carrying the CVC.
+//!
+//! Every attempt of an authenticated command (`sign`, `derive`, `xpub`, `change`, `backup`,
+//! `new`, `unseal`, `dump`, `read` and `wait` with the CVC) is reported to the installed
+//! [`AuditLog`] with the card's ident, the command name and its outcome, known once the card's
+//! answer was checked and its nonce taken over. The CVC itself is never reported. Organizations that must track who signed what and when keep these, e.g.
+//! appended to a file:
+//!
+//! ```ignore
+//! struct Journal(Mutex<File>);
+//!
+//! impl AuditLog for Journal {
+//!     fn auth_attempt(&self, ident: &str, command: &'static str, outcome: Result<(), &Error>) {
+//!         let mut file = self.0.lock().unwrap();
+//!         let _ = writeln!(file, "{ident} {command} {outcome:?}");
+//!     }
+//! }
+//!
+//! cktap_direct::audit::set_audit_log(Journal(Mutex::new(file)))?;
+//! ```
+
+use crate::apdu::Error;
+use std::sync::OnceLock;
+
+/// Receives every attempt of an authenticated command
+pub trait AuditLog: Send + Sync {
+    /// `command` was sent to the card `ident` (see [`crate::protocol::card_ident`]), and
+    /// succeeded or failed with the error
+    fn auth_attempt(&self, ident: &str, command: &'static str, outcome: Result<(), &Error>);
+}
+
+static AUDIT_LOG: OnceLock<Box<dyn AuditLog>> = OnceLock::new();
+
+/// Install the audit log for this process, fails if one was already installed
+pub fn set_audit_log(log: impl AuditLog + 'static) -> Result<(), Error> {
+    AUDIT_LOG
+        .set(Box::new(log))
+        .map_err(|_| Error::Audit("an audit log is already installed".to_string()))
+}
+
+pub(crate) fn auth_attempt(ident: &str, command: &'static str, outcome: Result<(), &Error>) {
+    if let Some(log) = AUDIT_LOG.get() {
+        log.auth_attempt(ident, command, outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SatsCard;
+    use crate::apdu::{CkTapError, StatusResponse, UnsealResponse};
+    use crate::commands::{Authentication as _, CkTransport};
+    use crate::cvc::Cvc;
+    use crate::test_util::BadAuthTransport;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use std::sync::Mutex;
+
+    type Attempts = Mutex<Vec<(String, &'static str, Option<Error>)>>;
+
+    struct TestLog(&'static Attempts);
+
+    /// Answers with the card nonce the test cards start with, as a reader replaying an old
+    /// answer would
+    struct ReplayTransport;
+
+    impl CkTransport for ReplayTransport {
+        async fn transmit_apdu(&self, _command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+            let answer = UnsealResponse {
+                slot: 0,
+                privkey: vec![0; 32],
+                pubkey: vec![0; 33],
+                master_pk: vec![0; 32],
+                chain_code: vec![0; 32],
+                card_nonce: CARD_NONCE,
+            };
+            let mut response = Vec::new();
+            ciborium::into_writer(&answer, &mut response)
+                .map_err(|e| Error::CiborValue(e.to_string()))?;
+            response.extend([0x90, 0x00]);
+            Ok(response)
+        }
+    }
+
+    const CARD_NONCE: [u8; 16] = [7; 16];
+
+    fn satscard<T: CkTransport>(transport: T, secret: u8) -> Result<SatsCard<T>, Error> {
+        let secret_key = SecretKey::from_slice(&[secret; 32]).expect("valid secret key");
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        let status = StatusResponse {
+            proto: 1,
+            ver: "1.0.3".to_string(),
+            birth: 700_000,
+            slots: Some((0, 10)),
+            addr: None,
+            tapsigner: None,
+            satschip: None,
+            path: None,
+            num_backups: None,
+            pubkey: pubkey.serialize().to_vec(),
+            card_nonce: CARD_NONCE,
+            testnet: None,
+            auth_delay: None,
+        };
+        SatsCard::from_status(transport, status)
+    }
+
+    impl AuditLog for TestLog {
+        fn auth_attempt(&self, ident: &str, command: &'static str, outcome: Result<(), &Error>) {
+            let mut attempts = self.0.lock().expect("not poisoned");
+            attempts.push((ident.to_string(), command, outcome.err().cloned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() -> Result<(), Error> {
+        static ATTEMPTS: OnceLock<Attempts> = OnceLock::new();
+        let attempts = ATTEMPTS.get_or_init(Mutex::default);
+        set_audit_log(TestLog(attempts)).expect("first audit log");
+        assert!(set_audit_log(TestLog(attempts)).is_err());
+
+        let mut sc = satscard(BadAuthTransport, 3)?;
+        let cvc = Cvc::from("123456");
+        assert!(sc.unseal(0, &cvc).await.is_err());
+        // without the CVC it isn't an authenticated command
+        assert!(sc.dump(0, None).await.is_err());
+
+        // the card accepted the CVC, but its answer doesn't check
+        let mut replayed = satscard(ReplayTransport, 4)?;
+        assert!(replayed.unseal(0, &cvc).await.is_err());
+        assert_eq!(*replayed.card_nonce(), CARD_NONCE);
+
+        let attempts = attempts.lock().expect("not poisoned");
+        let of = |ident: String| -> Vec<_> {
+            attempts
+                .iter()
+                .filter(|(i, ..)| *i == ident)
+                .map(|(_, command, error)| (*command, error.clone()))
+                .collect()
+        };
+        assert_eq!(
+            of(sc.ident()),
+            [("unseal", Some(Error::CkTap(CkTapError::BadAuth)))]
+        );
+        let replayed = of(replayed.ident());
+        assert!(matches!(
+            replayed.as_slice(),
+            [("unseal", Some(Error::UnexpectedNonce(_)))]
+        ));
+        Ok(())
+    }
+}
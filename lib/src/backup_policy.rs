@@ -0,0 +1,155 @@
+//! Backup hygiene for TAPSIGNERs: a [`BackupPolicy`] sees the card's backup count before every
+//! signature and can remind, or refuse to sign, when the card was never backed up or signed too
+//! much since its last backup.
+//!
+//! ```ignore
+//! let rule = BackupRule { max_signatures: Some(100), enforce: true };
+//! let state = Mutex::new(BackupState::default());
+//! let ts = ts.with_backup_policy(move |_card: &PublicKey, num_backups: Option<usize>| {
+//!     match state.lock().unwrap().record_signature(num_backups, &rule) {
+//!         Some(reminder) if rule.enforce => Err(Error::BackupRequired(reminder.to_string())),
+//!         Some(reminder) => Ok(eprintln!("Back up the card: {reminder}")),
+//!         None => Ok(()),
+//!     }
+//! });
+//! ```
+//!
+//! The card only knows how many backups it made, so counting signatures since the last one is
+//! up to the policy, e.g. in a [`BackupState`] kept per card.
+
+use core::fmt;
+
+use bitcoin::secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::apdu::Error;
+
+/// Why a card should be backed up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupReminder {
+    /// The card's `num_backups` is zero
+    NeverBackedUp,
+    /// The card's `num_backups` didn't increase over the last `signatures` signatures
+    Stale { signatures: u64 },
+}
+
+impl fmt::Display for BackupReminder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupReminder::NeverBackedUp => f.write_str("the card was never backed up"),
+            BackupReminder::Stale { signatures } => {
+                write!(
+                    f,
+                    "the card signed {signatures} times since its last backup"
+                )
+            }
+        }
+    }
+}
+
+/// When a [`BackupState`] reminds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackupRule {
+    /// Remind once the card signed this many times without a new backup, never if `None`
+    pub max_signatures: Option<u64>,
+    /// Refuse to sign instead of reminding
+    pub enforce: bool,
+}
+
+/// Signatures of a card since its backup count last changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupState {
+    pub num_backups: usize,
+    pub signatures: u64,
+}
+
+impl BackupState {
+    /// Count a signature by a card reporting `num_backups`, returning the reminder due if any.
+    /// A signature refused by an enforced rule isn't counted.
+    pub fn record_signature(
+        &mut self,
+        num_backups: Option<usize>,
+        rule: &BackupRule,
+    ) -> Option<BackupReminder> {
+        let num_backups = num_backups.unwrap_or_default();
+        if num_backups != self.num_backups {
+            *self = BackupState {
+                num_backups,
+                signatures: 0,
+            };
+        }
+        let reminder = if num_backups == 0 {
+            Some(BackupReminder::NeverBackedUp)
+        } else {
+            rule.max_signatures
+                .filter(|&max| self.signatures >= max)
+                .map(|_| BackupReminder::Stale {
+                    signatures: self.signatures,
+                })
+        };
+        if reminder.is_none() || !rule.enforce {
+            self.signatures += 1;
+        }
+        reminder
+    }
+}
+
+/// Sees the card's backup count before each `sign` command, see
+/// [`crate::tap_signer::TapSigner::with_backup_policy`]. An error refuses the signature before
+/// anything is sent to the card, [`Error::BackupRequired`] is the one meant for it.
+pub trait BackupPolicy: Send + Sync {
+    fn before_sign(&self, card_pubkey: &PublicKey, num_backups: Option<usize>)
+    -> Result<(), Error>;
+}
+
+impl<F: Fn(&PublicKey, Option<usize>) -> Result<(), Error> + Send + Sync> BackupPolicy for F {
+    fn before_sign(
+        &self,
+        card_pubkey: &PublicKey,
+        num_backups: Option<usize>,
+    ) -> Result<(), Error> {
+        self(card_pubkey, num_backups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_signature() {
+        let rule = BackupRule {
+            max_signatures: Some(2),
+            enforce: false,
+        };
+        let mut state = BackupState::default();
+        assert_eq!(
+            state.record_signature(Some(0), &rule),
+            Some(BackupReminder::NeverBackedUp)
+        );
+
+        // a backup resets the count
+        assert_eq!(state.record_signature(Some(1), &rule), None);
+        assert_eq!(state.record_signature(Some(1), &rule), None);
+        assert_eq!(
+            state.record_signature(Some(1), &rule),
+            Some(BackupReminder::Stale { signatures: 2 })
+        );
+        assert_eq!(state.signatures, 3);
+        assert_eq!(state.record_signature(Some(2), &rule), None);
+        assert_eq!(state.signatures, 1);
+
+        // refused signatures aren't counted
+        let enforced = BackupRule {
+            max_signatures: Some(1),
+            enforce: true,
+        };
+        assert!(state.record_signature(Some(2), &enforced).is_some());
+        assert!(state.record_signature(Some(2), &enforced).is_some());
+        assert_eq!(state.signatures, 1);
+        assert_eq!(
+            state.record_signature(Some(2), &BackupRule::default()),
+            None
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use core::fmt;
 use thiserror::Error;
 
 /// CCID (Chip Card Interface Device) protocol implementation
@@ -100,6 +101,9 @@ impl TryFrom<u8> for MessageType {
     }
 }
 
+/// bRequest of the ABORT class request, sent on the control pipe before PC_to_RDR_Abort
+pub const REQUEST_ABORT: u8 = 0x01;
+
 /// CCID commands
 #[derive(Debug, Clone)]
 pub struct CcidCommand {
@@ -119,16 +123,50 @@ impl CcidCommand {
         }
     }
 
+    /// Create a PC_to_RDR_IccPowerOff command
+    pub fn icc_power_off(slot: u8, sequence: u8) -> Self {
+        let header = CcidHeader::new(MessageType::PcToRdrIccPowerOff, 0, slot, sequence);
+
+        Self {
+            header,
+            data: Vec::new(),
+        }
+    }
+
     /// Create a PC_to_RDR_XfrBlock command
     pub fn xfr_block(slot: u8, sequence: u8, apdu: Vec<u8>) -> Self {
-        let header = CcidHeader::new(
+        Self::xfr_block_level(slot, sequence, apdu, LevelParameter::Whole)
+    }
+
+    /// Create a PC_to_RDR_XfrBlock command carrying a part of an APDU, see [`fragment`]
+    pub fn xfr_block_level(slot: u8, sequence: u8, data: Vec<u8>, level: LevelParameter) -> Self {
+        let mut header = CcidHeader::new(
             MessageType::PcToRdrXfrBlock,
-            apdu.len() as u32,
+            data.len() as u32,
             slot,
             sequence,
         );
+        // bBWI stays 0, wLevelParameter is little-endian
+        let [low, high] = (level as u16).to_le_bytes();
+        header.reserved = [0, low, high];
+
+        Self { header, data }
+    }
+
+    /// Create a PC_to_RDR_Abort command, the bulk half of the abort of the command `sequence`
+    /// (the ABORT control request carrying the same slot and sequence goes first)
+    pub fn abort(slot: u8, sequence: u8) -> Self {
+        let header = CcidHeader::new(MessageType::PcToRdrAbort, 0, slot, sequence);
 
-        Self { header, data: apdu }
+        Self {
+            header,
+            data: Vec::new(),
+        }
+    }
+
+    /// wValue of the ABORT control request matching [`CcidCommand::abort`]
+    pub fn abort_request_value(slot: u8, sequence: u8) -> u16 {
+        u16::from_le_bytes([slot, sequence])
     }
 
     /// Create a PC_to_RDR_GetSlotStatus command
@@ -167,19 +205,17 @@ impl CcidResponse {
         }
 
         let header = CcidHeader::from_bytes(bytes)?;
-        let data_len = header.length as usize;
-
-        if bytes.len() < 10 + data_len {
-            return Err(CcidError::InvalidResponse);
-        }
+        let data = usize::try_from(header.length)
+            .ok()
+            .and_then(|data_len| bytes[10..].get(..data_len))
+            .ok_or(CcidError::InvalidResponse)?
+            .to_vec();
 
         // For all RDR_to_PC messages, byte 7 is bStatus (slot status + error)
         let status_byte = bytes[7];
         let slot_status = SlotStatus::from_bits(status_byte & 0x03)?;
         let slot_error = SlotError::from_bits(status_byte >> 6)?;
 
-        let data = bytes[10..10 + data_len].to_vec();
-
         Ok(Self {
             header,
             data,
@@ -187,6 +223,238 @@ impl CcidResponse {
             slot_error,
         })
     }
+
+    /// Which part of the answer a RDR_to_PC_DataBlock holds (bChainParameter)
+    pub fn chain_parameter(&self) -> Result<LevelParameter, CcidError> {
+        LevelParameter::from_bits(self.header.reserved[2].into())
+    }
+}
+
+/// Which part of an APDU an XfrBlock carries at the extended APDU level (wLevelParameter), and
+/// which part of the answer a DataBlock carries (bChainParameter)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum LevelParameter {
+    /// The whole APDU
+    Whole = 0x0000,
+    /// The APDU begins and continues in the next block
+    Begins = 0x0001,
+    /// The APDU continued and ends with this block
+    Ends = 0x0002,
+    /// The APDU continued and continues in the next block
+    Continues = 0x0003,
+    /// No data: the reader expects (in a DataBlock) or is asked for (in an XfrBlock) the next
+    /// part of the answer
+    ResponseContinues = 0x0010,
+}
+
+impl LevelParameter {
+    fn from_bits(bits: u16) -> Result<Self, CcidError> {
+        match bits {
+            0x0000 => Ok(Self::Whole),
+            0x0001 => Ok(Self::Begins),
+            0x0002 => Ok(Self::Ends),
+            0x0003 => Ok(Self::Continues),
+            0x0010 => Ok(Self::ResponseContinues),
+            _ => Err(CcidError::InvalidLevelParameter(bits)),
+        }
+    }
+
+    /// More of the answer follows this block
+    pub fn continues(self) -> bool {
+        matches!(self, Self::Begins | Self::Continues)
+    }
+}
+
+/// Split `apdu` into the XfrBlock payloads of at most `max_data` bytes each, with the level of
+/// each. An APDU that fits is sent whole.
+pub fn fragment(apdu: &[u8], max_data: usize) -> Vec<(LevelParameter, &[u8])> {
+    if apdu.len() <= max_data {
+        return vec![(LevelParameter::Whole, apdu)];
+    }
+    let chunks: Vec<&[u8]> = apdu.chunks(max_data.max(1)).collect();
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let level = match i {
+                0 => LevelParameter::Begins,
+                i if i == last => LevelParameter::Ends,
+                _ => LevelParameter::Continues,
+            };
+            (level, chunk)
+        })
+        .collect()
+}
+
+/// The CCID class descriptor of a reader (type 0x21, in the extra bytes of its interface
+/// descriptor): what it says about the cards, rates and messages it handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcidDescriptor {
+    /// bcdCCID, the version of the CCID spec, e.g. 0x0110
+    pub ccid_version: u16,
+    /// bMaxSlotIndex, one less than the number of slots
+    pub max_slot_index: u8,
+    /// bVoltageSupport: 5V, 3V and 1.8V in bits 0 to 2
+    pub voltage_support: u8,
+    /// dwProtocols: T=0 and T=1 in bits 0 and 1
+    pub protocols: u32,
+    /// dwDefaultClock and dwMaximumClock, in kHz
+    pub default_clock: u32,
+    pub max_clock: u32,
+    /// dwDataRate and dwMaxDataRate, in bps
+    pub data_rate: u32,
+    pub max_data_rate: u32,
+    /// dwMaxIFSD, for T=1
+    pub max_ifsd: u32,
+    /// dwMechanical
+    pub mechanical: u32,
+    /// dwFeatures, the exchange level is in bits 16-18
+    pub features: u32,
+    /// dwMaxCCIDMessageLength, header included
+    pub max_message_length: u32,
+}
+
+/// How much of the exchange with the card the reader handles itself, from dwFeatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeLevel {
+    Character,
+    Tpdu,
+    ShortApdu,
+    /// Short and extended APDUs, chained with the level parameter
+    ExtendedApdu,
+}
+
+impl fmt::Display for ExchangeLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Character => "character",
+            Self::Tpdu => "TPDU",
+            Self::ShortApdu => "short APDU",
+            Self::ExtendedApdu => "short and extended APDU",
+        })
+    }
+}
+
+/// dwFeatures bits besides the exchange level
+const FEATURES: [(u32, &str); 11] = [
+    (
+        0x0000_0002,
+        "automatic parameter configuration based on ATR",
+    ),
+    (0x0000_0004, "automatic activation on insertion"),
+    (0x0000_0008, "automatic voltage selection"),
+    (0x0000_0010, "automatic clock frequency change"),
+    (0x0000_0020, "automatic baud rate change"),
+    (0x0000_0040, "automatic parameters negotiation"),
+    (0x0000_0080, "automatic PPS"),
+    (0x0000_0100, "clock stop mode"),
+    (0x0000_0200, "NAD other than 00"),
+    (0x0000_0400, "automatic IFSD exchange"),
+    (0x0010_0000, "USB wake up signaling"),
+];
+
+impl CcidDescriptor {
+    /// The dwMaxCCIDMessageLength of the spec for short APDUs, assumed when a reader reports
+    /// one too small to carry any data
+    pub const SHORT_APDU_MESSAGE_LENGTH: u32 = 271;
+
+    /// Find the class descriptor among the `extra` descriptors of the interface
+    pub fn from_extra(extra: &[u8]) -> Option<Self> {
+        let mut rest = extra;
+        while let [len, kind, ..] = *rest {
+            let len = usize::from(len);
+            if len < 2 || len > rest.len() {
+                return None;
+            }
+            if kind == 0x21 && len >= 48 {
+                let dword = |at: usize| {
+                    u32::from_le_bytes([rest[at], rest[at + 1], rest[at + 2], rest[at + 3]])
+                };
+                return Some(Self {
+                    ccid_version: u16::from_le_bytes([rest[2], rest[3]]),
+                    max_slot_index: rest[4],
+                    voltage_support: rest[5],
+                    protocols: dword(6),
+                    default_clock: dword(10),
+                    max_clock: dword(14),
+                    data_rate: dword(19),
+                    max_data_rate: dword(23),
+                    max_ifsd: dword(28),
+                    mechanical: dword(36),
+                    features: dword(40),
+                    max_message_length: dword(44),
+                });
+            }
+            rest = &rest[len..];
+        }
+        None
+    }
+
+    /// The CCID spec version, e.g. `1.10`
+    pub fn version(&self) -> String {
+        format!(
+            "{major:x}.{minor:02x}",
+            major = self.ccid_version >> 8,
+            minor = self.ccid_version & 0xff
+        )
+    }
+
+    pub fn exchange_level(&self) -> ExchangeLevel {
+        match self.features & 0x0007_0000 {
+            0 => ExchangeLevel::Character,
+            0x0001_0000 => ExchangeLevel::Tpdu,
+            0x0002_0000 => ExchangeLevel::ShortApdu,
+            _ => ExchangeLevel::ExtendedApdu,
+        }
+    }
+
+    /// The reader exchanges whole APDUs, chaining them with the level parameter (extended APDU
+    /// level), rather than TPDUs
+    pub fn extended_apdu_level(&self) -> bool {
+        self.exchange_level() == ExchangeLevel::ExtendedApdu
+    }
+
+    /// The voltages the reader powers cards with, e.g. `["5V", "3V"]`
+    pub fn voltages(&self) -> Vec<&'static str> {
+        [(0x01, "5V"), (0x02, "3V"), (0x04, "1.8V")]
+            .into_iter()
+            .filter(|(bit, _)| self.voltage_support & bit != 0)
+            .map(|(_, voltage)| voltage)
+            .collect()
+    }
+
+    /// The card protocols, e.g. `["T=0", "T=1"]`
+    pub fn protocol_names(&self) -> Vec<&'static str> {
+        [(0x01, "T=0"), (0x02, "T=1")]
+            .into_iter()
+            .filter(|(bit, _)| self.protocols & bit != 0)
+            .map(|(_, protocol)| protocol)
+            .collect()
+    }
+
+    /// The features set in dwFeatures besides the exchange level
+    pub fn feature_names(&self) -> Vec<&'static str> {
+        FEATURES
+            .into_iter()
+            .filter(|(bit, _)| self.features & bit != 0)
+            .map(|(_, feature)| feature)
+            .collect()
+    }
+
+    /// The most data an XfrBlock may carry
+    pub fn max_xfr_data(&self) -> usize {
+        max_xfr_data(self.max_message_length)
+    }
+}
+
+/// The most data an XfrBlock to a reader with this dwMaxCCIDMessageLength may carry
+pub fn max_xfr_data(max_message_length: u32) -> usize {
+    match max_message_length {
+        len if len > 10 => len as usize - 10,
+        _ => CcidDescriptor::SHORT_APDU_MESSAGE_LENGTH as usize - 10,
+    }
 }
 
 /// Voltage selection for ICC power on
@@ -257,6 +525,9 @@ pub enum CcidError {
     #[error("Invalid slot error")]
     InvalidSlotError,
 
+    #[error("Invalid level parameter: {0:#x}")]
+    InvalidLevelParameter(u16),
+
     #[error("ICC mute (no response)")]
     IccMute,
 
@@ -314,4 +585,88 @@ mod tests {
         assert_eq!(length, 4);
         assert_eq!(cmd.data, apdu);
     }
+
+    #[test]
+    fn test_response_length() {
+        // RDR_to_PC_DataBlock with 2 bytes of data
+        let bytes = [0x80, 2, 0, 0, 0, 0, 1, 0, 0, 0, 0x90, 0x00];
+        let response = CcidResponse::from_bytes(&bytes).expect("valid response");
+        assert_eq!(response.data, [0x90, 0x00]);
+
+        // length claiming more data than received
+        let bytes = [0x80, 0xff, 0xff, 0xff, 0xff, 0, 1, 0, 0, 0, 0x90];
+        assert!(CcidResponse::from_bytes(&bytes).is_err());
+        assert!(CcidResponse::from_bytes(&bytes[..9]).is_err());
+    }
+
+    #[test]
+    fn test_fragment() {
+        let apdu: Vec<u8> = (0..=9).collect();
+        assert_eq!(fragment(&apdu, 10), [(LevelParameter::Whole, &apdu[..])]);
+        assert_eq!(
+            fragment(&apdu, 4),
+            [
+                (LevelParameter::Begins, &apdu[..4]),
+                (LevelParameter::Continues, &apdu[4..8]),
+                (LevelParameter::Ends, &apdu[8..]),
+            ]
+        );
+
+        let cmd = CcidCommand::xfr_block_level(0, 1, apdu[..4].to_vec(), LevelParameter::Begins);
+        assert_eq!(&cmd.to_bytes()[7..10], [0, 0x01, 0x00]);
+        let cmd = CcidCommand::xfr_block_level(0, 2, vec![], LevelParameter::ResponseContinues);
+        assert_eq!(&cmd.to_bytes()[7..10], [0, 0x10, 0x00]);
+
+        // a DataBlock telling more of the answer follows
+        let bytes = [0x80, 1, 0, 0, 0, 0, 1, 0, 0, 0x01, 0x90];
+        let response = CcidResponse::from_bytes(&bytes).expect("valid response");
+        assert!(response.chain_parameter().expect("known level").continues());
+    }
+
+    #[test]
+    fn test_abort() {
+        let cmd = CcidCommand::abort(0, 7);
+        assert_eq!(cmd.to_bytes(), [0x72, 0, 0, 0, 0, 0, 7, 0, 0, 0]);
+        // bSlot in the low byte, bSeq in the high one
+        assert_eq!(CcidCommand::abort_request_value(0, 7), 0x0700);
+    }
+
+    #[test]
+    fn test_ccid_descriptor() {
+        // an endpoint-like descriptor first, then the class descriptor
+        let mut extra = vec![3, 0x05, 0];
+        let mut class = vec![0; 54];
+        class[0] = 54;
+        class[1] = 0x21;
+        class[2..4].copy_from_slice(&0x0110u16.to_le_bytes());
+        class[5] = 0x07;
+        class[6..10].copy_from_slice(&0x02u32.to_le_bytes());
+        class[23..27].copy_from_slice(&412_903u32.to_le_bytes());
+        class[40..44].copy_from_slice(&0x0004_0440u32.to_le_bytes());
+        class[44..48].copy_from_slice(&64u32.to_le_bytes());
+        extra.extend(class);
+
+        let descriptor = CcidDescriptor::from_extra(&extra).expect("class descriptor");
+        assert_eq!(descriptor.version(), "1.10");
+        assert_eq!(descriptor.voltages(), ["5V", "3V", "1.8V"]);
+        assert_eq!(descriptor.protocol_names(), ["T=1"]);
+        assert_eq!(descriptor.max_data_rate, 412_903);
+        assert_eq!(descriptor.exchange_level(), ExchangeLevel::ExtendedApdu);
+        assert!(descriptor.extended_apdu_level());
+        assert_eq!(
+            descriptor.feature_names(),
+            [
+                "automatic parameters negotiation",
+                "automatic IFSD exchange"
+            ]
+        );
+        assert_eq!(descriptor.max_message_length, 64);
+        assert_eq!(descriptor.max_xfr_data(), 54);
+        let broken = CcidDescriptor {
+            max_message_length: 0,
+            ..descriptor
+        };
+        assert_eq!(broken.max_xfr_data(), 261);
+        assert_eq!(CcidDescriptor::from_extra(&extra[..20]), None);
+    }
 }
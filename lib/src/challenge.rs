@@ -0,0 +1,110 @@
+//! Challenge-response authentication with a TAPSIGNER, e.g. to log in to a service or as a
+//! second factor: the service hands out a random [`Challenge`], the card signs it with the key at
+//! [`CHALLENGE_SUB_PATH`] below its derivation path, and the service checks the
+//! [`ChallengeResponse`] against the pubkey it enrolled for the card.
+//!
+//! The card signs a tagged hash of the challenge, never the challenge itself, so a challenge
+//! can't pass off a transaction sighash for signing.
+
+use bitcoin::bip32::{ChildNumber, DerivationPath};
+use bitcoin::hashes::{Hash as _, HashEngine as _, sha256};
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, ecdsa::Signature};
+
+use crate::apdu::Error;
+use crate::commands::CkTransport;
+use crate::cvc::Cvc;
+use crate::entropy::{EntropySource as _, ThreadRngSource};
+use crate::tap_signer::TapSigner;
+
+/// Sub path, below the card's derivation path, of the key answering challenges: `chal` in ASCII,
+/// far from the receive and change chains so no address ever uses it
+pub const CHALLENGE_SUB_PATH: [u32; 1] = [0x6368_616c];
+
+/// Tag of the hash the card signs, see [`Challenge::digest`]
+const TAG: &[u8] = b"cktap-direct/challenge";
+
+/// 32 bytes from the service, fresh for each login
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge(pub [u8; 32]);
+
+impl Challenge {
+    pub fn random() -> Self {
+        let mut challenge = [0u8; 32];
+        ThreadRngSource.fill_bytes(&mut challenge);
+        Challenge(challenge)
+    }
+
+    /// The digest the card signs, `SHA256(SHA256(tag) || SHA256(tag) || challenge)` as BIP340
+    /// tagged hashes are
+    pub fn digest(&self) -> [u8; 32] {
+        let tag = sha256::Hash::hash(TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(tag.as_ref());
+        engine.input(tag.as_ref());
+        engine.input(&self.0);
+        sha256::Hash::from_engine(engine).to_byte_array()
+    }
+}
+
+/// What the card answered to a [`Challenge`], everything the service needs to check it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeResponse {
+    pub challenge: Challenge,
+    /// The key the card signed with
+    pub pubkey: PublicKey,
+    /// Full path of that key, the card's derivation path followed by [`CHALLENGE_SUB_PATH`]
+    pub path: DerivationPath,
+    pub signature: Signature,
+}
+
+impl ChallengeResponse {
+    /// Check the card enrolled with `pubkey` signed the challenge
+    pub fn verify(&self, pubkey: &PublicKey) -> Result<(), Error> {
+        if self.pubkey != *pubkey {
+            return Err(Error::SignatureMismatch(format!(
+                "challenge signed with {signed_with}, expected enrolled key {pubkey}",
+                signed_with = self.pubkey
+            )));
+        }
+        Secp256k1::verification_only()
+            .verify_ecdsa(
+                &Message::from_digest(self.challenge.digest()),
+                &self.signature,
+                pubkey,
+            )
+            .map_err(|_| {
+                Error::SignatureMismatch(format!(
+                    "signature {signature} is not a signature of the challenge by {pubkey}",
+                    signature = self.signature
+                ))
+            })
+    }
+}
+
+impl<T: CkTransport> TapSigner<T> {
+    /// Sign `challenge` with the key at [`CHALLENGE_SUB_PATH`]. The pubkey of the response is the
+    /// one to enroll, it stays the same as long as the card's derivation path does.
+    pub async fn sign_challenge(
+        &mut self,
+        challenge: Challenge,
+        cvc: &Cvc,
+    ) -> Result<ChallengeResponse, Error> {
+        let response = self
+            .sign(challenge.digest(), CHALLENGE_SUB_PATH.to_vec(), cvc)
+            .await?;
+        let path = self
+            .path
+            .iter()
+            .flatten()
+            .map(|&index| index as u32)
+            .chain(CHALLENGE_SUB_PATH)
+            .map(ChildNumber::from)
+            .collect();
+        Ok(ChallengeResponse {
+            challenge,
+            pubkey: response.pubkey()?,
+            path,
+            signature: response.signature()?,
+        })
+    }
+}
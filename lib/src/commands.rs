@@ -1,17 +1,31 @@
+use crate::apdu::*;
+use crate::cvc::Cvc;
+use crate::entropy::EntropySource;
 use crate::factory_root_key::FactoryRootKey;
+use crate::progress::{ProgressListener, Step};
+use crate::protocol;
+use crate::status_word::{ResponseReader, SwError};
+use crate::transcript;
+use crate::version::check_protocol;
 use crate::{CkTapCard, SatsCard, TapSigner};
-use crate::{apdu::*, rand_nonce};
 
-use bitcoin::key::rand;
+use bitcoin::hashes::sha256;
 use bitcoin::secp256k1::ecdh::SharedSecret;
-use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId, Signature};
-use bitcoin::secp256k1::hashes::{Hash, sha256};
 use bitcoin::secp256k1::{self, All, Message, PublicKey, Secp256k1, SecretKey};
 
 use std::convert::TryFrom;
 
 use std::fmt::Debug;
 use std::future::Future;
+#[cfg(any(feature = "usb", feature = "pn532"))]
+use std::pin::Pin;
+#[cfg(any(feature = "usb", feature = "pn532"))]
+use std::task::{Context, Poll};
+
+pub use crate::protocol::{
+    calc_xcvc, card_ident, cert_chain_hash, opendime_digest, parse_cert_signature,
+    recover_cert_chain,
+};
 
 // Helper functions for authenticated commands.
 pub trait Authentication<T: CkTransport> {
@@ -24,30 +38,104 @@ pub trait Authentication<T: CkTransport> {
 
     fn transport(&self) -> &T;
 
-    fn calc_ekeys_xcvc(&self, cvc: &str, command: &str) -> (SecretKey, PublicKey, Vec<u8>) {
-        let secp = Self::secp(self);
-        let pubkey = Self::pubkey(self);
-        let nonce = Self::card_nonce(self);
-        let cvc_bytes = cvc.as_bytes();
-        let card_nonce_command = [nonce, command.as_bytes()].concat();
-        let (ephemeral_private_key, ephemeral_public_key) =
-            secp.generate_keypair(&mut rand::thread_rng());
-
-        let session_key = SharedSecret::new(pubkey, &ephemeral_private_key);
-        let md = sha256::Hash::hash(card_nonce_command.as_slice());
-        let md: &[u8; 32] = md.as_ref();
-
-        let mask: Vec<u8> = session_key
-            .as_ref()
-            .iter()
-            .zip(md)
-            .map(|(x, y)| x ^ y)
-            .take(cvc_bytes.len())
-            .collect();
-
-        let xcvc = cvc_bytes.iter().zip(mask).map(|(x, y)| x ^ y).collect();
+    /// The card's short identifier, see [`card_ident`]
+    fn ident(&self) -> String {
+        card_ident(self.pubkey())
+    }
+
+    /// Fail with [`Error::WrongCard`] unless this is the card identified by `expected` (in any
+    /// case), to check before authenticated commands when several cards are around
+    fn assert_ident(&self, expected: &str) -> Result<(), Error> {
+        let found = self.ident();
+        if found.eq_ignore_ascii_case(expected.trim()) {
+            Ok(())
+        } else {
+            Err(Error::WrongCard {
+                expected: expected.to_string(),
+                found,
+            })
+        }
+    }
+
+    /// Source of the nonces and ephemeral keys sent to the card
+    fn entropy(&self) -> &dyn EntropySource;
+
+    /// Listener for the steps of multi round-trip operations, if any
+    fn progress(&self) -> Option<&dyn ProgressListener> {
+        None
+    }
+
+    /// Report step `number` of `total` of `operation` to the progress listener
+    fn report_progress(
+        &self,
+        operation: &'static str,
+        description: &'static str,
+        number: usize,
+        total: usize,
+    ) {
+        if let Some(listener) = self.progress() {
+            listener.on_progress(Step {
+                operation,
+                description,
+                number,
+                total,
+            });
+        }
+    }
+
+    /// Send a command carrying the CVC and check the card's answer with `verify`, which takes
+    /// over the card nonce. The attempt is reported to the [`crate::audit`] log once the answer
+    /// was checked, failed if the card refused it or the check did.
+    fn transmit_authenticated<C, R, V>(
+        &mut self,
+        command: &C,
+        verify: impl FnOnce(&mut Self, R) -> Result<V, Error>,
+    ) -> impl Future<Output = Result<V, Error>>
+    where
+        C: CommandApdu + serde::Serialize + Debug,
+        R: ResponseApdu + serde::de::DeserializeOwned + Debug,
+    {
+        async move {
+            let result = match self.transport().transmit(command).await {
+                Ok(response) => verify(self, response),
+                Err(e) => Err(e),
+            };
+            crate::audit::auth_attempt(&self.ident(), C::name(), result.as_ref().map(|_| ()));
+            result
+        }
+    }
+
+    fn calc_ekeys_xcvc(&self, cvc: &Cvc, command: &str) -> (SecretKey, PublicKey, Vec<u8>) {
+        let ephemeral_private_key = self.entropy().secret_key();
+        let ephemeral_public_key = ephemeral_private_key.public_key(self.secp());
+        let xcvc = calc_xcvc(
+            self.pubkey(),
+            self.card_nonce(),
+            command,
+            cvc,
+            &ephemeral_private_key,
+        );
         (ephemeral_private_key, ephemeral_public_key, xcvc)
     }
+
+    /// Take over the nonce the card answered `command` with. The card picks a fresh nonce for
+    /// every command, getting back the one the command was computed with (or an all-zero one)
+    /// means the answer isn't the card's to this command, e.g. a reader replaying an old answer.
+    /// The nonce is left as it was then, fails with [`Error::UnexpectedNonce`].
+    fn advance_card_nonce(&mut self, command: &str, new_nonce: [u8; 16]) -> Result<(), Error> {
+        let reason = if new_nonce == *self.card_nonce() {
+            "the nonce it was sent with"
+        } else if new_nonce == [0; 16] {
+            "an all-zero nonce"
+        } else {
+            self.set_card_nonce(new_nonce);
+            return Ok(());
+        };
+        log::warn!("The card answered {command} with {reason}");
+        Err(Error::UnexpectedNonce(format!(
+            "card answered {command} with {reason}"
+        )))
+    }
 }
 
 pub trait CkTransport: Sized {
@@ -57,26 +145,105 @@ pub trait CkTransport: Sized {
         R: ResponseApdu + serde::de::DeserializeOwned + Debug,
     {
         async move {
-            let command_apdu = command.apdu_bytes();
-            log::debug!("Transmitting APDU: {command_apdu:02x?}");
+            let command_apdu = command.apdu_bytes()?;
+            log::debug!(
+                "Transmitting APDU: {apdu}",
+                apdu = transcript::log_command(&command_apdu)
+            );
+            log::trace!(
+                target: transcript::TARGET,
+                "> {apdu}",
+                apdu = transcript::command(&command_apdu)
+            );
+            #[cfg(feature = "metrics")]
+            let sent = {
+                crate::metrics::command_sent(C::name());
+                std::time::Instant::now()
+            };
 
-            let rapdu = self.transmit_apdu(command_apdu).await?;
+            let rapdu = self
+                .transmit_apdu(command_apdu.clone())
+                .await
+                .inspect_err(|e| {
+                    log::trace!(target: transcript::TARGET, "! {e}");
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::command_failed(C::name(), e);
+                })?;
+            #[cfg(feature = "metrics")]
+            crate::metrics::round_trip(C::name(), sent.elapsed());
             log::debug!(
-                "Received R-APDU ({len} bytes): {rapdu:02x?}",
-                len = rapdu.len()
+                "Received R-APDU ({len} bytes): {response}",
+                len = rapdu.len(),
+                response = transcript::log_response(&rapdu)
+            );
+            log::trace!(
+                target: transcript::TARGET,
+                "< {apdu}",
+                apdu = transcript::response(&rapdu)
             );
 
-            let response = R::from_cbor(rapdu.to_vec())?;
+            let response = response_body(self, &command_apdu, rapdu)
+                .await
+                .and_then(|body| R::from_cbor(body, self.parse_mode()))
+                .inspect_err(|e| {
+                    log::trace!(target: transcript::TARGET, "! {e}");
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::command_failed(C::name(), e);
+                })?;
             Ok(response)
         }
     }
     fn transmit_apdu(&self, command_apdu: Vec<u8>) -> impl Future<Output = Result<Vec<u8>, Error>>;
 
+    /// Send an arbitrary APDU and return the raw response, for diagnosing reader and firmware
+    /// quirks. Nothing is checked, error responses from the card are returned as is.
+    fn transmit_raw(
+        &self,
+        command_apdu: Vec<u8>,
+    ) -> impl Future<Output = Result<RawResponse, Error>> {
+        async move {
+            log::debug!(
+                "Transmitting raw APDU: {apdu}",
+                apdu = transcript::log_command(&command_apdu)
+            );
+            log::trace!(
+                target: transcript::TARGET,
+                "> {apdu}",
+                apdu = transcript::command(&command_apdu)
+            );
+            let rapdu = self.transmit_apdu(command_apdu).await.inspect_err(|e| {
+                log::trace!(target: transcript::TARGET, "! {e}");
+            })?;
+            log::debug!(
+                "Received raw R-APDU: {response}",
+                response = transcript::log_response(&rapdu)
+            );
+            log::trace!(
+                target: transcript::TARGET,
+                "< {apdu}",
+                apdu = transcript::response(&rapdu)
+            );
+            Ok(RawResponse::parse(&rapdu))
+        }
+    }
+
+    /// How the card's answers are parsed, [`ParseMode::Lenient`] unless the transport was set up
+    /// to check them strictly
+    fn parse_mode(&self) -> ParseMode {
+        ParseMode::Lenient
+    }
+
+    /// Whether a card is on the reader, for transports that can tell without sending an APDU
+    fn card_present(&self) -> impl Future<Output = Result<bool, Error>> {
+        async { Ok(true) }
+    }
+
     fn to_cktap(self) -> impl Future<Output = Result<CkTapCard<Self>, Error>> {
         async {
             // Get status from card
             let cmd = AppletSelect::default();
             let status_response: StatusResponse = self.transmit(&cmd).await?;
+            check_protocol(status_response.proto)?;
 
             // Return correct card variant using status
             match (status_response.tapsigner, status_response.satschip) {
@@ -85,8 +252,8 @@ pub trait CkTransport: Sized {
                     Ok(CkTapCard::TapSigner(tap_signer))
                 }
                 (Some(true), Some(true)) => {
-                    let tap_signer = TapSigner::try_from_status(self, status_response)?;
-                    Ok(CkTapCard::TapSigner(tap_signer))
+                    let sats_chip = TapSigner::try_from_status(self, status_response)?;
+                    Ok(CkTapCard::SatsChip(sats_chip))
                 }
                 (None, None) => {
                     let sats_card = SatsCard::from_status(self, status_response)?;
@@ -98,60 +265,158 @@ pub trait CkTransport: Sized {
     }
 }
 
-// card traits
-pub trait Read<T>: Authentication<T>
+/// The body of the answer to `command_apdu`, following the status word of its response `rapdu`,
+/// see [`ResponseReader`]
+async fn response_body<T: CkTransport>(
+    transport: &T,
+    command_apdu: &[u8],
+    mut rapdu: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let mut reader = ResponseReader::new(command_apdu);
+    while let Some(next) = reader.push(&rapdu)? {
+        log::trace!(
+            target: transcript::TARGET,
+            "> {apdu}",
+            apdu = transcript::command(&next)
+        );
+        rapdu = transport.transmit_apdu(next).await?;
+        log::trace!(
+            target: transcript::TARGET,
+            "< {apdu}",
+            apdu = transcript::response(&rapdu)
+        );
+    }
+    Ok(reader.into_body())
+}
+
+/// Select the applet again after the card came back to the reader, and take over its current
+/// nonce. Fails with [`Error::CardRemoved`] while the card is away or if another card is there.
+pub(crate) async fn resync<C, T>(card: &mut C) -> Result<(), Error>
 where
+    C: Authentication<T> + ?Sized,
     T: CkTransport,
 {
-    fn requires_auth(&self) -> bool;
-    fn slot(&self) -> Option<u8>;
-
-    fn read(&mut self, cvc: Option<String>) -> impl Future<Output = Result<ReadResponse, Error>> {
-        async move {
-            let card_nonce = *self.card_nonce();
-            let app_nonce = rand_nonce();
-
-            let (cmd, session_key) = if self.requires_auth() {
-                let cvc_str = cvc
-                    .as_ref()
-                    .ok_or(Error::CkTap(crate::apdu::CkTapError::NeedsAuth))?;
-                let (eprivkey, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc_str, ReadCommand::name());
-                (
-                    ReadCommand::authenticated(app_nonce, epubkey, xcvc),
-                    Some(SharedSecret::new(self.pubkey(), &eprivkey)),
-                )
-            } else {
-                (ReadCommand::unauthenticated(app_nonce), None)
-            };
+    if !card.transport().card_present().await? {
+        return Err(Error::CardRemoved);
+    }
+    let status: StatusResponse = card.transport().transmit(&AppletSelect::default()).await?;
+    if status.pubkey != card.pubkey().serialize() {
+        log::warn!("A different card was presented, waiting for the original one");
+        return Err(Error::CardRemoved);
+    }
+    card.set_card_nonce(status.card_nonce);
+    card.set_auth_delay(status.auth_delay);
+    Ok(())
+}
 
-            let read_response: ReadResponse = self.transport().transmit(&cmd).await?;
+/// Fail with [`Error::WrongCard`] unless `status` comes from the same card as `card`
+pub(crate) fn check_same_card<C, T>(card: &C, status: &StatusResponse) -> Result<(), Error>
+where
+    C: Authentication<T> + ?Sized,
+    T: CkTransport,
+{
+    if status.pubkey == card.pubkey().serialize() {
+        return Ok(());
+    }
+    let found = PublicKey::from_slice(&status.pubkey)
+        .map(|pubkey| card_ident(&pubkey))
+        .unwrap_or_else(|_| "an unknown card".to_string());
+    Err(Error::WrongCard {
+        expected: card.ident(),
+        found,
+    })
+}
 
-            self.secp().verify_ecdsa(
-                &self.message_digest(card_nonce, app_nonce.to_vec()),
-                &read_response.signature()?, // or add 'from' trait: Signature::from(response.sig: )
-                &read_response.pubkey(session_key)?,
-            )?;
+/// Errors saying the card's applet isn't selected anymore, so the command can be sent again
+/// once it is
+pub trait AppletDeselected {
+    fn applet_deselected(&self) -> bool;
+}
 
-            self.set_card_nonce(read_response.card_nonce);
+impl AppletDeselected for Error {
+    /// Status word 6A82: the card answers without its applet selected, e.g. after it lost power
+    /// for a moment
+    fn applet_deselected(&self) -> bool {
+        matches!(self, Error::StatusWord(SwError::NotFound))
+    }
+}
 
-            Ok(read_response)
+/// Run `command`, and when the card answers that its applet isn't selected, select it again,
+/// take over its nonce and run `command` once more, built from the new nonce. The refused
+/// command never reached the applet, so it had no effect (no CVC attempt spent).
+///
+/// A macro rather than a function taking an async closure, so the card futures stay `Send`.
+macro_rules! with_reselect {
+    ($card:expr, $command:expr) => {{
+        let mut reselected = false;
+        loop {
+            match $command {
+                Err(e)
+                    if !reselected && $crate::commands::AppletDeselected::applet_deselected(&e) =>
+                {
+                    log::warn!("The card's applet isn't selected anymore, selecting it again");
+                    $crate::commands::resync($card).await?;
+                    reselected = true;
+                }
+                result => break result,
+            }
         }
+    }};
+}
+pub(crate) use with_reselect;
+
+// card traits
+pub trait Read<T>: Authentication<T>
+where
+    T: CkTransport,
+{
+    fn requires_auth(&self) -> bool;
+    fn slot(&self) -> Option<u8>;
+
+    fn read(&mut self, cvc: Option<&Cvc>) -> impl Future<Output = Result<ReadResponse, Error>> {
+        async move { with_reselect!(self, read_once(self, cvc).await) }
     }
 
     fn message_digest(&self, card_nonce: [u8; 16], app_nonce: Vec<u8>) -> Message {
-        let mut message_bytes: Vec<u8> = Vec::new();
-        message_bytes.extend("OPENDIME".as_bytes());
-        message_bytes.extend(card_nonce);
-        message_bytes.extend(app_nonce);
+        opendime_digest(&[&card_nonce, &app_nonce, &[self.slot().unwrap_or(0)]])
+    }
+}
 
-        if let Some(slot) = self.slot() {
-            message_bytes.push(slot);
-        } else {
-            message_bytes.push(0);
+async fn read_once<C, T>(card: &mut C, cvc: Option<&Cvc>) -> Result<ReadResponse, Error>
+where
+    C: Read<T> + ?Sized,
+    T: CkTransport,
+{
+    let card_nonce = *card.card_nonce();
+    let app_nonce = card.entropy().nonce();
+
+    let (cmd, session_key) = if card.requires_auth() {
+        let cvc = cvc.ok_or(Error::CkTap(crate::apdu::CkTapError::NeedsAuth))?;
+        let (eprivkey, epubkey, xcvc) = card.calc_ekeys_xcvc(cvc, ReadCommand::name());
+        (
+            ReadCommand::authenticated(app_nonce, epubkey, xcvc),
+            Some(SharedSecret::new(card.pubkey(), &eprivkey)),
+        )
+    } else {
+        (ReadCommand::unauthenticated(app_nonce), None)
+    };
+
+    let verify = |card: &mut C, read_response: ReadResponse| {
+        card.secp().verify_ecdsa(
+            &card.message_digest(card_nonce, app_nonce.to_vec()),
+            &read_response.signature()?, // or add 'from' trait: Signature::from(response.sig: )
+            &read_response.pubkey(session_key)?,
+        )?;
+        card.advance_card_nonce(ReadCommand::name(), read_response.card_nonce)?;
+        Ok(read_response)
+    };
+
+    match session_key {
+        Some(_) => card.transmit_authenticated(&cmd, verify).await,
+        None => {
+            let read_response = card.transport().transmit(&cmd).await?;
+            verify(card, read_response)
         }
-
-        let hash = sha256::Hash::hash(message_bytes.as_slice());
-        Message::from_digest(hash.to_byte_array())
     }
 }
 
@@ -159,29 +424,49 @@ pub trait Wait<T>: Authentication<T>
 where
     T: CkTransport,
 {
-    fn wait(&mut self, cvc: Option<String>) -> impl Future<Output = Result<WaitResponse, Error>> {
-        async move {
-            let epubkey_xcvc = cvc.map(|cvc| {
-                let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(&cvc, WaitCommand::name());
-                (epubkey, xcvc)
-            });
+    fn wait(&mut self, cvc: Option<&Cvc>) -> impl Future<Output = Result<WaitResponse, Error>> {
+        async move { with_reselect!(self, wait_once(self, cvc).await) }
+    }
+}
 
-            let (epubkey, xcvc) = epubkey_xcvc
-                .map(|(epubkey, xcvc)| (Some(epubkey.serialize()), Some(xcvc)))
-                .unwrap_or((None, None));
+async fn wait_once<C, T>(card: &mut C, cvc: Option<&Cvc>) -> Result<WaitResponse, Error>
+where
+    C: Wait<T> + ?Sized,
+    T: CkTransport,
+{
+    let epubkey_xcvc = cvc.map(|cvc| {
+        let (_, epubkey, xcvc) = card.calc_ekeys_xcvc(cvc, WaitCommand::name());
+        (epubkey, xcvc)
+    });
 
-            let wait_command = WaitCommand::new(epubkey, xcvc);
+    let (epubkey, xcvc) = epubkey_xcvc
+        .map(|(epubkey, xcvc)| (Some(epubkey.serialize()), Some(xcvc)))
+        .unwrap_or((None, None));
 
-            let wait_response: WaitResponse = self.transport().transmit(&wait_command).await?;
-            if wait_response.auth_delay > 0 {
-                self.set_auth_delay(Some(wait_response.auth_delay));
-            } else {
-                self.set_auth_delay(None);
-            }
+    let wait_command = WaitCommand::new(epubkey, xcvc);
 
-            Ok(wait_response)
+    let wait_response: WaitResponse = match cvc {
+        Some(_) => {
+            card.transmit_authenticated(&wait_command, |_, response| Ok(response))
+                .await?
         }
+        None => card.transport().transmit(&wait_command).await?,
+    };
+    if wait_response.auth_delay > 0 {
+        card.set_auth_delay(Some(wait_response.auth_delay));
+    } else {
+        card.set_auth_delay(None);
     }
+
+    Ok(wait_response)
+}
+
+/// A card's certificate chain that led to a factory root key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifiedChain {
+    pub root: FactoryRootKey,
+    /// [`cert_chain_hash`] of the card's pubkey and chain
+    pub chain_hash: sha256::Hash,
 }
 
 pub trait Certificate<T>: Authentication<T>
@@ -192,48 +477,21 @@ where
 
     fn check_certificate(&mut self) -> impl Future<Output = Result<FactoryRootKey, Error>> {
         async {
-            let nonce = rand_nonce();
-
-            let card_nonce = *self.card_nonce();
-
-            let certs_cmd = CertsCommand::default();
-            let certs_response: CertsResponse = self.transport().transmit(&certs_cmd).await?;
-
-            let check_cmd = CheckCommand::new(nonce);
-            let check_response: CheckResponse = self.transport().transmit(&check_cmd).await?;
-
-            self.set_card_nonce(check_response.card_nonce);
-            self.verify_card_signature(check_response.auth_sig, card_nonce, nonce)?;
-
-            let mut pubkey = *self.pubkey();
-            for sig in &certs_response.cert_chain() {
-                // BIP-137: https://github.com/bitcoin/bips/blob/master/bip-0137.mediawiki
-                let subtract_by = match sig[0] {
-                    27..=30 => 27, // P2PKH uncompressed
-                    31..=34 => 31, // P2PKH compressed
-                    35..=38 => 35, // Segwit P2SH
-                    39..=42 => 39, // Segwit Bech32
-                    _ => {
-                        return Err(Error::IncorrectSignature(format!(
-                            "Unrecognized BIP-137 address type: {sig_type}",
-                            sig_type = sig[0]
-                        )));
-                    }
-                };
-
-                let rec_id = RecoveryId::from_i32((sig[0] as i32) - subtract_by)?;
-                let (_, sig) = sig.split_at(1);
-                let rec_sig = RecoverableSignature::from_compact(sig, rec_id)?;
-
-                let pubkey_hash = sha256::Hash::hash(&pubkey.serialize_uncompressed());
-                let md = Message::from_digest(pubkey_hash.to_byte_array());
-                pubkey = self.secp().recover_ecdsa(&md, &rec_sig)?;
-            }
-
-            FactoryRootKey::try_from(pubkey)
+            let chain = self.check_certificate_chain(None).await?;
+            Ok(chain.root)
         }
     }
 
+    /// Check the card like [`Certificate::check_certificate`], but trust the chain without
+    /// walking it again when it is the one verified as `known` for this card. The card still has
+    /// to prove it holds its key.
+    fn check_certificate_chain(
+        &mut self,
+        known: Option<&VerifiedChain>,
+    ) -> impl Future<Output = Result<VerifiedChain, Error>> {
+        async move { with_reselect!(self, check_certificate_chain_once(self, known).await) }
+    }
+
     fn verify_card_signature(
         &mut self,
         signature: Vec<u8>,
@@ -241,9 +499,67 @@ where
         app_nonce: [u8; 16],
     ) -> Result<(), secp256k1::Error> {
         let message = self.message_digest(card_nonce, app_nonce);
-        let signature = Signature::from_compact(signature.as_slice())?;
-        self.secp()
-            .verify_ecdsa(&message, &signature, self.pubkey())
+        protocol::verify_card_signature(self.secp(), &message, &signature, self.pubkey())
+    }
+}
+
+async fn check_certificate_chain_once<C, T>(
+    card: &mut C,
+    known: Option<&VerifiedChain>,
+) -> Result<VerifiedChain, Error>
+where
+    C: Certificate<T> + ?Sized,
+    T: CkTransport,
+{
+    const OPERATION: &str = "check_certificate";
+    let nonce = card.entropy().nonce();
+
+    let card_nonce = *card.card_nonce();
+
+    card.report_progress(OPERATION, "reading certificates", 1, 3);
+    let certs_cmd = CertsCommand::default();
+    let certs_response: CertsResponse = card.transport().transmit(&certs_cmd).await?;
+
+    card.report_progress(OPERATION, "checking the card's key", 2, 3);
+    let check_cmd = CheckCommand::new(nonce);
+    let check_response: CheckResponse = card.transport().transmit(&check_cmd).await?;
+
+    card.advance_card_nonce(CheckCommand::name(), check_response.card_nonce)?;
+    card.verify_card_signature(check_response.auth_sig, card_nonce, nonce)?;
+
+    card.report_progress(OPERATION, "checking certificate chain", 3, 3);
+    let chain_hash = cert_chain_hash(card.pubkey(), &certs_response);
+    if let Some(known) = known.filter(|known| known.chain_hash == chain_hash) {
+        return Ok(*known);
+    }
+    let pubkey = recover_cert_chain(card.secp(), *card.pubkey(), &certs_response)?;
+    Ok(VerifiedChain {
+        root: FactoryRootKey::try_from(pubkey)?,
+        chain_hash,
+    })
+}
+
+/// Returns `Pending` once, so a caller polling a command alongside a cancellation signal gets a
+/// chance to see the signal
+#[cfg(any(feature = "usb", feature = "pn532"))]
+pub(crate) fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+#[cfg(any(feature = "usb", feature = "pn532"))]
+pub(crate) struct YieldNow(bool);
+
+#[cfg(any(feature = "usb", feature = "pn532"))]
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
 
@@ -253,37 +569,48 @@ mod tests {
     use super::*;
 
     use crate::emulator::CVC;
-    use crate::emulator::find_emulator;
+    use crate::emulator::{EmulatedCard, Manager};
     use crate::rand_chaincode;
+    use bitcoin::key::rand;
 
     #[tokio::test]
     async fn test_new_command() {
+        for card in [EmulatedCard::SatsCard, EmulatedCard::TapSigner] {
+            check_new_command(card).await;
+        }
+    }
+
+    async fn check_new_command(card: EmulatedCard) {
         let rng = &mut rand::thread_rng();
         let chain_code = rand_chaincode(rng);
+        let cvc = Cvc::from(CVC);
 
-        let emulator = find_emulator().await.expect("Failed to find emulator");
+        let manager = Manager::spawn(card)
+            .await
+            .expect("Failed to start emulator");
+        let emulator = manager.connect().await.expect("Failed to find emulator");
         match emulator {
             CkTapCard::SatsCard(mut sc) => {
                 let current_slot = sc.slots.0;
-                let response = sc.unseal(current_slot, CVC).await;
+                let response = sc.unseal(current_slot, &cvc).await;
                 assert!(response.is_ok());
-                let response = sc.new_slot(current_slot + 1, Some(chain_code), CVC).await;
+                let response = sc.new_slot(current_slot + 1, Some(chain_code), &cvc).await;
                 assert!(response.is_ok());
                 assert_eq!(sc.slots.0, current_slot + 1);
                 // test with no new chain_code
                 let current_slot = sc.slots.0;
-                let response = sc.unseal(current_slot, CVC).await;
+                let response = sc.unseal(current_slot, &cvc).await;
                 assert!(response.is_ok());
-                let response = sc.new_slot(current_slot + 1, None, CVC).await;
+                let response = sc.new_slot(current_slot + 1, None, &cvc).await;
                 assert!(response.is_ok());
                 assert_eq!(sc.slots.0, current_slot + 1);
             }
             CkTapCard::TapSigner(mut ts) => {
-                let response = ts.init(chain_code, CVC).await;
+                let response = ts.init(chain_code, &cvc).await;
                 assert!(response.is_ok())
             }
             CkTapCard::SatsChip(mut sc) => {
-                let response = sc.init(chain_code, CVC).await;
+                let response = sc.init(chain_code, &cvc).await;
                 assert!(response.is_ok())
             }
         };
@@ -308,3 +635,270 @@ mod tests {
     //     );
     // }
 }
+
+#[cfg(test)]
+mod card_type_tests {
+    use super::*;
+
+    use ciborium::value::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Transport that answers every APDU with the same canned status response.
+    struct StatusTransport {
+        response: Vec<u8>,
+    }
+
+    impl CkTransport for StatusTransport {
+        async fn transmit_apdu(&self, _command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+            let mut response = self.response.clone();
+            response.extend([0x90, 0x00]);
+            Ok(response)
+        }
+    }
+
+    fn status_cbor(tapsigner: Option<bool>, satschip: Option<bool>) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).expect("valid secret key");
+        let pubkey = PublicKey::from_secret_key(&secp, &secret_key);
+
+        let mut fields = vec![
+            (Value::from("proto"), Value::from(1)),
+            (Value::from("ver"), Value::from("1.0.3")),
+            (Value::from("birth"), Value::from(700_000)),
+            (
+                Value::from("pubkey"),
+                Value::Bytes(pubkey.serialize().to_vec()),
+            ),
+            (Value::from("card_nonce"), Value::Bytes(vec![7u8; 16])),
+        ];
+        if let Some(tapsigner) = tapsigner {
+            fields.push((Value::from("tapsigner"), Value::from(tapsigner)));
+        }
+        if let Some(satschip) = satschip {
+            fields.push((Value::from("satschip"), Value::from(satschip)));
+        }
+        if tapsigner.is_none() {
+            fields.push((
+                Value::from("slots"),
+                Value::Array(vec![Value::from(0), Value::from(10)]),
+            ));
+        }
+
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&Value::Map(fields), &mut cbor).expect("serialize status");
+        cbor
+    }
+
+    #[tokio::test]
+    async fn test_to_cktap_card_types() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), None),
+        };
+        assert!(matches!(
+            transport.to_cktap().await?,
+            CkTapCard::TapSigner(_)
+        ));
+
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), Some(true)),
+        };
+        assert!(matches!(
+            transport.to_cktap().await?,
+            CkTapCard::SatsChip(_)
+        ));
+
+        let transport = StatusTransport {
+            response: status_cbor(None, None),
+        };
+        assert!(matches!(
+            transport.to_cktap().await?,
+            CkTapCard::SatsCard(_)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_summary() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), Some(true)),
+        };
+        let card = transport.to_cktap().await?;
+        let summary = card.status_summary();
+        assert_eq!(summary.card_type, "satschip");
+        assert_eq!(summary.ident, card.ident());
+        assert_eq!(summary.version, "1.0.3");
+        assert_eq!(card.version(), "1.0.3");
+        assert_eq!(summary.slots, None);
+        assert_eq!(
+            card.to_string(),
+            format!("SATSCHIP {ident}, applet 1.0.3", ident = card.ident())
+        );
+        let json = serde_json::to_value(&card).expect("serialize card");
+        assert_eq!(json["card_type"], "satschip");
+        assert_eq!(json["ident"], card.ident());
+
+        let transport = StatusTransport {
+            response: status_cbor(None, None),
+        };
+        let summary = transport.to_cktap().await?.status_summary();
+        assert_eq!(summary.card_type, "satscard");
+        assert_eq!(summary.slots, Some((0, 10)));
+        assert_eq!(summary.path, None);
+        assert!(
+            summary
+                .to_string()
+                .ends_with(", applet 1.0.3, slot 0 of 10")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resyncs_nonce() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), None),
+        };
+        let mut card = transport.to_cktap().await?;
+        if let CkTapCard::TapSigner(ts) = &mut card {
+            ts.set_card_nonce([0u8; 16]);
+        }
+
+        card.reconnect(Duration::ZERO, Duration::from_millis(1), tokio::time::sleep)
+            .await?;
+        let CkTapCard::TapSigner(ts) = &card else {
+            panic!("expected a TapSigner");
+        };
+        assert_eq!(ts.card_nonce(), &[7u8; 16]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_assert_ident() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), None),
+        };
+        let CkTapCard::TapSigner(ts) = transport.to_cktap().await? else {
+            panic!("expected a TapSigner");
+        };
+        let ident = ts.ident();
+        assert!(ident.starts_with("CARD-"));
+        ts.assert_ident(&ident)?;
+        ts.assert_ident(&ident.to_lowercase())?;
+        assert!(matches!(
+            ts.assert_ident("CARD-1234ABCD"),
+            Err(Error::WrongCard { found, .. }) if found == ident
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_advance_card_nonce() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), None),
+        };
+        let CkTapCard::TapSigner(mut ts) = transport.to_cktap().await? else {
+            panic!("expected a TapSigner");
+        };
+        // a replayed answer carries the nonce the command was sent with
+        assert!(matches!(
+            ts.advance_card_nonce("read", [7u8; 16]),
+            Err(Error::UnexpectedNonce(_))
+        ));
+        assert!(matches!(
+            ts.advance_card_nonce("read", [0u8; 16]),
+            Err(Error::UnexpectedNonce(_))
+        ));
+        assert_eq!(ts.card_nonce(), &[7u8; 16]);
+
+        ts.advance_card_nonce("read", [8u8; 16])?;
+        assert_eq!(ts.card_nonce(), &[8u8; 16]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_status() -> Result<(), Error> {
+        let transport = StatusTransport {
+            response: status_cbor(Some(true), None),
+        };
+        let CkTapCard::TapSigner(mut ts) = transport.to_cktap().await? else {
+            panic!("expected a TapSigner");
+        };
+        ts.card_nonce = [0u8; 16];
+        ts.auth_delay = Some(15);
+        ts.refresh_status().await?;
+        assert_eq!(ts.card_nonce, [7u8; 16]);
+        assert_eq!(ts.auth_delay, None);
+
+        let transport = StatusTransport {
+            response: status_cbor(None, None),
+        };
+        let CkTapCard::SatsCard(mut sc) = transport.to_cktap().await? else {
+            panic!("expected a SatsCard");
+        };
+        sc.slots = (1, 10);
+        sc.card_nonce = [0u8; 16];
+        sc.refresh_status().await?;
+        assert_eq!(sc.slots, (0, 10));
+        assert_eq!(sc.card_nonce, [7u8; 16]);
+
+        // another card answering keeps what was known of this one
+        let secret_key = SecretKey::from_slice(&[2u8; 32]).expect("valid secret key");
+        sc.pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        sc.slots = (1, 10);
+        assert!(matches!(
+            sc.refresh_status().await,
+            Err(Error::WrongCard { .. })
+        ));
+        assert_eq!(sc.slots, (1, 10));
+        Ok(())
+    }
+
+    /// Transport that refuses the next commands with 6A82, like a card that lost its applet
+    /// selection, and otherwise answers with a canned status response.
+    struct DeselectedTransport {
+        response: Vec<u8>,
+        refusals: AtomicUsize,
+        selects: AtomicUsize,
+    }
+
+    impl CkTransport for DeselectedTransport {
+        async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+            if command_apdu.starts_with(&SELECT_CLA_INS_P1P2) {
+                self.selects.fetch_add(1, Ordering::SeqCst);
+            } else if self
+                .refusals
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Ok(vec![0x6A, 0x82]);
+            }
+            let mut response = self.response.clone();
+            response.extend([0x90, 0x00]);
+            Ok(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reselect_applet() -> Result<(), Error> {
+        let transport = DeselectedTransport {
+            response: status_cbor(Some(true), None),
+            refusals: AtomicUsize::new(1),
+            selects: AtomicUsize::new(0),
+        };
+        let CkTapCard::TapSigner(mut ts) = transport.to_cktap().await? else {
+            panic!("expected a TapSigner");
+        };
+        ts.status().await?;
+        assert_eq!(ts.transport().selects.load(Ordering::SeqCst), 2);
+
+        // retried only once
+        ts.transport().refusals.store(2, Ordering::SeqCst);
+        assert!(matches!(
+            ts.status().await,
+            Err(Error::StatusWord(SwError::NotFound))
+        ));
+        assert_eq!(ts.transport().selects.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
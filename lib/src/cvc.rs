@@ -0,0 +1,84 @@
+use alloc::string::{String, ToString};
+use core::fmt;
+use core::sync::atomic::{Ordering, compiler_fence};
+
+/// A card's CVC (the PIN printed on the back). It is never printed, and its memory is zeroed
+/// when dropped, so it doesn't linger in freed buffers after the command that needed it.
+pub struct Cvc(String);
+
+impl Cvc {
+    /// The CVC itself, only to compute the `xcvc` sent to the card
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Takes ownership of the string, removing surrounding whitespace (e.g. the newline of a prompt
+/// or file) in place rather than copying
+impl From<String> for Cvc {
+    fn from(mut cvc: String) -> Self {
+        cvc.truncate(cvc.trim_end().len());
+        let leading = cvc.len() - cvc.trim_start().len();
+        cvc.drain(..leading);
+        Cvc(cvc)
+    }
+}
+
+impl From<&str> for Cvc {
+    fn from(cvc: &str) -> Self {
+        Cvc::from(cvc.to_string())
+    }
+}
+
+impl PartialEq for Cvc {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Cvc {}
+
+impl fmt::Debug for Cvc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cvc(<redacted>)")
+    }
+}
+
+impl Drop for Cvc {
+    fn drop(&mut self) {
+        // SAFETY: only zero bytes are written, within the allocation (including the spare
+        // capacity, which may hold bytes moved there by `From<String>`), and zeros are valid UTF-8
+        unsafe {
+            let bytes = self.0.as_mut_vec();
+            let ptr = bytes.as_mut_ptr();
+            for i in 0..bytes.capacity() {
+                ptr.add(i).write_volatile(0);
+            }
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_cvc() {
+        let cvc = Cvc::from(" 123456\n".to_string());
+        assert_eq!(cvc.expose_secret(), "123456");
+        assert_eq!(cvc.len(), 6);
+        assert_eq!(cvc, Cvc::from("123456"));
+        assert_ne!(cvc, Cvc::from("654321"));
+        assert_eq!(format!("{cvc:?}"), "Cvc(<redacted>)");
+    }
+}
@@ -1,7 +1,14 @@
-use crate::usb_transport::{UsbTransport, find_ccid_endpoints};
+use crate::acr122u;
+use crate::apdu::ParseMode;
+use crate::ccid::CcidDescriptor;
+use crate::reader_lock::{DEFAULT_LOCK_TIMEOUT, ReaderLock};
+use crate::usb_transport::{self, Framing, UsbTransport, find_ccid_endpoints};
 use crate::{CkTapCard, CkTransport, Error};
 use log::{debug, info};
 use rusb::{Context, Device, DeviceDescriptor, DeviceHandle, UsbContext};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// USB class code for Smart Card devices (CCID)
 const USB_CLASS_SMART_CARD: u8 = 0x0B;
@@ -23,81 +30,378 @@ pub struct CcidDeviceInfo {
     pub product: Option<String>,
     pub serial: Option<String>,
     pub is_coinkite: bool,
+    /// the device or one of its interfaces has the smart card (CCID) class
+    pub is_ccid: bool,
+    /// the device could be opened, strings and driver state are unknown otherwise
+    pub accessible: bool,
+    /// a kernel driver (e.g. for pcscd) is bound to the CCID interface, `None` if unknown
+    pub kernel_driver_active: Option<bool>,
 }
 
-/// Find the first available CCID card reader and connect to it
-pub async fn find_first() -> Result<CkTapCard<UsbTransport>, Error> {
-    let context = Context::new().map_err(Error::Usb)?;
+/// USB vendor ID of HID Global OMNIKEY readers, known to work well
+const OMNIKEY_VENDOR_ID: u16 = 0x076B;
+/// USB vendor ID of Yubico, a YubiKey shows up as a CCID reader without a card inserted
+const YUBICO_VENDOR_ID: u16 = 0x1050;
 
-    info!("Searching for CCID devices...");
+/// Matches USB devices by vendor ID and, optionally, product ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbMatch {
+    pub vendor_id: u16,
+    pub product_id: Option<u16>,
+}
 
-    for device in context.devices().map_err(Error::Usb)?.iter() {
-        if let Ok(info) = get_device_info(&device) {
-            if info.is_coinkite {
-                info!("Found Coinkite device: {info:?}");
+impl UsbMatch {
+    /// Match every product of a vendor
+    pub fn vendor(vendor_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id: None,
+        }
+    }
 
-                if let Ok(transport) = open_ccid_device(&device) {
-                    return transport.to_cktap().await;
-                }
+    /// Match a single product
+    pub fn product(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id: Some(product_id),
+        }
+    }
+
+    pub fn matches(&self, info: &CcidDeviceInfo) -> bool {
+        info.vendor_id == self.vendor_id
+            && self
+                .product_id
+                .is_none_or(|product_id| info.product_id == product_id)
+    }
+}
+
+/// Configurable reader discovery: Coinkite devices first (optionally), then the preferred
+/// readers in the order they were added, then any other CCID reader unless that fallback is
+/// turned off. Skipped readers are never tried.
+///
+/// The default prefers OMNIKEY readers and skips YubiKeys, like [`find_first`], and locks the
+/// reader it opens (see [`reader_lock`](crate::reader_lock)).
+#[derive(Debug, Clone)]
+pub struct DiscoveryBuilder {
+    prefer_coinkite: bool,
+    prefer: Vec<UsbMatch>,
+    skip: Vec<UsbMatch>,
+    any_ccid: bool,
+    lock_timeout: Option<Duration>,
+    io_timeout: Duration,
+    parse_mode: ParseMode,
+    /// how long to keep looking for a card, and how often
+    retry: Option<(Duration, Duration)>,
+    on_waiting: Option<WaitingListener>,
+}
+
+/// See [`DiscoveryBuilder::on_waiting`]
+#[derive(Clone)]
+struct WaitingListener(Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl fmt::Debug for WaitingListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WaitingListener")
+    }
+}
+
+impl Default for DiscoveryBuilder {
+    fn default() -> Self {
+        Self {
+            prefer_coinkite: true,
+            prefer: vec![UsbMatch::vendor(OMNIKEY_VENDOR_ID)],
+            skip: vec![UsbMatch::vendor(YUBICO_VENDOR_ID)],
+            any_ccid: true,
+            lock_timeout: Some(DEFAULT_LOCK_TIMEOUT),
+            io_timeout: usb_transport::DEFAULT_TIMEOUT,
+            parse_mode: ParseMode::Lenient,
+            retry: None,
+            on_waiting: None,
+        }
+    }
+}
+
+impl DiscoveryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start without any preferred or skipped readers
+    pub fn without_rules(mut self) -> Self {
+        self.prefer.clear();
+        self.skip.clear();
+        self
+    }
+
+    /// Try Coinkite devices before any other reader
+    pub fn prefer_coinkite(mut self, prefer_coinkite: bool) -> Self {
+        self.prefer_coinkite = prefer_coinkite;
+        self
+    }
+
+    /// Try matching readers after Coinkite devices and readers preferred earlier
+    pub fn prefer(mut self, rule: UsbMatch) -> Self {
+        self.prefer.push(rule);
+        self
+    }
+
+    /// Never try matching readers
+    pub fn skip(mut self, rule: UsbMatch) -> Self {
+        self.skip.push(rule);
+        self
+    }
+
+    /// Fall back to any other CCID reader when no preferred one has a card
+    pub fn any_ccid(mut self, any_ccid: bool) -> Self {
+        self.any_ccid = any_ccid;
+        self
+    }
+
+    /// How long to wait for another process to release a reader, `None` to open readers without
+    /// locking them
+    pub fn lock_timeout(mut self, lock_timeout: Option<Duration>) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// How long each USB transfer with the readers may take
+    pub fn io_timeout(mut self, io_timeout: Duration) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    /// How to parse the answers of the cards found, from the first status on
+    pub fn parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Keep looking for a card every `interval` until `timeout` instead of failing at once, so
+    /// the card can be placed on the reader after starting. The waits need a Tokio runtime.
+    pub fn retry(mut self, timeout: Duration, interval: Duration) -> Self {
+        self.retry = Some((timeout, interval));
+        self
+    }
+
+    /// Call `listener` with the reason no card was found (e.g. [`Error::NoCardOnReader`]) when
+    /// [`Self::retry`] starts waiting, to ask for the card right away
+    pub fn on_waiting(mut self, listener: impl Fn(&Error) + Send + Sync + 'static) -> Self {
+        self.on_waiting = Some(WaitingListener(Arc::new(listener)));
+        self
+    }
+
+    /// Position of a device in the search order, `None` if it shouldn't be tried
+    fn rank(&self, info: &CcidDeviceInfo) -> Option<usize> {
+        if !info.is_ccid || self.skip.iter().any(|rule| rule.matches(info)) {
+            return None;
+        }
+        if self.prefer_coinkite && info.is_coinkite {
+            return Some(0);
+        }
+        match self.prefer.iter().position(|rule| rule.matches(info)) {
+            Some(position) => Some(position + 1),
+            None if self.any_ccid => Some(self.prefer.len() + 1),
+            None => None,
+        }
+    }
+
+    /// Connect to the first reader, in preference order, that has a card
+    pub async fn find(&self) -> Result<CkTapCard<UsbTransport>, Error> {
+        self.with_retry(|| self.find_once()).await
+    }
+
+    async fn find_once(&self) -> Result<CkTapCard<UsbTransport>, Error> {
+        let context = Context::new().map_err(Error::Usb)?;
+
+        let mut blocked = None;
+        for (device, info) in self.candidates(&context)? {
+            info!("Trying reader: {info:?}");
+            if let Some(card) = self.try_device(&device, &mut blocked).await {
+                return Ok(card);
             }
         }
+
+        Err(blocked.unwrap_or(Error::DeviceNotFound))
     }
 
-    // If no Coinkite device found, try any CCID device
-    // Prioritize certain readers that are known to work well
-    let devices: Vec<_> = context.devices().map_err(Error::Usb)?.iter().collect();
+    /// Connect to the card in every reader that has one, in preference order. Each card keeps
+    /// its reader open (and locked) until it is dropped.
+    pub async fn find_all(&self) -> Result<Vec<CkTapCard<UsbTransport>>, Error> {
+        self.with_retry(|| self.find_all_once()).await
+    }
+
+    async fn find_all_once(&self) -> Result<Vec<CkTapCard<UsbTransport>>, Error> {
+        let context = Context::new().map_err(Error::Usb)?;
+
+        let mut blocked = None;
+        let mut cards = Vec::new();
+        for (device, info) in self.candidates(&context)? {
+            info!("Trying reader: {info:?}");
+            if let Some(card) = self.try_device(&device, &mut blocked).await {
+                cards.push(card);
+            }
+        }
 
-    // First try OMNIKEY readers (known to work well)
-    for device in &devices {
-        if let Ok(info) = get_device_info(device) {
-            if info.vendor_id == 0x076B {
-                // OMNIKEY vendor ID
-                info!("Trying OMNIKEY reader: {info:?}");
+        if cards.is_empty() {
+            return Err(blocked.unwrap_or(Error::DeviceNotFound));
+        }
+        Ok(cards)
+    }
 
-                match open_ccid_device(device) {
-                    Ok(transport) => match transport.to_cktap().await {
-                        Ok(card) => return Ok(card),
-                        Err(e) => debug!("Failed to initialize card: {e}"),
-                    },
-                    Err(e) => debug!("Failed to open device: {e}"),
+    /// Readers to try, in preference order
+    fn candidates(
+        &self,
+        context: &Context,
+    ) -> Result<Vec<(Device<Context>, CcidDeviceInfo)>, Error> {
+        info!("Searching for CCID devices...");
+
+        let mut candidates = Vec::new();
+        for device in context.devices().map_err(Error::Usb)?.iter() {
+            if let Ok(info) = get_device_info(&device) {
+                match self.rank(&info) {
+                    Some(rank) => candidates.push((rank, device, info)),
+                    None => debug!("Skipping device: {info:?}"),
                 }
             }
         }
+        // stable, so devices with the same rank keep the USB enumeration order
+        candidates.sort_by_key(|(rank, _, _)| *rank);
+
+        Ok(candidates
+            .into_iter()
+            .map(|(_, device, info)| (device, info))
+            .collect())
     }
 
-    // Then try other CCID devices
-    for device in &devices {
-        if is_ccid_device(device).unwrap_or(false) {
-            if let Ok(info) = get_device_info(device) {
-                // Skip YubiKey for now - it might not have a card inserted
-                if info.vendor_id == 0x1050 {
-                    debug!("Skipping YubiKey");
-                    continue;
+    /// Connect to the first CCID reader accepted by `filter`, regardless of the preferred and
+    /// skipped readers
+    pub async fn find_matching<F>(&self, filter: F) -> Result<CkTapCard<UsbTransport>, Error>
+    where
+        F: Fn(&CcidDeviceInfo) -> bool,
+    {
+        self.with_retry(|| self.find_matching_once(&filter)).await
+    }
+
+    async fn find_matching_once<F>(&self, filter: &F) -> Result<CkTapCard<UsbTransport>, Error>
+    where
+        F: Fn(&CcidDeviceInfo) -> bool,
+    {
+        let context = Context::new().map_err(Error::Usb)?;
+
+        let mut blocked = None;
+        for device in context.devices().map_err(Error::Usb)?.iter() {
+            if let Ok(info) = get_device_info(&device)
+                && info.is_ccid
+                && filter(&info)
+            {
+                info!("Trying selected reader: {info:?}");
+                if let Some(card) = self.try_device(&device, &mut blocked).await {
+                    return Ok(card);
                 }
+            }
+        }
 
-                debug!("Trying generic CCID device: {info:?}");
+        Err(blocked.unwrap_or(Error::DeviceNotFound))
+    }
 
-                match open_ccid_device(device) {
-                    Ok(transport) => match transport.to_cktap().await {
-                        Ok(card) => return Ok(card),
-                        Err(e) => debug!("Failed to initialize card: {e}"),
-                    },
-                    Err(e) => debug!("Failed to open device: {e}"),
+    /// Run `attempt` until it succeeds or the retry deadline passes, once without [`Self::retry`]
+    async fn with_retry<R, F, Fut>(&self, mut attempt: F) -> Result<R, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        let Some((timeout, interval)) = self.retry else {
+            return attempt().await;
+        };
+        let deadline = Instant::now() + timeout;
+        let mut waiting = false;
+        loop {
+            match attempt().await {
+                Ok(found) => return Ok(found),
+                Err(e) if Instant::now() + interval > deadline => return Err(e),
+                Err(e) => {
+                    debug!("No card yet, retrying: {e}");
+                    if let Some(WaitingListener(listener)) = &self.on_waiting
+                        && !waiting
+                    {
+                        listener(&e);
+                    }
+                    waiting = true;
                 }
             }
+            tokio::time::sleep(interval).await;
         }
     }
 
-    Err(Error::DeviceNotFound)
+    /// Open a reader and connect to its card. A reader held by another process or pcscd, or
+    /// one the platform doesn't let us use, is a likelier reason for finding no card than the
+    /// other failures, so it's kept in `blocked`,
+    /// and so is an empty reader ([`Error::NoCardOnReader`]) unless another was blocked.
+    async fn try_device(
+        &self,
+        device: &Device<Context>,
+        blocked: &mut Option<Error>,
+    ) -> Option<CkTapCard<UsbTransport>> {
+        let opened = open_ccid_device(device, self.lock_timeout, self.io_timeout).await;
+        match opened.map(|transport| transport.with_parse_mode(self.parse_mode)) {
+            Ok(transport) if !transport.card_present().await.unwrap_or(true) => {
+                debug!("No card on the reader");
+                blocked.get_or_insert(Error::NoCardOnReader);
+            }
+            Ok(transport) => match transport.to_cktap().await {
+                Ok(card) => return Some(card),
+                Err(e) => debug!("Failed to initialize card: {e}"),
+            },
+            Err(e @ (Error::PcscdConflict | Error::ReaderLocked(_) | Error::UsbAccess(_))) => {
+                *blocked = Some(e)
+            }
+            Err(e) => debug!("Failed to open device: {e}"),
+        }
+        None
+    }
+}
+
+/// Find the first available CCID card reader and connect to it, using the default
+/// [`DiscoveryBuilder`] preferences
+pub async fn find_first() -> Result<CkTapCard<UsbTransport>, Error> {
+    DiscoveryBuilder::default().find().await
 }
 
-/// List all available CCID devices
+/// Like [`find_first`], but keep looking for a card every `interval` until `timeout`
+pub async fn find_first_with_retry(
+    timeout: Duration,
+    interval: Duration,
+) -> Result<CkTapCard<UsbTransport>, Error> {
+    DiscoveryBuilder::default()
+        .retry(timeout, interval)
+        .find()
+        .await
+}
+
+/// Connect to the card in every CCID reader that has one, using the default [`DiscoveryBuilder`]
+/// preferences
+pub async fn find_all() -> Result<Vec<CkTapCard<UsbTransport>>, Error> {
+    DiscoveryBuilder::default().find_all().await
+}
+
+/// Find the first CCID reader accepted by `filter` and connect to it
+pub async fn find_matching<F>(filter: F) -> Result<CkTapCard<UsbTransport>, Error>
+where
+    F: Fn(&CcidDeviceInfo) -> bool,
+{
+    DiscoveryBuilder::default().find_matching(filter).await
+}
+
+/// List all CCID devices, and Coinkite devices even if they aren't CCID, including the ones
+/// that can't be opened so it's possible to see why a reader isn't used
 pub fn list_devices() -> Result<Vec<CcidDeviceInfo>, Error> {
     let context = Context::new().map_err(Error::Usb)?;
     let mut devices = Vec::new();
 
     for device in context.devices().map_err(Error::Usb)?.iter() {
-        if let Ok(info) = get_device_info(&device) {
+        if let Ok(info) = get_device_info(&device)
+            && (info.is_ccid || info.is_coinkite)
+        {
             devices.push(info);
         }
     }
@@ -105,39 +409,90 @@ pub fn list_devices() -> Result<Vec<CcidDeviceInfo>, Error> {
     Ok(devices)
 }
 
-/// Get information about a USB device
-fn get_device_info(device: &Device<Context>) -> Result<CcidDeviceInfo, Error> {
-    let desc = device.device_descriptor().map_err(Error::Usb)?;
+/// The readers of [`list_devices`] with their CCID class descriptor, `None` for the devices
+/// without one (e.g. a Coinkite device in another mode)
+pub fn describe_devices() -> Result<Vec<(CcidDeviceInfo, Option<CcidDescriptor>)>, Error> {
+    let context = Context::new().map_err(Error::Usb)?;
+    let mut devices = Vec::new();
 
-    if !is_ccid_device_descriptor(&desc, device)? {
-        return Err(Error::NotCcidDevice);
+    for device in context.devices().map_err(Error::Usb)?.iter() {
+        if let Ok(info) = get_device_info(&device)
+            && (info.is_ccid || info.is_coinkite)
+        {
+            devices.push((info, ccid_descriptor(&device)));
+        }
     }
 
-    let handle = device.open().map_err(Error::Usb)?;
+    Ok(devices)
+}
 
-    let manufacturer = read_string_descriptor(&handle, &desc, desc.manufacturer_string_index());
-    let product = read_string_descriptor(&handle, &desc, desc.product_string_index());
-    let serial = read_string_descriptor(&handle, &desc, desc.serial_number_string_index());
+/// The CCID class descriptor of the device's smart card interface. It follows the interface
+/// descriptor, though some readers put it after their endpoint descriptors.
+pub fn ccid_descriptor(device: &Device<Context>) -> Option<CcidDescriptor> {
+    let config = device.active_config_descriptor().ok()?;
+    config
+        .interfaces()
+        .flat_map(|interface| interface.descriptors())
+        .filter(|descriptor| descriptor.class_code() == USB_CLASS_SMART_CARD)
+        .find_map(|descriptor| {
+            CcidDescriptor::from_extra(descriptor.extra()).or_else(|| {
+                descriptor
+                    .endpoint_descriptors()
+                    .find_map(|endpoint| CcidDescriptor::from_extra(endpoint.extra()?))
+            })
+        })
+}
 
+/// Get information about a USB device. Devices that can't be opened are still described, without
+/// their strings.
+fn get_device_info(device: &Device<Context>) -> Result<CcidDeviceInfo, Error> {
+    let desc = device.device_descriptor().map_err(Error::Usb)?;
+
+    let is_ccid = is_ccid_device_descriptor(&desc, device)?;
     let is_coinkite = desc.vendor_id() == COINKITE_VENDOR_ID
         || COINKITE_PRODUCTS
             .iter()
             .any(|(pid, _)| desc.product_id() == *pid);
 
-    Ok(CcidDeviceInfo {
+    let mut info = CcidDeviceInfo {
         vendor_id: desc.vendor_id(),
         product_id: desc.product_id(),
-        manufacturer,
-        product,
-        serial,
+        manufacturer: None,
+        product: None,
+        serial: None,
         is_coinkite,
-    })
+        is_ccid,
+        accessible: false,
+        kernel_driver_active: None,
+    };
+
+    let Ok(handle) = device.open() else {
+        return Ok(info);
+    };
+    info.accessible = true;
+    info.manufacturer = read_string_descriptor(&handle, &desc, desc.manufacturer_string_index());
+    info.product = read_string_descriptor(&handle, &desc, desc.product_string_index());
+    info.serial = read_string_descriptor(&handle, &desc, desc.serial_number_string_index());
+    // Windows has no kernel drivers to ask about
+    if !cfg!(windows) {
+        info.kernel_driver_active = ccid_interface(device)
+            .and_then(|interface_num| handle.kernel_driver_active(interface_num).ok());
+    }
+
+    Ok(info)
 }
 
-/// Check if a device is a CCID device
-fn is_ccid_device(device: &Device<Context>) -> Result<bool, Error> {
-    let desc = device.device_descriptor().map_err(Error::Usb)?;
-    is_ccid_device_descriptor(&desc, device)
+/// Number of the device's CCID interface, if it has one
+fn ccid_interface(device: &Device<Context>) -> Option<u8> {
+    let config = device.active_config_descriptor().ok()?;
+    config
+        .interfaces()
+        .find(|interface| {
+            interface
+                .descriptors()
+                .any(|descriptor| descriptor.class_code() == USB_CLASS_SMART_CARD)
+        })
+        .map(|interface| interface.number())
 }
 
 /// Check if a device descriptor indicates a CCID device
@@ -167,9 +522,25 @@ fn is_ccid_device_descriptor(
     Ok(false)
 }
 
-/// Open a CCID device and create a transport
-fn open_ccid_device(device: &Device<Context>) -> Result<UsbTransport, Error> {
-    let handle = device.open().map_err(Error::Usb)?;
+/// Open a CCID device and create a transport with `io_timeout`, locking the reader first unless
+/// `lock_timeout` is `None`
+async fn open_ccid_device(
+    device: &Device<Context>,
+    lock_timeout: Option<Duration>,
+    io_timeout: Duration,
+) -> Result<UsbTransport, Error> {
+    let lock = match lock_timeout {
+        Some(timeout) => {
+            let key = format!(
+                "usb-{bus:03}-{address:03}",
+                bus = device.bus_number(),
+                address = device.address()
+            );
+            Some(ReaderLock::acquire(&key, timeout).await?)
+        }
+        None => None,
+    };
+    let handle = device.open().map_err(usb_error)?;
 
     // Find the CCID interface
     let config = device.active_config_descriptor().map_err(Error::Usb)?;
@@ -179,17 +550,22 @@ fn open_ccid_device(device: &Device<Context>) -> Result<UsbTransport, Error> {
             if descriptor.class_code() == USB_CLASS_SMART_CARD {
                 let interface_num = interface.number();
 
-                // Detach kernel driver if needed (Linux)
-                #[cfg(target_os = "linux")]
-                {
-                    if handle.kernel_driver_active(interface_num).unwrap_or(false) {
-                        handle.detach_kernel_driver(interface_num).ok();
+                // Detach the kernel driver if needed: on Linux, and on macOS where the system's
+                // smart card driver holds the CCID interface of composite devices (capturing it
+                // needs root). Windows has no kernel drivers to detach, the reader needs WinUSB.
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                let detached = handle.kernel_driver_active(interface_num).unwrap_or(false)
+                    && handle.detach_kernel_driver(interface_num).is_ok();
+                #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+                let detached = false;
+
+                if let Err(e) = claim_interface(&handle, interface_num) {
+                    if detached {
+                        handle.attach_kernel_driver(interface_num).ok();
                     }
+                    return Err(e);
                 }
 
-                // Claim the interface
-                handle.claim_interface(interface_num).map_err(Error::Usb)?;
-
                 // Find endpoints
                 let (endpoint_out, endpoint_in) = find_ccid_endpoints(&handle, interface_num)?;
 
@@ -197,12 +573,33 @@ fn open_ccid_device(device: &Device<Context>) -> Result<UsbTransport, Error> {
                     "Opened CCID device on interface {interface_num} (endpoints: out={endpoint_out:#x}, in={endpoint_in:#x})"
                 );
 
-                return Ok(UsbTransport::new(
-                    handle,
-                    interface_num,
-                    endpoint_out,
-                    endpoint_in,
-                ));
+                let descriptor = ccid_descriptor(device);
+                let transport = UsbTransport::new(handle, interface_num, endpoint_out, endpoint_in)
+                    .reattach_kernel_driver(detached)
+                    .with_timeout(io_timeout);
+                let transport = match descriptor {
+                    Some(descriptor) => {
+                        debug!(
+                            "CCID max message length {len}, features {features:#x}",
+                            len = descriptor.max_message_length,
+                            features = descriptor.features
+                        );
+                        transport.with_max_message_length(descriptor.max_message_length)
+                    }
+                    None => transport,
+                };
+                let transport = match lock {
+                    Some(lock) => transport.with_lock(lock),
+                    None => transport,
+                };
+                let desc = device.device_descriptor().map_err(Error::Usb)?;
+                if !acr122u::is_acr122u(desc.vendor_id(), desc.product_id()) {
+                    return Ok(transport);
+                }
+                info!("ACR122U, exchanging APDUs through its PN532");
+                let transport = transport.with_framing(Framing::Acr122u);
+                acr122u::init(&transport)?;
+                return Ok(transport);
             }
         }
     }
@@ -210,6 +607,58 @@ fn open_ccid_device(device: &Device<Context>) -> Result<UsbTransport, Error> {
     Err(Error::Ccid("No CCID interface found".to_string()))
 }
 
+/// Claim the CCID interface, telling a reader held by pcscd apart from other failures
+fn claim_interface(handle: &DeviceHandle<Context>, interface_num: u8) -> Result<(), Error> {
+    match handle.claim_interface(interface_num) {
+        Err(rusb::Error::Busy) if pcscd_running() == Some(true) => Err(Error::PcscdConflict),
+        result => result.map_err(usb_error),
+    }
+}
+
+/// What to do about a reader that can't be opened or claimed, for the errors with a likely
+/// platform-specific cause
+fn platform_hint(error: rusb::Error) -> Option<&'static str> {
+    match error {
+        rusb::Error::Access if cfg!(target_os = "linux") => Some(
+            "no permission to use the reader, install udev rules giving your user access to it \
+             or run as root",
+        ),
+        rusb::Error::Access | rusb::Error::Busy if cfg!(target_os = "macos") => Some(
+            "the reader is held by the macOS smart card driver, run as root so it can be \
+             captured or use the reader through PC/SC",
+        ),
+        rusb::Error::Access | rusb::Error::NotSupported | rusb::Error::NotFound
+            if cfg!(windows) =>
+        {
+            Some(
+                "the reader isn't bound to the WinUSB driver, install it for the reader (e.g. \
+                 with Zadig) to use it directly or use the reader through PC/SC",
+            )
+        }
+        _ => None,
+    }
+}
+
+/// [`Error::UsbAccess`] with a hint for the platform's usual cause of `error`, if it has one
+fn usb_error(error: rusb::Error) -> Error {
+    match platform_hint(error) {
+        Some(hint) => Error::UsbAccess(format!("{error}, {hint}")),
+        None => Error::Usb(error),
+    }
+}
+
+/// Whether the pcscd daemon is running, `None` where that can't be checked
+pub fn pcscd_running() -> Option<bool> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let processes = std::fs::read_dir("/proc").ok()?;
+    Some(processes.flatten().any(|process| {
+        std::fs::read_to_string(process.path().join("comm"))
+            .is_ok_and(|comm| comm.trim() == "pcscd")
+    }))
+}
+
 /// Read a string descriptor from a device
 fn read_string_descriptor(
     handle: &DeviceHandle<Context>,
@@ -226,6 +675,18 @@ fn read_string_descriptor(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_usb_error() {
+        assert!(matches!(
+            usb_error(rusb::Error::Timeout),
+            Error::Usb(rusb::Error::Timeout)
+        ));
+        let access = usb_error(rusb::Error::Access);
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            assert!(matches!(access, Error::UsbAccess(_)));
+        }
+    }
+
     #[test]
     fn test_coinkite_detection() {
         // Test known Coinkite vendor ID
@@ -238,4 +699,88 @@ mod tests {
                 .any(|(pid, name)| { *pid == 0xCC10 && *name == "TAPSIGNER" })
         );
     }
+
+    fn device(vendor_id: u16, product_id: u16, is_coinkite: bool) -> CcidDeviceInfo {
+        CcidDeviceInfo {
+            vendor_id,
+            product_id,
+            manufacturer: None,
+            product: None,
+            serial: None,
+            is_coinkite,
+            is_ccid: true,
+            accessible: true,
+            kernel_driver_active: None,
+        }
+    }
+
+    #[test]
+    fn test_discovery_rank() {
+        let tapsigner = device(COINKITE_VENDOR_ID, 0xCC10, true);
+        let omnikey = device(OMNIKEY_VENDOR_ID, 0x5422, false);
+        let yubikey = device(YUBICO_VENDOR_ID, 0x0407, false);
+        let acs = device(0x072F, 0x2200, false);
+
+        let default = DiscoveryBuilder::default();
+        assert_eq!(default.rank(&tapsigner), Some(0));
+        assert_eq!(default.rank(&omnikey), Some(1));
+        assert_eq!(default.rank(&acs), Some(2));
+        assert_eq!(default.rank(&yubikey), None);
+
+        let custom = DiscoveryBuilder::new()
+            .without_rules()
+            .prefer(UsbMatch::product(0x072F, 0x2200))
+            .skip(UsbMatch::vendor(OMNIKEY_VENDOR_ID))
+            .any_ccid(false);
+        assert_eq!(custom.rank(&tapsigner), Some(0));
+        assert_eq!(custom.rank(&acs), Some(1));
+        assert_eq!(custom.rank(&omnikey), None);
+        assert_eq!(custom.rank(&yubikey), None);
+
+        let not_ccid = CcidDeviceInfo {
+            is_ccid: false,
+            ..device(COINKITE_VENDOR_ID, 0x0100, true)
+        };
+        assert_eq!(default.rank(&not_ccid), None);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_retry() {
+        let interval = Duration::from_millis(1);
+        let mut attempts = 0;
+        let waits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = waits.clone();
+        let discovery = DiscoveryBuilder::default()
+            .retry(Duration::from_secs(5), interval)
+            .on_waiting(move |e| {
+                assert!(matches!(e, Error::NoCardOnReader));
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            });
+        let found = discovery
+            .with_retry(|| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    match attempt {
+                        3 => Ok(attempt),
+                        _ => Err(Error::NoCardOnReader),
+                    }
+                }
+            })
+            .await;
+        assert!(matches!(found, Ok(3)));
+        // told once, not on every attempt
+        assert_eq!(waits.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let discovery = DiscoveryBuilder::default().retry(Duration::ZERO, interval);
+        let mut attempts = 0;
+        let found: Result<(), Error> = discovery
+            .with_retry(|| {
+                attempts += 1;
+                async { Err(Error::DeviceNotFound) }
+            })
+            .await;
+        assert!(matches!(found, Err(Error::DeviceNotFound)));
+        assert_eq!(attempts, 1);
+    }
 }
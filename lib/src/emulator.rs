@@ -3,22 +3,136 @@ use crate::apdu::{AppletSelect, CommandApdu, Error, StatusCommand};
 use crate::commands::CkTransport;
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 use std::string::ToString;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 pub const CVC: &str = "123456";
 
+/// Pipe the emulator listens on when started by hand (`ecard.py emulate`)
+const DEFAULT_PIPE: &str = "/tmp/ecard-pipe";
+
+/// Emulator started by [`Manager::spawn`] unless `CKTAP_EMULATOR_CMD` names another one
+const DEFAULT_EMULATOR: &str = "ecard.py";
+
+/// How long [`Manager`] waits for a spawned emulator to create its pipe
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub async fn find_emulator() -> Result<CkTapCard<CardEmulator>, Error> {
-    let pipe_path = Path::new("/tmp/ecard-pipe");
+    connect_emulator(Path::new(DEFAULT_PIPE)).await
+}
+
+/// Connect to an emulator listening on `pipe_path`
+pub async fn connect_emulator(pipe_path: &Path) -> Result<CkTapCard<CardEmulator>, Error> {
     if !pipe_path.exists() {
         return Err(Error::Emulator("Emulator pipe doesn't exist.".to_string()));
     }
-    let stream = UnixStream::connect("/tmp/ecard-pipe")
+    let stream = UnixStream::connect(pipe_path)
         .map_err(|e| Error::Emulator(format!("Failed to connect to emulator pipe: {e}")))?;
     let card_emulator = CardEmulator { stream };
     card_emulator.to_cktap().await
 }
 
+/// Card type for the emulator to pretend to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatedCard {
+    SatsCard,
+    /// An uninitialized TAPSIGNER
+    TapSigner,
+}
+
+impl EmulatedCard {
+    fn args(&self) -> &'static [&'static str] {
+        match self {
+            EmulatedCard::SatsCard => &["-s"],
+            EmulatedCard::TapSigner => &["-t", "--no-init"],
+        }
+    }
+}
+
+/// Runs an emulator for the duration of a test, on its own pipe so tests don't depend on an
+/// emulator started by hand and can run side by side. The emulator is stopped when dropped.
+#[derive(Debug)]
+pub struct Manager {
+    child: Child,
+    pipe_path: PathBuf,
+}
+
+impl Manager {
+    /// Start the reference Python emulator (`ecard.py` from `PATH`, or the `CKTAP_EMULATOR_CMD`
+    /// command) emulating `card`, and wait until it accepts connections
+    pub async fn spawn(card: EmulatedCard) -> Result<Self, Error> {
+        let program =
+            std::env::var_os("CKTAP_EMULATOR_CMD").unwrap_or_else(|| DEFAULT_EMULATOR.into());
+        let mut command = Command::new(program);
+        command.arg("emulate").args(card.args());
+        Self::spawn_command(command).await
+    }
+
+    /// Start any emulator speaking the `ecard.py` pipe protocol. `--pipe <path>` with a fresh
+    /// socket path is appended to `command`.
+    pub async fn spawn_command(mut command: Command) -> Result<Self, Error> {
+        static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+        let pipe_path = std::env::temp_dir().join(format!(
+            "ecard-pipe-{pid}-{n}",
+            pid = std::process::id(),
+            n = SPAWNED.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let child = command
+            .arg("--pipe")
+            .arg(&pipe_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| Error::Emulator(format!("Failed to start emulator: {e}")))?;
+        let mut manager = Self { child, pipe_path };
+        manager.wait_ready(READY_TIMEOUT).await?;
+        Ok(manager)
+    }
+
+    async fn wait_ready(&mut self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        while !self.pipe_path.exists() {
+            let exited = self
+                .child
+                .try_wait()
+                .map_err(|e| Error::Emulator(e.to_string()))?;
+            if let Some(status) = exited {
+                return Err(Error::Emulator(format!(
+                    "Emulator exited before it was ready: {status}"
+                )));
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Emulator(format!(
+                    "Emulator not ready after {timeout:?}"
+                )));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(())
+    }
+
+    /// Connect to the emulated card
+    pub async fn connect(&self) -> Result<CkTapCard<CardEmulator>, Error> {
+        connect_emulator(&self.pipe_path).await
+    }
+
+    pub fn pipe_path(&self) -> &Path {
+        &self.pipe_path
+    }
+}
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.pipe_path);
+    }
+}
+
 #[derive(Debug)]
 pub struct CardEmulator {
     stream: UnixStream,
@@ -27,9 +141,9 @@ pub struct CardEmulator {
 impl CkTransport for CardEmulator {
     async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
         // convert select_apdu into StatusCommand apdu bytes
-        let select_apdu: Vec<u8> = AppletSelect::default().apdu_bytes();
+        let select_apdu: Vec<u8> = AppletSelect::default().apdu_bytes()?;
         let command_apdu = if command_apdu.eq(&select_apdu) {
-            StatusCommand::default().apdu_bytes()
+            StatusCommand::default().apdu_bytes()?
         } else {
             command_apdu
         };
@@ -40,25 +154,39 @@ impl CkTransport for CardEmulator {
             .map_err(|e| Error::Emulator(format!("Failed to clone unix stream: {e}")))?;
 
         // trim first 5 bytes from command apdu bytes to get the cbor data
+        let cbor = command_apdu
+            .get(5..)
+            .ok_or_else(|| Error::Emulator("APDU too short".to_string()))?;
         stream
-            .write_all(&command_apdu.as_slice()[5..])
+            .write_all(cbor)
             .map_err(|e| Error::Emulator(e.to_string()))?;
         let mut buffer = [0; 4096];
         // read up to 4096 bytes
-        stream
+        let len = stream
             .read(&mut buffer[..])
             .map_err(|e| Error::Emulator(e.to_string()))?;
-        Ok(buffer.to_vec())
+        // the emulator answers with the CBOR only, add the status word a card would send
+        let mut response = buffer[..len].to_vec();
+        response.extend([0x90, 0x00]);
+        Ok(response)
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::emulator::find_emulator;
+    use crate::CkTapCard;
+    use crate::emulator::{EmulatedCard, Manager};
 
     #[tokio::test]
     pub async fn test_transmit() {
-        let emulator = find_emulator().await.expect("Failed to find emulator");
-        dbg!(emulator);
+        let manager = Manager::spawn(EmulatedCard::TapSigner)
+            .await
+            .expect("Failed to start emulator");
+        let emulator = manager.connect().await.expect("Failed to find emulator");
+        assert!(matches!(emulator, CkTapCard::TapSigner(_)));
+
+        let pipe_path = manager.pipe_path().to_path_buf();
+        drop(manager);
+        assert!(!pipe_path.exists());
     }
 }
@@ -0,0 +1,70 @@
+use bitcoin::key::rand::{RngCore as _, thread_rng};
+use bitcoin::secp256k1::SecretKey;
+
+/// Source of the app nonces and ephemeral keys the card types send with their commands.
+///
+/// Defaults to [`ThreadRngSource`]; hardened deployments can plug in their own RNG (e.g. a
+/// hardware one) and tests a seeded one to get reproducible commands.
+pub trait EntropySource: Send + Sync {
+    /// Fill `dest` with random bytes
+    fn fill_bytes(&self, dest: &mut [u8]);
+
+    /// Nonce for commands that make the card sign a fresh message (`read`, `derive`, `check`)
+    fn nonce(&self) -> [u8; 16] {
+        let mut nonce = [0u8; 16];
+        self.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    /// Ephemeral private key used to encrypt the CVC (and the card's answer) for one command
+    fn secret_key(&self) -> SecretKey {
+        loop {
+            let mut bytes = [0u8; 32];
+            self.fill_bytes(&mut bytes);
+            // all but a negligible fraction of 32 byte values are valid keys
+            if let Ok(key) = SecretKey::from_slice(&bytes) {
+                return key;
+            }
+        }
+    }
+}
+
+/// The thread local RNG seeded from the OS, used unless a card is given another source
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadRngSource;
+
+impl EntropySource for ThreadRngSource {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        thread_rng().fill_bytes(dest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Counts up from a seed, as a deterministic source for tests
+    struct Counter(Mutex<u8>);
+
+    impl EntropySource for Counter {
+        fn fill_bytes(&self, dest: &mut [u8]) {
+            let mut next = self.0.lock().expect("not poisoned");
+            for byte in dest {
+                *byte = *next;
+                *next = next.wrapping_add(1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_entropy_source() {
+        let source = Counter(Mutex::new(1));
+        assert_eq!(source.nonce(), core::array::from_fn(|i| i as u8 + 1));
+        assert_eq!(
+            source.secret_key().secret_bytes(),
+            core::array::from_fn(|i| i as u8 + 17)
+        );
+        assert_ne!(ThreadRngSource.nonce(), ThreadRngSource.nonce());
+    }
+}
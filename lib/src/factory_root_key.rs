@@ -1,9 +1,10 @@
 use crate::apdu::Error;
+use alloc::string::{String, ToString};
 use bitcoin::secp256k1::PublicKey;
 use bitcoin::secp256k1::hashes::hex::DisplayHex;
-use std::convert::TryFrom;
-use std::fmt;
-use std::fmt::Debug;
+use core::convert::TryFrom;
+use core::fmt;
+use core::fmt::Debug;
 
 /// Published Coinkite factory root keys.
 const PUB_FACTORY_ROOT_KEY: &str =
@@ -12,6 +13,7 @@ const PUB_FACTORY_ROOT_KEY: &str =
 const DEV_FACTORY_ROOT_KEY: &str =
     "027722ef208e681bac05f1b4b3cc478d6bf353ac9a09ff0c843430138f65c27bab";
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum FactoryRootKey {
     Pub(PublicKey),
     Dev(PublicKey),
@@ -32,6 +34,12 @@ impl TryFrom<PublicKey> for FactoryRootKey {
 }
 
 impl FactoryRootKey {
+    pub fn pubkey(&self) -> &PublicKey {
+        match self {
+            FactoryRootKey::Pub(pk) | FactoryRootKey::Dev(pk) => pk,
+        }
+    }
+
     pub fn name(&self) -> String {
         match &self {
             FactoryRootKey::Pub(_) => "Root Factory Certificate".to_string(),
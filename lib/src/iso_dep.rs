@@ -0,0 +1,142 @@
+//! Plugging in an NFC driver: [`IsoDepChannel`] is all a driver has to provide, a blocking
+//! exchange of APDUs with an activated ISO-DEP (ISO 14443-4) card. Like the rest of the
+//! protocol core it builds without `std`, so firmware can [`transceive`] commands directly;
+//! with `std`, [`IsoDepTransport`] turns a channel into a [`CkTransport`](crate::commands::CkTransport)
+//! for the card types.
+
+use crate::apdu::{CommandApdu, Error, ParseMode, ResponseApdu};
+use crate::status_word::ResponseReader;
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display};
+
+/// A blocking APDU exchange with a card the driver already activated
+pub trait IsoDepChannel {
+    type Error: Display;
+
+    /// Send a command APDU and return the card's response APDU, status word included
+    fn transceive(&mut self, command_apdu: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Send `command` over `channel` and decode the card's response
+pub fn transceive<Ch, C, R>(channel: &mut Ch, command: &C) -> Result<R, Error>
+where
+    Ch: IsoDepChannel,
+    C: CommandApdu + serde::Serialize + Debug,
+    R: ResponseApdu + serde::de::DeserializeOwned + Debug,
+{
+    let command_apdu = command.apdu_bytes()?;
+    let mut reader = ResponseReader::new(&command_apdu);
+    let mut rapdu = channel.transceive(&command_apdu).map_err(channel_error)?;
+    while let Some(next) = reader.push(&rapdu)? {
+        rapdu = channel.transceive(&next).map_err(channel_error)?;
+    }
+    R::from_cbor(reader.into_body(), ParseMode::Lenient)
+}
+
+fn channel_error(e: impl Display) -> Error {
+    Error::Ccid(e.to_string())
+}
+
+#[cfg(feature = "std")]
+pub use transport::IsoDepTransport;
+
+#[cfg(feature = "std")]
+mod transport {
+    use super::{IsoDepChannel, channel_error};
+    use crate::apdu::Error;
+    use crate::commands::CkTransport;
+    use std::sync::Mutex;
+
+    /// [`CkTransport`] over an [`IsoDepChannel`]. Exchanges block the calling task like the USB
+    /// transport does, no async runtime is involved.
+    pub struct IsoDepTransport<C: IsoDepChannel> {
+        channel: Mutex<C>,
+    }
+
+    impl<C: IsoDepChannel> IsoDepTransport<C> {
+        pub fn new(channel: C) -> Self {
+            Self {
+                channel: Mutex::new(channel),
+            }
+        }
+
+        /// Give the channel back, e.g. to release the driver
+        pub fn into_inner(self) -> C {
+            self.channel
+                .into_inner()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+    }
+
+    impl<C: IsoDepChannel> CkTransport for IsoDepTransport<C> {
+        async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+            let mut channel = self
+                .channel
+                .lock()
+                .map_err(|_| Error::Ccid("ISO-DEP channel poisoned".to_string()))?;
+            channel.transceive(&command_apdu).map_err(channel_error)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CkTapCard;
+    use crate::apdu::{StatusCommand, StatusResponse};
+    use crate::commands::CkTransport;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use ciborium::value::Value;
+
+    /// Driver of a TapSigner that only answers `status`
+    struct StatusChannel;
+
+    impl IsoDepChannel for StatusChannel {
+        type Error = &'static str;
+
+        fn transceive(&mut self, command_apdu: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            if !command_apdu.starts_with(&[0x00, 0xA4]) && !command_apdu.ends_with(b"status") {
+                return Err("unexpected command");
+            }
+            let secret_key = SecretKey::from_slice(&[1u8; 32]).expect("valid secret key");
+            let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+            let status = Value::Map(vec![
+                (Value::from("proto"), Value::from(1)),
+                (Value::from("ver"), Value::from("1.0.3")),
+                (Value::from("birth"), Value::from(700_000)),
+                (
+                    Value::from("pubkey"),
+                    Value::Bytes(pubkey.serialize().to_vec()),
+                ),
+                (Value::from("card_nonce"), Value::Bytes(vec![7u8; 16])),
+                (Value::from("tapsigner"), Value::from(true)),
+            ]);
+            let mut cbor = Vec::new();
+            ciborium::ser::into_writer(&status, &mut cbor).map_err(|_| "CBOR")?;
+            cbor.extend([0x90, 0x00]);
+            Ok(cbor)
+        }
+    }
+
+    #[test]
+    fn test_transceive() {
+        let status: StatusResponse =
+            transceive(&mut StatusChannel, &StatusCommand::default()).unwrap();
+        assert_eq!(status.card_nonce, [7u8; 16]);
+
+        let error = StatusChannel
+            .transceive(&[0x00, 0xCB])
+            .map_err(channel_error);
+        assert!(matches!(error, Err(Error::Ccid(message)) if message == "unexpected command"));
+    }
+
+    #[tokio::test]
+    async fn test_iso_dep_transport() -> Result<(), Error> {
+        let card = IsoDepTransport::new(StatusChannel).to_cktap().await?;
+        assert!(matches!(card, CkTapCard::TapSigner(_)));
+        Ok(())
+    }
+}
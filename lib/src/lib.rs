@@ -1,35 +1,155 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 extern crate core;
 
+#[cfg(feature = "std")]
+use bitcoin::hashes::{Hash as _, sha256};
+#[cfg(feature = "std")]
 use bitcoin::key::rand::Rng as _;
-use commands::CkTransport;
+#[cfg(feature = "std")]
+use commands::{Authentication as _, Certificate as _, CkTransport};
+#[cfg(feature = "std")]
+use entropy::EntropySource as _;
+#[cfg(feature = "std")]
+use factory_root_key::FactoryRootKey;
+#[cfg(feature = "std")]
+use serde::Serialize;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
+// protocol core, also built without `std`
 pub mod apdu;
+pub mod cvc;
+pub mod factory_root_key;
+pub mod iso_dep;
+pub mod protocol;
+pub mod status_word;
+pub mod version;
+
+pub use bitcoin::secp256k1;
+
+// cards and transports
+#[cfg(feature = "usb")]
+pub mod acr122u;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backup_policy;
+#[cfg(feature = "std")]
 pub mod ccid;
+#[cfg(feature = "std")]
+pub mod challenge;
+#[cfg(feature = "std")]
 pub mod commands;
+#[cfg(feature = "usb")]
 pub mod discovery;
-pub mod factory_root_key;
+#[cfg(feature = "std")]
+pub mod entropy;
+#[cfg(any(feature = "usb", feature = "pn532"))]
+pub mod pn532;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod psbt;
+#[cfg(feature = "usb")]
+pub mod reader_lock;
+#[cfg(feature = "std")]
+pub mod transcript;
+#[cfg(feature = "usb")]
 pub mod usb_transport;
 
-pub use bitcoin::secp256k1::{self, rand};
+#[cfg(feature = "std")]
+pub use bitcoin::secp256k1::rand;
 
 #[cfg(feature = "emulator")]
 pub mod emulator;
 
+#[cfg(feature = "managed")]
+pub mod managed;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "std")]
 pub mod sats_card;
+#[cfg(feature = "std")]
+pub mod slot_url;
+#[cfg(feature = "std")]
 pub mod tap_signer;
 
+#[cfg(all(test, feature = "std"))]
+mod test_util;
+
+#[cfg(feature = "std")]
 pub type TapSigner<T> = tap_signer::TapSigner<T>;
+#[cfg(feature = "std")]
 pub type SatsCard<T> = sats_card::SatsCard<T>;
 
+#[cfg(feature = "std")]
 pub enum CkTapCard<T: CkTransport> {
     SatsCard(SatsCard<T>),
     TapSigner(TapSigner<T>),
     SatsChip(TapSigner<T>),
 }
 
+/// What a card last reported in its status, whatever its type, see [`CkTapCard::status_summary`]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusSummary {
+    /// "satscard", "tapsigner" or "satschip"
+    pub card_type: &'static str,
+    pub ident: String,
+    pub proto: usize,
+    /// Applet version, e.g. "1.0.3"
+    pub version: String,
+    pub birth: usize,
+    /// (SATSCARD only) current slot and number of slots
+    pub slots: Option<(u8, u8)>,
+    /// (SATSCARD only) the card makes testnet addresses
+    pub testnet: bool,
+    /// (TAPSIGNER and SATSCHIP only) derivation path, hardened steps with the high bit set
+    pub path: Option<Vec<usize>>,
+    /// (TAPSIGNER and SATSCHIP only) number of backups taken
+    pub num_backups: Option<usize>,
+    /// Seconds to wait before the next authenticated command
+    pub auth_delay: Option<usize>,
+}
+
+/// One line like "TAPSIGNER CARD-0279BE66, applet 1.0.3, path m/84'/0'/0', 1 backup"
+#[cfg(feature = "std")]
+impl core::fmt::Display for StatusSummary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{card_type} {ident}, applet {version}",
+            card_type = self.card_type.to_uppercase(),
+            ident = self.ident,
+            version = self.version
+        )?;
+        if let Some((current, total)) = self.slots {
+            write!(f, ", slot {current} of {total}")?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, ", path {path}", path = apdu::DisplayPath(path))?;
+        }
+        match self.num_backups {
+            Some(1) => f.write_str(", 1 backup")?,
+            Some(num_backups) => write!(f, ", {num_backups} backups")?,
+            None => {}
+        }
+        if let Some(auth_delay) = self.auth_delay.filter(|&delay| delay > 0) {
+            write!(f, ", {auth_delay}s auth delay")?;
+        }
+        Ok(())
+    }
+}
+
 // re-export
 pub use apdu::Error;
+pub use cvc::Cvc;
 
+#[cfg(feature = "std")]
 impl<T: CkTransport> core::fmt::Debug for CkTapCard<T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match &self {
@@ -46,19 +166,117 @@ impl<T: CkTransport> core::fmt::Debug for CkTapCard<T> {
     }
 }
 
+/// The card's [`StatusSummary`] on one line
+#[cfg(feature = "std")]
+impl<T: CkTransport> core::fmt::Display for CkTapCard<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.status_summary().fmt(f)
+    }
+}
+
+/// Serialized as its [`StatusSummary`]
+#[cfg(feature = "std")]
+impl<T: CkTransport> Serialize for CkTapCard<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.status_summary().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: CkTransport> CkTapCard<T> {
+    /// The same card over another transport, see [`TapSigner::map_transport`]
+    pub fn map_transport<U: CkTransport>(self, f: impl FnOnce(T) -> U) -> CkTapCard<U> {
+        match self {
+            CkTapCard::SatsCard(sc) => CkTapCard::SatsCard(sc.map_transport(f)),
+            CkTapCard::TapSigner(ts) => CkTapCard::TapSigner(ts.map_transport(f)),
+            CkTapCard::SatsChip(ts) => CkTapCard::SatsChip(ts.map_transport(f)),
+        }
+    }
+
+    /// The card's short identifier, see [`protocol::card_ident`]
+    pub fn ident(&self) -> String {
+        match self {
+            CkTapCard::SatsCard(sc) => sc.ident(),
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => ts.ident(),
+        }
+    }
+
+    /// Applet version as the card reports it, e.g. "1.0.3"
+    pub fn version(&self) -> &str {
+        match self {
+            CkTapCard::SatsCard(sc) => &sc.ver,
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => &ts.ver,
+        }
+    }
+
+    /// Check the card is genuine, see [`commands::Certificate::check_certificate`]
+    pub async fn check_certificate(&mut self) -> Result<FactoryRootKey, Error> {
+        match self {
+            CkTapCard::SatsCard(sc) => sc.check_certificate().await,
+            CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => ts.check_certificate().await,
+        }
+    }
+
+    /// What the card last reported in its status, without talking to it
+    pub fn status_summary(&self) -> StatusSummary {
+        match self {
+            CkTapCard::SatsCard(sc) => sc.status_summary(),
+            CkTapCard::TapSigner(ts) => ts.status_summary(),
+            CkTapCard::SatsChip(ts) => StatusSummary {
+                card_type: "satschip",
+                ..ts.status_summary()
+            },
+        }
+    }
+
+    /// Wait up to `timeout` for the card to be put back after [`Error::CardRemoved`], checking
+    /// every `interval`, then select the applet again and re-sync the nonce so the session can
+    /// go on. Fails with [`Error::CardRemoved`] if the same card isn't back in time.
+    ///
+    /// `sleep` waits out each `interval`, e.g. `tokio::time::sleep`, so the library doesn't pick
+    /// the async runtime.
+    pub async fn reconnect<S: Future<Output = ()>>(
+        &mut self,
+        timeout: Duration,
+        interval: Duration,
+        sleep: impl Fn(Duration) -> S,
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let resynced = match self {
+                CkTapCard::SatsCard(sc) => commands::resync(sc).await,
+                CkTapCard::TapSigner(ts) | CkTapCard::SatsChip(ts) => commands::resync(ts).await,
+            };
+            match resynced {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() + interval > deadline => return Err(e),
+                Err(e) => log::debug!("Card not back yet: {e}"),
+            }
+            sleep(interval).await;
+        }
+    }
+}
+
 // utility functions
 
+#[cfg(feature = "std")]
 pub fn rand_chaincode(rng: &mut rand::rngs::ThreadRng) -> [u8; 32] {
     let mut chain_code = [0u8; 32];
     rng.fill(&mut chain_code);
     chain_code
 }
 
+/// Mix user supplied entropy (e.g. dice rolls) with OS randomness into a chain code, so that
+/// neither a weak RNG nor poorly chosen user entropy alone determines the result.
+#[cfg(feature = "std")]
+pub fn mix_chaincode(rng: &mut rand::rngs::ThreadRng, user_entropy: &[u8]) -> [u8; 32] {
+    let os_entropy = rand_chaincode(rng);
+    sha256::Hash::hash(&[os_entropy.as_slice(), user_entropy].concat()).to_byte_array()
+}
+
+#[cfg(feature = "std")]
 pub fn rand_nonce() -> [u8; 16] {
-    let rng = &mut rand::thread_rng();
-    let mut nonce = [0u8; 16];
-    rng.fill(&mut nonce);
-    nonce
+    entropy::ThreadRngSource.nonce()
 }
 
 // Errors
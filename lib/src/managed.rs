@@ -0,0 +1,71 @@
+//! Share one card connection between tasks and threads, e.g. a GUI and its background jobs.
+
+use crate::CkTapCard;
+use crate::commands::CkTransport;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
+
+/// A [`CkTapCard`] behind an async mutex. Clones are handles to the same card; a task holding
+/// the guard from [`ManagedCard::lock`] runs its commands without any from other handles in
+/// between, which keeps the card nonce in sync. The mutex doesn't need a tokio runtime.
+pub struct ManagedCard<T: CkTransport> {
+    card: Arc<Mutex<CkTapCard<T>>>,
+}
+
+impl<T: CkTransport> ManagedCard<T> {
+    pub fn new(card: CkTapCard<T>) -> Self {
+        Self {
+            card: Arc::new(Mutex::new(card)),
+        }
+    }
+
+    /// Wait until no other handle uses the card, then use it until the guard is dropped
+    pub async fn lock(&self) -> MutexGuard<'_, CkTapCard<T>> {
+        self.card.lock().await
+    }
+
+    /// Like [`ManagedCard::lock`], for a guard to move into a spawned task
+    pub async fn lock_owned(&self) -> OwnedMutexGuard<CkTapCard<T>> {
+        Arc::clone(&self.card).lock_owned().await
+    }
+
+    /// The card, unless another handle is using it right now
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, CkTapCard<T>>> {
+        self.card.try_lock().ok()
+    }
+
+    /// Number of handles to the card, this one included
+    pub fn handles(&self) -> usize {
+        Arc::strong_count(&self.card)
+    }
+
+    /// Take the card back, fails with the handle while other handles are left
+    pub fn into_inner(self) -> Result<CkTapCard<T>, Self> {
+        Arc::try_unwrap(self.card)
+            .map(Mutex::into_inner)
+            .map_err(|card| Self { card })
+    }
+}
+
+impl<T: CkTransport> Clone for ManagedCard<T> {
+    fn clone(&self) -> Self {
+        Self {
+            card: Arc::clone(&self.card),
+        }
+    }
+}
+
+impl<T: CkTransport> From<CkTapCard<T>> for ManagedCard<T> {
+    fn from(card: CkTapCard<T>) -> Self {
+        Self::new(card)
+    }
+}
+
+impl<T: CkTransport> core::fmt::Debug for ManagedCard<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.card.try_lock() {
+            Ok(card) => f.debug_tuple("ManagedCard").field(&*card).finish(),
+            Err(_) => f.write_str("ManagedCard(<in use>)"),
+        }
+    }
+}
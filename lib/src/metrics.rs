@@ -0,0 +1,168 @@
+//! Command metrics, with the `metrics` feature.
+//!
+//! The library doesn't depend on the `metrics` crate: [`MetricsRecorder`] is its own trait, for
+//! the application to forward to whatever it monitors with.
+//!
+//! Every command sent through [`CkTransport::transmit`](crate::commands::CkTransport::transmit)
+//! is reported to the installed [`MetricsRecorder`]: when it is sent, the APDU round-trip
+//! latency, and failures labelled by [`error_label`]. Server deployments forward these to their
+//! monitoring, e.g. with the `metrics` crate:
+//!
+//! ```ignore
+//! struct Facade;
+//!
+//! impl MetricsRecorder for Facade {
+//!     fn command_sent(&self, command: &'static str) {
+//!         metrics::counter!("cktap_commands_total", "command" => command).increment(1);
+//!     }
+//!     fn command_failed(&self, command: &'static str, error: &Error) {
+//!         let code = error_label(error);
+//!         metrics::counter!("cktap_failures_total", "command" => command, "code" => code)
+//!             .increment(1);
+//!     }
+//!     fn round_trip(&self, command: &'static str, latency: Duration) {
+//!         metrics::histogram!("cktap_round_trip_seconds", "command" => command).record(latency);
+//!     }
+//! }
+//!
+//! cktap_direct::metrics::set_recorder(Facade)?;
+//! ```
+
+use crate::apdu::Error;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Receives command metrics, all methods default to doing nothing
+pub trait MetricsRecorder: Send + Sync {
+    /// A command is about to be sent to the card
+    fn command_sent(&self, _command: &'static str) {}
+
+    /// A command failed, in the transport or with an error from the card
+    fn command_failed(&self, _command: &'static str, _error: &Error) {}
+
+    /// Time from sending the command APDU to receiving the response APDU
+    fn round_trip(&self, _command: &'static str, _latency: Duration) {}
+}
+
+static RECORDER: OnceLock<Box<dyn MetricsRecorder>> = OnceLock::new();
+
+/// Install the recorder for this process, fails if one was already installed
+pub fn set_recorder(recorder: impl MetricsRecorder + 'static) -> Result<(), Error> {
+    RECORDER
+        .set(Box::new(recorder))
+        .map_err(|_| Error::Metrics("a metrics recorder is already installed".to_string()))
+}
+
+/// Low cardinality label for an error: the card's error code (e.g. `401`) for errors reported
+/// by the card, otherwise the kind of failure
+pub fn error_label(error: &Error) -> String {
+    match error {
+        Error::CkTap(e) => e.error_code().to_string(),
+        Error::CiborDe(_) | Error::CiborValue(_) => "cbor".to_string(),
+        Error::IncorrectSignature(_) | Error::SignatureMismatch(_) => "signature".to_string(),
+        Error::UnknownCardType(_) => "unknown_card".to_string(),
+        Error::WrongCard { .. } => "wrong_card".to_string(),
+        Error::InvalidUrl(_) => "url".to_string(),
+        Error::BackupRequired(_) => "backup".to_string(),
+        Error::AddressMismatch(_) => "address".to_string(),
+        Error::Bip32(_) => "bip32".to_string(),
+        Error::UnexpectedNonce(_) => "nonce".to_string(),
+        Error::StatusWord(_) => "status_word".to_string(),
+        #[cfg(feature = "usb")]
+        Error::Usb(_) | Error::UsbAccess(_) => "usb".to_string(),
+        #[cfg(feature = "usb")]
+        Error::PcscdConflict => "pcscd".to_string(),
+        #[cfg(feature = "usb")]
+        Error::ReaderLocked(_) => "locked".to_string(),
+        Error::DeviceNotFound | Error::NotCcidDevice => "usb".to_string(),
+        Error::Ccid(_) => "ccid".to_string(),
+        #[cfg(any(feature = "usb", feature = "pn532"))]
+        Error::Pn532(_) => "pn532".to_string(),
+        Error::CardRemoved => "removed".to_string(),
+        Error::NoCardOnReader => "no_card".to_string(),
+        Error::UnsupportedProtocol { .. } | Error::UnsupportedByFirmware { .. } => {
+            "unsupported".to_string()
+        }
+        Error::InvalidVersion(_) => "version".to_string(),
+        #[cfg(feature = "emulator")]
+        Error::Emulator(_) => "emulator".to_string(),
+        Error::Metrics(_) => "metrics".to_string(),
+        Error::Audit(_) => "audit".to_string(),
+    }
+}
+
+/// The applet select has no command name
+fn command_name(command: &'static str) -> &'static str {
+    if command.is_empty() {
+        "select"
+    } else {
+        command
+    }
+}
+
+pub(crate) fn command_sent(command: &'static str) {
+    if let Some(recorder) = RECORDER.get() {
+        recorder.command_sent(command_name(command));
+    }
+}
+
+pub(crate) fn command_failed(command: &'static str, error: &Error) {
+    if let Some(recorder) = RECORDER.get() {
+        recorder.command_failed(command_name(command), error);
+    }
+}
+
+pub(crate) fn round_trip(command: &'static str, latency: Duration) {
+    if let Some(recorder) = RECORDER.get() {
+        recorder.round_trip(command_name(command), latency);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu::{CkTapError, StatusCommand, StatusResponse};
+    use crate::commands::CkTransport;
+    use crate::test_util::BadAuthTransport;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorded {
+        sent: Vec<&'static str>,
+        failed: Vec<(&'static str, String)>,
+        round_trips: usize,
+    }
+
+    struct TestRecorder(&'static Mutex<Recorded>);
+
+    impl MetricsRecorder for TestRecorder {
+        fn command_sent(&self, command: &'static str) {
+            self.0.lock().expect("not poisoned").sent.push(command);
+        }
+        fn command_failed(&self, command: &'static str, error: &Error) {
+            let mut recorded = self.0.lock().expect("not poisoned");
+            recorded.failed.push((command, error_label(error)));
+        }
+        fn round_trip(&self, _command: &'static str, _latency: Duration) {
+            self.0.lock().expect("not poisoned").round_trips += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_recorder() {
+        static RECORDED: OnceLock<Mutex<Recorded>> = OnceLock::new();
+        let recorded = RECORDED.get_or_init(Mutex::default);
+        set_recorder(TestRecorder(recorded)).expect("first recorder");
+        assert!(set_recorder(TestRecorder(recorded)).is_err());
+
+        let result = BadAuthTransport
+            .transmit::<_, StatusResponse>(&StatusCommand::default())
+            .await;
+        assert_eq!(result.err(), Some(Error::CkTap(CkTapError::BadAuth)));
+
+        let recorded = recorded.lock().expect("not poisoned");
+        assert_eq!(recorded.sent, ["status"]);
+        assert_eq!(recorded.failed, [("status", "401".to_string())]);
+        assert_eq!(recorded.round_trips, 1);
+    }
+}
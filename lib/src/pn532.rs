@@ -0,0 +1,456 @@
+//! PN532 NFC controller: the commands reaching a contactless card through it, shared by the
+//! ACR122U (which embeds one, see `acr122u`) and, with the `pn532` feature, `Pn532Transport`
+//! for bare PN532 modules on a UART or SPI bus, e.g. on a Raspberry Pi.
+
+use crate::Error;
+#[cfg(feature = "pn532")]
+use crate::commands::{CkTransport, yield_now};
+#[cfg(feature = "pn532")]
+use std::io::{Read, Write};
+#[cfg(feature = "pn532")]
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Frame identifiers of commands to the PN532 and its answers
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+
+pub(crate) const GET_GENERAL_STATUS: u8 = 0x04;
+#[cfg(feature = "pn532")]
+const SAM_CONFIGURATION: u8 = 0x14;
+pub(crate) const RF_CONFIGURATION: u8 = 0x32;
+pub(crate) const IN_DATA_EXCHANGE: u8 = 0x40;
+pub(crate) const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+
+/// RFConfiguration item 5: MxRtyATR, MxRtyPSL and MxRtyPassiveActivation. Bounded passive
+/// activation retries make looking for a card return instead of polling (FeliCa included) until
+/// one shows up.
+pub(crate) const BOUNDED_RETRIES: [u8; 4] = [0x05, 0xFF, 0x01, 0x02];
+
+/// PN532 errors meaning the card is gone: timeout, and target released
+const TIMEOUT: u8 = 0x01;
+const RELEASED: u8 = 0x29;
+
+/// Longest APDU that fits in an InDataExchange with a one byte length
+pub(crate) const MAX_APDU_LEN: usize = 255 - 3;
+
+/// Host to PN532 frame: identifier, command code and parameters
+pub(crate) fn command_frame(command: u8, params: &[u8]) -> Vec<u8> {
+    let mut frame = vec![HOST_TO_PN532, command];
+    frame.extend_from_slice(params);
+    frame
+}
+
+/// The PN532's answer to `command`, without the frame identifier and command code
+pub(crate) fn response(command: u8, frame: &[u8]) -> Result<&[u8], Error> {
+    match frame {
+        [PN532_TO_HOST, code, data @ ..] if *code == command + 1 => Ok(data),
+        _ => Err(Error::Pn532(format!(
+            "Unexpected answer to {command:#04x}: {frame:02x?}"
+        ))),
+    }
+}
+
+/// Send a PN532 command with its parameters and return the answer's data
+pub(crate) type Exchange<'a> = dyn FnMut(u8, &[u8]) -> Result<Vec<u8>, Error> + 'a;
+
+/// Activate the card on the reader as an ISO 14443-A target at 106 kbps
+pub(crate) fn activate(exchange: &mut Exchange<'_>) -> Result<(), Error> {
+    let response = exchange(IN_LIST_PASSIVE_TARGET, &[0x01, 0x00])?;
+    match response.first() {
+        Some(targets) if *targets > 0 => Ok(()),
+        _ => Err(Error::CardRemoved),
+    }
+}
+
+/// Send an APDU to the card with InDataExchange, activating the card first if needed
+pub(crate) fn transmit(
+    exchange: &mut Exchange<'_>,
+    target_active: &AtomicBool,
+    apdu: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    if apdu.len() > MAX_APDU_LEN {
+        return Err(Error::Pn532(format!(
+            "APDU of {len} bytes is too long",
+            len = apdu.len()
+        )));
+    }
+    if !target_active.load(Ordering::Relaxed) {
+        activate(exchange)?;
+        target_active.store(true, Ordering::Relaxed);
+    }
+
+    let mut params = vec![0x01];
+    params.extend(apdu);
+    let response = exchange(IN_DATA_EXCHANGE, &params)?;
+    match response.split_first() {
+        Some((0x00, rapdu)) => Ok(rapdu.to_vec()),
+        Some((status, _)) => {
+            target_active.store(false, Ordering::Relaxed);
+            match status & 0x3F {
+                TIMEOUT | RELEASED => Err(Error::CardRemoved),
+                code => Err(Error::Pn532(format!("Error {code:#04x}"))),
+            }
+        }
+        None => Err(Error::Pn532("Empty answer".to_string())),
+    }
+}
+
+/// Whether a card is on the reader: an active target, or a card that can be activated
+pub(crate) fn card_present(
+    exchange: &mut Exchange<'_>,
+    target_active: &AtomicBool,
+) -> Result<bool, Error> {
+    let status = exchange(GET_GENERAL_STATUS, &[])?;
+    // error code, RF field, number of targets
+    if status.get(2).is_some_and(|&targets| targets > 0) {
+        return Ok(true);
+    }
+    target_active.store(false, Ordering::Relaxed);
+    match activate(exchange) {
+        Ok(()) => {
+            target_active.store(true, Ordering::Relaxed);
+            Ok(true)
+        }
+        Err(Error::CardRemoved) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(feature = "pn532")]
+fn io_error(e: std::io::Error) -> Error {
+    Error::Pn532(format!("I/O: {e}"))
+}
+
+#[cfg(feature = "pn532")]
+/// Normal information frame: preamble, start code, length and data with their checksums,
+/// postamble
+fn encode_frame(data: &[u8]) -> Vec<u8> {
+    let len = data.len() as u8;
+    let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    let mut frame = vec![0x00, 0x00, 0xFF, len, len.wrapping_neg()];
+    frame.extend_from_slice(data);
+    frame.extend([checksum.wrapping_neg(), 0x00]);
+    frame
+}
+
+#[cfg(feature = "pn532")]
+/// ACK frame the PN532 sends when it accepted a command
+const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+#[cfg(feature = "pn532")]
+/// Read one frame from `bytes` and return its data, empty for an ACK
+fn read_frame(bytes: &mut impl FnMut() -> Result<u8, Error>) -> Result<Vec<u8>, Error> {
+    // skip the preamble up to the 00 FF start code
+    let mut previous = bytes()?;
+    loop {
+        let byte = bytes()?;
+        if previous == 0x00 && byte == 0xFF {
+            break;
+        }
+        previous = byte;
+    }
+    let len = bytes()?;
+    let len_checksum = bytes()?;
+    // an ACK is the one frame with a zero length, and doesn't checksum
+    if (len, len_checksum) == (0x00, 0xFF) {
+        bytes()?; // postamble
+        return Ok(Vec::new());
+    }
+    if len == 0 || len.wrapping_add(len_checksum) != 0 {
+        return Err(Error::Pn532("Frame length checksum mismatch".to_string()));
+    }
+    let data = (0..len).map(|_| bytes()).collect::<Result<Vec<_>, _>>()?;
+    let checksum = bytes()?;
+    bytes()?; // postamble
+    if data.iter().fold(checksum, |sum, b| sum.wrapping_add(*b)) != 0 {
+        return Err(Error::Pn532("Frame data checksum mismatch".to_string()));
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "pn532")]
+/// A bus the PN532 is connected to, moving whole frames
+pub trait Pn532Link {
+    /// Send a command frame and wait for the PN532 to acknowledge it
+    fn send(&mut self, data: &[u8]) -> Result<(), Error>;
+    /// Wait for the answer frame and return its data
+    fn receive(&mut self) -> Result<Vec<u8>, Error>;
+}
+
+#[cfg(feature = "pn532")]
+/// PN532 on a UART (HSU), e.g. a serial device file or a `serialport` port (any
+/// `Box<dyn SerialPort>` is `Read + Write`), set to 115200 baud
+pub struct Uart<P: Read + Write> {
+    port: P,
+    awake: bool,
+}
+
+#[cfg(feature = "pn532")]
+impl<P: Read + Write> Uart<P> {
+    pub fn new(port: P) -> Self {
+        Self { port, awake: false }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut byte = [0u8];
+        self.port.read_exact(&mut byte).map_err(io_error)?;
+        Ok(byte[0])
+    }
+}
+
+#[cfg(feature = "pn532")]
+impl<P: Read + Write> Pn532Link for Uart<P> {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        if !self.awake {
+            // a long preamble wakes the PN532 from power down
+            let mut wakeup = vec![0x55, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+            wakeup.extend(encode_frame(data));
+            self.port.write_all(&wakeup).map_err(io_error)?;
+            self.awake = true;
+        } else {
+            self.port.write_all(&encode_frame(data)).map_err(io_error)?;
+        }
+        self.port.flush().map_err(io_error)?;
+        match read_frame(&mut || self.read_byte())? {
+            ack if ack.is_empty() => Ok(()),
+            frame => Err(Error::Pn532(format!("Expected an ACK, got {frame:02x?}"))),
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        read_frame(&mut || self.read_byte())
+    }
+}
+
+#[cfg(feature = "pn532")]
+/// Full-duplex SPI transfer with chip select held for the whole buffer, to plug in a spidev or
+/// embedded-hal SPI device (for an `embedded_hal::spi::SpiDevice`, `transfer_in_place` with its
+/// error mapped to `std::io::Error`). The bus runs in mode 0, at up to 5 MHz.
+pub trait SpiTransfer {
+    fn transfer(&mut self, buffer: &mut [u8]) -> std::io::Result<()>;
+}
+
+#[cfg(feature = "pn532")]
+/// SPI prefixes: data write, status read and data read
+const SPI_DATA_WRITE: u8 = 0x01;
+#[cfg(feature = "pn532")]
+const SPI_STATUS_READ: u8 = 0x02;
+#[cfg(feature = "pn532")]
+const SPI_DATA_READ: u8 = 0x03;
+#[cfg(feature = "pn532")]
+/// Longest frame the PN532 sends: preamble, start code, length, 255 bytes, checksum, postamble
+const MAX_FRAME_LEN: usize = 262;
+#[cfg(feature = "pn532")]
+/// How long the PN532 gets to be ready to send, the virtual card timeout set by `Pn532Transport`
+const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[cfg(feature = "pn532")]
+/// PN532 on an SPI bus. The PN532 sends and expects the least significant bit first; with
+/// `lsb_first` false (most SPI controllers, the Raspberry Pi's included) the bits are reversed
+/// in software.
+pub struct Spi<B: SpiTransfer> {
+    bus: B,
+    lsb_first: bool,
+}
+
+#[cfg(feature = "pn532")]
+impl<B: SpiTransfer> Spi<B> {
+    pub fn new(bus: B, lsb_first: bool) -> Self {
+        Self { bus, lsb_first }
+    }
+
+    fn transfer(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let reverse = |buffer: &mut [u8]| buffer.iter_mut().for_each(|b| *b = b.reverse_bits());
+        if !self.lsb_first {
+            reverse(buffer);
+        }
+        self.bus.transfer(buffer).map_err(io_error)?;
+        if !self.lsb_first {
+            reverse(buffer);
+        }
+        Ok(())
+    }
+
+    /// Poll the status byte until the PN532 has something to send, for up to [`READY_TIMEOUT`]
+    fn wait_ready(&mut self) -> Result<(), Error> {
+        let deadline = std::time::Instant::now() + READY_TIMEOUT;
+        loop {
+            let mut status = [SPI_STATUS_READ, 0x00];
+            self.transfer(&mut status)?;
+            if status[1] & 0x01 == 0x01 {
+                return Ok(());
+            }
+            if std::time::Instant::now() >= deadline {
+                return Err(Error::Pn532("Not ready to send".to_string()));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        self.wait_ready()?;
+        let mut buffer = vec![0x00; len + 1];
+        buffer[0] = SPI_DATA_READ;
+        self.transfer(&mut buffer)?;
+        buffer.remove(0);
+        Ok(buffer)
+    }
+}
+
+#[cfg(feature = "pn532")]
+impl<B: SpiTransfer> Pn532Link for Spi<B> {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut buffer = vec![SPI_DATA_WRITE];
+        buffer.extend(encode_frame(data));
+        self.transfer(&mut buffer)?;
+        match self.read(ACK.len())? {
+            ack if ack == ACK => Ok(()),
+            frame => Err(Error::Pn532(format!("Expected an ACK, got {frame:02x?}"))),
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let mut frame = self.read(MAX_FRAME_LEN)?.into_iter();
+        read_frame(&mut || {
+            frame
+                .next()
+                .ok_or_else(|| Error::Pn532("Truncated frame".to_string()))
+        })
+    }
+}
+
+#[cfg(feature = "pn532")]
+/// Transport for a bare PN532 module, exchanging APDUs with InDataExchange
+///
+/// ```no_run
+/// # async fn example() -> Result<(), cktap_direct::Error> {
+/// use cktap_direct::commands::CkTransport;
+/// use cktap_direct::pn532::{Pn532Transport, Uart};
+///
+/// // set the port up first, e.g. `stty -F /dev/serial0 115200 raw -echo`
+/// let port = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("/dev/serial0")
+///     .map_err(|e| cktap_direct::Error::Pn532(e.to_string()))?;
+/// let card = Pn532Transport::new(Uart::new(port))?.to_cktap().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Pn532Transport<L: Pn532Link> {
+    link: Mutex<L>,
+    /// the PN532 activated the card, so APDUs can be exchanged with it
+    target_active: AtomicBool,
+}
+
+#[cfg(feature = "pn532")]
+impl<L: Pn532Link> Pn532Transport<L> {
+    /// Set the PN532 up to talk to cards: normal SAM mode and bounded retries
+    pub fn new(link: L) -> Result<Self, Error> {
+        let transport = Self {
+            link: Mutex::new(link),
+            target_active: AtomicBool::new(false),
+        };
+        // normal mode, virtual card timeout of 1 s, IRQ pin used
+        transport.exchange(SAM_CONFIGURATION, &[0x01, 0x14, 0x01])?;
+        transport.exchange(RF_CONFIGURATION, &BOUNDED_RETRIES)?;
+        Ok(transport)
+    }
+
+    fn exchange(&self, command: u8, params: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut link = self
+            .link
+            .lock()
+            .map_err(|_| Error::Pn532("Link poisoned".to_string()))?;
+        link.send(&command_frame(command, params))?;
+        let frame = link.receive()?;
+        response(command, &frame).map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "pn532")]
+impl<L: Pn532Link> CkTransport for Pn532Transport<L> {
+    async fn card_present(&self) -> Result<bool, Error> {
+        card_present(
+            &mut |command, params| self.exchange(command, params),
+            &self.target_active,
+        )
+    }
+
+    async fn transmit_apdu(&self, apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        // the bus blocks like USB, so let a cancellation in before each APDU
+        yield_now().await;
+        transmit(
+            &mut |command, params| self.exchange(command, params),
+            &self.target_active,
+            apdu,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "pn532")]
+    #[test]
+    fn test_frames() {
+        let frame = encode_frame(&command_frame(GET_GENERAL_STATUS, &[]));
+        assert_eq!(
+            frame,
+            [0x00, 0x00, 0xFF, 0x02, 0xFE, 0xD4, 0x04, 0x28, 0x00]
+        );
+
+        let mut bytes = frame.into_iter();
+        let data = read_frame(&mut || bytes.next().ok_or(Error::DeviceNotFound)).unwrap();
+        assert_eq!(data, [0xD4, 0x04]);
+
+        let mut bytes = ACK.into_iter();
+        let ack = read_frame(&mut || bytes.next().ok_or(Error::DeviceNotFound)).unwrap();
+        assert!(ack.is_empty());
+
+        let mut corrupted = encode_frame(&[0xD5, 0x05]);
+        corrupted[6] ^= 0x01;
+        let mut bytes = corrupted.into_iter();
+        assert!(read_frame(&mut || bytes.next().ok_or(Error::DeviceNotFound)).is_err());
+    }
+
+    #[cfg(feature = "pn532")]
+    #[test]
+    fn test_spi_not_ready() {
+        struct Busy;
+        impl SpiTransfer for Busy {
+            fn transfer(&mut self, buffer: &mut [u8]) -> std::io::Result<()> {
+                buffer.fill(0x00);
+                Ok(())
+            }
+        }
+        let mut spi = Spi::new(Busy, true);
+        assert!(matches!(spi.send(&[HOST_TO_PN532]), Err(Error::Pn532(_))));
+    }
+
+    #[test]
+    fn test_transmit() {
+        let target_active = AtomicBool::new(false);
+        let mut commands = Vec::new();
+        let mut exchange = |command: u8, params: &[u8]| {
+            commands.push((command, params.to_vec()));
+            match command {
+                IN_LIST_PASSIVE_TARGET => Ok(vec![0x01, 0x01]),
+                _ => Ok(vec![0x00, 0x90, 0x00]),
+            }
+        };
+        let rapdu = transmit(&mut exchange, &target_active, vec![0x00, 0xA4]).unwrap();
+        assert_eq!(rapdu, [0x90, 0x00]);
+        assert!(target_active.load(Ordering::Relaxed));
+        assert_eq!(commands[1], (IN_DATA_EXCHANGE, vec![0x01, 0x00, 0xA4]));
+
+        let mut removed = |_: u8, _: &[u8]| Ok(vec![TIMEOUT]);
+        assert!(matches!(
+            transmit(&mut removed, &target_active, vec![0x00]),
+            Err(Error::CardRemoved)
+        ));
+        assert!(!target_active.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,48 @@
+//! Progress of the card operations taking several round-trips (certificate checks, address
+//! verification, PSBT signing), so a GUI can show "checking certificate chain (2/3)" instead of a
+//! spinner:
+//!
+//! ```ignore
+//! let ts = ts.with_progress(|step: Step| println!("{step}"));
+//! ```
+
+use core::fmt;
+
+/// One step of a multi round-trip operation, reported before it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// The operation, e.g. `check_certificate`
+    pub operation: &'static str,
+    /// What the step does, e.g. `checking certificate chain`
+    pub description: &'static str,
+    /// 1-based
+    pub number: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{description} ({number}/{total})",
+            description = self.description,
+            number = self.number,
+            total = self.total
+        )
+    }
+}
+
+/// Receives the steps of the operations of a card, see [`SatsCard::with_progress`] and
+/// [`TapSigner::with_progress`]
+///
+/// [`SatsCard::with_progress`]: crate::sats_card::SatsCard::with_progress
+/// [`TapSigner::with_progress`]: crate::tap_signer::TapSigner::with_progress
+pub trait ProgressListener: Send + Sync {
+    fn on_progress(&self, step: Step);
+}
+
+impl<F: Fn(Step) + Send + Sync> ProgressListener for F {
+    fn on_progress(&self, step: Step) {
+        self(step)
+    }
+}
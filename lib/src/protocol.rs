@@ -0,0 +1,153 @@
+//! The protocol math with no I/O: CVC encryption, the digests the card signs and the
+//! certificate chain. Together with [`apdu`](crate::apdu) it builds without the `std` feature
+//! (`no_std` + `alloc`), for devices that talk to cards over their own NFC stack.
+
+use crate::apdu::{CertsResponse, Error};
+use crate::cvc::Cvc;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId, Signature};
+use bitcoin::secp256k1::hashes::{Hash, HashEngine, sha256};
+use bitcoin::secp256k1::{self, All, Message, PublicKey, Secp256k1, SecretKey};
+
+/// Encrypt the CVC for `command`: XOR it with the session key (ECDH of the ephemeral key and the
+/// card's pubkey) masked by sha256(card_nonce || command). The CVC is XORed byte by byte
+/// straight into the result, no copy of it or of the mask is kept.
+pub fn calc_xcvc(
+    card_pubkey: &PublicKey,
+    card_nonce: &[u8; 16],
+    command: &str,
+    cvc: &Cvc,
+    ephemeral_private_key: &SecretKey,
+) -> Vec<u8> {
+    let session_key = SharedSecret::new(card_pubkey, ephemeral_private_key);
+    let mut engine = sha256::Hash::engine();
+    engine.input(card_nonce);
+    engine.input(command.as_bytes());
+    let md = sha256::Hash::from_engine(engine);
+    let md: &[u8; 32] = md.as_ref();
+
+    cvc.expose_secret()
+        .as_bytes()
+        .iter()
+        .zip(session_key.as_ref().iter().zip(md))
+        .map(|(cvc_byte, (key_byte, md_byte))| cvc_byte ^ key_byte ^ md_byte)
+        .collect()
+}
+
+/// The message the card signs to prove it holds a key: sha256 of `OPENDIME` followed by `parts`
+/// (nonces, slot, chain code), hashed as they are instead of being gathered in a buffer first
+pub fn opendime_digest(parts: &[&[u8]]) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(b"OPENDIME");
+    for part in parts {
+        engine.input(part);
+    }
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+/// Check a signature the card made over `message` (e.g. an [`opendime_digest`]) with `pubkey`
+pub fn verify_card_signature(
+    secp: &Secp256k1<All>,
+    message: &Message,
+    signature: &[u8],
+    pubkey: &PublicKey,
+) -> Result<(), secp256k1::Error> {
+    let signature = Signature::from_compact(signature)?;
+    secp.verify_ecdsa(message, &signature, pubkey)
+}
+
+/// Parse a certificate from the `certs` response: a BIP-137 header byte followed by a 64 byte
+/// compact signature
+pub fn parse_cert_signature(cert: &[u8]) -> Result<RecoverableSignature, Error> {
+    let (&header, sig) = cert
+        .split_first()
+        .ok_or_else(|| Error::IncorrectSignature("Empty certificate".to_string()))?;
+
+    // BIP-137: https://github.com/bitcoin/bips/blob/master/bip-0137.mediawiki
+    let subtract_by = match header {
+        27..=30 => 27, // P2PKH uncompressed
+        31..=34 => 31, // P2PKH compressed
+        35..=38 => 35, // Segwit P2SH
+        39..=42 => 39, // Segwit Bech32
+        _ => {
+            return Err(Error::IncorrectSignature(format!(
+                "Unrecognized BIP-137 address type: {header}"
+            )));
+        }
+    };
+
+    let rec_id = RecoveryId::from_i32(i32::from(header - subtract_by))?;
+    Ok(RecoverableSignature::from_compact(sig, rec_id)?)
+}
+
+/// Walk the certificate chain from the card's pubkey up, returning the key that signed the last
+/// certificate, which should be a factory root key
+pub fn recover_cert_chain(
+    secp: &Secp256k1<All>,
+    card_pubkey: PublicKey,
+    certs: &CertsResponse,
+) -> Result<PublicKey, Error> {
+    let mut pubkey = card_pubkey;
+    for cert in &certs.cert_chain() {
+        let rec_sig = parse_cert_signature(cert)?;
+        let pubkey_hash = sha256::Hash::hash(&pubkey.serialize_uncompressed());
+        let md = Message::from_digest(pubkey_hash.to_byte_array());
+        pubkey = secp.recover_ecdsa(&md, &rec_sig)?;
+    }
+    Ok(pubkey)
+}
+
+/// Hash of a card's pubkey and its certificate chain, identifying a chain that was verified for
+/// that card
+pub fn cert_chain_hash(card_pubkey: &PublicKey, certs: &CertsResponse) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&card_pubkey.serialize());
+    for cert in &certs.cert_chain() {
+        engine.input(cert);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// Short identifier of a card, `CARD-` and the first 4 bytes of its pubkey in hex, as the CLI
+/// shows it
+pub fn card_ident(card_pubkey: &PublicKey) -> String {
+    let serialized = card_pubkey.serialize();
+    let prefix = u32::from_be_bytes([serialized[0], serialized[1], serialized[2], serialized[3]]);
+    format!("CARD-{prefix:X}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_card_ident() {
+        let secp = Secp256k1::new();
+        let pubkey = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp);
+        let serialized = pubkey.serialize();
+        let ident = card_ident(&pubkey);
+        assert_eq!(
+            ident,
+            format!(
+                "CARD-{:X}",
+                serialized[0..4]
+                    .iter()
+                    .fold(0u32, |acc, &b| (acc << 8) | b as u32)
+            )
+        );
+        // the parity byte is 2 or 3, no leading zero
+        assert_eq!(ident.len(), "CARD-".len() + 7);
+    }
+
+    #[test]
+    fn test_parse_cert_signature() {
+        assert!(parse_cert_signature(&[]).is_err());
+        assert!(parse_cert_signature(&[31]).is_err());
+        assert!(parse_cert_signature(&[0; 65]).is_err());
+        assert!(parse_cert_signature(&[31; 65]).is_ok());
+    }
+}
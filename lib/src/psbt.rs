@@ -0,0 +1,483 @@
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint};
+use bitcoin::psbt::{Input, Psbt, PsbtSighashType};
+use bitcoin::script::PushBytes;
+use bitcoin::{Amount, EcdsaSighashType, ScriptBuf, Transaction, TxOut, Witness};
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FinalizeError {
+    #[error("Missing input at index: {0}")]
+    MissingInput(usize),
+
+    #[error("Missing UTXO at index: {0}")]
+    MissingUtxo(usize),
+
+    #[error("Missing signature at index: {0}")]
+    MissingSignature(usize),
+
+    #[error("Invalid script: index: {0}")]
+    InvalidScript(usize),
+
+    #[error("Extract error: {0}")]
+    ExtractError(String),
+}
+
+/// Script type of the output spent by a PSBT input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputScriptType {
+    P2pkh,
+    P2sh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Unknown,
+}
+
+impl InputScriptType {
+    /// Classify the script of a spent output. A P2SH output is only known to wrap P2WPKH when
+    /// the input carries the matching redeem script.
+    pub fn classify(script_pubkey: &ScriptBuf, redeem_script: Option<&ScriptBuf>) -> Self {
+        if script_pubkey.is_p2wpkh() {
+            Self::P2wpkh
+        } else if script_pubkey.is_p2sh() {
+            match redeem_script {
+                Some(redeem_script)
+                    if redeem_script.is_p2wpkh()
+                        && ScriptBuf::new_p2sh(&redeem_script.script_hash()) == *script_pubkey =>
+                {
+                    Self::P2shP2wpkh
+                }
+                _ => Self::P2sh,
+            }
+        } else if script_pubkey.is_p2pkh() {
+            Self::P2pkh
+        } else if script_pubkey.is_p2wsh() {
+            Self::P2wsh
+        } else if script_pubkey.is_p2tr() {
+            Self::P2tr
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// What a TapSigner could do with a single PSBT input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputAnalysis {
+    pub index: usize,
+    /// value of the spent output, if the PSBT includes it
+    pub amount: Option<Amount>,
+    /// script type of the spent output, if the PSBT includes it
+    pub script_type: Option<InputScriptType>,
+    /// sighash the signature would commit to
+    pub sighash_type: PsbtSighashType,
+    /// key origin matching the card, if any
+    pub key_source: Option<(Fingerprint, DerivationPath)>,
+    /// why the card can't sign this input, `None` if it can
+    pub unsupported_reason: Option<UnsupportedReason>,
+}
+
+impl InputAnalysis {
+    pub fn signable(&self) -> bool {
+        self.unsupported_reason.is_none()
+    }
+}
+
+/// Why a TapSigner can't sign a PSBT input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsupportedReason {
+    /// segwit v0 sighashes commit to the spent amount, so the witness UTXO is required
+    MissingWitnessUtxo,
+    /// the card only makes ECDSA signatures for single key P2WPKH and P2SH-P2WPKH outputs, so
+    /// taproot and script outputs can't be signed
+    ScriptType(InputScriptType),
+    /// the card always signs with SIGHASH_ALL
+    SighashType(PsbtSighashType),
+    MissingKeyOrigin,
+    FingerprintMismatch,
+    /// the card signs for `account/chain/index` paths with an unhardened chain and index
+    DerivationPath(DerivationPath),
+}
+
+impl core::fmt::Display for UnsupportedReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::MissingWitnessUtxo => write!(f, "missing witness UTXO"),
+            Self::ScriptType(InputScriptType::P2tr) => {
+                write!(
+                    f,
+                    "taproot input needs a Schnorr signature, the card only signs ECDSA"
+                )
+            }
+            Self::ScriptType(script_type) => write!(f, "unsupported script type {script_type:?}"),
+            Self::SighashType(sighash_type) => write!(f, "unsupported sighash type {sighash_type}"),
+            Self::MissingKeyOrigin => write!(f, "missing key origin"),
+            Self::FingerprintMismatch => write!(f, "no key origin matches the card fingerprint"),
+            Self::DerivationPath(path) => write!(f, "unsupported derivation path m/{path}"),
+        }
+    }
+}
+
+/// A PSBT input the card can't sign
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("input {index}: {reason}")]
+pub struct UnsupportedInput {
+    pub index: usize,
+    pub reason: UnsupportedReason,
+}
+
+/// Summary of a PSBT from the point of view of a TapSigner, computed without talking to the card
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PsbtAnalysis {
+    pub inputs: Vec<InputAnalysis>,
+    /// sum of all input amounts, `None` if any input is missing its spent output
+    pub total_in: Option<Amount>,
+    pub total_out: Amount,
+    pub fee: Option<Amount>,
+}
+
+impl PsbtAnalysis {
+    /// Inputs the card can't sign, along with the reason
+    pub fn unsupported_inputs(&self) -> Vec<UnsupportedInput> {
+        self.inputs
+            .iter()
+            .filter_map(|input| {
+                Some(UnsupportedInput {
+                    index: input.index,
+                    reason: input.unsupported_reason.clone()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The output spent by a PSBT input, from either the witness or the non-witness UTXO
+pub fn spent_output(psbt: &Psbt, index: usize) -> Option<&TxOut> {
+    let input = psbt.inputs.get(index)?;
+    if let Some(witness_utxo) = &input.witness_utxo {
+        return Some(witness_utxo);
+    }
+    let prevout = psbt.unsigned_tx.input.get(index)?.previous_output;
+    input
+        .non_witness_utxo
+        .as_ref()?
+        .output
+        .get(prevout.vout as usize)
+}
+
+/// Find the key origin in the input that belongs to the card. Without a fingerprint the first
+/// origin is used, which is what `TapSigner::sign_psbt` does.
+fn card_key_source(
+    input: &Input,
+    fingerprint: Option<Fingerprint>,
+) -> Option<(Fingerprint, DerivationPath)> {
+    input
+        .bip32_derivation
+        .values()
+        .find(|(fp, _)| fingerprint.is_none_or(|card_fp| card_fp == *fp))
+        .cloned()
+}
+
+fn unsupported_reason(
+    input: &Input,
+    script_type: Option<InputScriptType>,
+    sighash_type: PsbtSighashType,
+    key_source: Option<&(Fingerprint, DerivationPath)>,
+) -> Option<UnsupportedReason> {
+    // the card only signs segwit v0 inputs, which need the spent output's value
+    if input.witness_utxo.is_none() {
+        return Some(UnsupportedReason::MissingWitnessUtxo);
+    }
+    match script_type {
+        Some(InputScriptType::P2wpkh | InputScriptType::P2shP2wpkh) => {}
+        Some(other) => return Some(UnsupportedReason::ScriptType(other)),
+        None => return Some(UnsupportedReason::MissingWitnessUtxo),
+    }
+    if sighash_type != PsbtSighashType::from(EcdsaSighashType::All) {
+        return Some(UnsupportedReason::SighashType(sighash_type));
+    }
+    let Some((_, path)) = key_source else {
+        return if input.bip32_derivation.is_empty() {
+            Some(UnsupportedReason::MissingKeyOrigin)
+        } else {
+            Some(UnsupportedReason::FingerprintMismatch)
+        };
+    };
+    // account path (hardened) followed by chain and address index (not hardened)
+    let children: &[ChildNumber] = path.as_ref();
+    if children.len() != 5 || children[3..].iter().any(ChildNumber::is_hardened) {
+        return Some(UnsupportedReason::DerivationPath(path.clone()));
+    }
+    None
+}
+
+/// Report for every input whether the card could sign it, along with the transaction amounts.
+/// No APDUs are sent.
+pub fn analyze_psbt(psbt: &Psbt, fingerprint: Option<Fingerprint>) -> PsbtAnalysis {
+    let inputs: Vec<InputAnalysis> = psbt
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let spent = spent_output(psbt, index);
+            let script_type = spent.map(|txout| {
+                InputScriptType::classify(&txout.script_pubkey, input.redeem_script.as_ref())
+            });
+            let sighash_type = input
+                .sighash_type
+                .unwrap_or(PsbtSighashType::from(EcdsaSighashType::All));
+            let key_source = card_key_source(input, fingerprint);
+            let unsupported_reason =
+                unsupported_reason(input, script_type, sighash_type, key_source.as_ref());
+
+            InputAnalysis {
+                index,
+                amount: spent.map(|txout| txout.value),
+                script_type,
+                sighash_type,
+                key_source,
+                unsupported_reason,
+            }
+        })
+        .collect();
+
+    let total_in = inputs
+        .iter()
+        .map(|input| input.amount)
+        .sum::<Option<Amount>>();
+    let total_out = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|txout| txout.value)
+        .sum::<Amount>();
+    let fee = total_in.and_then(|total_in| total_in.checked_sub(total_out));
+
+    PsbtAnalysis {
+        inputs,
+        total_in,
+        total_out,
+        fee,
+    }
+}
+
+/// Finalize a single P2WPKH or P2SH-P2WPKH input from its partial signature, clearing the
+/// fields a finalized input no longer needs (BIP-174). Already finalized inputs are left alone.
+pub fn finalize_input(psbt: &mut Psbt, index: usize) -> Result<(), FinalizeError> {
+    let input = psbt
+        .inputs
+        .get_mut(index)
+        .ok_or(FinalizeError::MissingInput(index))?;
+    if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+        return Ok(());
+    }
+    let witness_utxo = input
+        .witness_utxo
+        .as_ref()
+        .ok_or(FinalizeError::MissingUtxo(index))?;
+
+    let script_type =
+        InputScriptType::classify(&witness_utxo.script_pubkey, input.redeem_script.as_ref());
+    let (program, script_sig) = match (script_type, &input.redeem_script) {
+        (InputScriptType::P2wpkh, _) => (&witness_utxo.script_pubkey, None),
+        (InputScriptType::P2shP2wpkh, Some(redeem_script)) => {
+            let push: &PushBytes = redeem_script
+                .as_bytes()
+                .try_into()
+                .map_err(|_| FinalizeError::InvalidScript(index))?;
+            let script_sig = ScriptBuf::builder().push_slice(push).into_script();
+            (redeem_script, Some(script_sig))
+        }
+        _ => return Err(FinalizeError::InvalidScript(index)),
+    };
+
+    // the signature made with the key the witness program commits to
+    let (pubkey, sig) = input
+        .partial_sigs
+        .iter()
+        .find(|(pubkey, _)| {
+            pubkey
+                .wpubkey_hash()
+                .is_ok_and(|hash| ScriptBuf::new_p2wpkh(&hash) == *program)
+        })
+        .ok_or(FinalizeError::MissingSignature(index))?;
+    let witness = Witness::p2wpkh(sig, &pubkey.inner);
+
+    input.final_script_witness = Some(witness);
+    input.final_script_sig = script_sig;
+    input.partial_sigs.clear();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation.clear();
+    Ok(())
+}
+
+/// Finalize every input of a signed PSBT
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<(), FinalizeError> {
+    (0..psbt.inputs.len()).try_for_each(|index| finalize_input(psbt, index))
+}
+
+/// Finalize a signed PSBT and extract the network serializable transaction
+pub fn extract_tx(mut psbt: Psbt) -> Result<Transaction, FinalizeError> {
+    finalize_psbt(&mut psbt)?;
+    psbt.extract_tx()
+        .map_err(|e| FinalizeError::ExtractError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash as _;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use bitcoin::transaction::Version;
+    use bitcoin::{
+        CompressedPublicKey, OutPoint, Sequence, Transaction, TxIn, WPubkeyHash, Witness,
+    };
+
+    fn test_pubkey() -> PublicKey {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).expect("valid secret key");
+        PublicKey::from_secret_key(&secp, &secret_key)
+    }
+
+    fn test_psbt(spent: Vec<TxOut>, fingerprint: Fingerprint) -> Psbt {
+        let unsigned_tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: spent
+                .iter()
+                .enumerate()
+                .map(|(vout, _)| TxIn {
+                    previous_output: OutPoint::new(bitcoin::Txid::all_zeros(), vout as u32),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).expect("unsigned tx");
+        let path: DerivationPath = vec![
+            ChildNumber::from_hardened_idx(84).expect("valid index"),
+            ChildNumber::from_hardened_idx(0).expect("valid index"),
+            ChildNumber::from_hardened_idx(0).expect("valid index"),
+            ChildNumber::from_normal_idx(0).expect("valid index"),
+            ChildNumber::from_normal_idx(3).expect("valid index"),
+        ]
+        .into();
+        for (input, txout) in psbt.inputs.iter_mut().zip(spent) {
+            input.witness_utxo = Some(txout);
+            input
+                .bip32_derivation
+                .insert(test_pubkey(), (fingerprint, path.clone()));
+        }
+        psbt
+    }
+
+    #[test]
+    fn test_analyze_psbt() {
+        let fingerprint = Fingerprint::from([1, 2, 3, 4]);
+        let p2wpkh = ScriptBuf::new_p2wpkh(&CompressedPublicKey(test_pubkey()).wpubkey_hash());
+        let p2tr = ScriptBuf::new_p2tr(&Secp256k1::new(), test_pubkey().into(), None);
+        let psbt = test_psbt(
+            vec![
+                TxOut {
+                    value: Amount::from_sat(60_000),
+                    script_pubkey: p2wpkh,
+                },
+                TxOut {
+                    value: Amount::from_sat(40_000),
+                    script_pubkey: p2tr,
+                },
+            ],
+            fingerprint,
+        );
+
+        let analysis = analyze_psbt(&psbt, Some(fingerprint));
+        assert_eq!(analysis.total_in, Some(Amount::from_sat(100_000)));
+        assert_eq!(analysis.total_out, Amount::from_sat(90_000));
+        assert_eq!(analysis.fee, Some(Amount::from_sat(10_000)));
+
+        assert!(analysis.inputs[0].signable());
+        assert_eq!(
+            analysis.inputs[0].script_type,
+            Some(InputScriptType::P2wpkh)
+        );
+        assert!(!analysis.inputs[1].signable());
+        assert_eq!(analysis.inputs[1].script_type, Some(InputScriptType::P2tr));
+        assert_eq!(
+            analysis.unsupported_inputs(),
+            vec![UnsupportedInput {
+                index: 1,
+                reason: UnsupportedReason::ScriptType(InputScriptType::P2tr),
+            }]
+        );
+
+        let analysis = analyze_psbt(&psbt, Some(Fingerprint::from([9, 9, 9, 9])));
+        assert_eq!(
+            analysis.inputs[0].unsupported_reason,
+            Some(UnsupportedReason::FingerprintMismatch)
+        );
+        assert!(analysis.inputs[0].key_source.is_none());
+    }
+
+    #[test]
+    fn test_finalize_psbt() -> Result<(), FinalizeError> {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).expect("valid secret key");
+        let pubkey = bitcoin::PublicKey::new(test_pubkey());
+        let compressed = CompressedPublicKey(test_pubkey());
+        let redeem_script = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+        let spent = vec![
+            TxOut {
+                value: Amount::from_sat(60_000),
+                script_pubkey: redeem_script.clone(),
+            },
+            TxOut {
+                value: Amount::from_sat(40_000),
+                script_pubkey: ScriptBuf::new_p2sh(&redeem_script.script_hash()),
+            },
+        ];
+        let mut psbt = test_psbt(spent, Fingerprint::from([1, 2, 3, 4]));
+        psbt.inputs[1].redeem_script = Some(redeem_script.clone());
+
+        let analysis = analyze_psbt(&psbt, None);
+        assert_eq!(
+            analysis.inputs[1].script_type,
+            Some(InputScriptType::P2shP2wpkh)
+        );
+        assert!(analysis.inputs.iter().all(InputAnalysis::signable));
+
+        assert_eq!(
+            finalize_psbt(&mut psbt.clone()),
+            Err(FinalizeError::MissingSignature(0))
+        );
+        assert_eq!(
+            finalize_input(&mut psbt.clone(), 2),
+            Err(FinalizeError::MissingInput(2))
+        );
+
+        // any valid signature will do, finalizing doesn't verify it
+        let message = bitcoin::secp256k1::Message::from_digest([7u8; 32]);
+        let sig = bitcoin::ecdsa::Signature::sighash_all(secp.sign_ecdsa(&message, &secret_key));
+        for input in psbt.inputs.iter_mut() {
+            input.partial_sigs.insert(pubkey, sig);
+        }
+
+        let tx = extract_tx(psbt)?;
+        assert_eq!(tx.input[0].witness.len(), 2);
+        assert!(tx.input[0].script_sig.is_empty());
+        assert_eq!(tx.input[1].witness.len(), 2);
+        assert_eq!(
+            tx.input[1].script_sig.as_bytes()[1..],
+            *redeem_script.as_bytes()
+        );
+        Ok(())
+    }
+}
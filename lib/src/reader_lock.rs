@@ -0,0 +1,106 @@
+//! Advisory lock on a reader, so two processes don't interleave APDUs on the same card and break
+//! each other's nonce chain. Taken with `flock` on a file per reader in the temp directory, which
+//! the OS releases when the process exits, even if it crashes. Other platforms don't lock.
+
+use crate::Error;
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long discovery waits by default for another process to release a reader
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A held reader lock, released when dropped
+#[derive(Debug)]
+pub struct ReaderLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl ReaderLock {
+    /// Lock the reader identified by `key` (e.g. `usb-001-004` for its bus and address), waiting
+    /// up to `timeout` for another process holding it. The wait sleeps on the Tokio timer, so it
+    /// needs a Tokio runtime and leaves its thread to other tasks meanwhile.
+    pub async fn acquire(key: &str, timeout: Duration) -> Result<Self, Error> {
+        let path = std::env::temp_dir().join(format!("cktap-direct-{key}.lock"));
+        let file = open(&path).map_err(|e| {
+            Error::ReaderLocked(format!("can't open {path}: {e}", path = path.display()))
+        })?;
+
+        let deadline = Instant::now() + timeout;
+        while !try_lock(&file) {
+            if Instant::now() >= deadline {
+                return Err(Error::ReaderLocked(format!(
+                    "another process held {path} for more than {secs}s",
+                    path = path.display(),
+                    secs = timeout.as_secs_f32(),
+                )));
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+        debug!("Locked reader {key} ({path})", path = path.display());
+
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Lock files are shared by all users of the reader, one created by another user may only be
+/// readable, which is enough to lock it
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .or_else(|_| File::open(path))
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is valid for the lifetime of `file`
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reader_lock() -> Result<(), Error> {
+        let key = format!("test-{pid}", pid = std::process::id());
+        let lock = ReaderLock::acquire(&key, Duration::ZERO).await?;
+
+        // flock locks belong to the open file, so a second open conflicts even in this process
+        let second = ReaderLock::acquire(&key, Duration::from_millis(100)).await;
+        assert!(matches!(second, Err(Error::ReaderLocked(_))));
+
+        // waiting doesn't block the runtime's only thread, the task releasing the lock still runs
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(lock);
+        });
+        let lock = ReaderLock::acquire(&key, Duration::from_secs(5)).await?;
+        assert!(release.is_finished());
+
+        let path = lock.path().to_path_buf();
+        drop(lock);
+        ReaderLock::acquire(&key, Duration::ZERO).await?;
+        std::fs::remove_file(path).ok();
+        Ok(())
+    }
+}
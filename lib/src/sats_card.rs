@@ -1,13 +1,67 @@
-use bitcoin::hashes::{Hash as _, sha256};
+use bitcoin::bip32::{ChainCode, ChildNumber, Xpriv, Xpub};
 use bitcoin::key::CompressedPublicKey as BitcoinPublicKey;
-use bitcoin::secp256k1::{All, Message, PublicKey, Secp256k1, ecdsa::Signature};
-use bitcoin::{Address, Network};
+use bitcoin::secp256k1::{
+    self, All, Message, PublicKey, Secp256k1, SecretKey, ecdh::SharedSecret, ecdsa::Signature,
+};
+use bitcoin::{Address, Network, NetworkKind, PrivateKey};
 
+use crate::StatusSummary;
 use crate::apdu::{
     CommandApdu as _, DeriveCommand, DeriveResponse, DumpCommand, DumpResponse, Error, NewCommand,
-    NewResponse, StatusResponse, UnsealCommand, UnsealResponse,
+    NewResponse, StatusCommand, StatusResponse, UnsealCommand, UnsealResponse,
+};
+use crate::commands::{
+    Authentication, Certificate, CkTransport, Read, Wait, check_same_card, opendime_digest,
+    with_reselect,
 };
-use crate::commands::{Authentication, Certificate, CkTransport, Read, Wait};
+use crate::cvc::Cvc;
+use crate::entropy::{EntropySource, ThreadRngSource};
+use crate::progress::ProgressListener;
+use crate::version::FirmwareVersion;
+
+/// A slot address checked by [`SatsCard::verify_address`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressVerification {
+    pub slot: u8,
+    /// The slot pubkey, as read and as derived from the master key and chain code
+    pub pubkey: PublicKey,
+    pub address: String,
+}
+
+/// The checks of [`SatsCard::check_derive`], each passing or failing on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeriveCheck {
+    pub slot: u8,
+    pub master_pubkey: PublicKey,
+    pub chain_code: ChainCode,
+    /// The card signed the chain code and our nonce with the master key
+    pub signature_valid: bool,
+    /// The slot pubkey the card signs `read` with
+    pub slot_pubkey: PublicKey,
+    /// The slot pubkey derived from the master pubkey and chain code
+    pub derived_pubkey: PublicKey,
+    /// The address of the derived pubkey
+    pub address: String,
+    /// The (censored) address the card reported in its status, if any
+    pub card_address: Option<String>,
+}
+
+impl DeriveCheck {
+    pub fn pubkey_matches(&self) -> bool {
+        self.slot_pubkey == self.derived_pubkey
+    }
+
+    /// Whether the address matches the card's, `None` if the card reported none
+    pub fn address_matches(&self) -> Option<bool> {
+        self.card_address
+            .as_deref()
+            .map(|card_address| matches_censored_address(&self.address, card_address))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.signature_valid && self.pubkey_matches() && self.address_matches() != Some(false)
+    }
+}
 
 pub struct SatsCard<T: CkTransport> {
     pub transport: T,
@@ -17,9 +71,16 @@ pub struct SatsCard<T: CkTransport> {
     pub birth: usize,
     pub slots: (u8, u8),
     pub addr: Option<String>,
+    /// The card makes testnet addresses, see [`SatsCard::network`]
+    pub testnet: bool,
     pub pubkey: PublicKey,
     pub card_nonce: [u8; 16],
     pub auth_delay: Option<usize>,
+    pub entropy: Box<dyn EntropySource>,
+    /// Listener for the steps of multi round-trip operations, see [`SatsCard::with_progress`]
+    pub progress: Option<Box<dyn ProgressListener>>,
+    /// Firmware baseline for the commands changing the card, see [`SatsCard::require_version`]
+    pub required_version: Option<FirmwareVersion>,
 }
 
 impl<T: CkTransport> Authentication<T> for SatsCard<T> {
@@ -50,6 +111,14 @@ impl<T: CkTransport> Authentication<T> for SatsCard<T> {
     fn transport(&self) -> &T {
         &self.transport
     }
+
+    fn entropy(&self) -> &dyn EntropySource {
+        self.entropy.as_ref()
+    }
+
+    fn progress(&self) -> Option<&dyn ProgressListener> {
+        self.progress.as_deref()
+    }
 }
 
 impl<T: CkTransport> SatsCard<T> {
@@ -70,82 +139,374 @@ impl<T: CkTransport> SatsCard<T> {
             pubkey,
             card_nonce: status_response.card_nonce,
             auth_delay: status_response.auth_delay,
+            entropy: Box::new(ThreadRngSource),
+            progress: None,
             slots,
             addr: status_response.addr,
+            testnet: status_response.testnet.unwrap_or(false),
+            required_version: None,
         })
     }
 
+    /// Use `entropy` instead of the thread RNG for the nonces and ephemeral keys sent to the card
+    pub fn with_entropy(mut self, entropy: impl EntropySource + 'static) -> Self {
+        self.entropy = Box::new(entropy);
+        self
+    }
+
+    /// Report the steps of multi round-trip operations (certificate checks, address verification) to `listener`
+    pub fn with_progress(mut self, listener: impl ProgressListener + 'static) -> Self {
+        self.progress = Some(Box::new(listener));
+        self
+    }
+
+    /// Refuse the commands changing the card (`new` and `unseal`) unless its firmware is at least
+    /// `min`. They fail with [`Error::UnsupportedByFirmware`] before anything is sent.
+    pub fn require_version(mut self, min: FirmwareVersion) -> Self {
+        self.required_version = Some(min);
+        self
+    }
+
+    /// The same card over another transport, e.g. wrapped in an enum of the transports an app
+    /// chooses from at runtime
+    pub fn map_transport<U: CkTransport>(self, f: impl FnOnce(T) -> U) -> SatsCard<U> {
+        SatsCard {
+            transport: f(self.transport),
+            secp: self.secp,
+            proto: self.proto,
+            ver: self.ver,
+            birth: self.birth,
+            slots: self.slots,
+            addr: self.addr,
+            testnet: self.testnet,
+            pubkey: self.pubkey,
+            card_nonce: self.card_nonce,
+            auth_delay: self.auth_delay,
+            entropy: self.entropy,
+            progress: self.progress,
+            required_version: self.required_version,
+        }
+    }
+
+    /// Run the status command again and take over what it reports (slots, address, auth delay and
+    /// nonce), so a long-lived session stays in sync without connecting again. Fails with
+    /// [`Error::WrongCard`] if another card answers.
+    pub async fn refresh_status(&mut self) -> Result<StatusResponse, Error> {
+        let status = with_reselect!(self, self.status_once().await)?;
+        check_same_card(self, &status)?;
+        self.slots = status
+            .slots
+            .ok_or_else(|| Error::CiborValue("Missing slots".to_string()))?;
+        self.addr = status.addr.clone();
+        self.testnet = status.testnet.unwrap_or(false);
+        self.auth_delay = status.auth_delay;
+        self.card_nonce = status.card_nonce;
+        Ok(status)
+    }
+
+    async fn status_once(&mut self) -> Result<StatusResponse, Error> {
+        self.transport.transmit(&StatusCommand::default()).await
+    }
+
+    /// What the card last reported in its status, see [`crate::CkTapCard::status_summary`]
+    pub fn status_summary(&self) -> StatusSummary {
+        StatusSummary {
+            card_type: "satscard",
+            ident: self.ident(),
+            proto: self.proto,
+            version: self.ver.clone(),
+            birth: self.birth,
+            slots: Some(self.slots),
+            testnet: self.testnet,
+            path: None,
+            num_backups: None,
+            auth_delay: self.auth_delay,
+        }
+    }
+
+    fn check_required_version(&self) -> Result<(), Error> {
+        match &self.required_version {
+            Some(min) => min.require(&self.ver),
+            None => Ok(()),
+        }
+    }
+
     pub async fn new_slot(
         &mut self,
         slot: u8,
         chain_code: Option<[u8; 32]>,
-        cvc: &str,
+        cvc: &Cvc,
     ) -> Result<NewResponse, Error> {
+        with_reselect!(self, self.new_slot_once(slot, chain_code, cvc).await)
+    }
+
+    async fn new_slot_once(
+        &mut self,
+        slot: u8,
+        chain_code: Option<[u8; 32]>,
+        cvc: &Cvc,
+    ) -> Result<NewResponse, Error> {
+        self.check_required_version()?;
         let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, NewCommand::name());
         let new_command = NewCommand::new(Some(slot), chain_code, epubkey, xcvc);
-        let new_response: NewResponse = self.transport.transmit(&new_command).await?;
-        self.card_nonce = new_response.card_nonce;
+        let new_response = self
+            .transmit_authenticated(&new_command, |card, response: NewResponse| {
+                card.advance_card_nonce(NewCommand::name(), response.card_nonce)?;
+                Ok(response)
+            })
+            .await?;
         self.slots.0 = new_response.slot;
 
         Ok(new_response)
     }
 
     pub async fn derive(&mut self) -> Result<DeriveResponse, Error> {
-        let nonce = crate::rand_nonce();
+        let (resp, signature_valid) = self.derive_unverified().await?;
+        if !signature_valid {
+            return Err(secp256k1::Error::IncorrectSignature.into());
+        }
+        Ok(resp)
+    }
+
+    /// `derive`, with whether its signature verifies rather than failing when it doesn't
+    async fn derive_unverified(&mut self) -> Result<(DeriveResponse, bool), Error> {
+        with_reselect!(self, self.derive_unverified_once().await)
+    }
+
+    async fn derive_unverified_once(&mut self) -> Result<(DeriveResponse, bool), Error> {
+        let nonce = self.entropy().nonce();
         let card_nonce = *self.card_nonce();
 
         let cmd = DeriveCommand::for_satscard(nonce);
         let resp: DeriveResponse = self.transport().transmit(&cmd).await?;
-        self.set_card_nonce(resp.card_nonce);
+        self.advance_card_nonce(DeriveCommand::name(), resp.card_nonce)?;
 
         // Verify signature
-        let mut message_bytes: Vec<u8> = Vec::new();
-        message_bytes.extend("OPENDIME".as_bytes());
-        message_bytes.extend(card_nonce);
-        message_bytes.extend(nonce);
-        message_bytes.extend(resp.chain_code);
-
-        let message_bytes_hash = sha256::Hash::hash(message_bytes.as_slice());
-        let message = Message::from_digest(message_bytes_hash.to_byte_array());
+        let message = opendime_digest(&[&card_nonce, &nonce, &resp.chain_code]);
 
         let signature = Signature::from_compact(&resp.sig)?;
 
         let pubkey = PublicKey::from_slice(&resp.master_pubkey)?;
-        self.secp().verify_ecdsa(&message, &signature, &pubkey)?;
+        let signature_valid = self
+            .secp()
+            .verify_ecdsa(&message, &signature, &pubkey)
+            .is_ok();
 
-        Ok(resp)
+        Ok((resp, signature_valid))
     }
 
-    pub async fn unseal(&mut self, slot: u8, cvc: &str) -> Result<UnsealResponse, Error> {
-        let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, UnsealCommand::name());
-        let unseal_command = UnsealCommand::new(slot, epubkey, xcvc);
-        let unseal_response: UnsealResponse = self.transport.transmit(&unseal_command).await?;
-        self.set_card_nonce(unseal_response.card_nonce);
+    /// Compute the slot pubkey (`m/0`) from the master pubkey and chain code returned by `derive`
+    pub fn derive_slot_pubkey(&self, derive_response: &DeriveResponse) -> Result<PublicKey, Error> {
+        let master = Xpub {
+            network: NetworkKind::Main,
+            depth: 0,
+            parent_fingerprint: Default::default(),
+            child_number: ChildNumber::Normal { index: 0 },
+            public_key: PublicKey::from_slice(&derive_response.master_pubkey)?,
+            chain_code: ChainCode::from(derive_response.chain_code),
+        };
+        let slot = master.ckd_pub(self.secp(), ChildNumber::Normal { index: 0 })?;
+        Ok(slot.public_key)
+    }
+
+    /// Run every check the protocol intends for `derive` on the current slot: the signature over
+    /// the chain code, the slot pubkey `read` against the one derived from the master pubkey and
+    /// chain code, and its address against the (censored) one the card reported in its status.
+    /// A failed check is reported in the result, not as an error.
+    pub async fn check_derive(&mut self) -> Result<DeriveCheck, Error> {
+        const OPERATION: &str = "check_derive";
+        self.report_progress(OPERATION, "reading the slot pubkey", 1, 2);
+        let slot_pubkey = self.read(None).await?.pubkey(None)?;
+        self.report_progress(OPERATION, "deriving from the master key", 2, 2);
+        let (derive, signature_valid) = self.derive_unverified().await?;
+        let derived_pubkey = self.derive_slot_pubkey(&derive)?;
+
+        let address =
+            Address::p2wpkh(&BitcoinPublicKey(derived_pubkey), self.network()).to_string();
+        Ok(DeriveCheck {
+            slot: self.slots.0,
+            master_pubkey: PublicKey::from_slice(&derive.master_pubkey)?,
+            chain_code: ChainCode::from(derive.chain_code),
+            signature_valid,
+            slot_pubkey,
+            derived_pubkey,
+            address,
+            card_address: self.addr.clone(),
+        })
+    }
 
+    /// Check the current slot's address the way the protocol intends, see
+    /// [`SatsCard::check_derive`], failing on the first check that doesn't pass
+    pub async fn verify_address(&mut self) -> Result<AddressVerification, Error> {
+        let check = self.check_derive().await?;
+        if !check.signature_valid {
+            return Err(secp256k1::Error::IncorrectSignature.into());
+        }
+        if !check.pubkey_matches() {
+            return Err(Error::AddressMismatch(format!(
+                "slot pubkey {read_pubkey} does not match pubkey {derived_pubkey} derived from the card's master key",
+                read_pubkey = check.slot_pubkey,
+                derived_pubkey = check.derived_pubkey
+            )));
+        }
+        if let Some(card_address) = &check.card_address
+            && check.address_matches() == Some(false)
+        {
+            return Err(Error::AddressMismatch(format!(
+                "derived address {address} does not match address {card_address} reported by the card",
+                address = check.address
+            )));
+        }
+
+        Ok(AddressVerification {
+            slot: check.slot,
+            pubkey: check.derived_pubkey,
+            address: check.address,
+        })
+    }
+
+    /// Unseal `slot`, its private key and master private key returned decrypted
+    pub async fn unseal(&mut self, slot: u8, cvc: &Cvc) -> Result<UnsealResponse, Error> {
+        with_reselect!(self, self.unseal_once(slot, cvc).await)
+    }
+
+    async fn unseal_once(&mut self, slot: u8, cvc: &Cvc) -> Result<UnsealResponse, Error> {
+        self.check_required_version()?;
+        let (eprivkey, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, UnsealCommand::name());
+        let unseal_command = UnsealCommand::new(slot, epubkey, xcvc);
+        let mut unseal_response = self
+            .transmit_authenticated(&unseal_command, |card, response: UnsealResponse| {
+                card.advance_card_nonce(UnsealCommand::name(), response.card_nonce)?;
+                Ok(response)
+            })
+            .await?;
+
+        let session_key = SharedSecret::new(&self.pubkey, &eprivkey);
+        decrypt(&mut unseal_response.privkey, &session_key);
+        decrypt(&mut unseal_response.master_pk, &session_key);
         Ok(unseal_response)
     }
 
-    pub async fn dump(&self, slot: usize, cvc: Option<String>) -> Result<DumpResponse, Error> {
-        let epubkey_xcvc = cvc.map(|cvc| {
-            let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(&cvc, DumpCommand::name());
-            (epubkey, xcvc)
-        });
+    /// Details of `slot`. With the CVC the keys of an unsealed slot are included, decrypted.
+    pub async fn dump(&mut self, slot: usize, cvc: Option<&Cvc>) -> Result<DumpResponse, Error> {
+        let ekeys_xcvc = cvc.map(|cvc| self.calc_ekeys_xcvc(cvc, DumpCommand::name()));
 
-        let (epubkey, xcvc) = epubkey_xcvc
-            .map(|(epubkey, xcvc)| (Some(epubkey), Some(xcvc)))
+        let (epubkey, xcvc) = ekeys_xcvc
+            .as_ref()
+            .map(|(_, epubkey, xcvc)| (Some(*epubkey), Some(xcvc.clone())))
             .unwrap_or((None, None));
 
         let dump_command = DumpCommand::new(slot, epubkey, xcvc);
-        self.transport.transmit(&dump_command).await
+        // the card answers with the nonce of the next command, with or without the CVC
+        let verify = |card: &mut Self, response: DumpResponse| {
+            card.advance_card_nonce(DumpCommand::name(), response.card_nonce)?;
+            Ok(response)
+        };
+        let mut dump_response = match ekeys_xcvc {
+            Some(_) => self.transmit_authenticated(&dump_command, verify).await?,
+            None => {
+                let response = self.transport.transmit(&dump_command).await?;
+                verify(self, response)?
+            }
+        };
+
+        if let Some((eprivkey, _, _)) = ekeys_xcvc {
+            let session_key = SharedSecret::new(&self.pubkey, &eprivkey);
+            for key in [&mut dump_response.privkey, &mut dump_response.master_pk]
+                .into_iter()
+                .flatten()
+            {
+                decrypt(key, &session_key);
+            }
+        }
+        Ok(dump_response)
+    }
+
+    /// The network of the card's addresses, testnet if its status says so
+    pub fn network(&self) -> Network {
+        match self.testnet {
+            true => Network::Testnet,
+            false => Network::Bitcoin,
+        }
+    }
+
+    /// The current slot's address, from the pubkey the card signs `read` with. Fails with
+    /// [`Error::AddressMismatch`] if it isn't the (censored) address the card reported in its
+    /// status.
+    pub async fn address(&mut self) -> Result<Address, Error> {
+        let slot_pubkey = self.read(None).await?.pubkey(None)?;
+        let address = Address::p2wpkh(&BitcoinPublicKey(slot_pubkey), self.network());
+        check_card_address(&address, self.addr.as_deref())?;
+        Ok(address)
     }
+}
 
-    pub async fn address(&mut self) -> Result<String, Error> {
-        // TODO: support testnet
-        let network = Network::Bitcoin;
-        let slot_pubkey = self.read(None).await?.pubkey;
-        let pk = BitcoinPublicKey::from_slice(&slot_pubkey)?;
-        let address = Address::p2wpkh(&pk, network);
-        Ok(address.to_string())
+/// Undo the card's encryption of a private key: XOR with the session key
+fn decrypt(key: &mut [u8], session_key: &SharedSecret) {
+    for (byte, key_byte) in key.iter_mut().zip(session_key.as_ref()) {
+        *byte ^= key_byte;
+    }
+}
+
+/// A slot's keys recomputed by [`recover_slot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredSlot {
+    pub privkey: PrivateKey,
+    pub pubkey: PublicKey,
+    pub address: Address,
+}
+
+/// Recompute an unsealed slot's private key (`m/0`), pubkey and address from the decrypted
+/// `master_pk` and the `chain_code` of its `unseal` or `dump` response, without the card
+pub fn recover_slot(
+    master_pk: &[u8],
+    chain_code: &[u8],
+    network: Network,
+) -> Result<RecoveredSlot, Error> {
+    let chain_code = <[u8; 32]>::try_from(chain_code).map_err(|_| {
+        Error::CiborValue(format!(
+            "invalid chain code length {len}",
+            len = chain_code.len()
+        ))
+    })?;
+    let master = Xpriv {
+        network: NetworkKind::from(network),
+        depth: 0,
+        parent_fingerprint: Default::default(),
+        child_number: ChildNumber::Normal { index: 0 },
+        private_key: SecretKey::from_slice(master_pk)?,
+        chain_code: ChainCode::from(chain_code),
+    };
+    let secp = Secp256k1::new();
+    let slot = master.derive_priv(&secp, &[ChildNumber::Normal { index: 0 }])?;
+    let privkey = PrivateKey::new(slot.private_key, network);
+    let pubkey = slot.private_key.public_key(&secp);
+    Ok(RecoveredSlot {
+        privkey,
+        pubkey,
+        address: Address::p2wpkh(&BitcoinPublicKey(pubkey), network),
+    })
+}
+
+/// Fail with [`Error::AddressMismatch`] unless `address` is the one the card reported, if any
+fn check_card_address(address: &Address, card_address: Option<&str>) -> Result<(), Error> {
+    match card_address {
+        Some(card_address) if !matches_censored_address(&address.to_string(), card_address) => {
+            Err(Error::AddressMismatch(format!(
+                "slot address {address} does not match address {card_address} reported by the card"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The card reports its address with the middle replaced by underscores, compare what is left
+fn matches_censored_address(address: &str, censored: &str) -> bool {
+    match (censored.find('_'), censored.rfind('_')) {
+        (Some(start), Some(end)) => {
+            address.starts_with(&censored[..start]) && address.ends_with(&censored[end + 1..])
+        }
+        _ => address == censored,
     }
 }
 
@@ -162,19 +523,28 @@ impl<T: CkTransport> Read<T> for SatsCard<T> {
 
 impl<T: CkTransport> Certificate<T> for SatsCard<T> {
     fn message_digest(&mut self, card_nonce: [u8; 16], app_nonce: [u8; 16]) -> Message {
-        let mut message_bytes: Vec<u8> = Vec::new();
-        message_bytes.extend("OPENDIME".as_bytes());
-        message_bytes.extend(card_nonce);
-        message_bytes.extend(app_nonce);
         if self.ver != "0.9.0" {
             // Since this calls an async method, for now we don't include the pubkey
             // A better solution would be to store the pubkey
             // let pubkey = self.read(None).await.unwrap().pubkey;
-            // message_bytes.extend(pubkey);
+            // and add it to the digested parts
         }
 
-        let message_bytes_hash = sha256::Hash::hash(message_bytes.as_slice());
-        Message::from_digest(message_bytes_hash.to_byte_array())
+        opendime_digest(&[&card_nonce, &app_nonce])
+    }
+}
+
+/// The card's [`StatusSummary`] on one line
+impl<T: CkTransport> core::fmt::Display for SatsCard<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.status_summary().fmt(f)
+    }
+}
+
+/// Serialized as its [`StatusSummary`]
+impl<T: CkTransport> serde::Serialize for SatsCard<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.status_summary().serialize(serializer)
     }
 }
 
@@ -192,3 +562,44 @@ impl<T: CkTransport> core::fmt::Debug for SatsCard<T> {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_censored_address() {
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        assert!(matches_censored_address(address, "bc1qw508d6___xw7kv8f3t4"));
+        assert!(matches_censored_address(address, address));
+        assert!(!matches_censored_address(
+            address,
+            "bc1qxxxxxx___xw7kv8f3t4"
+        ));
+        assert!(!matches_censored_address(
+            address,
+            "bc1qw508d6___xw7kv8f3t5"
+        ));
+    }
+
+    #[test]
+    fn test_check_card_address() {
+        let secp = Secp256k1::new();
+        let pubkey = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[1; 32]).unwrap());
+        let mainnet = Address::p2wpkh(&BitcoinPublicKey(pubkey), Network::Bitcoin);
+        let testnet = Address::p2wpkh(&BitcoinPublicKey(pubkey), Network::Testnet);
+        let mainnet_text = mainnet.to_string();
+        let censored = format!(
+            "{start}___{end}",
+            start = &mainnet_text[..10],
+            end = &mainnet_text[mainnet_text.len() - 10..]
+        );
+
+        assert!(check_card_address(&mainnet, None).is_ok());
+        assert!(check_card_address(&mainnet, Some(&censored)).is_ok());
+        assert!(matches!(
+            check_card_address(&testnet, Some(&censored)),
+            Err(Error::AddressMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Shareable links proving a SATSCARD slot is sealed, in the style of the URL the card itself
+//! hands to phones over NFC (`getsatscard.com/start#u=S&o=0&r=…&n=…&s=…`): a merchant reads
+//! the card once, and anyone opening the link can check the card signed a fresh `read` with the
+//! key of the slot's address, without the card.
+//!
+//! The fragment holds the slot state (`u=S`, the card only signs `read` with the key of its
+//! current slot, which is sealed), the slot (`o`), its address (`r`), the card and app nonces
+//! (`n`) and the card's signature over them (`s`). The pubkey isn't in the link, it's recovered
+//! from the signature and must lead to the address.
+
+use bitcoin::hashes::hex::{DisplayHex as _, FromHex as _};
+use bitcoin::key::CompressedPublicKey;
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId, Signature};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bitcoin::{Address, Network};
+
+use crate::apdu::{CommandApdu as _, Error, ReadCommand, ReadResponse};
+use crate::commands::{Authentication as _, CkTransport, opendime_digest};
+use crate::sats_card::SatsCard;
+
+/// Where the links point to when no other base URL is given
+pub const DEFAULT_BASE_URL: &str = "https://getsatscard.com/start";
+
+/// What a slot link proves, see [`SatsCard::slot_proof`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotProof {
+    pub slot: u8,
+    pub address: String,
+    pub card_nonce: [u8; 16],
+    pub app_nonce: [u8; 16],
+    /// The slot key's signature of the `read` digest of the nonces and slot
+    pub signature: Signature,
+}
+
+impl SlotProof {
+    /// The link, `base` followed by the fragment
+    pub fn url(&self, base: &str) -> String {
+        format!(
+            "{base}#u=S&o={slot}&r={address}&n={card_nonce}{app_nonce}&s={signature}",
+            slot = self.slot,
+            address = self.address,
+            card_nonce = self.card_nonce.as_hex(),
+            app_nonce = self.app_nonce.as_hex(),
+            signature = self.signature.serialize_compact().as_hex(),
+        )
+    }
+
+    /// Read the proof back from a link made by [`SlotProof::url`], whatever its base
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidUrl(format!("{reason} in {url}"));
+        let (_, fragment) = url.split_once('#').ok_or_else(|| invalid("no fragment"))?;
+        let field = |name: &str| {
+            fragment
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .ok_or_else(|| invalid(&format!("no `{name}`")))
+        };
+        if field("u")? != "S" {
+            return Err(invalid("not a sealed slot"));
+        }
+        let slot = field("o")?.parse().map_err(|_| invalid("invalid slot"))?;
+        let nonces = <[u8; 32]>::from_hex(field("n")?).map_err(|_| invalid("invalid nonces"))?;
+        let signature =
+            <[u8; 64]>::from_hex(field("s")?).map_err(|_| invalid("invalid signature"))?;
+        let ([card_nonce, app_nonce], []) = nonces.as_chunks::<16>() else {
+            return Err(invalid("invalid nonces"));
+        };
+        Ok(SlotProof {
+            slot,
+            address: field("r")?.to_string(),
+            card_nonce: *card_nonce,
+            app_nonce: *app_nonce,
+            signature: Signature::from_compact(&signature)?,
+        })
+    }
+
+    /// Check the slot key signed the nonces and slot, and its address is the one of the link.
+    /// Returns the slot pubkey.
+    pub fn verify(&self, network: Network) -> Result<PublicKey, Error> {
+        let secp = Secp256k1::verification_only();
+        let digest = opendime_digest(&[&self.card_nonce, &self.app_nonce, &[self.slot]]);
+        let compact = self.signature.serialize_compact();
+        (0..4)
+            .filter_map(|id| {
+                let id = RecoveryId::from_i32(id).ok()?;
+                let signature = RecoverableSignature::from_compact(&compact, id).ok()?;
+                secp.recover_ecdsa(&digest, &signature).ok()
+            })
+            .find(|pubkey| {
+                Address::p2wpkh(&CompressedPublicKey(*pubkey), network).to_string() == self.address
+            })
+            .ok_or_else(|| {
+                Error::AddressMismatch(format!(
+                    "the signature isn't by the key of {address} on {network}",
+                    address = self.address
+                ))
+            })
+    }
+}
+
+impl<T: CkTransport> SatsCard<T> {
+    /// Have the card sign a fresh `read` with the key of its current slot, for a link proving
+    /// the slot's address is sealed on this card, see [`SlotProof::url`]
+    pub async fn slot_proof(&mut self, network: Network) -> Result<SlotProof, Error> {
+        let card_nonce = *self.card_nonce();
+        let app_nonce = self.entropy().nonce();
+        let read: ReadResponse = self
+            .transport()
+            .transmit(&ReadCommand::unauthenticated(app_nonce))
+            .await?;
+        self.advance_card_nonce(ReadCommand::name(), read.card_nonce)?;
+
+        let pubkey = read.pubkey(None)?;
+        let proof = SlotProof {
+            slot: self.slots.0,
+            address: Address::p2wpkh(&CompressedPublicKey(pubkey), network).to_string(),
+            card_nonce,
+            app_nonce,
+            signature: read.signature()?,
+        };
+        // the same check anyone opening the link makes
+        proof.verify(network)?;
+        Ok(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::SecretKey;
+
+    #[test]
+    fn test_slot_url() -> Result<(), Error> {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[7; 32])?;
+        let pubkey = key.public_key(&secp);
+        let (card_nonce, app_nonce) = ([1; 16], [2; 16]);
+        let digest = opendime_digest(&[&card_nonce, &app_nonce, &[3]]);
+        let proof = SlotProof {
+            slot: 3,
+            address: Address::p2wpkh(&CompressedPublicKey(pubkey), Network::Bitcoin).to_string(),
+            card_nonce,
+            app_nonce,
+            signature: secp.sign_ecdsa(&digest, &key),
+        };
+
+        let url = proof.url(DEFAULT_BASE_URL);
+        assert!(url.starts_with("https://getsatscard.com/start#u=S&o=3&r=bc1q"));
+        assert!(url.contains(&format!("&n={}{}&s=", "01".repeat(16), "02".repeat(16))));
+        let parsed = SlotProof::from_url(&url)?;
+        assert_eq!(parsed, proof);
+        assert_eq!(parsed.verify(Network::Bitcoin)?, pubkey);
+
+        // another slot, address or network than the card signed for
+        let other_slot = SlotProof {
+            slot: 4,
+            ..proof.clone()
+        };
+        assert!(other_slot.verify(Network::Bitcoin).is_err());
+        assert!(proof.verify(Network::Testnet).is_err());
+        let other_address = url.replace("&o=3&r=bc1q", "&o=3&r=bc1p");
+        assert!(
+            SlotProof::from_url(&other_address)?
+                .verify(Network::Bitcoin)
+                .is_err()
+        );
+
+        assert!(SlotProof::from_url(DEFAULT_BASE_URL).is_err());
+        assert!(SlotProof::from_url(&url.replace("u=S", "u=U")).is_err());
+        assert!(SlotProof::from_url(&url.replace("&s=", "&s=00")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! ISO 7816-4 status words (SW1 SW2) ending every response APDU. Only `9000` carries a card
+//! answer as is: `61xx` says more of it waits to be fetched with GET RESPONSE, `6Cxx` asks for the
+//! command again with the right Le, and the others report why the command was refused.
+
+use crate::apdu::Error;
+use alloc::format;
+use alloc::vec::Vec;
+
+/// Command fetching the rest of a response after `61xx`, without its Le
+pub const GET_RESPONSE: [u8; 4] = [0x00, 0xC0, 0x00, 0x00];
+
+/// GET RESPONSEs sent for one answer before giving up on a reader that never says `9000`. Card
+/// answers are a few hundred bytes, this covers them in 16 byte chunks.
+pub const MAX_GET_RESPONSES: usize = 64;
+
+/// What the status word of a response asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusWord {
+    /// `9000`: the body is the (rest of the) answer
+    Ok,
+    /// `61xx`: xx more bytes (0 for 256 or more) to fetch with GET RESPONSE
+    MoreData(u8),
+    /// `6Cxx`: send the command again with Le xx
+    WrongLe(u8),
+    /// The command was refused
+    Error(SwError),
+}
+
+impl From<u16> for StatusWord {
+    fn from(sw: u16) -> Self {
+        let [sw1, sw2] = sw.to_be_bytes();
+        match (sw1, sw2) {
+            (0x90, 0x00) => StatusWord::Ok,
+            (0x61, remaining) => StatusWord::MoreData(remaining),
+            (0x6C, le) => StatusWord::WrongLe(le),
+            _ => StatusWord::Error(SwError::from(sw)),
+        }
+    }
+}
+
+/// Status word of a refused command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SwError {
+    #[error("wrong length (6700)")]
+    WrongLength,
+    #[error("security status not satisfied (6982)")]
+    SecurityStatusNotSatisfied,
+    #[error("conditions of use not satisfied (6985)")]
+    ConditionsNotSatisfied,
+    /// Usually the applet isn't selected, e.g. after the card lost power
+    #[error("applet or file not found (6A82)")]
+    NotFound,
+    #[error("incorrect P1 or P2 (6A86)")]
+    IncorrectP1P2,
+    #[error("instruction not supported (6D00)")]
+    InsNotSupported,
+    #[error("class not supported (6E00)")]
+    ClaNotSupported,
+    #[error("no precise diagnosis (6F00)")]
+    NoDiagnosis,
+    #[error("status word {0:04X}")]
+    Other(u16),
+}
+
+impl From<u16> for SwError {
+    fn from(sw: u16) -> Self {
+        match sw {
+            0x6700 => SwError::WrongLength,
+            0x6982 => SwError::SecurityStatusNotSatisfied,
+            0x6985 => SwError::ConditionsNotSatisfied,
+            0x6A82 => SwError::NotFound,
+            0x6A86 => SwError::IncorrectP1P2,
+            0x6D00 => SwError::InsNotSupported,
+            0x6E00 => SwError::ClaNotSupported,
+            0x6F00 => SwError::NoDiagnosis,
+            sw => SwError::Other(sw),
+        }
+    }
+}
+
+/// Split a response APDU into its body and status word, `None` if it is too short to have one
+pub fn split(rapdu: &[u8]) -> Option<(&[u8], StatusWord)> {
+    let (body, &[sw1, sw2]) = rapdu.split_last_chunk::<2>()?;
+    Some((body, StatusWord::from(u16::from_be_bytes([sw1, sw2]))))
+}
+
+/// Collects the answer to a command across the exchanges its status words ask for, e.g. the
+/// chunks of a reader splitting long answers with `61xx`:
+///
+/// ```ignore
+/// let mut reader = ResponseReader::new(&command_apdu);
+/// let mut rapdu = send(command_apdu.clone())?;
+/// while let Some(next) = reader.push(&rapdu)? {
+///     rapdu = send(next)?;
+/// }
+/// let body = reader.into_body();
+/// ```
+#[derive(Debug)]
+pub struct ResponseReader<'a> {
+    command_apdu: &'a [u8],
+    body: Vec<u8>,
+    retried: bool,
+    fetched: usize,
+}
+
+impl<'a> ResponseReader<'a> {
+    pub fn new(command_apdu: &'a [u8]) -> Self {
+        Self {
+            command_apdu,
+            body: Vec::new(),
+            retried: false,
+            fetched: 0,
+        }
+    }
+
+    /// Take the next response APDU. Returns the APDU to send next, or `None` once the answer is
+    /// complete.
+    pub fn push(&mut self, rapdu: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let (body, sw) = split(rapdu)
+            .ok_or_else(|| Error::Ccid(format!("response without status word: {rapdu:02x?}")))?;
+        match sw {
+            StatusWord::Ok => {
+                self.body.extend_from_slice(body);
+                Ok(None)
+            }
+            StatusWord::WrongLe(le) if !self.retried && self.fetched == 0 => {
+                self.retried = true;
+                Ok(Some(with_le(self.command_apdu, le)))
+            }
+            StatusWord::WrongLe(_) => Err(Error::StatusWord(SwError::WrongLength)),
+            StatusWord::MoreData(remaining) if self.fetched < MAX_GET_RESPONSES => {
+                self.fetched += 1;
+                self.body.extend_from_slice(body);
+                Ok(Some(get_response(remaining)))
+            }
+            StatusWord::MoreData(_) => Err(Error::Ccid(format!(
+                "answer still incomplete after {MAX_GET_RESPONSES} GET RESPONSEs"
+            ))),
+            StatusWord::Error(e) => Err(Error::StatusWord(e)),
+        }
+    }
+
+    /// The answer, without the status words
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
+
+/// GET RESPONSE for the `remaining` bytes announced by `61xx`
+pub fn get_response(remaining: u8) -> Vec<u8> {
+    let mut apdu = GET_RESPONSE.to_vec();
+    apdu.push(remaining);
+    apdu
+}
+
+/// `command_apdu` again with the Le the card asked for with `6Cxx`
+pub fn with_le(command_apdu: &[u8], le: u8) -> Vec<u8> {
+    let mut apdu = command_apdu.to_vec();
+    apdu.push(le);
+    apdu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split(&[0xa0, 0x90, 0x00]),
+            Some((&[0xa0][..], StatusWord::Ok))
+        );
+        assert_eq!(
+            split(&[0x61, 0x10]),
+            Some((&[][..], StatusWord::MoreData(0x10)))
+        );
+        assert_eq!(
+            split(&[0x6C, 0x20]).map(|(_, sw)| sw),
+            Some(StatusWord::WrongLe(0x20))
+        );
+        assert_eq!(
+            split(&[0x6A, 0x82]).map(|(_, sw)| sw),
+            Some(StatusWord::Error(SwError::NotFound))
+        );
+        assert_eq!(
+            split(&[0x63, 0xC2]).map(|(_, sw)| sw),
+            Some(StatusWord::Error(SwError::Other(0x63C2)))
+        );
+        assert_eq!(split(&[0x90]), None);
+        assert_eq!(get_response(0), [0x00, 0xC0, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_response_reader() -> Result<(), Error> {
+        let command = [0x00, 0xCB, 0x00, 0x00, 0x01, 0xa0];
+
+        let mut reader = ResponseReader::new(&command);
+        assert_eq!(reader.push(&[0x6C, 0x10])?, Some(with_le(&command, 0x10)));
+        assert_eq!(reader.push(&[1, 2, 0x61, 0x04])?, Some(get_response(4)));
+        assert_eq!(reader.push(&[3, 4, 0x61, 0x02])?, Some(get_response(2)));
+        assert_eq!(reader.push(&[5, 6, 0x90, 0x00])?, None);
+        assert_eq!(reader.into_body(), [1, 2, 3, 4, 5, 6]);
+
+        let mut reader = ResponseReader::new(&command);
+        for _ in 0..MAX_GET_RESPONSES {
+            assert!(reader.push(&[0x61, 0x00])?.is_some());
+        }
+        assert!(reader.push(&[0x61, 0x00]).is_err());
+
+        let mut reader = ResponseReader::new(&command);
+        assert_eq!(
+            reader.push(&[0x6D, 0x00]),
+            Err(Error::StatusWord(SwError::InsNotSupported))
+        );
+        assert!(reader.push(&[0x90]).is_err());
+        Ok(())
+    }
+}
@@ -1,16 +1,28 @@
-use bitcoin::secp256k1::{
-    self, All, Message, PublicKey, Secp256k1,
-    ecdsa::Signature,
-    hashes::{Hash as _, sha256},
-};
+use bitcoin::NetworkKind;
+use bitcoin::bip32::{ChainCode, ChildNumber, DerivationPath, Fingerprint, KeySource, Xpub};
+use bitcoin::hashes::{Hash as _, hash160};
+use bitcoin::secp256k1::{self, All, Message, PublicKey, Secp256k1, ecdsa::Signature};
 use log::error;
+use std::collections::BTreeMap;
 
+use crate::StatusSummary;
 use crate::apdu::{
     CommandApdu as _, DeriveCommand, DeriveResponse, Error, NewCommand, NewResponse, SignCommand,
     SignResponse, StatusCommand, StatusResponse,
-    tap_signer::{BackupCommand, BackupResponse, ChangeCommand, ChangeResponse},
+    tap_signer::{
+        BackupCommand, BackupResponse, ChangeCommand, ChangeResponse, XpubCommand, XpubResponse,
+    },
+};
+use crate::backup_policy::BackupPolicy;
+use crate::commands::{
+    AppletDeselected, Authentication, Certificate, CkTransport, Read, Wait, check_same_card,
+    opendime_digest, with_reselect,
 };
-use crate::commands::{Authentication, Certificate, CkTransport, Read, Wait};
+use crate::cvc::Cvc;
+use crate::entropy::{EntropySource, ThreadRngSource};
+use crate::progress::ProgressListener;
+use crate::psbt::{InputScriptType, UnsupportedInput, analyze_psbt};
+use crate::version::{Feature, FirmwareVersion};
 
 pub struct TapSigner<T: CkTransport> {
     pub transport: T,
@@ -24,6 +36,27 @@ pub struct TapSigner<T: CkTransport> {
     pub pubkey: PublicKey,
     pub card_nonce: [u8; 16],
     pub auth_delay: Option<usize>,
+    pub entropy: Box<dyn EntropySource>,
+    /// Listener for the steps of multi round-trip operations, see [`TapSigner::with_progress`]
+    pub progress: Option<Box<dyn ProgressListener>>,
+    /// Firmware baseline for the commands changing the card, see [`TapSigner::require_version`]
+    pub required_version: Option<FirmwareVersion>,
+    /// Xpub at `path` as learned from `derive` or `xpub`, the keys `sign` must use derive from it
+    pub path_xpub: Option<Xpub>,
+    /// Sees `num_backups` before each signature, see [`TapSigner::with_backup_policy`]
+    pub backup_policy: Option<Box<dyn BackupPolicy>>,
+}
+
+/// Key derived by [`TapSigner::derive`], ready for PSBTs and descriptors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivedKey {
+    /// Key at the derived path. The card doesn't give the parent key, so the parent fingerprint is
+    /// only set one level below the master key, [`TapSigner::xpub`] has it at any depth.
+    pub xpub: Xpub,
+    /// Fingerprint of the master key and the path from it
+    pub origin: KeySource,
+    /// The master key (`m`)
+    pub master_pubkey: PublicKey,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
@@ -35,6 +68,12 @@ pub enum TapSignerError {
     CvcChangeError(#[from] CvcChangeError),
 }
 
+impl AppletDeselected for TapSignerError {
+    fn applet_deselected(&self) -> bool {
+        matches!(self, TapSignerError::ApduError(e) if e.applet_deselected())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
 pub enum CvcChangeError {
     #[error("new cvc is too short, must be at least 6 bytes, was only {0} bytes")]
@@ -75,6 +114,9 @@ pub enum PsbtSignError {
 
     #[error("Invalid path at index: {0}")]
     InvalidPath(usize),
+
+    #[error("Unsupported inputs: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    UnsupportedInputs(Vec<UnsupportedInput>),
 }
 
 impl<T: CkTransport> Authentication<T> for TapSigner<T> {
@@ -105,6 +147,14 @@ impl<T: CkTransport> Authentication<T> for TapSigner<T> {
     fn transport(&self) -> &T {
         &self.transport
     }
+
+    fn entropy(&self) -> &dyn EntropySource {
+        self.entropy.as_ref()
+    }
+
+    fn progress(&self) -> Option<&dyn ProgressListener> {
+        self.progress.as_deref()
+    }
 }
 
 impl<T: CkTransport> TapSigner<T> {
@@ -123,26 +173,136 @@ impl<T: CkTransport> TapSigner<T> {
             pubkey,
             card_nonce: status_response.card_nonce,
             auth_delay: status_response.auth_delay,
+            entropy: Box::new(ThreadRngSource),
+            progress: None,
+            required_version: None,
+            path_xpub: None,
+            backup_policy: None,
         })
     }
 
+    /// Use `entropy` instead of the thread RNG for the nonces and ephemeral keys sent to the card
+    pub fn with_entropy(mut self, entropy: impl EntropySource + 'static) -> Self {
+        self.entropy = Box::new(entropy);
+        self
+    }
+
+    /// Report the steps of multi round-trip operations (certificate checks, PSBT signing) to `listener`
+    pub fn with_progress(mut self, listener: impl ProgressListener + 'static) -> Self {
+        self.progress = Some(Box::new(listener));
+        self
+    }
+
+    /// Have `policy` check the card's backup count before every `sign` command (so every input of
+    /// `sign_psbt`), to remind about backups or refuse to sign without a recent one
+    pub fn with_backup_policy(mut self, policy: impl BackupPolicy + 'static) -> Self {
+        self.backup_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Refuse the commands changing the card (`init`, `derive`, `change` and `backup`) unless its
+    /// firmware is at least `min`, e.g. to keep a fleet on a tested baseline. They fail with
+    /// [`Error::UnsupportedByFirmware`] before anything is sent.
+    pub fn require_version(mut self, min: FirmwareVersion) -> Self {
+        self.required_version = Some(min);
+        self
+    }
+
+    /// The same card over another transport, e.g. wrapped in an enum of the transports an app
+    /// chooses from at runtime
+    pub fn map_transport<U: CkTransport>(self, f: impl FnOnce(T) -> U) -> TapSigner<U> {
+        TapSigner {
+            transport: f(self.transport),
+            secp: self.secp,
+            proto: self.proto,
+            ver: self.ver,
+            birth: self.birth,
+            path: self.path,
+            num_backups: self.num_backups,
+            pubkey: self.pubkey,
+            card_nonce: self.card_nonce,
+            auth_delay: self.auth_delay,
+            entropy: self.entropy,
+            progress: self.progress,
+            required_version: self.required_version,
+            path_xpub: self.path_xpub,
+            backup_policy: self.backup_policy,
+        }
+    }
+
+    fn check_required_version(&self) -> Result<(), Error> {
+        match &self.required_version {
+            Some(min) => min.require(&self.ver),
+            None => Ok(()),
+        }
+    }
+
     /// Initialize the tap signer, can only be done once
     pub async fn init(
         &mut self,
         chain_code: [u8; 32],
-        cvc: &str,
+        cvc: &Cvc,
     ) -> Result<NewResponse, TapSignerError> {
+        with_reselect!(self, self.init_once(chain_code, cvc).await)
+    }
+
+    async fn init_once(
+        &mut self,
+        chain_code: [u8; 32],
+        cvc: &Cvc,
+    ) -> Result<NewResponse, TapSignerError> {
+        self.check_required_version()?;
         let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, NewCommand::name());
 
         let new_command = NewCommand::new(Some(0), Some(chain_code), epubkey, xcvc);
-        let new_response: NewResponse = self.transport.transmit(&new_command).await?;
-
-        self.card_nonce = new_response.card_nonce;
+        let new_response = self
+            .transmit_authenticated(&new_command, |card, response: NewResponse| {
+                card.advance_card_nonce(NewCommand::name(), response.card_nonce)?;
+                Ok(response)
+            })
+            .await?;
+        self.path_xpub = None;
         Ok(new_response)
     }
 
     /// Get the status of the tap signer, including the current card nonce
     pub async fn status(&mut self) -> Result<StatusResponse, Error> {
+        with_reselect!(self, self.status_once().await)
+    }
+
+    /// Run the status command again and take over what it reports (path, number of backups, auth
+    /// delay and nonce), so a long-lived session stays in sync without connecting again. Fails
+    /// with [`Error::WrongCard`] if another card answers.
+    pub async fn refresh_status(&mut self) -> Result<StatusResponse, Error> {
+        let status = self.status().await?;
+        check_same_card(self, &status)?;
+        if status.path != self.path {
+            // learned for the previous path
+            self.path_xpub = None;
+        }
+        self.path = status.path.clone();
+        self.num_backups = status.num_backups;
+        self.auth_delay = status.auth_delay;
+        Ok(status)
+    }
+
+    /// What the card last reported in its status, see [`crate::CkTapCard::status_summary`]
+    pub fn status_summary(&self) -> StatusSummary {
+        StatusSummary {
+            card_type: "tapsigner",
+            ident: self.ident(),
+            proto: self.proto,
+            version: self.ver.clone(),
+            birth: self.birth,
+            slots: None,
+            testnet: false,
+            path: self.path.clone(),
+            num_backups: self.num_backups,
+            auth_delay: self.auth_delay,
+        }
+    }
+
+    async fn status_once(&mut self) -> Result<StatusResponse, Error> {
         let cmd = StatusCommand::default();
         let status_response: StatusResponse = self.transport.transmit(&cmd).await?;
         self.card_nonce = status_response.card_nonce;
@@ -154,7 +314,21 @@ impl<T: CkTransport> TapSigner<T> {
         &mut self,
         digest: [u8; 32],
         sub_path: Vec<u32>,
-        cvc: &str,
+        cvc: &Cvc,
+    ) -> Result<SignResponse, Error> {
+        if let Some(policy) = &self.backup_policy {
+            // a policy goes by num_backups, which older firmware doesn't report
+            Feature::BackupCounter.check(&self.ver)?;
+            policy.before_sign(&self.pubkey, self.num_backups)?;
+        }
+        with_reselect!(self, self.sign_once(digest, sub_path.clone(), cvc).await)
+    }
+
+    async fn sign_once(
+        &mut self,
+        digest: [u8; 32],
+        sub_path: Vec<u32>,
+        cvc: &Cvc,
     ) -> Result<SignResponse, Error> {
         let (eprivkey, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, SignCommand::name());
 
@@ -165,7 +339,7 @@ impl<T: CkTransport> TapSigner<T> {
         let xdigest_vec: Vec<u8> = session_key
             .as_ref()
             .iter()
-            .zip(digest.into_iter())
+            .zip(digest)
             .map(|(session_key_byte, digest_byte)| session_key_byte ^ digest_byte)
             .collect();
 
@@ -176,15 +350,20 @@ impl<T: CkTransport> TapSigner<T> {
         let sign_command =
             SignCommand::for_tapsigner(sub_path.clone(), xdigest, epubkey, xcvc.clone());
 
-        let mut sign_response: Result<SignResponse, Error> =
-            self.transport.transmit(&sign_command).await;
+        let verify = |card: &mut Self, response: SignResponse| {
+            card.advance_card_nonce(SignCommand::name(), response.card_nonce)?;
+            card.verify_signature(&response, digest, &sub_path)?;
+            Ok(response)
+        };
+
+        let mut sign_response = self.transmit_authenticated(&sign_command, verify).await;
 
         let mut unlucky_number_retries = 0;
         while let Err(Error::CkTap(crate::apdu::CkTapError::UnluckyNumber)) = sign_response {
             let sign_command =
                 SignCommand::for_tapsigner(sub_path.clone(), xdigest, epubkey, xcvc.clone());
 
-            sign_response = self.transport.transmit(&sign_command).await;
+            sign_response = self.transmit_authenticated(&sign_command, verify).await;
             unlucky_number_retries += 1;
 
             if unlucky_number_retries > 3 {
@@ -192,18 +371,51 @@ impl<T: CkTransport> TapSigner<T> {
             }
         }
 
-        let sign_response = sign_response?;
-        self.card_nonce = sign_response.card_nonce;
-        Ok(sign_response)
+        sign_response
+    }
+
+    /// Check the card signed `digest` with the key at its path followed by `sub_path`, derived
+    /// from [`TapSigner::path_xpub`]. Until `derive` or `xpub` was used that key is unknown, and
+    /// the signature is only checked against the pubkey the card returned.
+    fn verify_signature(
+        &self,
+        response: &SignResponse,
+        digest: [u8; 32],
+        sub_path: &[u32],
+    ) -> Result<(), Error> {
+        let signed_with = response.pubkey()?;
+        let expected = match &self.path_xpub {
+            Some(xpub) => {
+                let sub_path: Vec<ChildNumber> = sub_path
+                    .iter()
+                    .map(|&index| ChildNumber::from(index))
+                    .collect();
+                xpub.derive_pub(&self.secp, &sub_path)?.public_key
+            }
+            None => signed_with,
+        };
+        if signed_with != expected {
+            return Err(Error::SignatureMismatch(format!(
+                "card signed with {signed_with}, expected key {expected} of its derivation path"
+            )));
+        }
+        let signature = response.signature()?;
+        self.secp
+            .verify_ecdsa(&Message::from_digest(digest), &signature, &expected)
+            .map_err(|_| {
+                Error::SignatureMismatch(format!(
+                    "signature {signature} is not a signature of the digest by {expected}"
+                ))
+            })
     }
 
-    /// Sign a BIP84, currently only P2WPKH (BIP84) (Native SegWit) PSBT
-    /// This function will return a signed but not finalized PSBT. You will need to finalize the
-    /// PSBT yourself before it can be broadcasted.
+    /// Sign a PSBT with P2WPKH (BIP84) or P2SH-P2WPKH (BIP49) inputs
+    /// This function will return a signed but not finalized PSBT. Use
+    /// [`crate::psbt::finalize_psbt`] before it can be broadcasted.
     pub async fn sign_psbt(
         &mut self,
         mut psbt: bitcoin::Psbt,
-        cvc: &str,
+        cvc: &Cvc,
     ) -> Result<bitcoin::Psbt, PsbtSignError> {
         use bitcoin::{
             secp256k1::ecdsa,
@@ -212,10 +424,18 @@ impl<T: CkTransport> TapSigner<T> {
 
         type Error = PsbtSignError;
 
+        // refuse up front rather than leaving the PSBT partially signed
+        let unsupported = analyze_psbt(&psbt, None).unsupported_inputs();
+        if !unsupported.is_empty() {
+            return Err(Error::UnsupportedInputs(unsupported));
+        }
+
         let unsigned_tx = psbt.unsigned_tx.clone();
         let mut sighash_cache = SighashCache::new(&unsigned_tx);
 
+        let total = psbt.inputs.len();
         for (input_index, input) in psbt.inputs.iter_mut().enumerate() {
+            self.report_progress("sign_psbt", "signing input", input_index + 1, total);
             // extract previous output data from the PSBT
             let witness_utxo = input
                 .witness_utxo
@@ -224,11 +444,18 @@ impl<T: CkTransport> TapSigner<T> {
 
             let amount = witness_utxo.value;
 
-            // extract the P2WPKH script from PSBT
-            let script_pubkey = &witness_utxo.script_pubkey;
-            if !script_pubkey.is_p2wpkh() {
-                return Err(Error::InvalidScript(input_index));
-            }
+            // extract the P2WPKH script from PSBT, either native or wrapped in P2SH
+            let script_pubkey = match InputScriptType::classify(
+                &witness_utxo.script_pubkey,
+                input.redeem_script.as_ref(),
+            ) {
+                InputScriptType::P2wpkh => witness_utxo.script_pubkey.clone(),
+                InputScriptType::P2shP2wpkh => input
+                    .redeem_script
+                    .clone()
+                    .ok_or(Error::InvalidScript(input_index))?,
+                _ => return Err(Error::InvalidScript(input_index)),
+            };
 
             // get the public key from the PSBT
             let key_pairs = &input.bip32_derivation;
@@ -259,28 +486,24 @@ impl<T: CkTransport> TapSigner<T> {
             // the digest is the sighash
             let digest: &[u8; 32] = sighash.as_ref();
 
-            // send digest to TAPSIGNER for signing
-            let mut sign_response = self.sign(*digest, sub_path.clone(), cvc).await?;
-            let mut signature_raw = sign_response.sig;
-
-            // verify that TAPSIGNER used the same public key as the PSBT
-            if sign_response.pubkey != psbt_pubkey.serialize() {
-                // try deriving the TAPSIGNER and try again
-                // take the hardened path and remove the the hardened bit, because `sign` hardens it
-                let path: Vec<u32> = path.into_iter().map(|p| p ^ (1 << 31)).take(3).collect();
-                let derive_response = self.derive(&path, cvc).await;
-                if derive_response.is_err() {
+            // the card signs with its current account and the sub path, so switch it to the
+            // account the PSBT names first: the key signing is then the one at the PSBT's path
+            let account: Vec<usize> = path[..3].iter().map(|&p| p as usize).collect();
+            if self.path.as_ref() != Some(&account) {
+                // take the hardened path and remove the the hardened bit, because `derive` hardens it
+                let account: Vec<u32> = path[..3].iter().map(|p| p ^ HARDENED).collect();
+                if self.derive(&account, cvc).await.is_err() {
                     return Err(Error::PubkeyMismatch(input_index));
                 }
+            }
 
-                // update signature to the new one we just derived
-                sign_response = self.sign(*digest, sub_path, cvc).await?;
-                signature_raw = sign_response.sig;
+            // send digest to TAPSIGNER for signing
+            let sign_response = self.sign(*digest, sub_path, cvc).await?;
+            let signature_raw = sign_response.sig;
 
-                // if still not matching, return error
-                if sign_response.pubkey != psbt_pubkey.serialize() {
-                    return Err(Error::PubkeyMismatch(input_index));
-                }
+            // verify that TAPSIGNER used the same public key as the PSBT
+            if sign_response.pubkey != psbt_pubkey.serialize() {
+                return Err(Error::PubkeyMismatch(input_index));
             }
 
             // update the PSBT input with the signature
@@ -295,56 +518,164 @@ impl<T: CkTransport> TapSigner<T> {
     }
 
     /// Derive a public key at the given hardened path
-    pub async fn derive(
-        &mut self,
-        path: &[u32],
-        cvc: &str,
-    ) -> Result<DeriveResponse, TapSignerError> {
+    pub async fn derive(&mut self, path: &[u32], cvc: &Cvc) -> Result<DerivedKey, TapSignerError> {
+        with_reselect!(self, self.derive_once(path, cvc).await)
+    }
+
+    async fn derive_once(&mut self, path: &[u32], cvc: &Cvc) -> Result<DerivedKey, TapSignerError> {
+        self.check_required_version()?;
         // set most significant bit to 1 to represent hardened path steps
-        let path = path.iter().map(|p| p ^ (1 << 31)).collect();
-        let app_nonce = crate::rand_nonce();
+        let path: Vec<u32> = path.iter().map(|p| p ^ (1 << 31)).collect();
+        let app_nonce = self.entropy().nonce();
         let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, DeriveCommand::name());
-        let cmd = DeriveCommand::for_tapsigner(app_nonce, path, epubkey, xcvc);
-        let derive_response: DeriveResponse = self.transport.transmit(&cmd).await?;
-
-        let card_nonce = self.card_nonce();
-        let sig = &derive_response.sig;
-
-        let mut message_bytes: Vec<u8> = Vec::new();
-        message_bytes.extend("OPENDIME".as_bytes());
-        message_bytes.extend(card_nonce);
-        message_bytes.extend(app_nonce);
-        message_bytes.extend(&derive_response.chain_code);
+        let cmd = DeriveCommand::for_tapsigner(app_nonce, path.clone(), epubkey, xcvc);
+        let (derive_response, master_pubkey, pubkey, verified) = self
+            .transmit_authenticated(&cmd, |card, derive_response: DeriveResponse| {
+                let card_nonce = card.card_nonce();
+                let sig = &derive_response.sig;
+
+                let message =
+                    opendime_digest(&[card_nonce, &app_nonce, &derive_response.chain_code]);
+
+                let signature = Signature::from_compact(sig)?;
+                let master_pubkey = PublicKey::from_slice(&derive_response.master_pubkey)?;
+                let pubkey = match &derive_response.pubkey {
+                    Some(pubkey) => PublicKey::from_slice(pubkey)?,
+                    None => master_pubkey,
+                };
+
+                // TODO: actually return as error when we can figure out why its not working on the card
+                let verified = card
+                    .secp()
+                    .verify_ecdsa(&message, &signature, &pubkey)
+                    .is_ok();
+                if !verified {
+                    error!("verify derive command ecdsa signature failed");
+                };
+
+                card.advance_card_nonce(DeriveCommand::name(), derive_response.card_nonce)?;
+                Ok((derive_response, master_pubkey, pubkey, verified))
+            })
+            .await?;
+        let master_fingerprint = fingerprint(&master_pubkey);
+        let xpub = Xpub {
+            network: NetworkKind::Main,
+            depth: path.len() as u8,
+            parent_fingerprint: match path.len() {
+                1 => master_fingerprint,
+                _ => Fingerprint::default(),
+            },
+            child_number: path
+                .last()
+                .map_or(ChildNumber::Normal { index: 0 }, |&index| {
+                    ChildNumber::from(index)
+                }),
+            public_key: pubkey,
+            chain_code: ChainCode::from(derive_response.chain_code),
+        };
+        // the card now signs with the derived key, remember it when the card proved holding it
+        self.path_xpub = (derive_response.pubkey.is_some() && verified).then_some(xpub);
+        let origin = (
+            master_fingerprint,
+            path.iter().map(|&index| ChildNumber::from(index)).collect(),
+        );
+        self.path = Some(path.into_iter().map(|p| p as usize).collect());
+        Ok(DerivedKey {
+            xpub,
+            origin,
+            master_pubkey,
+        })
+    }
 
-        let message_bytes_hash = sha256::Hash::hash(message_bytes.as_slice());
-        let message = Message::from_digest(message_bytes_hash.to_byte_array());
+    /// Get the master (`m`) XPUB, or the XPUB at the currently derived path
+    pub async fn xpub(&mut self, master: bool, cvc: &Cvc) -> Result<Xpub, TapSignerError> {
+        with_reselect!(self, self.xpub_once(master, cvc).await)
+    }
 
-        let signature = Signature::from_compact(sig).map_err(Error::from)?;
-        let pubkey = match &derive_response.pubkey {
-            Some(pubkey) => PublicKey::from_slice(pubkey).map_err(Error::from)?,
-            None => PublicKey::from_slice(&derive_response.master_pubkey).map_err(Error::from)?,
-        };
+    async fn xpub_once(&mut self, master: bool, cvc: &Cvc) -> Result<Xpub, TapSignerError> {
+        Feature::Xpub.check(&self.ver)?;
+        let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, XpubCommand::name());
+        let xpub_command = XpubCommand::new(master, epubkey, xcvc);
+        let xpub = self
+            .transmit_authenticated(&xpub_command, |card, response: XpubResponse| {
+                card.advance_card_nonce(XpubCommand::name(), response.card_nonce)?;
+                Ok(Xpub::decode(&response.xpub)?)
+            })
+            .await?;
+        if !master {
+            self.path_xpub = Some(xpub);
+        }
+        Ok(xpub)
+    }
 
-        // TODO: actually return as error when we can figure out why its not working on the card
-        if self
-            .secp()
-            .verify_ecdsa(&message, &signature, &pubkey)
-            .map_err(Error::from)
-            .is_err()
-        {
-            error!("verify derive command ecdsa signature failed");
-        };
+    /// Derive several hardened paths and read the XPUB at each, e.g. to set up a wallet with
+    /// more than one account. Afterwards the card is switched back to the path it was using.
+    pub async fn derive_many(
+        &mut self,
+        paths: &[Vec<u32>],
+        cvc: &Cvc,
+    ) -> Result<BTreeMap<DerivationPath, Xpub>, TapSignerError> {
+        // take the hardened path and remove the hardened bit, because `derive` hardens it
+        let original_path: Option<Vec<u32>> = self
+            .path
+            .as_ref()
+            .map(|path| path.iter().map(|&p| p as u32 ^ (1 << 31)).collect());
+
+        let xpubs = self.derive_xpubs(paths, cvc).await;
+
+        // a refused CVC would only be spent again on the way back
+        let refused = matches!(
+            &xpubs,
+            Err(TapSignerError::ApduError(Error::CkTap(
+                crate::apdu::CkTapError::BadAuth
+                    | crate::apdu::CkTapError::NeedsAuth
+                    | crate::apdu::CkTapError::RateLimited
+            )))
+        );
+        if let Some(original_path) = original_path.filter(|_| !refused) {
+            let restored = self.derive(&original_path, cvc).await;
+            if let (Ok(_), Err(e)) = (&xpubs, restored) {
+                return Err(e);
+            }
+        }
+        xpubs
+    }
 
-        self.card_nonce = derive_response.card_nonce;
-        Ok(derive_response)
+    async fn derive_xpubs(
+        &mut self,
+        paths: &[Vec<u32>],
+        cvc: &Cvc,
+    ) -> Result<BTreeMap<DerivationPath, Xpub>, TapSignerError> {
+        let mut xpubs = BTreeMap::new();
+        for path in paths {
+            self.derive(path, cvc).await?;
+            let xpub = self.xpub(false, cvc).await?;
+            let path: Vec<ChildNumber> = path
+                .iter()
+                .map(|&index| ChildNumber::Hardened { index })
+                .collect();
+            xpubs.insert(DerivationPath::from(path), xpub);
+        }
+        Ok(xpubs)
     }
 
     /// Change the CVC used for card authentication to a new user provided one
     pub async fn change(
         &mut self,
-        new_cvc: &str,
-        cvc: &str,
+        new_cvc: &Cvc,
+        cvc: &Cvc,
+    ) -> Result<ChangeResponse, TapSignerError> {
+        with_reselect!(self, self.change_once(new_cvc, cvc).await)
+    }
+
+    async fn change_once(
+        &mut self,
+        new_cvc: &Cvc,
+        cvc: &Cvc,
     ) -> Result<ChangeResponse, TapSignerError> {
+        Feature::Change.check(&self.ver)?;
+        self.check_required_version()?;
+
         if new_cvc.len() < 6 {
             return Err(CvcChangeError::TooShort(new_cvc.len()).into());
         }
@@ -367,29 +698,48 @@ impl<T: CkTransport> TapSigner<T> {
         let xnew_cvc: Vec<u8> = session_key
             .as_ref()
             .iter()
-            .zip(new_cvc.as_bytes().iter())
+            .zip(new_cvc.expose_secret().as_bytes())
             .map(|(session_key_byte, cvc_byte)| session_key_byte ^ cvc_byte)
             .collect();
 
         let change_command = ChangeCommand::new(xnew_cvc, epubkey, xcvc);
-        let change_response: ChangeResponse = self.transport.transmit(&change_command).await?;
-
-        self.card_nonce = change_response.card_nonce;
+        let change_response = self
+            .transmit_authenticated(&change_command, |card, response: ChangeResponse| {
+                card.advance_card_nonce(ChangeCommand::name(), response.card_nonce)?;
+                Ok(response)
+            })
+            .await?;
         Ok(change_response)
     }
 
     /// Backup the current card, the backup is encrypted with the "Backup Password" on the back of the card
-    pub async fn backup(&mut self, cvc: &str) -> Result<BackupResponse, TapSignerError> {
+    pub async fn backup(&mut self, cvc: &Cvc) -> Result<BackupResponse, TapSignerError> {
+        with_reselect!(self, self.backup_once(cvc).await)
+    }
+
+    async fn backup_once(&mut self, cvc: &Cvc) -> Result<BackupResponse, TapSignerError> {
+        Feature::Backup.check(&self.ver)?;
+        self.check_required_version()?;
         let (_, epubkey, xcvc) = self.calc_ekeys_xcvc(cvc, "backup");
 
         let backup_command = BackupCommand::new(epubkey, xcvc);
-        let backup_response: BackupResponse = self.transport.transmit(&backup_command).await?;
-
-        self.card_nonce = backup_response.card_nonce;
+        let backup_response = self
+            .transmit_authenticated(&backup_command, |card, response: BackupResponse| {
+                card.advance_card_nonce(BackupCommand::name(), response.card_nonce)?;
+                Ok(response)
+            })
+            .await?;
+        self.num_backups = Some(self.num_backups.unwrap_or_default() + 1);
         Ok(backup_response)
     }
 }
 
+/// BIP-32 fingerprint of `pubkey`, the first 4 bytes of its HASH160
+fn fingerprint(pubkey: &PublicKey) -> Fingerprint {
+    let hash = hash160::Hash::hash(&pubkey.serialize());
+    Fingerprint::from([hash[0], hash[1], hash[2], hash[3]])
+}
+
 impl<T: CkTransport> Wait<T> for TapSigner<T> {}
 
 impl<T: CkTransport> Read<T> for TapSigner<T> {
@@ -404,13 +754,21 @@ impl<T: CkTransport> Read<T> for TapSigner<T> {
 
 impl<T: CkTransport> Certificate<T> for TapSigner<T> {
     fn message_digest(&mut self, card_nonce: [u8; 16], app_nonce: [u8; 16]) -> Message {
-        let mut message_bytes: Vec<u8> = Vec::new();
-        message_bytes.extend("OPENDIME".as_bytes());
-        message_bytes.extend(card_nonce);
-        message_bytes.extend(app_nonce);
+        opendime_digest(&[&card_nonce, &app_nonce])
+    }
+}
+
+/// The card's [`StatusSummary`] on one line
+impl<T: CkTransport> core::fmt::Display for TapSigner<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.status_summary().fmt(f)
+    }
+}
 
-        let message_bytes_hash = sha256::Hash::hash(message_bytes.as_slice());
-        Message::from_digest(message_bytes_hash.to_byte_array())
+/// Serialized as its [`StatusSummary`]
+impl<T: CkTransport> serde::Serialize for TapSigner<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.status_summary().serialize(serializer)
     }
 }
 
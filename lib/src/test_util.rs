@@ -0,0 +1,21 @@
+//! Transports shared by the unit tests
+
+use crate::apdu::Error;
+use crate::commands::CkTransport;
+
+/// Answers every command with a `401` bad auth error
+pub(crate) struct BadAuthTransport;
+
+impl CkTransport for BadAuthTransport {
+    async fn transmit_apdu(&self, _command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        // {"error": "bad auth", "code": 401}
+        let mut response = vec![0xa2, 0x65];
+        response.extend(b"error");
+        response.push(0x68);
+        response.extend(b"bad auth");
+        response.push(0x64);
+        response.extend(b"code");
+        response.extend([0x19, 0x01, 0x91, 0x90, 0x00]);
+        Ok(response)
+    }
+}
@@ -0,0 +1,215 @@
+//! Session transcript, for bug reports about reader specific failures.
+//!
+//! The APDUs exchanged with the card, the CCID framing around them and transport errors are
+//! logged at `trace` level under the [`TARGET`] log target, with secrets (the encrypted CVC,
+//! private keys, backups and chain codes) replaced by their length. Applications route that
+//! target to a file to record a session.
+//!
+//! The debug logs redact APDUs the same way, unless [`set_log_secrets`] turns that off.
+
+use crate::apdu::RawResponse;
+use bitcoin::secp256k1::hashes::hex::DisplayHex;
+use ciborium::de::from_reader;
+use ciborium::value::Value;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Log target of the transcript records
+pub const TARGET: &str = "cktap_direct::transcript";
+
+/// CBOR map keys holding values that must not end up in a transcript
+const SECRET_KEYS: &[&str] = &["xcvc", "privkey", "data", "chain_code"];
+
+/// Whether the debug logs show APDUs in full
+static LOG_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Show APDUs in full in the debug logs, secrets included, for protocol debugging against the
+/// emulator. The transcript stays redacted.
+pub fn set_log_secrets(log_secrets: bool) {
+    LOG_SECRETS.store(log_secrets, Ordering::Relaxed);
+}
+
+/// A command APDU for the debug logs, redacted like in the transcript unless [`set_log_secrets`]
+pub(crate) fn log_command(apdu: &[u8]) -> String {
+    if LOG_SECRETS.load(Ordering::Relaxed) {
+        apdu.as_hex().to_string()
+    } else {
+        command(apdu)
+    }
+}
+
+/// A response APDU for the debug logs, redacted like in the transcript unless [`set_log_secrets`]
+pub(crate) fn log_response(rapdu: &[u8]) -> String {
+    if LOG_SECRETS.load(Ordering::Relaxed) {
+        rapdu.as_hex().to_string()
+    } else {
+        response(rapdu)
+    }
+}
+
+/// Raw bytes carrying APDUs (e.g. CCID frames) for the debug logs, only their length unless
+/// [`set_log_secrets`]
+#[cfg(feature = "usb")]
+pub(crate) fn log_bytes(bytes: &[u8]) -> String {
+    if LOG_SECRETS.load(Ordering::Relaxed) {
+        bytes.as_hex().to_string()
+    } else {
+        format!("<{len} bytes>", len = bytes.len())
+    }
+}
+
+/// Length of the CLA INS P1 P2 Lc header before the CBOR data of a command APDU
+const HEADER_LEN: usize = 5;
+
+/// Describe a command APDU: the header in hex followed by the CBOR command with secrets redacted,
+/// or all of it in hex if it isn't a CBOR command (e.g. the applet select)
+pub fn command(apdu: &[u8]) -> String {
+    if apdu.len() > HEADER_LEN
+        && let Ok(cbor) = from_reader::<Value, _>(&apdu[HEADER_LEN..])
+    {
+        let mut out = format!("{header} ", header = apdu[..HEADER_LEN].as_hex());
+        diag(&cbor, &mut out);
+        return out;
+    }
+    apdu.as_hex().to_string()
+}
+
+/// Describe a response APDU: the CBOR response with secrets redacted and the status word, or
+/// all of it in hex if it isn't CBOR
+pub fn response(rapdu: &[u8]) -> String {
+    let response = RawResponse::parse(rapdu);
+    let mut out = String::new();
+    match &response.cbor {
+        Some(cbor) => diag(cbor, &mut out),
+        None => out.push_str(&response.body.as_hex().to_string()),
+    }
+    if let Some(sw) = response.sw {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        let _ = write!(out, "sw={sw:04x}");
+    }
+    out
+}
+
+/// Write a CBOR value in diagnostic notation, masking the values of [`SECRET_KEYS`]
+fn diag(value: &Value, out: &mut String) {
+    match value {
+        Value::Integer(i) => {
+            let _ = write!(out, "{i}", i = i128::from(*i));
+        }
+        Value::Bytes(bytes) => {
+            let _ = write!(out, "h'{}'", bytes.as_hex());
+        }
+        Value::Float(f) => {
+            let _ = write!(out, "{f}");
+        }
+        Value::Text(text) => {
+            let _ = write!(out, "{text:?}");
+        }
+        Value::Bool(b) => {
+            let _ = write!(out, "{b}");
+        }
+        Value::Null => out.push_str("null"),
+        Value::Tag(tag, value) => {
+            let _ = write!(out, "{tag}(");
+            diag(value, out);
+            out.push(')');
+        }
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                diag(value, out);
+            }
+            out.push(']');
+        }
+        Value::Map(entries) => {
+            out.push('{');
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                diag(key, out);
+                out.push_str(": ");
+                match key {
+                    Value::Text(key) if SECRET_KEYS.contains(&key.as_str()) => {
+                        let _ = write!(out, "<redacted {len} bytes>", len = redacted_len(value));
+                    }
+                    _ => diag(value, out),
+                }
+            }
+            out.push('}');
+        }
+        _ => out.push('?'),
+    }
+}
+
+fn redacted_len(value: &Value) -> usize {
+    match value {
+        Value::Bytes(bytes) => bytes.len(),
+        Value::Text(text) => text.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu::{AppletSelect, CommandApdu, Error, ReadCommand, UnsealCommand};
+    use bitcoin::secp256k1::PublicKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_transcript_redaction() -> Result<(), Error> {
+        let select = AppletSelect::default().apdu_bytes()?;
+        assert_eq!(command(&select), select.as_hex().to_string());
+
+        let read = ReadCommand::unauthenticated([0xaa; 16]).apdu_bytes()?;
+        let described = command(&read);
+        assert!(described.starts_with("00cb0000"));
+        assert!(described.contains(r#""cmd": "read""#));
+        assert!(described.contains(&format!("h'{}'", [0xaa; 16].as_hex())));
+
+        let epubkey = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )?;
+        let unseal = UnsealCommand::new(0, epubkey, vec![0x55; 6]).apdu_bytes()?;
+        let described = command(&unseal);
+        assert!(described.contains(r#""xcvc": <redacted 6 bytes>"#));
+        assert!(!described.contains("555555"));
+
+        let mut rapdu = Vec::new();
+        let body = Value::Map(vec![
+            (Value::from("slot"), Value::from(0)),
+            (Value::from("privkey"), Value::Bytes(vec![0x11; 32])),
+        ]);
+        ciborium::ser::into_writer(&body, &mut rapdu).expect("serialize");
+        rapdu.extend([0x90, 0x00]);
+        assert_eq!(
+            response(&rapdu),
+            r#"{"slot": 0, "privkey": <redacted 32 bytes>} sw=9000"#
+        );
+        assert_eq!(response(&[0x6a, 0x82]), "sw=6a82");
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_secrets() -> Result<(), Error> {
+        let epubkey = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )?;
+        let unseal = UnsealCommand::new(0, epubkey, vec![0x55; 6]).apdu_bytes()?;
+        assert!(!log_command(&unseal).contains("555555"));
+        #[cfg(feature = "usb")]
+        assert_eq!(log_bytes(&unseal), format!("<{} bytes>", unseal.len()));
+
+        set_log_secrets(true);
+        let logged = log_command(&unseal);
+        set_log_secrets(false);
+        assert!(logged.contains("555555"));
+        Ok(())
+    }
+}
@@ -1,10 +1,33 @@
 use crate::Error;
-use crate::ccid::{CcidCommand, CcidResponse, SlotError, SlotStatus, VoltageSelection};
-use crate::commands::CkTransport;
+use crate::acr122u;
+use crate::apdu::ParseMode;
+use crate::ccid::{
+    self, CcidCommand, CcidDescriptor, CcidResponse, LevelParameter, SlotError, SlotStatus,
+    VoltageSelection,
+};
+use crate::commands::{CkTransport, yield_now};
+use crate::reader_lock::ReaderLock;
+use crate::transcript;
 use rusb::{Context, DeviceHandle};
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::time::Duration;
 
+/// How long a USB transfer may take before it fails, unless set with [`UsbTransport::with_timeout`]
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers to earlier commands dropped while waiting for the one to the command just sent
+const MAX_STALE_RESPONSES: usize = 8;
+
+/// How APDUs reach the card
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// In XfrBlock commands, for readers that handle contactless cards themselves
+    #[default]
+    Ccid,
+    /// Wrapped in PN532 commands, see [`crate::acr122u`]
+    Acr122u,
+}
+
 /// USB CCID transport implementation
 pub struct UsbTransport {
     device: DeviceHandle<Context>,
@@ -13,6 +36,16 @@ pub struct UsbTransport {
     endpoint_in: u8,
     sequence: AtomicU8,
     timeout: Duration,
+    /// keeps other processes off the reader while this transport is open
+    lock: Option<ReaderLock>,
+    /// the kernel driver was detached to claim the interface, and is given back on drop
+    reattach_kernel_driver: bool,
+    framing: Framing,
+    /// the ACR122U activated the card, so APDUs can be exchanged with it
+    target_active: AtomicBool,
+    /// dwMaxCCIDMessageLength of the reader, longer APDUs are sent in several XfrBlocks
+    max_message_length: u32,
+    parse_mode: ParseMode,
 }
 
 impl UsbTransport {
@@ -29,17 +62,87 @@ impl UsbTransport {
             endpoint_out,
             endpoint_in,
             sequence: AtomicU8::new(0),
-            timeout: Duration::from_secs(5),
+            timeout: DEFAULT_TIMEOUT,
+            lock: None,
+            reattach_kernel_driver: false,
+            framing: Framing::Ccid,
+            target_active: AtomicBool::new(false),
+            max_message_length: CcidDescriptor::SHORT_APDU_MESSAGE_LENGTH,
+            parse_mode: ParseMode::Lenient,
         }
     }
 
+    /// Exchange APDUs with `framing` instead of plain XfrBlock commands
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Fail USB transfers taking longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Parse the card's answers in `mode`
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Split APDUs not fitting in a `max_message_length` bytes CCID message (the reader's
+    /// dwMaxCCIDMessageLength, see [`crate::discovery::ccid_descriptor`]) over several XfrBlocks
+    pub fn with_max_message_length(mut self, max_message_length: u32) -> Self {
+        self.max_message_length = max_message_length;
+        self
+    }
+
+    pub fn max_message_length(&self) -> u32 {
+        self.max_message_length
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Reattach the kernel driver (e.g. for pcscd) when the transport is dropped, after it was
+    /// detached to claim the interface
+    pub fn reattach_kernel_driver(mut self, reattach: bool) -> Self {
+        self.reattach_kernel_driver = reattach;
+        self
+    }
+
+    /// Hold `lock` for as long as the transport is open
+    pub fn with_lock(mut self, lock: ReaderLock) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// The reader lock held, if any
+    pub fn lock(&self) -> Option<&ReaderLock> {
+        self.lock.as_ref()
+    }
+
+    /// The state of the card slot, asked with GetSlotStatus: no card, a card not powered, or an
+    /// active one. Nothing reaches the card.
+    pub async fn slot_status(&self) -> Result<SlotStatus, Error> {
+        let sequence = self.next_sequence();
+        self.send_command(CcidCommand::get_slot_status(0, sequence))?;
+        let response = self.read_response_to(sequence)?;
+        Ok(response.slot_status)
+    }
+
     /// Power on the card and get ATR
     pub async fn power_on(&self) -> Result<Vec<u8>, Error> {
+        self.power_on_blocking()
+    }
+
+    pub(crate) fn power_on_blocking(&self) -> Result<Vec<u8>, Error> {
         let sequence = self.next_sequence();
         let cmd = CcidCommand::icc_power_on(0, sequence, VoltageSelection::Automatic);
 
-        self.send_command(cmd).await?;
-        let response = self.read_response().await?;
+        self.send_command(cmd)?;
+        let response = self.read_response_to(sequence)?;
 
         self.check_response_status(&response)?;
 
@@ -47,8 +150,115 @@ impl UsbTransport {
         Ok(response.data)
     }
 
+    /// Power off the card, leaving the reader ready for the next session
+    pub async fn power_off(&self) -> Result<(), Error> {
+        self.power_off_blocking()
+    }
+
+    fn power_off_blocking(&self) -> Result<(), Error> {
+        let sequence = self.next_sequence();
+        self.send_command(CcidCommand::icc_power_off(0, sequence))?;
+        let response = self.read_response_to(sequence)?;
+        self.check_response_status(&response)
+    }
+
+    /// Abort whatever the reader is busy with, e.g. an exchange with a card pulled mid-command,
+    /// and drop the answers still queued, so the next command gets its own answer
+    pub async fn abort(&self) -> Result<(), Error> {
+        self.abort_blocking()
+    }
+
+    /// The ABORT control request then PC_to_RDR_Abort, both with a new sequence number, then
+    /// read until the reader's SlotStatus for that sequence
+    fn abort_blocking(&self) -> Result<(), Error> {
+        let sequence = self.next_sequence();
+        log::debug!("Aborting, seq={sequence}");
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Class,
+            rusb::Recipient::Interface,
+        );
+        self.device
+            .write_control(
+                request_type,
+                ccid::REQUEST_ABORT,
+                CcidCommand::abort_request_value(0, sequence),
+                self.interface.into(),
+                &[],
+                self.timeout,
+            )
+            .map_err(Error::Usb)?;
+        self.send_command(CcidCommand::abort(0, sequence))?;
+        let response = self.read_response_to(sequence)?;
+        self.check_response_status(&response)
+    }
+
+    /// Send `data` in XfrBlock commands and return the data of the answer. Data longer than the
+    /// reader takes in one message is chained over several blocks with the level parameter,
+    /// and so is an answer longer than it sends in one.
+    ///
+    /// A reader not answering in time is aborted before the timeout is returned, so it's ready
+    /// for the next command instead of needing a replug.
+    pub(crate) fn xfr_block_blocking(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let result = self.xfr_block_chain(data);
+        if let Err(Error::Usb(rusb::Error::Timeout)) = result
+            && let Err(e) = self.abort_blocking()
+        {
+            log::debug!("Abort returned: {e}");
+        }
+        result
+    }
+
+    fn xfr_block_chain(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let max_data = ccid::max_xfr_data(self.max_message_length);
+        let mut response = None;
+        for (level, part) in ccid::fragment(&data, max_data) {
+            let sequence = self.next_sequence();
+            self.send_command(CcidCommand::xfr_block_level(
+                0,
+                sequence,
+                part.to_vec(),
+                level,
+            ))?;
+            let part_response = self.read_response_to(sequence)?;
+            self.check_response_status(&part_response)?;
+            if matches!(level, LevelParameter::Begins | LevelParameter::Continues)
+                && !matches!(
+                    part_response.chain_parameter(),
+                    Ok(LevelParameter::ResponseContinues)
+                )
+            {
+                return Err(Error::Ccid(format!(
+                    "The reader didn't take the APDU part ({level:?})"
+                )));
+            }
+            response = Some(part_response);
+        }
+        let mut response =
+            response.ok_or_else(|| Error::Ccid("No APDU part was sent".to_string()))?;
+
+        let mut answer = std::mem::take(&mut response.data);
+        // readers exchanging TPDUs leave bChainParameter at 0, any other value isn't a chain
+        while response
+            .chain_parameter()
+            .is_ok_and(LevelParameter::continues)
+        {
+            let sequence = self.next_sequence();
+            self.send_command(CcidCommand::xfr_block_level(
+                0,
+                sequence,
+                Vec::new(),
+                LevelParameter::ResponseContinues,
+            ))?;
+            response = self.read_response_to(sequence)?;
+            self.check_response_status(&response)?;
+            answer.append(&mut response.data);
+        }
+        Ok(answer)
+    }
+
     /// Send a CCID command
-    async fn send_command(&self, cmd: CcidCommand) -> Result<(), Error> {
+    fn send_command(&self, cmd: CcidCommand) -> Result<(), Error> {
         let bytes = cmd.to_bytes();
 
         log::debug!(
@@ -57,7 +267,17 @@ impl UsbTransport {
             bytes.len(),
             cmd.header.sequence
         );
-        log::trace!("Command bytes: {bytes:02x?}");
+        log::trace!(
+            "Command bytes: {bytes}",
+            bytes = transcript::log_bytes(&bytes)
+        );
+        log::trace!(
+            target: transcript::TARGET,
+            "ccid > type={message_type:#04x} seq={sequence} len={len}",
+            message_type = cmd.header.message_type,
+            sequence = cmd.header.sequence,
+            len = bytes.len()
+        );
 
         self.device
             .write_bulk(self.endpoint_out, &bytes, self.timeout)
@@ -67,8 +287,8 @@ impl UsbTransport {
     }
 
     /// Read a CCID response
-    async fn read_response(&self) -> Result<CcidResponse, Error> {
-        let mut buffer = vec![0u8; 1024];
+    fn read_response(&self) -> Result<CcidResponse, Error> {
+        let mut buffer = vec![0u8; (self.max_message_length as usize).max(1024)];
 
         let len = self
             .device
@@ -76,7 +296,10 @@ impl UsbTransport {
             .map_err(Error::Usb)?;
 
         log::debug!("Received {len} bytes");
-        log::trace!("Response bytes: {:02x?}", &buffer[..len.min(64)]);
+        log::trace!(
+            "Response bytes: {bytes}",
+            bytes = transcript::log_bytes(&buffer[..len])
+        );
 
         if len < 10 {
             return Err(Error::Ccid("Response too short".to_string()));
@@ -91,10 +314,34 @@ impl UsbTransport {
             response.slot_status,
             response.slot_error
         );
+        log::trace!(
+            target: transcript::TARGET,
+            "ccid < type={message_type:#04x} seq={sequence} len={len} status={status:?} error={error:?}",
+            message_type = response.header.message_type,
+            sequence = response.header.sequence,
+            status = response.slot_status,
+            error = response.slot_error
+        );
 
         Ok(response)
     }
 
+    /// Read the answer to the command `sequence`, dropping the ones left over from an
+    /// exchange given up on
+    fn read_response_to(&self, sequence: u8) -> Result<CcidResponse, Error> {
+        for _ in 0..MAX_STALE_RESPONSES {
+            let response = self.read_response()?;
+            let response_sequence = response.header.sequence;
+            if response_sequence == sequence {
+                return Ok(response);
+            }
+            log::debug!("Dropping the answer to seq={response_sequence}, waiting for {sequence}");
+        }
+        Err(Error::Ccid(format!(
+            "No answer to seq={sequence}, the reader is out of step"
+        )))
+    }
+
     /// Check response status and convert to error if needed
     fn check_response_status(&self, response: &CcidResponse) -> Result<(), Error> {
         match response.slot_error {
@@ -109,14 +356,15 @@ impl UsbTransport {
                 );
 
                 if response.slot_status == SlotStatus::NoICCPresent {
-                    Err(Error::Ccid("No card present".to_string()))
+                    Err(Error::CardRemoved)
                 } else if response.data.is_empty() {
                     // Some errors don't have additional data
                     Err(Error::Ccid("Command error".to_string()))
                 } else {
                     match response.data[0] {
                         0xFF => Err(Error::Ccid("Command aborted".to_string())),
-                        0xFE => Err(Error::Ccid("ICC mute (no card?)".to_string())),
+                        // the card stopped answering, it was most likely pulled away
+                        0xFE => Err(Error::CardRemoved),
                         0xFD => Err(Error::Ccid("XFR parity error".to_string())),
                         0xFC => Err(Error::Ccid("XFR overrun".to_string())),
                         code => Err(Error::Ccid(format!("Command error: {code:#x}"))),
@@ -138,37 +386,62 @@ impl UsbTransport {
 }
 
 impl CkTransport for UsbTransport {
+    fn parse_mode(&self) -> ParseMode {
+        self.parse_mode
+    }
+
+    /// Asks the reader for the slot status, without powering the card
+    async fn card_present(&self) -> Result<bool, Error> {
+        if self.framing == Framing::Acr122u {
+            return acr122u::card_present(self, &self.target_active);
+        }
+        Ok(self.slot_status().await? != SlotStatus::NoICCPresent)
+    }
+
     async fn transmit_apdu(&self, apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
-        // Always try to power on first - this is safer than checking status
-        // If already powered on, this is typically a no-op
-        match self.power_on().await {
-            Ok(_) => {
-                // Card powered on successfully
+        // USB transfers block, so this is the only point where a command can be cancelled:
+        // before an APDU is sent, never between a response and the card state it updates
+        yield_now().await;
+
+        // Power the card on only when the slot says it's off. The ACR122U's slot is its PN532,
+        // whose card is checked by acr122u::transmit.
+        match self.slot_status().await {
+            Ok(SlotStatus::ActiveICC) => {}
+            Ok(SlotStatus::NoICCPresent) if self.framing == Framing::Ccid => {
+                return Err(Error::CardRemoved);
             }
-            Err(e) => {
-                // Log but don't fail - card might already be powered on
-                log::debug!("Power on returned: {e}");
+            status => {
+                if let Err(e) = status {
+                    log::debug!("Slot status returned: {e}");
+                }
+                // Log but don't fail - the answer to the APDU tells
+                if let Err(e) = self.power_on().await {
+                    log::debug!("Power on returned: {e}");
+                }
             }
         }
 
-        // Send APDU via XfrBlock command
-        let sequence = self.next_sequence();
-        let cmd = CcidCommand::xfr_block(0, sequence, apdu);
-
-        self.send_command(cmd).await?;
-        let response = self.read_response().await?;
-
-        self.check_response_status(&response)?;
-
-        // Response data contains the R-APDU
-        Ok(response.data)
+        match self.framing {
+            // Response data contains the R-APDU
+            Framing::Ccid => self.xfr_block_blocking(apdu),
+            Framing::Acr122u => acr122u::transmit(self, &self.target_active, apdu),
+        }
     }
 }
 
 impl Drop for UsbTransport {
+    /// Power down the card and give the interface back, also when a command was cancelled, so
+    /// the reader doesn't need to be replugged
     fn drop(&mut self) {
-        // Release the interface when dropping
+        if let Err(e) = self.power_off_blocking() {
+            log::debug!("Power off returned: {e}");
+        }
         let _ = self.device.release_interface(self.interface);
+        if self.reattach_kernel_driver
+            && let Err(e) = self.device.attach_kernel_driver(self.interface)
+        {
+            log::debug!("Failed to reattach kernel driver: {e}");
+        }
     }
 }
 
@@ -182,9 +455,10 @@ pub fn find_ccid_endpoints(
         .active_config_descriptor()
         .map_err(Error::Usb)?;
 
+    // by number, not position: the CCID interface of a composite device needn't be the first
     let interface_desc = config
         .interfaces()
-        .nth(interface as usize)
+        .find(|candidate| candidate.number() == interface)
         .ok_or_else(|| Error::Ccid("Interface not found".to_string()))?
         .descriptors()
         .next()
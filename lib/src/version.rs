@@ -0,0 +1,202 @@
+use crate::apdu::Error;
+use alloc::string::ToString;
+use core::cmp::Ordering;
+use core::fmt;
+use core::str::FromStr;
+use log::warn;
+
+/// Version of the Coinkite tap protocol (`proto` in the status response) this library implements
+pub const PROTOCOL_VERSION: usize = 1;
+
+/// Check the card speaks a protocol version this library understands. Newer versions are
+/// expected to stay compatible and only log a warning, older ones are refused up front rather
+/// than failing later inside an authenticated command.
+pub fn check_protocol(proto: usize) -> Result<(), Error> {
+    match proto.cmp(&PROTOCOL_VERSION) {
+        Ordering::Equal => Ok(()),
+        Ordering::Greater => {
+            warn!(
+                "Card uses protocol version {proto}, newer than version {PROTOCOL_VERSION} known to this library, some responses may not be understood"
+            );
+            Ok(())
+        }
+        Ordering::Less => Err(Error::UnsupportedProtocol {
+            proto,
+            supported: PROTOCOL_VERSION,
+        }),
+    }
+}
+
+/// Applet firmware version as reported by the card in `status` (`ver`), e.g. `1.0.3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FirmwareVersion {
+    /// Check the card's firmware version (`ver`) is at least this one. Unlike [`Feature::check`]
+    /// an unparsable version fails, as the card can't be shown to meet the baseline.
+    pub fn require(&self, ver: &str) -> Result<(), Error> {
+        let have = ver.parse::<FirmwareVersion>()?;
+        if have < *self {
+            return Err(Error::UnsupportedByFirmware { needs: *self, have });
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = Error;
+
+    /// Parse `major.minor.patch`, missing components count as 0 and anything after the numbers
+    /// (e.g. a `-beta` suffix) is ignored
+    fn from_str(ver: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidVersion(ver.to_string());
+        let numeric = ver
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default();
+        let mut parts = numeric.split('.').map(|part| part.parse::<u16>());
+        let major = parts.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+        let mut next = || parts.next().transpose().map_err(|_| invalid());
+        let minor = next()?.unwrap_or(0);
+        let patch = next()?.unwrap_or(0);
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{major}.{minor}.{patch}",
+            major = self.major,
+            minor = self.minor,
+            patch = self.patch
+        )
+    }
+}
+
+/// Commands and status fields that don't exist on every firmware version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// `xpub` command
+    Xpub,
+    /// `change` command, to set a new CVC
+    Change,
+    /// `backup` command
+    Backup,
+    /// `num_backups` in the status response
+    BackupCounter,
+}
+
+impl Feature {
+    /// First firmware version implementing the feature. The TAPSIGNER commands and status
+    /// fields shipped with its first firmware, 1.0.0; the SATSCARD only 0.9 firmware before it
+    /// knows none of them.
+    pub fn min_version(&self) -> FirmwareVersion {
+        match self {
+            Feature::Xpub => FirmwareVersion::new(1, 0, 0),
+            Feature::Change => FirmwareVersion::new(1, 0, 0),
+            Feature::Backup => FirmwareVersion::new(1, 0, 0),
+            Feature::BackupCounter => FirmwareVersion::new(1, 0, 0),
+        }
+    }
+
+    /// Check the card's firmware version (`ver`) supports the feature. An unparsable version
+    /// is let through, the card will reject the command itself if it doesn't know it.
+    pub fn check(&self, ver: &str) -> Result<(), Error> {
+        let have = match ver.parse::<FirmwareVersion>() {
+            Ok(have) => have,
+            Err(e) => {
+                warn!("Not checking {self:?} support: {e}");
+                return Ok(());
+            }
+        };
+        let needs = self.min_version();
+        if have < needs {
+            return Err(Error::UnsupportedByFirmware { needs, have });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_protocol() {
+        assert_eq!(check_protocol(1), Ok(()));
+        assert_eq!(check_protocol(2), Ok(()));
+        assert_eq!(
+            check_protocol(0),
+            Err(Error::UnsupportedProtocol {
+                proto: 0,
+                supported: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_firmware_version() -> Result<(), Error> {
+        assert_eq!(
+            "1.0.3".parse::<FirmwareVersion>()?,
+            FirmwareVersion::new(1, 0, 3)
+        );
+        assert_eq!(
+            "0.9".parse::<FirmwareVersion>()?,
+            FirmwareVersion::new(0, 9, 0)
+        );
+        assert_eq!(
+            "1.2.0-beta".parse::<FirmwareVersion>()?,
+            FirmwareVersion::new(1, 2, 0)
+        );
+        assert_eq!(
+            "".parse::<FirmwareVersion>(),
+            Err(Error::InvalidVersion("".to_string()))
+        );
+        assert_eq!(
+            "x.1".parse::<FirmwareVersion>(),
+            Err(Error::InvalidVersion("x.1".to_string()))
+        );
+        assert!(FirmwareVersion::new(0, 9, 9) < FirmwareVersion::new(1, 0, 0));
+
+        for feature in [
+            Feature::Xpub,
+            Feature::Change,
+            Feature::Backup,
+            Feature::BackupCounter,
+        ] {
+            assert_eq!(feature.check("1.0.3"), Ok(()));
+            assert_eq!(feature.check(&feature.min_version().to_string()), Ok(()));
+            assert_eq!(feature.check("unknown"), Ok(()));
+            assert_eq!(
+                feature.check("0.9.9"),
+                Err(Error::UnsupportedByFirmware {
+                    needs: feature.min_version(),
+                    have: FirmwareVersion::new(0, 9, 9),
+                })
+            );
+        }
+
+        let baseline = FirmwareVersion::new(1, 0, 3);
+        assert_eq!(baseline.require("1.0.3"), Ok(()));
+        assert_eq!(baseline.require("1.1"), Ok(()));
+        assert!(baseline.require("1.0.2").is_err());
+        assert!(baseline.require("unknown").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Cross-check of the xcvc, read signature and certificate chain math against a second
+//! implementation of the protocol spec, `tests/vectors/generate.py`. The vectors are
+//! self-generated, not Coinkite's, so this is not a conformance test: it catches the Rust code
+//! drifting from the spec as read by that script, not misreadings both share.
+
+use cktap_direct::apdu::{
+    CertsResponse, Error, ParseMode, ReadResponse, ResponseApdu, StatusResponse,
+};
+use cktap_direct::commands::{Certificate, CkTransport, Read, calc_xcvc, recover_cert_chain};
+use cktap_direct::secp256k1::ecdh::SharedSecret;
+use cktap_direct::secp256k1::hashes::hex::{DisplayHex, FromHex};
+use cktap_direct::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use cktap_direct::{Cvc, SatsCard, TapSigner};
+use serde_json::Value;
+use std::error::Error as StdError;
+
+type Result<T, E = Box<dyn StdError>> = std::result::Result<T, E>;
+
+/// Transport for cards built from a status response, the tests never talk to a card
+struct NoCard;
+
+impl CkTransport for NoCard {
+    async fn transmit_apdu(&self, _command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Err(Error::Ccid("no card in cross-check tests".to_string()))
+    }
+}
+
+fn vectors(json: &str) -> Result<Vec<Value>> {
+    Ok(serde_json::from_str(json)?)
+}
+
+fn str_field<'a>(vector: &'a Value, field: &str) -> Result<&'a str> {
+    Ok(vector[field]
+        .as_str()
+        .ok_or(format!("missing string field {field}"))?)
+}
+
+fn bytes(vector: &Value, field: &str) -> Result<Vec<u8>> {
+    Ok(Vec::from_hex(str_field(vector, field)?)?)
+}
+
+fn nonce(vector: &Value, field: &str) -> Result<[u8; 16]> {
+    Ok(bytes(vector, field)?
+        .try_into()
+        .map_err(|_| format!("{field} is not a 16 byte nonce"))?)
+}
+
+fn pubkey(vector: &Value, field: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from_slice(&bytes(vector, field)?)?)
+}
+
+fn secret_key(vector: &Value, field: &str) -> Result<SecretKey> {
+    Ok(SecretKey::from_slice(&bytes(vector, field)?)?)
+}
+
+fn status(card_pubkey: PublicKey, card_nonce: [u8; 16], slot: Option<u8>) -> StatusResponse {
+    StatusResponse {
+        proto: 1,
+        ver: "1.0.3".to_string(),
+        birth: 700_000,
+        slots: slot.map(|slot| (slot, 10)),
+        addr: None,
+        tapsigner: slot.is_none().then_some(true),
+        satschip: None,
+        path: None,
+        num_backups: None,
+        pubkey: card_pubkey.serialize().to_vec(),
+        card_nonce,
+        testnet: None,
+        auth_delay: None,
+    }
+}
+
+#[test]
+fn test_xcvc_vectors() -> Result<()> {
+    let secp = Secp256k1::new();
+    for vector in vectors(include_str!("vectors/xcvc.json"))? {
+        let card_pubkey = pubkey(&vector, "card_pubkey")?;
+        let ephemeral_key = secret_key(&vector, "ephemeral_privkey")?;
+
+        assert_eq!(ephemeral_key.public_key(&secp), pubkey(&vector, "epubkey")?);
+        assert_eq!(
+            SharedSecret::new(&card_pubkey, &ephemeral_key)
+                .as_ref()
+                .as_hex()
+                .to_string(),
+            vector["session_key"]
+        );
+
+        let xcvc = calc_xcvc(
+            &card_pubkey,
+            &nonce(&vector, "card_nonce")?,
+            str_field(&vector, "command")?,
+            &Cvc::from(str_field(&vector, "cvc")?),
+            &ephemeral_key,
+        );
+        assert_eq!(xcvc, bytes(&vector, "xcvc")?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_read_vectors() -> Result<()> {
+    let secp = Secp256k1::new();
+    for vector in vectors(include_str!("vectors/read.json"))? {
+        let card_nonce = nonce(&vector, "card_nonce")?;
+        let app_nonce = nonce(&vector, "app_nonce")?;
+        let response = ReadResponse {
+            sig: bytes(&vector, "sig")?,
+            pubkey: bytes(&vector, "pubkey")?,
+            card_nonce: [0; 16],
+        };
+
+        let (digest, session_key) = match vector["card"].as_str() {
+            Some("satscard") => {
+                let slot = u8::try_from(vector["slot"].as_u64().ok_or("missing slot")?)?;
+                let card = SatsCard::from_status(
+                    NoCard,
+                    status(pubkey(&vector, "pubkey")?, card_nonce, Some(slot)),
+                )?;
+                (
+                    Read::message_digest(&card, card_nonce, app_nonce.to_vec()),
+                    None,
+                )
+            }
+            Some("tapsigner") => {
+                let card_pubkey = pubkey(&vector, "card_pubkey")?;
+                let card =
+                    TapSigner::try_from_status(NoCard, status(card_pubkey, card_nonce, None))?;
+                let session_key =
+                    SharedSecret::new(&card_pubkey, &secret_key(&vector, "ephemeral_privkey")?);
+                (
+                    Read::message_digest(&card, card_nonce, app_nonce.to_vec()),
+                    Some(session_key),
+                )
+            }
+            card => return Err(format!("unknown card type {card:?}").into()),
+        };
+        assert_eq!(digest.as_ref().as_hex().to_string(), vector["digest"]);
+
+        let read_pubkey = response.pubkey(session_key)?;
+        assert_eq!(read_pubkey, pubkey(&vector, "expected_pubkey")?);
+        let signature = response.signature()?;
+        assert!(secp.verify_ecdsa(&digest, &signature, &read_pubkey).is_ok());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cert_vectors() -> Result<()> {
+    let secp = Secp256k1::new();
+    for vector in vectors(include_str!("vectors/certs.json"))? {
+        let card_pubkey = pubkey(&vector, "card_pubkey")?;
+        let chain = vector["cert_chain"]
+            .as_array()
+            .ok_or("missing cert chain")?
+            .iter()
+            .map(|cert| {
+                let hex = cert.as_str().ok_or("cert is not a hex string")?;
+                Ok(ciborium::Value::Bytes(Vec::from_hex(hex)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(
+            &ciborium::Value::Map(vec![(
+                ciborium::Value::from("cert_chain"),
+                ciborium::Value::Array(chain),
+            )]),
+            &mut cbor,
+        )?;
+        let certs = CertsResponse::from_cbor(cbor, ParseMode::Lenient)?;
+
+        let root = recover_cert_chain(&secp, card_pubkey, &certs)?;
+        assert_eq!(root, pubkey(&vector, "root_pubkey")?);
+
+        // the card's proof of holding its key, as checked by check_certificate
+        let card_nonce = nonce(&vector, "card_nonce")?;
+        let app_nonce = nonce(&vector, "app_nonce")?;
+        let mut card = TapSigner::try_from_status(NoCard, status(card_pubkey, card_nonce, None))?;
+        assert_eq!(
+            card.verify_card_signature(bytes(&vector, "auth_sig")?, card_nonce, app_nonce),
+            Ok(())
+        );
+        let mut other_nonce = app_nonce;
+        other_nonce[0] ^= 1;
+        assert!(
+            card.verify_card_signature(bytes(&vector, "auth_sig")?, card_nonce, other_nonce)
+                .is_err()
+        );
+    }
+    Ok(())
+}
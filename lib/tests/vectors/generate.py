@@ -0,0 +1,223 @@
+#!/usr/bin/env python3
+"""Generate the cross-check vectors in this directory.
+
+An independent, dependency free implementation of the cktap protocol math (xcvc, read
+signatures and certificate chains) as described in the protocol spec, so the Rust
+implementation is checked against something other than itself. These are self-generated
+vectors, not Coinkite's: a misreading of the spec shared by both implementations goes
+unnoticed until the upstream vectors are imported. Deterministic: running it again
+reproduces the same files.
+
+    python3 lib/tests/vectors/generate.py
+"""
+
+import hashlib
+import hmac
+import json
+import os
+
+P = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F
+N = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141
+G = (
+    0x79BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798,
+    0x483ADA7726A3C4655DA4FBFC0E1108A8FD17B448A68554199C47D08FFB10D4B8,
+)
+
+
+def sha256(data):
+    return hashlib.sha256(data).digest()
+
+
+def point_add(a, b):
+    if a is None:
+        return b
+    if b is None:
+        return a
+    if a[0] == b[0] and (a[1] + b[1]) % P == 0:
+        return None
+    if a == b:
+        lam = 3 * a[0] * a[0] * pow(2 * a[1], -1, P) % P
+    else:
+        lam = (b[1] - a[1]) * pow(b[0] - a[0], -1, P) % P
+    x = (lam * lam - a[0] - b[0]) % P
+    return (x, (lam * (a[0] - x) - a[1]) % P)
+
+
+def point_mul(k, point=G):
+    result = None
+    while k:
+        if k & 1:
+            result = point_add(result, point)
+        point = point_add(point, point)
+        k >>= 1
+    return result
+
+
+def compressed(point):
+    return bytes([2 + (point[1] & 1)]) + point[0].to_bytes(32, "big")
+
+
+def uncompressed(point):
+    return b"\x04" + point[0].to_bytes(32, "big") + point[1].to_bytes(32, "big")
+
+
+def privkey(label):
+    return int.from_bytes(sha256(label.encode()), "big") % N
+
+
+def ecdh(secret, point):
+    """Session key as libsecp256k1 computes it: sha256 of the compressed shared point"""
+    return sha256(compressed(point_mul(secret, point)))
+
+
+def rfc6979_nonce(secret, digest):
+    x = secret.to_bytes(32, "big")
+    v = b"\x01" * 32
+    k = b"\x00" * 32
+    k = hmac.new(k, v + b"\x00" + x + digest, hashlib.sha256).digest()
+    v = hmac.new(k, v, hashlib.sha256).digest()
+    k = hmac.new(k, v + b"\x01" + x + digest, hashlib.sha256).digest()
+    v = hmac.new(k, v, hashlib.sha256).digest()
+    while True:
+        v = hmac.new(k, v, hashlib.sha256).digest()
+        nonce = int.from_bytes(v, "big")
+        if 1 <= nonce < N:
+            return nonce
+        k = hmac.new(k, v + b"\x00", hashlib.sha256).digest()
+        v = hmac.new(k, v, hashlib.sha256).digest()
+
+
+def sign(secret, digest):
+    """Low-S ECDSA signature, returns (r || s, recovery id)"""
+    nonce = rfc6979_nonce(secret, digest)
+    point = point_mul(nonce)
+    r = point[0] % N
+    s = pow(nonce, -1, N) * (int.from_bytes(digest, "big") + r * secret) % N
+    rec_id = (point[1] & 1) | (2 if point[0] >= N else 0)
+    if s > N // 2:
+        s = N - s
+        rec_id ^= 1
+    return r.to_bytes(32, "big") + s.to_bytes(32, "big"), rec_id
+
+
+def xcvc_vectors():
+    vectors = []
+    for i, (cvc, command) in enumerate(
+        [("123456", "read"), ("123456", "sign"), ("00000000000000000000", "change")]
+    ):
+        card_key = privkey(f"xcvc card {i}")
+        ephemeral_key = privkey(f"xcvc ephemeral {i}")
+        card_nonce = sha256(f"xcvc card nonce {i}".encode())[:16]
+        session_key = ecdh(ephemeral_key, point_mul(card_key))
+        md = sha256(card_nonce + command.encode())
+        mask = bytes(a ^ b for a, b in zip(session_key, md))
+        xcvc = bytes(c ^ m for c, m in zip(cvc.encode(), mask))
+        vectors.append(
+            {
+                "card_pubkey": compressed(point_mul(card_key)).hex(),
+                "card_nonce": card_nonce.hex(),
+                "command": command,
+                "cvc": cvc,
+                "ephemeral_privkey": ephemeral_key.to_bytes(32, "big").hex(),
+                "epubkey": compressed(point_mul(ephemeral_key)).hex(),
+                "session_key": session_key.hex(),
+                "xcvc": xcvc.hex(),
+            }
+        )
+    return vectors
+
+
+def read_vectors():
+    vectors = []
+    # SATSCARD: the slot pubkey is sent in the clear and the slot number is signed
+    for slot in [0, 3]:
+        slot_key = privkey(f"read satscard slot {slot}")
+        card_nonce = sha256(f"read satscard card nonce {slot}".encode())[:16]
+        app_nonce = sha256(f"read satscard app nonce {slot}".encode())[:16]
+        digest = sha256(b"OPENDIME" + card_nonce + app_nonce + bytes([slot]))
+        sig, _ = sign(slot_key, digest)
+        vectors.append(
+            {
+                "card": "satscard",
+                "slot": slot,
+                "card_nonce": card_nonce.hex(),
+                "app_nonce": app_nonce.hex(),
+                "digest": digest.hex(),
+                "sig": sig.hex(),
+                "pubkey": compressed(point_mul(slot_key)).hex(),
+                "expected_pubkey": compressed(point_mul(slot_key)).hex(),
+            }
+        )
+    # TAPSIGNER: the derived pubkey is encrypted with the session key and slot 0 is signed
+    card_key = privkey("read tapsigner card")
+    derived_key = privkey("read tapsigner derived")
+    ephemeral_key = privkey("read tapsigner ephemeral")
+    card_nonce = sha256(b"read tapsigner card nonce")[:16]
+    app_nonce = sha256(b"read tapsigner app nonce")[:16]
+    digest = sha256(b"OPENDIME" + card_nonce + app_nonce + b"\x00")
+    sig, _ = sign(derived_key, digest)
+    session_key = ecdh(ephemeral_key, point_mul(card_key))
+    derived_pubkey = compressed(point_mul(derived_key))
+    encrypted = derived_pubkey[:1] + bytes(a ^ b for a, b in zip(derived_pubkey[1:], session_key))
+    vectors.append(
+        {
+            "card": "tapsigner",
+            "slot": None,
+            "card_nonce": card_nonce.hex(),
+            "app_nonce": app_nonce.hex(),
+            "digest": digest.hex(),
+            "sig": sig.hex(),
+            "card_pubkey": compressed(point_mul(card_key)).hex(),
+            "ephemeral_privkey": ephemeral_key.to_bytes(32, "big").hex(),
+            "pubkey": encrypted.hex(),
+            "expected_pubkey": derived_pubkey.hex(),
+        }
+    )
+    return vectors
+
+
+def cert_vectors():
+    """Chain card -> batch -> root: each certificate is the signer's recoverable signature over
+    sha256 of the signed key (uncompressed), with a BIP-137 compressed P2PKH header (31 + recid).
+    The card proves it holds its key by signing sha256(b'OPENDIME' + card_nonce + app_nonce)
+    in the check response."""
+    vectors = []
+    for i in range(2):
+        card_key = privkey(f"certs card {i}")
+        batch_key = privkey(f"certs batch {i}")
+        root_key = privkey(f"certs root {i}")
+        chain = []
+        for signer, signed in [(batch_key, card_key), (root_key, batch_key)]:
+            sig, rec_id = sign(signer, sha256(uncompressed(point_mul(signed))))
+            chain.append((bytes([31 + rec_id]) + sig).hex())
+        card_nonce = sha256(f"certs card nonce {i}".encode())[:16]
+        app_nonce = sha256(f"certs app nonce {i}".encode())[:16]
+        auth_sig, _ = sign(card_key, sha256(b"OPENDIME" + card_nonce + app_nonce))
+        vectors.append(
+            {
+                "card_pubkey": compressed(point_mul(card_key)).hex(),
+                "card_nonce": card_nonce.hex(),
+                "app_nonce": app_nonce.hex(),
+                "auth_sig": auth_sig.hex(),
+                "cert_chain": chain,
+                "batch_pubkey": compressed(point_mul(batch_key)).hex(),
+                "root_pubkey": compressed(point_mul(root_key)).hex(),
+            }
+        )
+    return vectors
+
+
+def main():
+    here = os.path.dirname(os.path.abspath(__file__))
+    for name, vectors in [
+        ("xcvc", xcvc_vectors()),
+        ("read", read_vectors()),
+        ("certs", cert_vectors()),
+    ]:
+        with open(os.path.join(here, f"{name}.json"), "w") as f:
+            json.dump(vectors, f, indent=2)
+            f.write("\n")
+
+
+if __name__ == "__main__":
+    main()
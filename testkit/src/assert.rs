@@ -0,0 +1,77 @@
+//! Assertions for tests against the test cards, checking the answers with the fixture keys.
+
+use crate::fixtures;
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1};
+use cktap_direct::apdu::{
+    CertsCommand, CertsResponse, CheckCommand, CheckResponse, CkTapError, Error, SignResponse,
+};
+use cktap_direct::commands::{Authentication, CkTransport, opendime_digest, recover_cert_chain};
+use cktap_direct::protocol::verify_card_signature;
+use cktap_direct::tap_signer::TapSignerError;
+use std::fmt::Debug;
+
+/// Check a test card like `check_certificate` checks a real one: the card must sign a fresh
+/// nonce with its key and its certificate chain must lead to [`fixtures::root_pubkey`].
+pub async fn check_test_certificate<C, T>(card: &mut C) -> Result<(), Error>
+where
+    C: Authentication<T>,
+    T: CkTransport,
+{
+    let nonce = card.entropy().nonce();
+    let card_nonce = *card.card_nonce();
+    let certs: CertsResponse = card.transport().transmit(&CertsCommand::default()).await?;
+    let check: CheckResponse = card.transport().transmit(&CheckCommand::new(nonce)).await?;
+    card.set_card_nonce(check.card_nonce);
+
+    let message = opendime_digest(&[&card_nonce, &nonce]);
+    verify_card_signature(card.secp(), &message, &check.auth_sig, card.pubkey())?;
+    let root = recover_cert_chain(card.secp(), *card.pubkey(), &certs)?;
+    if root != fixtures::root_pubkey() {
+        return Err(Error::IncorrectSignature(format!(
+            "certificate chain leads to {root}, not to the test root"
+        )));
+    }
+    Ok(())
+}
+
+/// Assert `sig` is a valid compact signature of `digest` by `pubkey`
+#[track_caller]
+pub fn assert_signed(digest: [u8; 32], sig: &[u8], pubkey: &PublicKey) {
+    let signature = Signature::from_compact(sig).expect("compact signature");
+    let message = Message::from_digest(digest);
+    assert!(
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature, pubkey)
+            .is_ok(),
+        "signature {signature} is not a signature of {message} by {pubkey}"
+    );
+}
+
+/// Assert a test TAPSIGNER signed `digest` with its key at `path` (hardened steps have the high
+/// bit set), e.g. the current path followed by the sign subpath
+#[track_caller]
+pub fn assert_tapsigner_signed(response: &SignResponse, digest: [u8; 32], path: &[u32]) {
+    let expected = fixtures::tapsigner_key(path)
+        .private_key
+        .public_key(&Secp256k1::new());
+    let pubkey = PublicKey::from_slice(&response.pubkey).expect("pubkey");
+    assert_eq!(
+        pubkey, expected,
+        "signed with another key than the one at {path:?}"
+    );
+    assert_signed(digest, &response.sig, &pubkey);
+}
+
+/// Assert `result` is the card refusing the command with `expected`. Takes the results of both
+/// the shared commands and the TAPSIGNER ones.
+#[track_caller]
+pub fn assert_card_error<R: Debug, E: Into<TapSignerError>>(
+    result: Result<R, E>,
+    expected: CkTapError,
+) {
+    match result.map_err(Into::into) {
+        Err(TapSignerError::ApduError(Error::CkTap(error))) => assert_eq!(error, expected),
+        other => panic!("expected the card to answer {expected:?}, got {other:?}"),
+    }
+}
@@ -0,0 +1,860 @@
+//! In-memory cards answering the CBOR commands the way the real ones do, with the keys from
+//! [`crate::fixtures`]: nonces rotate, the CVC is checked (three wrong ones start the
+//! authentication delay), keys are zipped with the session key, and every answer is signed.
+
+use crate::fixtures::{self, BIRTH, CHAIN_CODE, DEFAULT_PATH, HARDENED, MASTER_KEY, NUM_SLOTS};
+use bitcoin::bip32::{ChildNumber, Xpriv, Xpub};
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{All, Message, PublicKey, Secp256k1, SecretKey};
+use ciborium::value::Value;
+use cktap_direct::CkTapCard;
+use cktap_direct::apdu::{AppletSelect, CBOR_CLA_INS_P1P2, CkTapError, CommandApdu as _, Error};
+use cktap_direct::commands::{CkTransport, opendime_digest};
+use cktap_direct::status_word::GET_RESPONSE;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Seconds of authentication delay after the third wrong CVC
+pub const AUTH_DELAY: usize = 15;
+
+/// Wrong CVCs accepted before the authentication delay starts
+const MAX_FAILED_AUTHS: usize = 3;
+
+/// Status word of a non-cktap APDU: instruction not supported
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const SW_OK: [u8; 2] = [0x90, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardKind {
+    SatsCard,
+    TapSigner,
+    SatsChip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    /// No key picked yet, `new` sets the slot up
+    Unused,
+    Sealed,
+    /// The private key was revealed by `unseal`
+    Unsealed,
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    master: Option<Xpriv>,
+    state: SlotState,
+}
+
+/// A card with known keys, see [`crate::fixtures`]. Send it APDUs with [`TestCard::transmit`],
+/// or use it through a [`TestTransport`].
+#[derive(Debug)]
+pub struct TestCard {
+    kind: CardKind,
+    secp: Secp256k1<All>,
+    card_key: SecretKey,
+    cvc: Vec<u8>,
+    version: String,
+    card_nonce: [u8; 16],
+    nonces: u64,
+    failed_auths: usize,
+    auth_delay: usize,
+    commands: Vec<String>,
+    // TAPSIGNER and SATSCHIP
+    master: Option<Xpriv>,
+    path: Vec<u32>,
+    num_backups: usize,
+    // SATSCARD
+    slots: Vec<Slot>,
+    active_slot: u8,
+}
+
+impl TestCard {
+    fn new(kind: CardKind) -> Self {
+        let mut card = Self {
+            kind,
+            secp: Secp256k1::new(),
+            card_key: fixtures::secret_key(fixtures::CARD_KEY),
+            cvc: fixtures::CVC.as_bytes().to_vec(),
+            version: fixtures::VERSION.to_string(),
+            card_nonce: [0; 16],
+            nonces: 0,
+            failed_auths: 0,
+            auth_delay: 0,
+            commands: Vec::new(),
+            master: None,
+            path: Vec::new(),
+            num_backups: 0,
+            slots: Vec::new(),
+            active_slot: 0,
+        };
+        card.next_nonce();
+        card
+    }
+
+    /// A TAPSIGNER set up with [`fixtures::MASTER_KEY`] and [`fixtures::CHAIN_CODE`], on the
+    /// default path m/84'/0'/0'
+    pub fn tapsigner() -> Self {
+        let mut card = Self::uninitialized_tapsigner();
+        card.master = Some(fixtures::tapsigner_master());
+        card.path = DEFAULT_PATH.to_vec();
+        card
+    }
+
+    /// A TAPSIGNER fresh from the factory, waiting for `new`
+    pub fn uninitialized_tapsigner() -> Self {
+        Self::new(CardKind::TapSigner)
+    }
+
+    /// A SATSCHIP set up like [`TestCard::tapsigner`]
+    pub fn satschip() -> Self {
+        Self {
+            kind: CardKind::SatsChip,
+            ..Self::tapsigner()
+        }
+    }
+
+    /// A SATSCARD with slot 0 sealed, set up with [`fixtures::CHAIN_CODE`]
+    pub fn satscard() -> Self {
+        let mut card = Self::new(CardKind::SatsCard);
+        card.slots = (0..NUM_SLOTS)
+            .map(|_| Slot {
+                master: None,
+                state: SlotState::Unused,
+            })
+            .collect();
+        card.slots[0] = Slot {
+            master: Some(fixtures::slot_master(0, CHAIN_CODE)),
+            state: SlotState::Sealed,
+        };
+        card
+    }
+
+    pub fn with_cvc(mut self, cvc: &str) -> Self {
+        self.cvc = cvc.as_bytes().to_vec();
+        self
+    }
+
+    /// Report another firmware version, e.g. to test version checks
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Start in the state left by too many wrong CVCs
+    pub fn with_auth_delay(mut self, auth_delay: usize) -> Self {
+        self.auth_delay = auth_delay;
+        self
+    }
+
+    pub fn kind(&self) -> CardKind {
+        self.kind
+    }
+
+    pub fn pubkey(&self) -> PublicKey {
+        self.card_key.public_key(&self.secp)
+    }
+
+    pub fn cvc(&self) -> String {
+        String::from_utf8_lossy(&self.cvc).into_owned()
+    }
+
+    pub fn card_nonce(&self) -> [u8; 16] {
+        self.card_nonce
+    }
+
+    pub fn auth_delay(&self) -> usize {
+        self.auth_delay
+    }
+
+    /// TAPSIGNER derivation path in use, hardened steps with the high bit set
+    pub fn path(&self) -> &[u32] {
+        &self.path
+    }
+
+    pub fn num_backups(&self) -> usize {
+        self.num_backups
+    }
+
+    pub fn active_slot(&self) -> u8 {
+        self.active_slot
+    }
+
+    pub fn slot_state(&self, slot: u8) -> Option<SlotState> {
+        self.slots.get(usize::from(slot)).map(|slot| slot.state)
+    }
+
+    /// Names of the commands received so far, `select` for the applet selection
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    /// Answer a command APDU with a response APDU
+    pub fn transmit(&mut self, apdu: &[u8]) -> Vec<u8> {
+        if AppletSelect::default()
+            .apdu_bytes()
+            .is_ok_and(|select| apdu == select)
+        {
+            self.commands.push("select".to_string());
+            return response(self.status());
+        }
+        let Some(cbor) = apdu
+            .strip_prefix(&CBOR_CLA_INS_P1P2[..])
+            .and_then(|rest| rest.get(1..))
+        else {
+            return SW_INS_NOT_SUPPORTED.to_vec();
+        };
+        let args = match ciborium::de::from_reader(cbor) {
+            Ok(Value::Map(args)) => Args(args),
+            _ => return error_response(CkTapError::BadCBOR),
+        };
+        let Some(cmd) = args.text("cmd") else {
+            return error_response(CkTapError::BadCBOR);
+        };
+        self.commands.push(cmd.to_string());
+        match self.handle(cmd, &args) {
+            Ok(fields) => response(fields),
+            Err(error) => error_response(error),
+        }
+    }
+
+    fn handle(&mut self, cmd: &str, args: &Args) -> Result<Fields, CkTapError> {
+        let tapsigner = self.kind != CardKind::SatsCard;
+        match cmd {
+            "status" => Ok(self.status()),
+            "certs" => Ok(self.certs()),
+            "check" => self.check(args),
+            "read" => self.read(args),
+            "derive" if tapsigner => self.derive_path(args),
+            "derive" => self.derive_slot(args),
+            "sign" => self.sign(args),
+            "new" if tapsigner => self.new_tapsigner(args),
+            "new" => self.new_slot(args),
+            "wait" => Ok(self.wait()),
+            "nfc" => Ok(self.nfc()),
+            "unseal" if !tapsigner => self.unseal(args),
+            "dump" if !tapsigner => self.dump(args),
+            "xpub" if tapsigner => self.xpub(args),
+            "change" if tapsigner => self.change(args),
+            "backup" if tapsigner => self.backup(args),
+            _ => Err(CkTapError::UnknownCommand),
+        }
+    }
+
+    /// Pick the nonce for the next command, returning it for the response
+    fn next_nonce(&mut self) -> Value {
+        self.nonces += 1;
+        let nonce = sha256::Hash::hash(&self.nonces.to_be_bytes());
+        self.card_nonce.copy_from_slice(&nonce[..16]);
+        Value::Bytes(self.card_nonce.to_vec())
+    }
+
+    /// Check the encrypted CVC of `cmd` and return the session key
+    fn authenticate(&mut self, cmd: &str, args: &Args) -> Result<SharedSecret, CkTapError> {
+        if self.auth_delay > 0 {
+            return Err(CkTapError::RateLimited);
+        }
+        let (Some(epubkey), Some(xcvc)) = (args.bytes("epubkey"), args.bytes("xcvc")) else {
+            return Err(CkTapError::NeedsAuth);
+        };
+        let epubkey = PublicKey::from_slice(epubkey).map_err(|_| CkTapError::BadArguments)?;
+        let session_key = SharedSecret::new(&epubkey, &self.card_key);
+
+        let mut engine = sha256::Hash::engine();
+        engine.input(&self.card_nonce);
+        engine.input(cmd.as_bytes());
+        let mask = sha256::Hash::from_engine(engine);
+        let cvc: Vec<u8> = xor(&xor(xcvc, session_key.as_ref()), mask.as_ref());
+        if cvc != self.cvc {
+            self.failed_auths += 1;
+            if self.failed_auths >= MAX_FAILED_AUTHS {
+                self.auth_delay = AUTH_DELAY;
+            }
+            return Err(CkTapError::BadAuth);
+        }
+        self.failed_auths = 0;
+        Ok(session_key)
+    }
+
+    fn sign_digest(&self, message: &Message, key: &SecretKey) -> Value {
+        let signature = self.secp.sign_ecdsa(message, key);
+        Value::Bytes(signature.serialize_compact().to_vec())
+    }
+
+    fn tapsigner_master(&self) -> Result<&Xpriv, CkTapError> {
+        self.master.as_ref().ok_or(CkTapError::InvalidState)
+    }
+
+    fn derive_priv(&self, master: &Xpriv, path: &[u32]) -> Result<Xpriv, CkTapError> {
+        let path: Vec<ChildNumber> = path.iter().map(|&index| ChildNumber::from(index)).collect();
+        master
+            .derive_priv(&self.secp, &path)
+            .map_err(|_| CkTapError::UnluckyNumber)
+    }
+
+    fn slot(&self, slot: u8) -> Result<&Slot, CkTapError> {
+        self.slots
+            .get(usize::from(slot))
+            .ok_or(CkTapError::BadArguments)
+    }
+
+    fn slot_master(&self, slot: u8) -> Result<&Xpriv, CkTapError> {
+        self.slot(slot)?
+            .master
+            .as_ref()
+            .ok_or(CkTapError::InvalidState)
+    }
+
+    /// The slot named in the command, which must be the active one
+    fn active_slot_arg(&self, args: &Args) -> Result<u8, CkTapError> {
+        match args.int("slot") {
+            Some(slot) if slot == u64::from(self.active_slot) => Ok(self.active_slot),
+            _ => Err(CkTapError::BadArguments),
+        }
+    }
+
+    fn status(&self) -> Fields {
+        let mut fields = vec![
+            ("proto", Value::from(1)),
+            ("ver", Value::from(self.version.as_str())),
+            ("birth", Value::from(BIRTH as u64)),
+            ("pubkey", Value::Bytes(self.pubkey().serialize().to_vec())),
+            ("card_nonce", Value::Bytes(self.card_nonce.to_vec())),
+        ];
+        match self.kind {
+            CardKind::SatsCard => {
+                fields.push((
+                    "slots",
+                    Value::Array(vec![
+                        Value::from(self.active_slot),
+                        Value::from(self.slots.len() as u64),
+                    ]),
+                ));
+                if let Ok(master) = self.slot_master(self.active_slot) {
+                    let pubkey = fixtures::slot_key(master).public_key(&self.secp);
+                    fields.push(("addr", Value::from(censor(&fixtures::address(&pubkey)))));
+                }
+            }
+            CardKind::TapSigner | CardKind::SatsChip => {
+                fields.push(("tapsigner", Value::Bool(true)));
+                if self.kind == CardKind::SatsChip {
+                    fields.push(("satschip", Value::Bool(true)));
+                }
+                if self.master.is_some() {
+                    fields.push(("path", int_array(&self.path)));
+                }
+                fields.push(("num_backups", Value::from(self.num_backups as u64)));
+            }
+        }
+        if self.auth_delay > 0 {
+            fields.push(("auth_delay", Value::from(self.auth_delay as u64)));
+        }
+        fields
+    }
+
+    /// Certificates from the card key up to [`fixtures::ROOT_KEY`], BIP-137 signatures of the
+    /// hash of each uncompressed pubkey
+    fn certs(&self) -> Fields {
+        let batch_key = fixtures::secret_key(fixtures::BATCH_KEY);
+        let root_key = fixtures::secret_key(fixtures::ROOT_KEY);
+        let certify = |pubkey: PublicKey, signer: &SecretKey| {
+            let digest = sha256::Hash::hash(&pubkey.serialize_uncompressed());
+            let message = Message::from_digest(digest.to_byte_array());
+            let (rec_id, signature) = self
+                .secp
+                .sign_ecdsa_recoverable(&message, signer)
+                .serialize_compact();
+            let mut cert = vec![39 + rec_id.to_i32() as u8];
+            cert.extend(signature);
+            Value::Bytes(cert)
+        };
+        vec![(
+            "cert_chain",
+            Value::Array(vec![
+                certify(self.pubkey(), &batch_key),
+                certify(batch_key.public_key(&self.secp), &root_key),
+            ]),
+        )]
+    }
+
+    fn check(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let nonce = args.nonce()?;
+        let message = opendime_digest(&[&self.card_nonce, nonce]);
+        let auth_sig = self.sign_digest(&message, &self.card_key);
+        Ok(vec![
+            ("auth_sig", auth_sig),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    fn read(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let nonce = args.nonce()?;
+        let (slot, key, pubkey) = match self.kind {
+            CardKind::SatsCard => {
+                let key = fixtures::slot_key(self.slot_master(self.active_slot)?);
+                let pubkey = key.public_key(&self.secp).serialize().to_vec();
+                (self.active_slot, key, pubkey)
+            }
+            CardKind::TapSigner | CardKind::SatsChip => {
+                let session_key = self.authenticate("read", args)?;
+                let key = self
+                    .derive_priv(self.tapsigner_master()?, &self.path)?
+                    .private_key;
+                // the x coordinate is encrypted with the session key
+                let pubkey = key.public_key(&self.secp).serialize();
+                let mut zipped = vec![pubkey[0]];
+                zipped.extend(xor(&pubkey[1..], session_key.as_ref()));
+                (0, key, zipped)
+            }
+        };
+        let message = opendime_digest(&[&self.card_nonce, nonce, &[slot]]);
+        Ok(vec![
+            ("sig", self.sign_digest(&message, &key)),
+            ("pubkey", Value::Bytes(pubkey)),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    /// SATSCARD `derive`: the master key and chain code of the active slot, signed
+    fn derive_slot(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let nonce = args.nonce()?;
+        let master = *self.slot_master(self.active_slot)?;
+        let chain_code = master.chain_code.to_bytes();
+        let message = opendime_digest(&[&self.card_nonce, nonce, &chain_code]);
+        Ok(vec![
+            ("sig", self.sign_digest(&message, &master.private_key)),
+            ("chain_code", Value::Bytes(chain_code.to_vec())),
+            (
+                "master_pubkey",
+                Value::Bytes(
+                    master
+                        .private_key
+                        .public_key(&self.secp)
+                        .serialize()
+                        .to_vec(),
+                ),
+            ),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    /// TAPSIGNER `derive`: switch to the path and sign with its key
+    fn derive_path(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let nonce = *args.nonce()?;
+        self.authenticate("derive", args)?;
+        let path = args.path("path")?;
+        let master = *self.tapsigner_master()?;
+        let derived = self.derive_priv(&master, &path)?;
+        let chain_code = derived.chain_code.to_bytes();
+        let message = opendime_digest(&[&self.card_nonce, &nonce, &chain_code]);
+        self.path = path;
+        Ok(vec![
+            ("sig", self.sign_digest(&message, &derived.private_key)),
+            ("chain_code", Value::Bytes(chain_code.to_vec())),
+            (
+                "master_pubkey",
+                Value::Bytes(
+                    master
+                        .private_key
+                        .public_key(&self.secp)
+                        .serialize()
+                        .to_vec(),
+                ),
+            ),
+            (
+                "pubkey",
+                Value::Bytes(
+                    derived
+                        .private_key
+                        .public_key(&self.secp)
+                        .serialize()
+                        .to_vec(),
+                ),
+            ),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    fn sign(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let session_key = self.authenticate("sign", args)?;
+        let digest = args
+            .bytes("digest")
+            .filter(|digest| digest.len() == 32)
+            .ok_or(CkTapError::BadArguments)?;
+        let digest: [u8; 32] = xor(digest, session_key.as_ref())
+            .try_into()
+            .map_err(|_| CkTapError::BadArguments)?;
+        let (slot, key) = match self.kind {
+            CardKind::SatsCard => {
+                let slot = args
+                    .int("slot")
+                    .and_then(|slot| u8::try_from(slot).ok())
+                    .unwrap_or(self.active_slot);
+                if self.slot(slot)?.state != SlotState::Unsealed {
+                    return Err(CkTapError::InvalidState);
+                }
+                (slot, fixtures::slot_key(self.slot_master(slot)?))
+            }
+            CardKind::TapSigner | CardKind::SatsChip => {
+                let sub_path = args.path("subpath")?;
+                if sub_path.len() > 2 || sub_path.iter().any(|&index| index >= HARDENED) {
+                    return Err(CkTapError::BadArguments);
+                }
+                let path = [self.path.clone(), sub_path].concat();
+                (
+                    0,
+                    self.derive_priv(self.tapsigner_master()?, &path)?
+                        .private_key,
+                )
+            }
+        };
+        let message = Message::from_digest(digest);
+        Ok(vec![
+            ("slot", Value::from(slot)),
+            ("sig", self.sign_digest(&message, &key)),
+            (
+                "pubkey",
+                Value::Bytes(key.public_key(&self.secp).serialize().to_vec()),
+            ),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    /// TAPSIGNER `new`: pick [`fixtures::MASTER_KEY`] with the app's chain code, once
+    fn new_tapsigner(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        self.authenticate("new", args)?;
+        if self.master.is_some() {
+            return Err(CkTapError::InvalidState);
+        }
+        let chain_code = args.chain_code()?.ok_or(CkTapError::BadArguments)?;
+        self.master = Some(fixtures::xpriv(
+            fixtures::secret_key(MASTER_KEY),
+            chain_code,
+        ));
+        self.path = DEFAULT_PATH.to_vec();
+        Ok(vec![
+            ("slot", Value::from(0)),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    /// SATSCARD `new`: set up the active slot once the previous one was unsealed
+    fn new_slot(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        self.authenticate("new", args)?;
+        let slot = self.active_slot_arg(args)?;
+        if self.slot(slot)?.state != SlotState::Unused {
+            return Err(CkTapError::InvalidState);
+        }
+        let chain_code = args.chain_code()?.unwrap_or(CHAIN_CODE);
+        self.slots[usize::from(slot)] = Slot {
+            master: Some(fixtures::slot_master(slot, chain_code)),
+            state: SlotState::Sealed,
+        };
+        Ok(vec![
+            ("slot", Value::from(slot)),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    fn unseal(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let session_key = self.authenticate("unseal", args)?;
+        let slot = self.active_slot_arg(args)?;
+        if self.slot(slot)?.state != SlotState::Sealed {
+            return Err(CkTapError::InvalidState);
+        }
+        let master = *self.slot_master(slot)?;
+        self.slots[usize::from(slot)].state = SlotState::Unsealed;
+        if usize::from(slot) + 1 < self.slots.len() {
+            self.active_slot += 1;
+        }
+        let mut fields = vec![("slot", Value::from(slot))];
+        fields.extend(self.slot_secrets(&master, &session_key));
+        fields.push(("card_nonce", self.next_nonce()));
+        Ok(fields)
+    }
+
+    /// The keys of an unsealed slot, private keys encrypted with the session key
+    fn slot_secrets(&self, master: &Xpriv, session_key: &SharedSecret) -> Fields {
+        let key = fixtures::slot_key(master);
+        vec![
+            (
+                "privkey",
+                Value::Bytes(xor(&key.secret_bytes(), session_key.as_ref())),
+            ),
+            (
+                "pubkey",
+                Value::Bytes(key.public_key(&self.secp).serialize().to_vec()),
+            ),
+            (
+                "master_pk",
+                Value::Bytes(xor(
+                    &master.private_key.secret_bytes(),
+                    session_key.as_ref(),
+                )),
+            ),
+            (
+                "chain_code",
+                Value::Bytes(master.chain_code.to_bytes().to_vec()),
+            ),
+        ]
+    }
+
+    fn dump(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let slot = args
+            .int("slot")
+            .and_then(|slot| u8::try_from(slot).ok())
+            .ok_or(CkTapError::BadArguments)?;
+        let session_key = match args.bytes("epubkey") {
+            Some(_) => Some(self.authenticate("dump", args)?),
+            None => None,
+        };
+        let Slot { master, state } = self.slot(slot)?.clone();
+        let mut fields = vec![("slot", Value::from(slot))];
+        match (master, state, session_key) {
+            (Some(master), SlotState::Unsealed, Some(session_key)) => {
+                fields.extend(self.slot_secrets(&master, &session_key));
+            }
+            (master, state, _) => {
+                fields.push(("used", Value::Bool(state != SlotState::Unused)));
+                fields.push(("sealed", Value::Bool(state == SlotState::Sealed)));
+                if let (Some(master), SlotState::Unsealed) = (master, state) {
+                    let pubkey = fixtures::slot_key(&master).public_key(&self.secp);
+                    fields.push(("addr", Value::from(fixtures::address(&pubkey))));
+                }
+            }
+        }
+        fields.push(("card_nonce", self.next_nonce()));
+        Ok(fields)
+    }
+
+    fn xpub(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        self.authenticate("xpub", args)?;
+        let master = *self.tapsigner_master()?;
+        let xpriv = match args.bool("master") {
+            Some(true) => master,
+            _ => self.derive_priv(&master, &self.path)?,
+        };
+        let xpub = Xpub::from_priv(&self.secp, &xpriv);
+        Ok(vec![
+            ("xpub", Value::Bytes(xpub.encode().to_vec())),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    fn change(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        let session_key = self.authenticate("change", args)?;
+        let new_cvc = args
+            .bytes("data")
+            .map(|data| xor(data, session_key.as_ref()))
+            .filter(|cvc| (6..=32).contains(&cvc.len()))
+            .ok_or(CkTapError::BadArguments)?;
+        self.cvc = new_cvc;
+        Ok(vec![
+            ("success", Value::Bool(true)),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    /// The backup is the master xprv in the clear, not encrypted like a real card's
+    fn backup(&mut self, args: &Args) -> Result<Fields, CkTapError> {
+        self.authenticate("backup", args)?;
+        let master = *self.tapsigner_master()?;
+        self.num_backups += 1;
+        Ok(vec![
+            ("data", Value::Bytes(master.to_string().into_bytes())),
+            ("card_nonce", self.next_nonce()),
+        ])
+    }
+
+    fn wait(&mut self) -> Fields {
+        self.auth_delay = self.auth_delay.saturating_sub(1);
+        vec![
+            ("success", Value::Bool(true)),
+            ("auth_delay", Value::from(self.auth_delay as u64)),
+        ]
+    }
+
+    fn nfc(&self) -> Fields {
+        let url = match self.kind {
+            CardKind::SatsCard => "https://getsatscard.com/start",
+            CardKind::TapSigner | CardKind::SatsChip => "https://tapsigner.com/start",
+        };
+        vec![("url", Value::from(url))]
+    }
+}
+
+/// Fields of a response map, in order
+type Fields = Vec<(&'static str, Value)>;
+
+fn response(fields: Fields) -> Vec<u8> {
+    let map = Value::Map(
+        fields
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value))
+            .collect(),
+    );
+    let mut rapdu = Vec::new();
+    ciborium::ser::into_writer(&map, &mut rapdu).expect("CBOR encoding to a Vec");
+    rapdu.extend(SW_OK);
+    rapdu
+}
+
+fn error_response(error: CkTapError) -> Vec<u8> {
+    response(vec![
+        ("error", Value::from(error.to_string())),
+        ("code", Value::from(error.error_code())),
+    ])
+}
+
+fn xor(data: &[u8], key: &[u8]) -> Vec<u8> {
+    data.iter().zip(key).map(|(a, b)| a ^ b).collect()
+}
+
+fn int_array(values: &[u32]) -> Value {
+    Value::Array(values.iter().map(|&value| Value::from(value)).collect())
+}
+
+/// The card hides the middle of the address in `status`
+fn censor(address: &str) -> String {
+    format!(
+        "{start}___{end}",
+        start = &address[..10],
+        end = &address[address.len() - 10..]
+    )
+}
+
+/// Arguments of a command
+struct Args(Vec<(Value, Value)>);
+
+impl Args {
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.as_text() == Some(key))
+            .map(|(_, value)| value)
+    }
+
+    fn text(&self, key: &str) -> Option<&str> {
+        self.get(key)?.as_text()
+    }
+
+    fn bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get(key)?.as_bytes().map(Vec::as_slice)
+    }
+
+    fn int(&self, key: &str) -> Option<u64> {
+        let value = self.get(key)?.as_integer()?;
+        u64::try_from(value).ok()
+    }
+
+    fn bool(&self, key: &str) -> Option<bool> {
+        self.get(key)?.as_bool()
+    }
+
+    fn nonce(&self) -> Result<&[u8; 16], CkTapError> {
+        let nonce: &[u8; 16] = self
+            .bytes("nonce")
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or(CkTapError::BadArguments)?;
+        // the card refuses nonces of a single repeated byte
+        if nonce.iter().all(|&byte| byte == nonce[0]) {
+            return Err(CkTapError::WeakNonce);
+        }
+        Ok(nonce)
+    }
+
+    fn chain_code(&self) -> Result<Option<[u8; 32]>, CkTapError> {
+        self.bytes("chain_code")
+            .map(|chain_code| chain_code.try_into().map_err(|_| CkTapError::BadArguments))
+            .transpose()
+    }
+
+    /// A derivation path, empty when absent
+    fn path(&self, key: &str) -> Result<Vec<u32>, CkTapError> {
+        match self.get(key) {
+            None | Some(Value::Null) => Ok(Vec::new()),
+            Some(Value::Array(steps)) => steps
+                .iter()
+                .map(|step| {
+                    step.as_integer()
+                        .and_then(|step| u32::try_from(step).ok())
+                        .ok_or(CkTapError::BadArguments)
+                })
+                .collect(),
+            Some(_) => Err(CkTapError::BadArguments),
+        }
+    }
+}
+
+/// [`CkTransport`] to a [`TestCard`]. Clones share the card, so a test can keep one to look at
+/// the card's state after the library used it.
+#[derive(Debug, Clone)]
+pub struct TestTransport {
+    card: Arc<Mutex<TestCard>>,
+    /// With [`TestTransport::fragmenting`], the size of the chunks and the answer not sent yet
+    chunk_size: Option<usize>,
+    pending: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TestTransport {
+    pub fn new(card: TestCard) -> Self {
+        Self {
+            card: Arc::new(Mutex::new(card)),
+            chunk_size: None,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Answer like a reader returning long answers in `chunk_size` byte chunks, each but the last
+    /// ending with `61xx` for the library to fetch the next one with GET RESPONSE
+    pub fn fragmenting(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    fn next_chunk(&self, chunk_size: usize, answer: Option<Vec<u8>>) -> Vec<u8> {
+        let mut pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(answer) = answer {
+            *pending = answer;
+        }
+        let len = chunk_size.min(pending.len());
+        let mut chunk: Vec<u8> = pending.drain(..len).collect();
+        match pending.len() {
+            0 => chunk.extend(SW_OK),
+            // 00 for 256 bytes or more
+            remaining => chunk.extend([0x61, remaining.min(256) as u8]),
+        }
+        chunk
+    }
+
+    pub fn card(&self) -> MutexGuard<'_, TestCard> {
+        self.card
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CkTransport for TestTransport {
+    async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let Some(chunk_size) = self.chunk_size else {
+            return Ok(self.card().transmit(&command_apdu));
+        };
+        if command_apdu.starts_with(&GET_RESPONSE) {
+            return Ok(self.next_chunk(chunk_size, None));
+        }
+        let mut rapdu = self.card().transmit(&command_apdu);
+        if !rapdu.ends_with(&SW_OK) {
+            return Ok(rapdu);
+        }
+        rapdu.truncate(rapdu.len() - SW_OK.len());
+        Ok(self.next_chunk(chunk_size, Some(rapdu)))
+    }
+}
+
+/// Select the test card like a reader would and return it as the library's card type
+pub async fn connect(card: TestCard) -> Result<CkTapCard<TestTransport>, Error> {
+    TestTransport::new(card).to_cktap().await
+}
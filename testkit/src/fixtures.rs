@@ -0,0 +1,98 @@
+//! The known keys and values of the test cards, to compute what a test should expect.
+
+use bitcoin::bip32::{ChainCode, ChildNumber, Xpriv};
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::key::CompressedPublicKey;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::{Address, Network, NetworkKind};
+
+/// CVC of every test card, unless changed with [`crate::TestCard::with_cvc`]
+pub const CVC: &str = "123456";
+
+/// Version the test cards report, new enough for every command the library knows
+pub const VERSION: &str = "1.0.3";
+
+/// Block height the test cards report as their birth
+pub const BIRTH: usize = 700_000;
+
+/// Number of slots of the test SATSCARD
+pub const NUM_SLOTS: u8 = 10;
+
+/// Private key identifying the card (its `pubkey` in `status`)
+pub const CARD_KEY: [u8; 32] = [0x11; 32];
+/// Key of the test factory batch, certifying the card key
+pub const BATCH_KEY: [u8; 32] = [0x22; 32];
+/// Key of the test factory root, certifying the batch key. It is *not* a Coinkite root, so
+/// `check_certificate` rejects the test cards as counterfeit; use
+/// [`crate::assert::check_test_certificate`] to check their chain.
+pub const ROOT_KEY: [u8; 32] = [0x33; 32];
+/// TAPSIGNER master private key, picked by `new`
+pub const MASTER_KEY: [u8; 32] = [0x44; 32];
+/// Chain code the initialized TAPSIGNER and the first SATSCARD slot were set up with
+pub const CHAIN_CODE: [u8; 32] = [0x55; 32];
+
+/// Path a TAPSIGNER uses after `new`, m/84'/0'/0'
+pub const DEFAULT_PATH: [u32; 3] = [84 | HARDENED, HARDENED, HARDENED];
+
+pub(crate) const HARDENED: u32 = 1 << 31;
+
+pub fn secret_key(bytes: [u8; 32]) -> SecretKey {
+    SecretKey::from_slice(&bytes).expect("valid fixture key")
+}
+
+pub fn card_pubkey() -> PublicKey {
+    PublicKey::from_secret_key(&Secp256k1::new(), &secret_key(CARD_KEY))
+}
+
+pub fn root_pubkey() -> PublicKey {
+    PublicKey::from_secret_key(&Secp256k1::new(), &secret_key(ROOT_KEY))
+}
+
+/// Master key of `slot` on the test SATSCARD: a key picked for the slot and its chain code
+pub fn slot_master(slot: u8, chain_code: [u8; 32]) -> Xpriv {
+    let key = sha256::Hash::hash(&[&MASTER_KEY[..], &[slot]].concat());
+    xpriv(secret_key(key.to_byte_array()), chain_code)
+}
+
+/// Private key of a SATSCARD slot, `m/0` from its master key
+pub fn slot_key(master: &Xpriv) -> SecretKey {
+    master
+        .derive_priv(&Secp256k1::new(), &[ChildNumber::Normal { index: 0 }])
+        .expect("derivable slot key")
+        .private_key
+}
+
+/// Payment address of SATSCARD slot 0, as set up by [`crate::TestCard::satscard`]
+pub fn satscard_address() -> String {
+    let key = slot_key(&slot_master(0, CHAIN_CODE));
+    address(&PublicKey::from_secret_key(&Secp256k1::new(), &key))
+}
+
+/// TAPSIGNER master key, as set up by [`crate::TestCard::tapsigner`]
+pub fn tapsigner_master() -> Xpriv {
+    xpriv(secret_key(MASTER_KEY), CHAIN_CODE)
+}
+
+/// Key the TAPSIGNER signs with at `path` (hardened steps have the high bit set)
+pub fn tapsigner_key(path: &[u32]) -> Xpriv {
+    let path: Vec<ChildNumber> = path.iter().map(|&index| ChildNumber::from(index)).collect();
+    tapsigner_master()
+        .derive_priv(&Secp256k1::new(), &path)
+        .expect("derivable path")
+}
+
+pub(crate) fn xpriv(private_key: SecretKey, chain_code: [u8; 32]) -> Xpriv {
+    Xpriv {
+        network: NetworkKind::Main,
+        depth: 0,
+        parent_fingerprint: Default::default(),
+        child_number: ChildNumber::Normal { index: 0 },
+        private_key,
+        chain_code: ChainCode::from(chain_code),
+    }
+}
+
+/// P2WPKH mainnet address of `pubkey`, the kind a SATSCARD shows
+pub fn address(pubkey: &PublicKey) -> String {
+    Address::p2wpkh(&CompressedPublicKey(*pubkey), Network::Bitcoin).to_string()
+}
@@ -0,0 +1,27 @@
+//! Test TAPSIGNER and SATSCARD for code using `cktap-direct`, without hardware or the Python
+//! emulator: [`TestCard`] answers the protocol in memory with the known keys of [`fixtures`],
+//! [`transcript`] records and replays sessions, and [`assert`] checks the answers.
+//!
+//! ```
+//! # async fn example() -> Result<(), cktap_direct::Error> {
+//! use cktap_direct::CkTapCard;
+//! use cktap_testkit::{TestCard, assert, fixtures};
+//!
+//! let CkTapCard::TapSigner(mut card) = cktap_testkit::connect(TestCard::tapsigner()).await?
+//! else {
+//!     unreachable!()
+//! };
+//! let response = card.sign([1; 32], vec![0, 0], &fixtures::CVC.into()).await?;
+//! let path = [&fixtures::DEFAULT_PATH[..], &[0, 0]].concat();
+//! assert::assert_tapsigner_signed(&response, [1; 32], &path);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod assert;
+mod card;
+pub mod fixtures;
+pub mod transcript;
+
+pub use card::{AUTH_DELAY, CardKind, SlotState, TestCard, TestTransport, connect};
+pub use transcript::SeededEntropy;
@@ -0,0 +1,250 @@
+//! Canned transcripts: the APDUs of a session with a test card, recorded once and replayed
+//! without it. A [`Replay`] fails on the first command that differs from the transcript, so a
+//! test notices when the library starts sending something else.
+//!
+//! The app side of a session is only reproducible with fixed nonces and ephemeral keys, use
+//! [`SeededEntropy`] with the card types' `with_entropy`.
+
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use bitcoin::hex::{DisplayHex, FromHex};
+use ciborium::value::Value;
+use cktap_direct::apdu::{AppletSelect, CommandApdu as _, Error};
+use cktap_direct::commands::CkTransport;
+use cktap_direct::entropy::EntropySource;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// A TAPSIGNER from [`crate::TestCard::tapsigner`] selected, reading its pubkey and signing
+/// [`SIGNED_DIGEST`] at subpath 0/0, with `SeededEntropy::new(0)`
+pub const TAPSIGNER_SIGN: &str = include_str!("../transcripts/tapsigner_sign.txt");
+
+/// A SATSCARD from [`crate::TestCard::satscard`] selected, verifying its address and unsealing slot 0,
+/// with `SeededEntropy::new(0)`
+pub const SATSCARD_UNSEAL: &str = include_str!("../transcripts/satscard_unseal.txt");
+
+/// Digest signed in [`TAPSIGNER_SIGN`]
+pub const SIGNED_DIGEST: [u8; 32] = [0x42; 32];
+
+/// Deterministic [`EntropySource`]: sha256 of the seed and a counter
+#[derive(Debug)]
+pub struct SeededEntropy {
+    seed: u64,
+    counter: Mutex<u64>,
+}
+
+impl SeededEntropy {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            counter: Mutex::new(0),
+        }
+    }
+}
+
+impl EntropySource for SeededEntropy {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        let mut counter = self
+            .counter
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for chunk in dest.chunks_mut(32) {
+            *counter += 1;
+            let mut engine = sha256::Hash::engine();
+            engine.input(&self.seed.to_be_bytes());
+            engine.input(&counter.to_be_bytes());
+            let block = sha256::Hash::from_engine(engine);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub command: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+/// The exchanges of a session. As text, one `> command` and one `< response` line in hex per
+/// exchange; blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Transcript {
+    /// [`TAPSIGNER_SIGN`], parsed
+    pub fn tapsigner_sign() -> Self {
+        TAPSIGNER_SIGN.parse().expect("valid canned transcript")
+    }
+
+    /// [`SATSCARD_UNSEAL`], parsed
+    pub fn satscard_unseal() -> Self {
+        SATSCARD_UNSEAL.parse().expect("valid canned transcript")
+    }
+
+    /// Names of the commands sent, `select` for the applet selection
+    pub fn commands(&self) -> Vec<String> {
+        self.exchanges
+            .iter()
+            .map(|exchange| {
+                if AppletSelect::default()
+                    .apdu_bytes()
+                    .is_ok_and(|select| exchange.command == select)
+                {
+                    return "select".to_string();
+                }
+                let cmd = exchange.command.get(5..).and_then(|cbor| {
+                    let Ok(Value::Map(args)) = ciborium::de::from_reader(cbor) else {
+                        return None;
+                    };
+                    args.into_iter()
+                        .find(|(key, _)| key.as_text() == Some("cmd"))
+                        .and_then(|(_, cmd)| cmd.into_text().ok())
+                });
+                cmd.unwrap_or_else(|| exchange.command.as_hex().to_string())
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for Exchange { command, response } in &self.exchanges {
+            writeln!(f, "> {}", command.as_hex())?;
+            writeln!(f, "< {}", response.as_hex())?;
+        }
+        Ok(())
+    }
+}
+
+/// A transcript line that isn't a command or response in hex, or a response without a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTranscriptError {
+    pub line: usize,
+}
+
+impl fmt::Display for ParseTranscriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid transcript line {line}", line = self.line)
+    }
+}
+
+impl std::error::Error for ParseTranscriptError {}
+
+impl FromStr for Transcript {
+    type Err = ParseTranscriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut exchanges = Vec::new();
+        let mut command = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = ParseTranscriptError { line: i + 1 };
+            let (direction, hex) = line.split_at_checked(1).ok_or(error.clone())?;
+            let bytes = Vec::from_hex(hex.trim()).map_err(|_| error.clone())?;
+            match (direction, command.take()) {
+                (">", None) => command = Some(bytes),
+                ("<", Some(command)) => exchanges.push(Exchange {
+                    command,
+                    response: bytes,
+                }),
+                _ => return Err(error),
+            }
+        }
+        if command.is_some() {
+            return Err(ParseTranscriptError {
+                line: s.lines().count(),
+            });
+        }
+        Ok(Self { exchanges })
+    }
+}
+
+/// [`CkTransport`] recording the exchanges with the transport it wraps
+#[derive(Debug)]
+pub struct Recorder<T> {
+    inner: T,
+    transcript: Mutex<Transcript>,
+}
+
+impl<T: CkTransport> Recorder<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            transcript: Mutex::new(Transcript::default()),
+        }
+    }
+
+    /// The exchanges recorded so far
+    pub fn transcript(&self) -> Transcript {
+        self.transcript
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl<T: CkTransport> CkTransport for Recorder<T> {
+    async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let response = self.inner.transmit_apdu(command_apdu.clone()).await?;
+        self.transcript
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .exchanges
+            .push(Exchange {
+                command: command_apdu,
+                response: response.clone(),
+            });
+        Ok(response)
+    }
+}
+
+/// [`CkTransport`] answering from a transcript, as long as the commands match it
+#[derive(Debug)]
+pub struct Replay {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replay {
+    pub fn new(transcript: Transcript) -> Self {
+        Self {
+            exchanges: Mutex::new(transcript.exchanges.into()),
+        }
+    }
+
+    /// Exchanges of the transcript not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+}
+
+impl CkTransport for Replay {
+    async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut exchanges = self
+            .exchanges
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(exchange) = exchanges.pop_front() else {
+            return Err(Error::Ccid(format!(
+                "transcript ended before command {}",
+                command_apdu.as_hex()
+            )));
+        };
+        if exchange.command != command_apdu {
+            return Err(Error::Ccid(format!(
+                "command {} differs from {} in the transcript",
+                command_apdu.as_hex(),
+                exchange.command.as_hex()
+            )));
+        }
+        Ok(exchange.response)
+    }
+}
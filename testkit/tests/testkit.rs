@@ -0,0 +1,557 @@
+use bitcoin::Network;
+use bitcoin::bip32::{ChildNumber, Xpub};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use cktap_direct::apdu::{CkTapError, Error};
+use cktap_direct::backup_policy::{BackupRule, BackupState};
+use cktap_direct::challenge::{CHALLENGE_SUB_PATH, Challenge, ChallengeResponse};
+use cktap_direct::commands::{Authentication as _, CkTransport, Read as _, Wait as _};
+use cktap_direct::managed::ManagedCard;
+use cktap_direct::progress::Step;
+use cktap_direct::slot_url::{self, SlotProof};
+use cktap_direct::tap_signer::TapSignerError;
+use cktap_direct::version::FirmwareVersion;
+use cktap_direct::{CkTapCard, Cvc, SatsCard, TapSigner, sats_card};
+use cktap_testkit::assert::{assert_card_error, assert_tapsigner_signed, check_test_certificate};
+use cktap_testkit::transcript::{Recorder, Replay, SIGNED_DIGEST, Transcript};
+use cktap_testkit::{
+    AUTH_DELAY, SeededEntropy, SlotState, TestCard, TestTransport, connect, fixtures,
+};
+use std::sync::{Arc, Mutex};
+
+fn cvc() -> Cvc {
+    Cvc::from(fixtures::CVC)
+}
+
+async fn tapsigner<T: CkTransport>(transport: T) -> Result<TapSigner<T>, Error> {
+    match transport.to_cktap().await? {
+        CkTapCard::TapSigner(card) => Ok(card.with_entropy(SeededEntropy::new(0))),
+        card => panic!("expected a TAPSIGNER, got {card:?}"),
+    }
+}
+
+async fn satscard<T: CkTransport>(transport: T) -> Result<SatsCard<T>, Error> {
+    match transport.to_cktap().await? {
+        CkTapCard::SatsCard(card) => Ok(card.with_entropy(SeededEntropy::new(0))),
+        card => panic!("expected a SATSCARD, got {card:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_tapsigner() -> Result<(), TapSignerError> {
+    let transport = TestTransport::new(TestCard::tapsigner());
+    let mut card = tapsigner(transport.clone()).await?;
+    let secp = Secp256k1::new();
+
+    let read = card.read(Some(&cvc())).await?;
+    let default_key = fixtures::tapsigner_key(&fixtures::DEFAULT_PATH);
+    assert_eq!(read.card_nonce, transport.card().card_nonce());
+
+    let xpub = card.xpub(false, &cvc()).await?;
+    assert_eq!(xpub, Xpub::from_priv(&secp, &default_key));
+
+    let derived = card.derive(&[48, 0, 0, 2], &cvc()).await?;
+    let path: Vec<u32> = [48, 0, 0, 2].map(|index| index | 1 << 31).to_vec();
+    assert_eq!(transport.card().path(), path);
+    let expected = Xpub::from_priv(&secp, &fixtures::tapsigner_key(&path));
+    assert_eq!(derived.xpub.public_key, expected.public_key);
+    assert_eq!(derived.xpub.chain_code, expected.chain_code);
+    assert_eq!(derived.xpub.depth, 4);
+    let master = Xpub::from_priv(&secp, &fixtures::tapsigner_master());
+    assert_eq!(derived.master_pubkey, master.public_key);
+    assert_eq!(
+        derived.origin,
+        (
+            master.fingerprint(),
+            path.iter().copied().map(ChildNumber::from).collect()
+        )
+    );
+
+    let response = card.sign(SIGNED_DIGEST, vec![1, 5], &cvc()).await?;
+    assert_tapsigner_signed(&response, SIGNED_DIGEST, &[&path[..], &[1, 5]].concat());
+
+    card.change(&Cvc::from("654321"), &cvc()).await?;
+    assert_eq!(transport.card().cvc(), "654321");
+    let backup = card.backup(&Cvc::from("654321")).await?;
+    assert_eq!(
+        backup.data,
+        fixtures::tapsigner_master().to_string().into_bytes()
+    );
+    assert_eq!(transport.card().num_backups(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_init_tapsigner() -> Result<(), TapSignerError> {
+    let transport = TestTransport::new(TestCard::uninitialized_tapsigner());
+    let mut card = tapsigner(transport.clone()).await?;
+    assert_card_error(card.xpub(true, &cvc()).await, CkTapError::InvalidState);
+
+    card.init(fixtures::CHAIN_CODE, &cvc()).await?;
+    let xpub = card.xpub(true, &cvc()).await?;
+    assert_eq!(
+        xpub,
+        Xpub::from_priv(&Secp256k1::new(), &fixtures::tapsigner_master())
+    );
+    assert_card_error(
+        card.init(fixtures::CHAIN_CODE, &cvc()).await,
+        CkTapError::InvalidState,
+    );
+    Ok(())
+}
+
+/// Transport rewriting a field of the card's `sign` responses, like a misbehaving reader
+struct Tamper {
+    inner: TestTransport,
+    field: &'static str,
+    tamper: fn(&mut Vec<u8>),
+}
+
+impl CkTransport for Tamper {
+    async fn transmit_apdu(&self, command_apdu: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let response = self.inner.transmit_apdu(command_apdu).await?;
+        let signed = self.inner.card().commands().last().map(String::as_str) == Some("sign");
+        if !signed || !response.ends_with(&[0x90, 0x00]) {
+            return Ok(response);
+        }
+        let mut value: ciborium::Value =
+            ciborium::from_reader(&response[..response.len() - 2]).expect("cbor response");
+        for (key, value) in value.as_map_mut().expect("cbor map") {
+            if key.as_text() == Some(self.field) {
+                let ciborium::Value::Bytes(bytes) = value else {
+                    panic!("{} is not bytes", self.field);
+                };
+                (self.tamper)(bytes);
+            }
+        }
+        let mut tampered = Vec::new();
+        ciborium::into_writer(&value, &mut tampered).expect("cbor");
+        tampered.extend([0x90, 0x00]);
+        Ok(tampered)
+    }
+}
+
+#[tokio::test]
+async fn test_sign_verification() -> Result<(), TapSignerError> {
+    let transport = TestTransport::new(TestCard::tapsigner());
+    let tamper = |field, tamper| Tamper {
+        inner: transport.clone(),
+        field,
+        tamper,
+    };
+    let mismatch = |result| matches!(result, Err(Error::SignatureMismatch(_)));
+
+    let mut card = tapsigner(tamper("sig", |sig| sig[63] ^= 1)).await?;
+    assert!(mismatch(card.sign(SIGNED_DIGEST, vec![0, 0], &cvc()).await));
+
+    // a valid signature, but by another key than the one at the card's path
+    let mut card = tapsigner(tamper("pubkey", |pubkey| {
+        *pubkey = fixtures::tapsigner_key(&[7])
+            .private_key
+            .public_key(&Secp256k1::new())
+            .serialize()
+            .to_vec();
+    }))
+    .await?;
+    card.xpub(false, &cvc()).await?;
+    assert!(mismatch(card.sign(SIGNED_DIGEST, vec![0, 0], &cvc()).await));
+
+    let mut card = tapsigner(tamper("none", |_| {})).await?;
+    card.derive(&[48, 0, 0, 2], &cvc()).await?;
+    card.sign(SIGNED_DIGEST, vec![1, 5], &cvc()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_required_version() -> Result<(), TapSignerError> {
+    let card = TestCard::uninitialized_tapsigner().with_version("1.0.2");
+    let transport = TestTransport::new(card);
+    let mut card = tapsigner(transport.clone())
+        .await?
+        .require_version(FirmwareVersion::new(1, 0, 3));
+    assert!(matches!(
+        card.init(fixtures::CHAIN_CODE, &cvc()).await,
+        Err(TapSignerError::ApduError(
+            Error::UnsupportedByFirmware { .. }
+        ))
+    ));
+    // refused before sending anything
+    assert_eq!(transport.card().commands(), ["select"]);
+
+    card.required_version = Some(FirmwareVersion::new(1, 0, 2));
+    card.init(fixtures::CHAIN_CODE, &cvc()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_feature_versions() -> Result<(), TapSignerError> {
+    let unsupported = |result: Result<(), TapSignerError>| {
+        matches!(
+            result,
+            Err(TapSignerError::ApduError(
+                Error::UnsupportedByFirmware { .. }
+            ))
+        )
+    };
+    let transport = TestTransport::new(TestCard::tapsigner().with_version("0.9.9"));
+    let mut card = tapsigner(transport.clone()).await?;
+    assert!(unsupported(card.xpub(false, &cvc()).await.map(|_| ())));
+    assert!(unsupported(card.backup(&cvc()).await.map(|_| ())));
+    assert!(unsupported(card.change(&cvc(), &cvc()).await.map(|_| ())));
+    let mut card = card.with_backup_policy(|_: &PublicKey, _: Option<usize>| Ok(()));
+    assert!(unsupported(
+        card.sign(SIGNED_DIGEST, vec![0, 0], &cvc())
+            .await
+            .map(|_| ())
+            .map_err(TapSignerError::from)
+    ));
+    // refused before sending anything
+    assert_eq!(transport.card().commands(), ["select"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backup_policy() -> Result<(), TapSignerError> {
+    let transport = TestTransport::new(TestCard::tapsigner());
+    let rule = BackupRule {
+        max_signatures: Some(1),
+        enforce: true,
+    };
+    let state = Mutex::new(BackupState::default());
+    let mut card = tapsigner(transport.clone()).await?.with_backup_policy(
+        move |_: &PublicKey, num_backups: Option<usize>| match state
+            .lock()
+            .unwrap()
+            .record_signature(num_backups, &rule)
+        {
+            Some(reminder) => Err(Error::BackupRequired(reminder.to_string())),
+            None => Ok(()),
+        },
+    );
+    let sent = transport.card().commands().len();
+    assert!(matches!(
+        card.sign(SIGNED_DIGEST, vec![], &cvc()).await,
+        Err(Error::BackupRequired(_))
+    ));
+    // refused before sending anything
+    assert_eq!(transport.card().commands().len(), sent);
+
+    card.backup(&cvc()).await?;
+    assert_eq!(card.num_backups, Some(1));
+    card.sign(SIGNED_DIGEST, vec![], &cvc()).await?;
+    assert!(matches!(
+        card.sign(SIGNED_DIGEST, vec![], &cvc()).await,
+        Err(Error::BackupRequired(message)) if message.contains("signed 1 times")
+    ));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_managed_card() -> Result<(), Error> {
+    let transport = TestTransport::new(TestCard::tapsigner());
+    let card = ManagedCard::from(transport.clone().to_cktap().await?);
+
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let card = card.clone();
+            tokio::spawn(async move {
+                let CkTapCard::TapSigner(ts) = &mut *card.lock().await else {
+                    panic!("expected a TAPSIGNER");
+                };
+                ts.read(Some(&cvc())).await.map(|_| ())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.expect("task")?;
+    }
+    // every read was computed with the nonce the previous one left
+    assert_eq!(transport.card().commands().len(), 9);
+
+    let Ok(CkTapCard::TapSigner(ts)) = card.into_inner() else {
+        panic!("expected the only handle to a TAPSIGNER");
+    };
+    assert_eq!(*ts.card_nonce(), transport.card().card_nonce());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limiting() -> Result<(), Error> {
+    let transport = TestTransport::new(TestCard::tapsigner().with_cvc("999999"));
+    let mut card = tapsigner(transport.clone()).await?;
+    for _ in 0..3 {
+        assert_card_error(card.read(Some(&cvc())).await, CkTapError::BadAuth);
+    }
+    assert_card_error(card.read(Some(&cvc())).await, CkTapError::RateLimited);
+    assert_eq!(transport.card().auth_delay(), AUTH_DELAY);
+
+    for _ in 0..AUTH_DELAY {
+        card.wait(None).await?;
+    }
+    card.read(Some(&Cvc::from("999999"))).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_derive_many_bad_cvc() -> Result<(), TapSignerError> {
+    let transport = TestTransport::new(TestCard::tapsigner());
+    let mut card = tapsigner(transport.clone()).await?;
+    card.derive(&[48, 0, 0, 2], &cvc()).await?;
+    let path = transport.card().path().to_vec();
+    let derives = |transport: &TestTransport| {
+        let card = transport.card();
+        card.commands()
+            .iter()
+            .filter(|name| *name == "derive")
+            .count()
+    };
+    let before = derives(&transport);
+
+    assert_card_error(
+        card.derive_many(&[vec![84, 0, 0]], &Cvc::from("999999"))
+            .await,
+        CkTapError::BadAuth,
+    );
+    assert_eq!(derives(&transport), before + 1);
+    assert_eq!(transport.card().path(), path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dump_then_unseal() -> Result<(), Error> {
+    let transport = TestTransport::new(TestCard::satscard());
+    let mut card = satscard(transport.clone()).await?;
+
+    // as `satscard unseal --slot` does: check the slot first, then unseal it with the nonce the
+    // dump answered with
+    let dump = card.dump(0, None).await?;
+    assert_eq!(dump.sealed, Some(true));
+    card.unseal(0, &cvc()).await?;
+    assert_eq!(transport.card().slot_state(0), Some(SlotState::Unsealed));
+
+    card.dump(0, Some(&cvc())).await?;
+    card.new_slot(1, Some([0x66; 32]), &cvc()).await?;
+    assert_eq!(transport.card().slot_state(1), Some(SlotState::Sealed));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_satscard() -> Result<(), Error> {
+    let transport = TestTransport::new(TestCard::satscard());
+    let mut card = satscard(transport.clone()).await?;
+
+    let verification = card.verify_address().await?;
+    assert_eq!(verification.address, fixtures::satscard_address());
+
+    let unsealed = card.unseal(0, &cvc()).await?;
+    let privkey = unsealed.privkey.clone();
+    assert_eq!(transport.card().slot_state(0), Some(SlotState::Unsealed));
+    assert_eq!(
+        PublicKey::from_slice(&unsealed.pubkey).map_err(Error::from)?,
+        verification.pubkey
+    );
+    // the keys come encrypted with the session key, unseal decrypts them
+    let master = fixtures::slot_master(0, fixtures::CHAIN_CODE);
+    assert_eq!(privkey, fixtures::slot_key(&master).secret_bytes());
+    assert_eq!(unsealed.master_pk, master.private_key.secret_bytes());
+    let recovered =
+        sats_card::recover_slot(&unsealed.master_pk, &unsealed.chain_code, Network::Bitcoin)?;
+    assert_eq!(recovered.privkey.inner.secret_bytes(), privkey[..]);
+    assert_eq!(recovered.address.to_string(), fixtures::satscard_address());
+
+    assert_eq!(transport.card().active_slot(), 1);
+    assert_card_error(card.read(None).await, CkTapError::InvalidState);
+    card.new_slot(1, Some([0x66; 32]), &cvc()).await?;
+    assert_eq!(transport.card().slot_state(1), Some(SlotState::Sealed));
+    // select again for the address of the new slot
+    let mut card = satscard(transport.clone()).await?;
+    let verification = card.verify_address().await?;
+    assert_eq!(verification.slot, 1);
+
+    let dump = card.dump(0, None).await?;
+    assert_eq!(dump.sealed, Some(false));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_challenge() -> Result<(), Error> {
+    let mut card = tapsigner(TestTransport::new(TestCard::tapsigner())).await?;
+    let challenge = Challenge::random();
+    let response = card.sign_challenge(challenge, &cvc()).await?;
+
+    let path = [&fixtures::DEFAULT_PATH[..], &CHALLENGE_SUB_PATH].concat();
+    let enrolled = fixtures::tapsigner_key(&path)
+        .private_key
+        .public_key(&Secp256k1::new());
+    assert_eq!(response.pubkey, enrolled);
+    assert_eq!(response.path.to_string(), "84'/0'/0'/1667785068");
+    response.verify(&enrolled)?;
+
+    // another challenge, or another card's key, doesn't verify
+    let replayed = ChallengeResponse {
+        challenge: Challenge([0; 32]),
+        ..response.clone()
+    };
+    assert!(matches!(
+        replayed.verify(&enrolled),
+        Err(Error::SignatureMismatch(_))
+    ));
+    assert!(response.verify(&fixtures::card_pubkey()).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_derive() -> Result<(), Error> {
+    let mut card = satscard(TestTransport::new(TestCard::satscard())).await?;
+    let check = card.check_derive().await?;
+    assert!(check.signature_valid && check.pubkey_matches());
+    assert_eq!(check.address_matches(), Some(true));
+    assert!(check.passed());
+    assert_eq!(check.address, fixtures::satscard_address());
+    assert_eq!(check.chain_code.to_bytes(), fixtures::CHAIN_CODE);
+
+    // a card reporting another address fails only that check
+    card.addr = Some("bc1qxxxxxx___xw7kv8f3t4".to_string());
+    let check = card.check_derive().await?;
+    assert!(check.signature_valid && check.pubkey_matches());
+    assert_eq!(check.address_matches(), Some(false));
+    assert!(!check.passed());
+    assert!(matches!(
+        card.verify_address().await,
+        Err(Error::AddressMismatch(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slot_proof() -> Result<(), Error> {
+    let mut card = satscard(TestTransport::new(TestCard::satscard())).await?;
+    let proof = card.slot_proof(Network::Bitcoin).await?;
+    assert_eq!(proof.address, fixtures::satscard_address());
+
+    let url = proof.url(slot_url::DEFAULT_BASE_URL);
+    let parsed = SlotProof::from_url(&url)?;
+    assert_eq!(parsed, proof);
+    let slot_key = fixtures::slot_key(&fixtures::slot_master(0, fixtures::CHAIN_CODE));
+    assert_eq!(
+        parsed.verify(Network::Bitcoin)?,
+        slot_key.public_key(&Secp256k1::new())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fragmenting_reader() -> Result<(), Error> {
+    let transport = TestTransport::new(TestCard::satscard()).fragmenting(16);
+    let mut card = satscard(transport).await?;
+    check_test_certificate(&mut card).await?;
+    card.verify_address().await?;
+    let dump = card.dump(0, None).await?;
+    assert_eq!(dump.sealed, Some(true));
+
+    let mut card = tapsigner(TestTransport::new(TestCard::tapsigner()).fragmenting(7)).await?;
+    check_test_certificate(&mut card).await?;
+    card.read(Some(&cvc())).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_map_transport() -> Result<(), Error> {
+    let card = TestTransport::new(TestCard::tapsigner()).to_cktap().await?;
+    let CkTapCard::TapSigner(mut ts) = card.map_transport(Recorder::new) else {
+        panic!("expected a TAPSIGNER");
+    };
+    // the session goes on with the card's nonce and settings over the new transport
+    ts.read(Some(&cvc())).await?;
+    assert_eq!(ts.transport.transcript().exchanges.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_progress() -> Result<(), Error> {
+    let steps = Arc::new(Mutex::new(Vec::new()));
+    let listener = steps.clone();
+    let mut card = satscard(TestTransport::new(TestCard::satscard()))
+        .await?
+        .with_progress(move |step: Step| listener.lock().unwrap().push(step.to_string()));
+
+    card.verify_address().await?;
+    use cktap_direct::commands::Certificate as _;
+    let _ = card.check_certificate().await;
+    assert_eq!(
+        *steps.lock().unwrap(),
+        [
+            "reading the slot pubkey (1/2)",
+            "deriving from the master key (2/2)",
+            "reading certificates (1/3)",
+            "checking the card's key (2/3)",
+            "checking certificate chain (3/3)",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_certificate() -> Result<(), Error> {
+    let mut card = satscard(TestTransport::new(TestCard::satscard())).await?;
+    check_test_certificate(&mut card).await?;
+
+    // the test chain doesn't lead to a Coinkite root
+    use cktap_direct::commands::Certificate as _;
+    assert!(card.check_certificate().await.is_err());
+
+    let CkTapCard::SatsChip(mut card) = connect(TestCard::satschip()).await? else {
+        panic!("expected a SATSCHIP");
+    };
+    check_test_certificate(&mut card).await
+}
+
+#[tokio::test]
+async fn test_transcripts() -> Result<(), Error> {
+    let recorder = Recorder::new(TestTransport::new(TestCard::tapsigner()));
+    let mut card = tapsigner(recorder).await?;
+    card.read(Some(&cvc())).await?;
+    card.sign(SIGNED_DIGEST, vec![0, 0], &cvc()).await?;
+    check_canned(card.transport.transcript(), "tapsigner_sign");
+
+    let recorder = Recorder::new(TestTransport::new(TestCard::satscard()));
+    let mut card = satscard(recorder).await?;
+    card.verify_address().await?;
+    card.unseal(0, &cvc()).await?;
+    check_canned(card.transport.transcript(), "satscard_unseal");
+
+    let mut card = tapsigner(Replay::new(Transcript::tapsigner_sign())).await?;
+    card.read(Some(&cvc())).await?;
+    let response = card.sign(SIGNED_DIGEST, vec![0, 0], &cvc()).await?;
+    let path = [&fixtures::DEFAULT_PATH[..], &[0, 0]].concat();
+    assert_tapsigner_signed(&response, SIGNED_DIGEST, &path);
+    assert_eq!(card.transport.remaining(), 0);
+
+    // a session doing something else fails at the first command that differs
+    let mut card = satscard(Replay::new(Transcript::satscard_unseal())).await?;
+    assert!(matches!(card.unseal(0, &cvc()).await, Err(Error::Ccid(_))));
+    assert_eq!(
+        Transcript::satscard_unseal().commands(),
+        ["select", "read", "derive", "unseal"]
+    );
+    Ok(())
+}
+
+/// Compare a recording with the canned transcript, or rewrite the canned one when
+/// `UPDATE_TRANSCRIPTS` is set
+fn check_canned(recorded: Transcript, name: &str) {
+    let path = format!(
+        "{dir}/transcripts/{name}.txt",
+        dir = env!("CARGO_MANIFEST_DIR")
+    );
+    if std::env::var_os("UPDATE_TRANSCRIPTS").is_some() {
+        std::fs::write(&path, recorded.to_string()).expect("write transcript");
+    }
+    let canned: Transcript = std::fs::read_to_string(&path)
+        .expect("read transcript")
+        .parse()
+        .expect("valid transcript");
+    assert_eq!(
+        recorded, canned,
+        "{name} changed, rerun with UPDATE_TRANSCRIPTS=1"
+    );
+}